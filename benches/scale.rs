@@ -0,0 +1,64 @@
+//! How Horust copes with many tiny oneshot services, like the fan-out of a CI pipeline: all of
+//! them start at once, and a last service starts after all of them.
+//!
+//! The services are simulated, so only Horust's own work is measured:
+//! ```sh
+//! cargo bench --features simulation --bench scale
+//! ```
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use horust::horust::simulation::{Behavior, Simulation};
+use horust::Horust;
+use tempdir::TempDir;
+
+/// How many services are supervised, in each run.
+const SCALES: [usize; 3] = [1_000, 5_000, 10_000];
+
+/// How long each oneshot service runs for.
+const RUN_TIME: Duration = Duration::from_millis(500);
+
+fn main() {
+    println!("services\tload\tsimulation\tsimulated time");
+    for scale in SCALES {
+        let (load, simulation, simulated) = fan_out(scale);
+        println!("{scale}\t{load:?}\t{simulation:?}\t{simulated:?}");
+    }
+}
+
+/// Returns how long it took to load the services, to supervise them until they've all finished,
+/// and the simulated time passed.
+fn fan_out(scale: usize) -> (Duration, Duration, Duration) {
+    let services_dir = TempDir::new("horust-bench").unwrap();
+    let names: Vec<String> = (0..scale).map(|i| format!("job-{i}.toml")).collect();
+    for name in &names {
+        fs::write(services_dir.path().join(name), "command = \"/bin/true\"\n").unwrap();
+    }
+    let start_after = names
+        .iter()
+        .map(|name| format!("\"{name}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    fs::write(
+        services_dir.path().join("report.toml"),
+        format!("command = \"/bin/true\"\nstart-after = [{start_after}]\n"),
+    )
+    .unwrap();
+
+    let started = Instant::now();
+    let horust = Horust::from_services_dirs(&[services_dir.path().into()]).unwrap();
+    let load = started.elapsed();
+
+    let started = Instant::now();
+    let oneshot = Behavior::default().exits_after(RUN_TIME, 0);
+    let mut simulation = names
+        .iter()
+        .chain(std::iter::once(&"report.toml".to_string()))
+        .fold(Simulation::new(&horust), |simulation, name| {
+            simulation.with_behavior(name, oneshot.clone())
+        });
+    let report = simulation.run_until_finished(Duration::from_secs(3600));
+    assert!(report.is_some(), "The services didn't finish");
+    (load, started.elapsed(), simulation.elapsed())
+}
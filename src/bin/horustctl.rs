@@ -0,0 +1,762 @@
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use horust::horust::control::protocol::{
+    send_request, Connection, ExecContext, Request, Response, Run, Selection, ServiceState, Stream,
+    Transient,
+};
+use horust::horust::{RestartStrategy, ServiceStatus};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
+
+#[derive(clap::Parser, Debug)]
+#[clap(author, about)]
+/// Control a running Horust instance.
+struct Opts {
+    #[clap(long, env = "HORUST_CONTROL_SOCKET")]
+    /// Path of Horust's control socket (see `horust --control-socket`).
+    socket: PathBuf,
+
+    #[clap(subcommand)]
+    subcommand: Subcommand,
+}
+
+/// The services a command applies to: all the ones selected by any of the options. They're
+/// handled in the order they're started in.
+#[derive(clap::Args, Debug)]
+struct SelectionArgs {
+    /// Name of the service, or of a template followed by `@` for all its instances.
+    service: Option<String>,
+    #[clap(long = "target")]
+    /// The services named, or providing this name (see `provides`). Can be repeated.
+    targets: Vec<String>,
+    #[clap(long = "tag")]
+    /// The services having this tag. Can be repeated.
+    tags: Vec<String>,
+}
+
+impl From<SelectionArgs> for Selection {
+    fn from(args: SelectionArgs) -> Self {
+        Selection {
+            service: args.service,
+            targets: args.targets,
+            tags: args.tags,
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Run a command with the same user, environment, working directory, cgroup and namespaces
+    /// of a service.
+    Exec {
+        /// Name of the service.
+        service: String,
+        #[clap(required = true, last = true)]
+        /// The command to run, prefixed by --
+        command: Vec<String>,
+    },
+    /// Attach to the output of a running service. Detach with the detach keys or Ctrl-C.
+    Attach {
+        /// Name of the service.
+        service: String,
+        #[clap(long, default_value = "ctrl-p,ctrl-q")]
+        /// Sequence of keys used for detaching, e.g. `ctrl-p,ctrl-q` or `ctrl-a,d`.
+        detach_keys: String,
+    },
+    /// Print the status of the services, with the outcome of their last healthcheck.
+    Status {
+        /// Name of the service, all the services if not set.
+        service: Option<String>,
+        #[clap(long = "tag")]
+        /// Only the services having this tag. Can be repeated, for the services having any of them.
+        tags: Vec<String>,
+        #[clap(long)]
+        /// Print the status as JSON.
+        json: bool,
+    },
+    /// Start the services which are waiting to be started, e.g. with `auto-start = false` or
+    /// stopped.
+    Start {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+    },
+    /// Gracefully stop services, each one after the selected services which start after it.
+    /// They're not started again until they're started with `start`.
+    Stop {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+    },
+    /// Reload the definitions of the services, like SIGHUP. The changed services are restarted.
+    Reload,
+    /// Switch to a variant of a service, like `app@green`: it's started, and the other variants
+    /// are stopped once it's running.
+    Switch {
+        /// Name of the variant.
+        service: String,
+    },
+    /// Restart services one batch at a time, in the order they're started in, waiting for each
+    /// batch to be running again.
+    Restart {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+        #[clap(long, default_value = "1")]
+        /// How many instances can be restarting at the same time.
+        max_unavailable: usize,
+    },
+    /// Scale a template (like `worker@`) to a number of instances: the instances beyond it are
+    /// gracefully stopped, and the others are started.
+    Scale {
+        /// Name of the template, followed by `@`.
+        service: String,
+        /// How many instances are running.
+        instances: usize,
+        #[clap(long)]
+        /// Keep this number of instances when Horust restarts.
+        persist: bool,
+    },
+    /// Reset a service which is broken after crash-looping, and start it again without waiting
+    /// for its cooldown.
+    Clear {
+        /// Name of the service.
+        service: String,
+    },
+    /// Pause a service, stopping its processes (SIGSTOP) until it's resumed, e.g. for quiescing a
+    /// noisy service while debugging.
+    Pause {
+        /// Name of the service.
+        service: String,
+    },
+    /// Resume a paused service, continuing its processes (SIGCONT).
+    Resume {
+        /// Name of the service.
+        service: String,
+    },
+    /// Print the past runs of the services from the run history, oldest first.
+    History {
+        /// Name of the service, all the services if not set.
+        service: Option<String>,
+        #[clap(long)]
+        /// Only the runs which have ended after this time: how long ago, like `2h` or `7days`, or
+        /// a timestamp, like `2024-01-31T10:00:00Z`.
+        since: Option<String>,
+        #[clap(long)]
+        /// Only the runs which have ended before this time, in the same formats of `--since`.
+        until: Option<String>,
+        #[clap(long)]
+        /// Print the runs as JSON.
+        json: bool,
+    },
+    /// Explain why a service isn't running: the services it's waiting for, the conditions which
+    /// aren't met, the backoff or cooldown left before it's restarted, or whether it's disabled.
+    WhyNotRunning {
+        /// Name of the service.
+        service: String,
+    },
+    /// Run a command as a transient service, like `systemd-run`: it's supervised like the other
+    /// services (reaping, output, limits), and kept until Horust exits.
+    Run {
+        #[clap(long)]
+        /// Name of the service.
+        name: String,
+        #[clap(long)]
+        /// Working directory of the command, Horust's one if not set.
+        working_directory: Option<PathBuf>,
+        #[clap(long, default_value = "never", value_parser = ["always", "on-failure", "never"])]
+        /// When the command is run again, once it has exited.
+        restart: String,
+        #[clap(long, default_value = "0")]
+        /// How many times it's retried if it fails while starting, like `restart.attempts`.
+        attempts: u32,
+        #[clap(long, value_parser = humantime::parse_duration)]
+        /// How long it can run, before it's killed and counts as failed.
+        wall_time: Option<Duration>,
+        #[clap(long, value_parser = humantime::parse_duration)]
+        /// The CPU time it can use (RLIMIT_CPU).
+        cpu_time: Option<Duration>,
+        #[clap(required = true, last = true)]
+        /// The command to run, prefixed by --
+        command: Vec<String>,
+    },
+}
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+    match opts.subcommand {
+        Subcommand::Exec { service, command } => {
+            let context = match send_request(&opts.socket, &Request::Exec { service })? {
+                Response::Exec(context) => context,
+                response => bail!("Unexpected response: {:?}", response),
+            };
+            let status = exec(&context, &command)?;
+            std::process::exit(status);
+        }
+        Subcommand::Attach {
+            service,
+            detach_keys,
+        } => attach(&opts.socket, service, &parse_detach_keys(&detach_keys)?),
+        Subcommand::Status {
+            service,
+            tags,
+            json,
+        } => {
+            let services = match send_request(&opts.socket, &Request::Status { service, tags })? {
+                Response::Status { services } => services,
+                response => bail!("Unexpected response: {:?}", response),
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&services)?);
+            } else {
+                print!("{}", format_status(&services));
+            }
+            Ok(())
+        }
+        Subcommand::Start { selection } => {
+            match send_request(&opts.socket, &Request::Start(selection.into()))? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Stop { selection } => {
+            match send_request(&opts.socket, &Request::Stop(selection.into()))? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Reload => match send_request(&opts.socket, &Request::Reload)? {
+            Response::Accepted => Ok(()),
+            response => bail!("Unexpected response: {:?}", response),
+        },
+        Subcommand::Switch { service } => {
+            match send_request(&opts.socket, &Request::Switch { service })? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Restart {
+            selection,
+            max_unavailable,
+        } => {
+            let request = Request::Restart {
+                selection: selection.into(),
+                max_unavailable,
+            };
+            match send_request(&opts.socket, &request)? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Scale {
+            service,
+            instances,
+            persist,
+        } => {
+            let request = Request::Scale {
+                service,
+                instances,
+                persist,
+            };
+            match send_request(&opts.socket, &request)? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Clear { service } => {
+            match send_request(&opts.socket, &Request::Clear { service })? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Pause { service } => {
+            match send_request(&opts.socket, &Request::Pause { service })? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Resume { service } => {
+            match send_request(&opts.socket, &Request::Resume { service })? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::History {
+            service,
+            since,
+            until,
+            json,
+        } => {
+            let now = SystemTime::now();
+            let request = Request::History {
+                service,
+                since: since.map(|since| parse_time(&since, now)).transpose()?,
+                until: until.map(|until| parse_time(&until, now)).transpose()?,
+            };
+            let runs = match send_request(&opts.socket, &request)? {
+                Response::History { runs } => runs,
+                response => bail!("Unexpected response: {:?}", response),
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&runs)?);
+            } else {
+                print!("{}", format_history(&runs));
+            }
+            Ok(())
+        }
+        Subcommand::WhyNotRunning { service } => {
+            match send_request(&opts.socket, &Request::WhyNotRunning { service })? {
+                Response::WhyNotRunning {
+                    service,
+                    status,
+                    reasons,
+                } => {
+                    print!("{}", format_why_not_running(&service, &status, &reasons));
+                    Ok(())
+                }
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+        Subcommand::Run {
+            name,
+            working_directory,
+            restart,
+            attempts,
+            wall_time,
+            cpu_time,
+            command,
+        } => {
+            let transient = Transient {
+                name,
+                command,
+                working_directory,
+                restart: RestartStrategy::from(restart),
+                attempts,
+                wall_time,
+                cpu_time,
+            };
+            match send_request(&opts.socket, &Request::Run(transient))? {
+                Response::Accepted => Ok(()),
+                response => bail!("Unexpected response: {:?}", response),
+            }
+        }
+    }
+}
+
+/// Parses either how long before `now`, like `2h`, or a timestamp, like `2024-01-31T10:00:00Z`.
+fn parse_time(time: &str, now: SystemTime) -> Result<SystemTime> {
+    if let Ok(ago) = humantime::parse_duration(time) {
+        return now
+            .checked_sub(ago)
+            .with_context(|| format!("Invalid time: {}", time));
+    }
+    humantime::parse_rfc3339_weak(time).with_context(|| format!("Invalid time: {}", time))
+}
+
+/// A table with a row per run.
+fn format_history(runs: &[Run]) -> String {
+    let header = [
+        "STARTED",
+        "DURATION",
+        "SERVICE",
+        "GENERATION",
+        "PID",
+        "EXIT",
+    ];
+    let rows: Vec<[String; 6]> = runs
+        .iter()
+        .map(|run| {
+            // Rounded to the second, it's as precise as the start time.
+            let duration = run.ended.duration_since(run.started).unwrap_or_default();
+            let duration = Duration::from_secs(duration.as_secs());
+            let mut exit = match (&run.signal, run.exit_code) {
+                (Some(signal), _) => signal.clone(),
+                (None, Some(code)) => code.to_string(),
+                (None, None) => "-".to_string(),
+            };
+            if run.failed {
+                exit.push_str(" (failed)");
+            }
+            [
+                humantime::format_rfc3339_seconds(run.started).to_string(),
+                humantime::format_duration(duration).to_string(),
+                run.service.clone(),
+                run.generation.to_string(),
+                run.pid.to_string(),
+                exit,
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .chain([header[column].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let header = header.map(str::to_string);
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// A line per service, followed by why it couldn't be spawned, and by the output of its last
+/// healthcheck if it has failed.
+fn format_status(services: &[ServiceState]) -> String {
+    let mut out = String::new();
+    for service in services {
+        out.push_str(&service.name);
+        if let Some(description) = &service.description {
+            out.push_str(&format!(" ({})", description));
+        }
+        if !service.tags.is_empty() {
+            out.push_str(&format!(" [{}]", service.tags.join(", ")));
+        }
+        out.push_str(&format!(": {}", service.status));
+        if let Some(pid) = service.pid {
+            out.push_str(&format!(", pid {}", pid));
+        }
+        if service.paused {
+            out.push_str(", paused");
+        }
+        if let Some(healthcheck) = &service.healthcheck {
+            let health = if healthcheck.healthy {
+                "healthy"
+            } else {
+                "unhealthy"
+            };
+            out.push_str(&format!(", {} ({}ms)", health, healthcheck.latency_ms));
+        }
+        out.push('\n');
+        if let Some(spawn_error) = &service.spawn_error {
+            out.push_str(&format!("  failed spawning: {}\n", spawn_error));
+        }
+        let output = service.healthcheck.iter().flat_map(|h| h.output.lines());
+        for line in output {
+            out.push_str(&format!("  {}\n", line));
+        }
+    }
+    out
+}
+
+/// Whether the service is running, or a line per reason why it isn't.
+fn format_why_not_running(service: &str, status: &ServiceStatus, reasons: &[String]) -> String {
+    if reasons.is_empty() {
+        return format!("{} is running.\n", service);
+    }
+    let mut out = format!("{} is not running ({}):\n", service, status);
+    for reason in reasons {
+        out.push_str(&format!("  - {}\n", reason));
+    }
+    out
+}
+
+/// Prints the output of `service`, until the detach keys are pressed or Horust exits.
+fn attach(socket: &Path, service: String, detach_keys: &[u8]) -> Result<()> {
+    let mut connection = Connection::connect(socket)?;
+    match connection.request(&Request::Attach { service })? {
+        Response::Attached => (),
+        response => bail!("Unexpected response: {:?}", response),
+    }
+    let terminal = Terminal::unbuffered();
+    let detach_keys = detach_keys.to_vec();
+    let stdin_terminal = terminal.clone();
+    thread::spawn(move || {
+        let mut matched = 0;
+        for byte in io::stdin().lock().bytes() {
+            let Ok(byte) = byte else { break };
+            matched = if byte == detach_keys[matched] {
+                matched + 1
+            } else {
+                usize::from(byte == detach_keys[0])
+            };
+            if matched == detach_keys.len() {
+                stdin_terminal.restore();
+                std::process::exit(0);
+            }
+        }
+    });
+    while let Some(response) = connection.next_response()? {
+        if let Response::Output { stream, data } = response {
+            match stream {
+                Stream::Stdout => io::stdout().write_all(&data)?,
+                Stream::Stderr => io::stderr().write_all(&data)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a comma separated list of keys, like `ctrl-p,ctrl-q`.
+fn parse_detach_keys(keys: &str) -> Result<Vec<u8>> {
+    keys.split(',')
+        .map(|key| {
+            let key = key.trim();
+            let byte = match key.strip_prefix("ctrl-") {
+                Some(ctrl) if ctrl.len() == 1 && ctrl.as_bytes()[0].is_ascii_alphabetic() => {
+                    ctrl.as_bytes()[0].to_ascii_uppercase() - b'@'
+                }
+                None if key.len() == 1 => key.as_bytes()[0],
+                _ => bail!("Invalid detach key: {}", key),
+            };
+            Ok(byte)
+        })
+        .collect()
+}
+
+/// Switches the terminal (if stdin is one) to non-canonical mode without echo, so that the detach
+/// keys are read as soon as they're pressed. The previous mode is restored on drop.
+#[derive(Clone)]
+struct Terminal {
+    previous: Option<Termios>,
+}
+
+impl Terminal {
+    fn unbuffered() -> Self {
+        let previous = tcgetattr(io::stdin()).ok();
+        if let Some(previous) = &previous {
+            let mut termios = previous.clone();
+            termios.local_flags &= !(LocalFlags::ICANON | LocalFlags::ECHO);
+            let _ = tcsetattr(io::stdin(), SetArg::TCSANOW, &termios);
+        }
+        Self { previous }
+    }
+
+    fn restore(&self) {
+        if let Some(previous) = &self.previous {
+            let _ = tcsetattr(io::stdin(), SetArg::TCSANOW, previous);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Runs `command` in `context`, and returns its exit code.
+fn exec(context: &ExecContext, command: &[String]) -> Result<i32> {
+    #[cfg(target_os = "linux")]
+    if let Some(pid) = context.pid {
+        linux::join_cgroup(pid)?;
+        linux::join_namespaces(pid)?;
+    }
+    let (program, args) = command.split_first().context("Missing command")?;
+    let mut cmd = Command::new(program);
+    if let Some(gid) = context.gid {
+        cmd.gid(gid);
+    }
+    let status = cmd
+        .args(args)
+        .env_clear()
+        .envs(
+            context
+                .environment
+                .iter()
+                .filter_map(|kv| kv.split_once('=')),
+        )
+        .current_dir(&context.working_directory)
+        .uid(context.uid)
+        .status()
+        .with_context(|| format!("Failed running: {}", program))?;
+    // Like shells do, report signal deaths as 128 + signal.
+    Ok(status.code().unwrap_or_else(|| {
+        use std::os::unix::process::ExitStatusExt;
+        128 + status.signal().unwrap_or(0)
+    }))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::{self, File};
+
+    use anyhow::{Context, Result};
+    use nix::sched::{setns, CloneFlags};
+
+    /// Namespaces which can be joined, the mount namespace must be the last one: after joining
+    /// it, the paths in /proc might not be available anymore.
+    const NAMESPACES: [&str; 6] = ["cgroup", "ipc", "uts", "net", "pid", "mnt"];
+
+    /// Moves this process into the (v2) cgroup of `pid`.
+    pub(super) fn join_cgroup(pid: i32) -> Result<()> {
+        let cgroup_of = |pid: &str| -> Option<String> {
+            let content = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+            content
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(str::to_owned))
+        };
+        let (Some(target), Some(current)) = (cgroup_of(&pid.to_string()), cgroup_of("self")) else {
+            return Ok(());
+        };
+        if target != current {
+            let procs = format!("/sys/fs/cgroup{}/cgroup.procs", target);
+            fs::write(&procs, std::process::id().to_string())
+                .with_context(|| format!("Failed joining cgroup: {}", target))?;
+        }
+        Ok(())
+    }
+
+    /// Joins the namespaces of `pid` which are different from the current ones.
+    /// The pid namespace applies only to the children of this process.
+    pub(super) fn join_namespaces(pid: i32) -> Result<()> {
+        let mut to_join = vec![];
+        for ns in NAMESPACES {
+            let target = format!("/proc/{}/ns/{}", pid, ns);
+            let current = format!("/proc/self/ns/{}", ns);
+            match (fs::read_link(&target), fs::read_link(current)) {
+                (Ok(target_ns), Ok(current_ns)) if target_ns != current_ns => {
+                    let file = File::open(&target)
+                        .with_context(|| format!("Failed opening namespace: {}", target))?;
+                    to_join.push((ns, file));
+                }
+                _ => (),
+            }
+        }
+        for (ns, file) in to_join {
+            setns(file, CloneFlags::empty())
+                .with_context(|| format!("Failed joining the {} namespace", ns))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use horust::horust::control::protocol::{Run, ServiceState};
+    use horust::horust::{HealthcheckOutput, ServiceStatus};
+
+    use crate::{
+        format_history, format_status, format_why_not_running, parse_detach_keys, parse_time,
+    };
+
+    #[test]
+    fn test_parse_detach_keys() {
+        assert_eq!(
+            parse_detach_keys("ctrl-p,ctrl-q").unwrap(),
+            vec![0x10, 0x11]
+        );
+        assert_eq!(parse_detach_keys("ctrl-a, d").unwrap(), vec![0x01, b'd']);
+        parse_detach_keys("ctrl-1").unwrap_err();
+        parse_detach_keys("").unwrap_err();
+    }
+
+    #[test]
+    fn test_format_status() {
+        let services = vec![
+            ServiceState {
+                name: "a.toml".into(),
+                description: Some("The API".into()),
+                tags: vec!["critical".into(), "web".into()],
+                status: ServiceStatus::Running,
+                pid: Some(42),
+                healthcheck: Some(HealthcheckOutput {
+                    healthy: false,
+                    output: "http-endpoint: http://localhost/ answered 503".into(),
+                    latency_ms: 3,
+                }),
+                paused: true,
+                spawn_error: None,
+            },
+            ServiceState {
+                name: "b.toml".into(),
+                description: None,
+                tags: vec![],
+                status: ServiceStatus::Initial,
+                pid: None,
+                healthcheck: None,
+                paused: false,
+                spawn_error: Some(
+                    "Failed changing to the working directory: ENOENT: No such file or directory"
+                        .into(),
+                ),
+            },
+        ];
+        assert_eq!(
+            format_status(&services),
+            "a.toml (The API) [critical, web]: Running, pid 42, paused, unhealthy (3ms)\n  http-endpoint: http://localhost/ answered 503\nb.toml: Initial\n  failed spawning: Failed changing to the working directory: ENOENT: No such file or directory\n"
+        );
+    }
+
+    #[test]
+    fn test_format_why_not_running() {
+        assert_eq!(
+            format_why_not_running("a.toml", &ServiceStatus::Running, &[]),
+            "a.toml is running.\n"
+        );
+        let reasons = [
+            "waiting for db.toml (Starting)".to_string(),
+            "waiting for web (api.toml: Failed)".to_string(),
+        ];
+        assert_eq!(
+            format_why_not_running("b.toml", &ServiceStatus::Initial, &reasons),
+            "b.toml is not running (Initial):\n  - waiting for db.toml (Starting)\n  - waiting for web (api.toml: Failed)\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = humantime::parse_rfc3339("2024-01-31T10:00:00Z").unwrap();
+        assert_eq!(
+            parse_time("2h", now).unwrap(),
+            humantime::parse_rfc3339("2024-01-31T08:00:00Z").unwrap()
+        );
+        assert_eq!(
+            parse_time("2024-01-30T10:00:00Z", now).unwrap(),
+            now - Duration::from_secs(24 * 60 * 60)
+        );
+        parse_time("yesterday", now).unwrap_err();
+    }
+
+    #[test]
+    fn test_format_history() {
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1_706_695_200);
+        let runs = vec![
+            Run {
+                service: "web.toml".into(),
+                generation: 1,
+                pid: 42,
+                started,
+                ended: started + Duration::from_millis(90_500),
+                exit_code: None,
+                signal: Some("SIGKILL".into()),
+                failed: true,
+            },
+            Run {
+                service: "db.toml".into(),
+                generation: 12,
+                pid: 1234,
+                started,
+                ended: started + Duration::from_secs(3),
+                exit_code: Some(0),
+                signal: None,
+                failed: false,
+            },
+        ];
+        assert_eq!(
+            format_history(&runs),
+            "STARTED               DURATION  SERVICE   GENERATION  PID   EXIT\n\
+             2024-01-31T10:00:00Z  1m 30s    web.toml  1           42    SIGKILL (failed)\n\
+             2024-01-31T10:00:00Z  3s        db.toml   12          1234  0\n"
+        );
+        assert_eq!(
+            format_history(&[]),
+            "STARTED  DURATION  SERVICE  GENERATION  PID  EXIT\n"
+        );
+    }
+}
@@ -3,9 +3,18 @@
 //! all the publishers. The bus reads from the receiver, and publishes to all the `senders`.
 //! This is a very simple wrapper around crossbeam, that allows multiple sender send messages which
 //! will arrive to every receiver. For this reason, the message should implement Clone.
+//! The bus keeps a bounded history of the last messages, which can be replayed to the components
+//! joining it late, so that they can catch up (e.g. on the current status of the services).
+//! For debugging, all the messages can also be traced to a file (see `--trace-events`).
 //!
 
+use std::collections::VecDeque;
 use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::panic::Location;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{
     fmt::Debug,
     sync::{Arc, Mutex},
@@ -13,6 +22,35 @@ use std::{
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
 
+/// How many of the last messages are kept, for being replayed.
+const REPLAY_CAPACITY: usize = 1024;
+
+/// Bus output: the senders of all the connections, and the last messages sent to them.
+struct Subscribers<T>
+where
+    T: Clone,
+{
+    senders: Vec<Sender<Message<T>>>,
+    /// The last `capacity` messages, oldest first.
+    history: VecDeque<Message<T>>,
+    capacity: usize,
+}
+
+impl<T> Subscribers<T>
+where
+    T: Clone,
+{
+    fn record(&mut self, message: &Message<T>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(message.clone());
+    }
+}
+
 /// Bus state shared between `Bus` and all `BusConnector` instances.
 /// It contains all necessary components to send data and join the bus.
 #[derive(Clone)]
@@ -23,7 +61,7 @@ where
     /// Bus input - sender side
     sender: Sender<Message<T>>,
     /// Bus output - all the senders
-    senders: Arc<Mutex<Vec<Sender<Message<T>>>>>,
+    subscribers: Arc<Mutex<Subscribers<T>>>,
 }
 
 impl<T> SharedState<T>
@@ -32,10 +70,28 @@ where
 {
     /// Add another connection to the bus
     pub fn join_bus(&self) -> BusConnector<T> {
-        let mut senders = self.senders.lock().unwrap();
+        self.join(false)
+    }
+
+    /// Add another connection to the bus, which first receives the last messages sent on the bus
+    /// (at most `REPLAY_CAPACITY`), and then the new ones.
+    pub fn join_bus_with_replay(&self) -> BusConnector<T> {
+        self.join(true)
+    }
+
+    fn join(&self, replay: bool) -> BusConnector<T> {
+        let mut subscribers = self.subscribers.lock().unwrap();
 
         let (sender, receiver) = unbounded();
-        senders.push(sender);
+        if replay {
+            // The dispatcher holds the lock while sending, so no message is lost or duplicated.
+            for message in &subscribers.history {
+                sender
+                    .send(message.clone())
+                    .expect("The receiver is still alive");
+            }
+        }
+        subscribers.senders.push(sender);
 
         BusConnector::new(receiver, self.clone())
     }
@@ -52,6 +108,8 @@ where
     state: SharedState<T>,
     /// Bus input - receiver side
     receiver: Receiver<Message<T>>,
+    /// Where the dispatched messages are traced, if enabled.
+    trace: Option<Trace>,
 }
 
 impl<T> Debug for Bus<T>
@@ -62,26 +120,42 @@ where
         write!(
             f,
             "Bus {{ senders.len(): {} }}",
-            self.state.senders.lock().unwrap().len(),
+            self.state.subscribers.lock().unwrap().senders.len(),
         )
     }
 }
 
 impl<T> Bus<T>
 where
-    T: Clone,
+    T: Clone + Debug,
 {
     pub fn new() -> Self {
+        Self::with_replay_capacity(REPLAY_CAPACITY)
+    }
+
+    /// A bus keeping the last `capacity` messages for being replayed. 0 disables the replay.
+    pub fn with_replay_capacity(capacity: usize) -> Self {
         let (public_sender, receiver) = unbounded();
         Bus {
             state: SharedState {
                 sender: public_sender,
-                senders: Default::default(),
+                subscribers: Arc::new(Mutex::new(Subscribers {
+                    senders: vec![],
+                    history: VecDeque::with_capacity(capacity),
+                    capacity,
+                })),
             },
             receiver,
+            trace: None,
         }
     }
 
+    /// Writes all the messages to `path`, as they're dispatched.
+    pub fn trace_to(&mut self, path: &Path) -> io::Result<()> {
+        self.trace = Some(Trace::create(path)?);
+        Ok(())
+    }
+
     /// Blocking
     pub fn run(self) {
         self.dispatch();
@@ -92,15 +166,52 @@ where
         self.state.join_bus()
     }
 
+    /// Add another connection to the bus, which first receives the last messages sent on it.
+    pub fn join_bus_with_replay(&self) -> BusConnector<T> {
+        self.state.join_bus_with_replay()
+    }
+
     /// Dispatching loop
     /// As soon as we don't have any senders it will exit
     fn dispatch(self) {
-        drop(self.state.sender);
-        for ev in self.receiver {
-            let mut senders = self.state.senders.lock().unwrap();
-            senders.retain(|sender| sender.send(ev.clone()).is_ok());
+        let Bus {
+            state,
+            receiver,
+            mut trace,
+        } = self;
+        drop(state.sender);
+        for ev in receiver {
+            deliver(&state.subscribers, &mut trace, ev);
         }
     }
+
+    /// Delivers the messages sent so far, without waiting for new ones.
+    /// Returns how many have been delivered.
+    #[cfg(any(test, feature = "simulation"))]
+    pub(crate) fn dispatch_pending(&mut self) -> usize {
+        let pending: Vec<Message<T>> = self.receiver.try_iter().collect();
+        let delivered = pending.len();
+        for ev in pending {
+            deliver(&self.state.subscribers, &mut self.trace, ev);
+        }
+        delivered
+    }
+}
+
+/// Sends the message to all the subscribers, dropping the ones which have disconnected.
+fn deliver<T: Clone + Debug>(
+    subscribers: &Mutex<Subscribers<T>>,
+    trace: &mut Option<Trace>,
+    ev: Message<T>,
+) {
+    if let Some(trace) = trace {
+        trace.record(&ev);
+    }
+    let mut subscribers = subscribers.lock().unwrap();
+    subscribers.record(&ev);
+    subscribers
+        .senders
+        .retain(|sender| sender.send(ev.clone()).is_ok());
 }
 
 /// The payload with wrapped with some metadata
@@ -110,14 +221,21 @@ where
     T: Clone,
 {
     payload: T,
+    /// Where the message has been sent from.
+    source: &'static Location<'static>,
+    sent_at: SystemTime,
 }
 
 impl<T> Message<T>
 where
     T: Clone,
 {
-    pub fn new(payload: T) -> Self {
-        Self { payload }
+    pub fn new(payload: T, source: &'static Location<'static>) -> Self {
+        Self {
+            payload,
+            source,
+            sent_at: SystemTime::now(),
+        }
     }
 
     /// Consume the messages into the payload
@@ -158,8 +276,9 @@ where
         self.state.join_bus()
     }
 
+    #[track_caller]
     fn wrap(&self, payload: T) -> Message<T> {
-        Message::new(payload)
+        Message::new(payload, Location::caller())
     }
 
     /// Blocking
@@ -182,6 +301,32 @@ where
         self.receiver.try_iter().map(|m| m.into_payload()).collect()
     }
 
+    /// Receives the events until `deadline` fires, or an event matching `wake` is received.
+    pub(crate) fn get_events_until(
+        &self,
+        deadline: &Receiver<Instant>,
+        wake: impl Fn(&T) -> bool,
+    ) -> Vec<T> {
+        let mut events = vec![];
+        loop {
+            select! {
+                recv(self.receiver) -> message => {
+                    let Ok(message) = message else {
+                        return events;
+                    };
+                    let ev = message.into_payload();
+                    let woken = wake(&ev);
+                    events.push(ev);
+                    if woken {
+                        return events;
+                    }
+                },
+                recv(deadline) -> _ => return events,
+            }
+        }
+    }
+
+    #[track_caller]
     pub(crate) fn send_event(&self, ev: T) {
         self.state
             .sender
@@ -190,6 +335,55 @@ where
     }
 }
 
+/// The trace of all the messages dispatched on the bus: a line per message, with its sequence
+/// number, when it was sent (in nanoseconds since the Unix epoch) and the component which sent it.
+struct Trace {
+    file: LineWriter<File>,
+    seq: u64,
+}
+
+impl Trace {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: LineWriter::new(File::create(path)?),
+            seq: 0,
+        })
+    }
+
+    fn record<T: Clone + Debug>(&mut self, message: &Message<T>) {
+        let time = message
+            .sent_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let result = writeln!(
+            self.file,
+            "seq={} time={}.{:09} source={} event={:?}",
+            self.seq,
+            time.as_secs(),
+            time.subsec_nanos(),
+            component(message.source.file()),
+            message.payload
+        );
+        if let Err(error) = result {
+            error!("Failed writing the events trace: {}", error);
+        }
+        self.seq += 1;
+    }
+}
+
+/// The component a source file belongs to, e.g. `supervisor/process_spawner` for
+/// `src/horust/supervisor/process_spawner.rs`.
+fn component(file: &str) -> &str {
+    let path = file
+        .split_once("src/horust/")
+        .map_or(file, |(_, path)| path);
+    match path.strip_suffix("mod.rs") {
+        Some("") => "horust",
+        Some(dir) => dir.trim_end_matches('/'),
+        None => path.strip_suffix(".rs").unwrap_or(path),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::thread;
@@ -197,7 +391,7 @@ mod test {
 
     use crossbeam::channel;
 
-    use crate::horust::bus::{Bus, BusConnector};
+    use crate::horust::bus::{component, Bus, BusConnector};
     //TODO: remove this reference:
     use crate::horust::formats::{Event, ServiceStatus, ShuttingDown};
 
@@ -284,6 +478,91 @@ mod test {
             .expect("Didn't receive an answer on time.");
     }
 
+    #[test]
+    fn test_get_events_until() {
+        let (a, b, _dispatcher) = init_bus();
+        let never = crossbeam::channel::never();
+        b.send_event(Event::Kill("a".into()));
+        b.send_event(Event::ChildrenExited);
+        b.send_event(Event::Kill("b".into()));
+        let events = a.get_events_until(&never, |ev| *ev == Event::ChildrenExited);
+        assert_eq!(events, vec![Event::Kill("a".into()), Event::ChildrenExited]);
+        let deadline = crossbeam::channel::after(Duration::from_millis(100));
+        let events = a.get_events_until(&deadline, |ev| *ev == Event::ChildrenExited);
+        assert_eq!(events, vec![Event::Kill("b".into())]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let bus = Bus::with_replay_capacity(2);
+        let a = bus.join_bus();
+        let (sender, receiver) = channel::bounded(48);
+        let _handle = thread::spawn(move || {
+            bus.run();
+            sender
+                .send(())
+                .expect("test didn't terminate in time, so chan is closed!");
+        });
+        let ev = |status| Event::new_status_changed("sample", status);
+        a.send_event(ev(ServiceStatus::Initial));
+        a.send_event(ev(ServiceStatus::Starting));
+        a.send_event(ev(ServiceStatus::Started));
+        a.get_n_events_blocking(3);
+
+        let late = a.state.join_bus_with_replay();
+        let not_replayed = a.join_bus();
+        a.send_event(ev(ServiceStatus::Running));
+        assert_eq!(
+            late.get_n_events_blocking(3),
+            vec![
+                ev(ServiceStatus::Starting),
+                ev(ServiceStatus::Started),
+                ev(ServiceStatus::Running)
+            ]
+        );
+        assert_eq!(
+            not_replayed.get_n_events_blocking(1),
+            vec![ev(ServiceStatus::Running)]
+        );
+        drop(a);
+        drop(late);
+        drop(not_replayed);
+        receiver
+            .recv_timeout(Duration::from_secs(3))
+            .expect("Didn't receive an answer on time.");
+    }
+
+    #[test]
+    fn test_trace() {
+        let tempdir = tempdir::TempDir::new("bus-trace").unwrap();
+        let path = tempdir.path().join("events.trace");
+        let mut bus = Bus::new();
+        bus.trace_to(&path).unwrap();
+        let a = bus.join_bus();
+        a.send_event(Event::new_status_changed("sample", ServiceStatus::Initial));
+        a.send_event(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
+        drop(a);
+        bus.run();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", trace);
+        assert!(lines[0].starts_with("seq=0 time="));
+        assert!(lines[0].ends_with(r#" source=bus event=StatusChanged("sample", Initial)"#));
+        assert!(lines[1].starts_with("seq=1 "));
+        assert!(lines[1].ends_with("event=ShuttingDownInitiated(Gracefully)"));
+    }
+
+    #[test]
+    fn test_component() {
+        assert_eq!(
+            component("src/horust/supervisor/process_spawner.rs"),
+            "supervisor/process_spawner"
+        );
+        assert_eq!(component("src/horust/healthcheck/mod.rs"), "healthcheck");
+        assert_eq!(component("src/horust/mod.rs"), "horust");
+        assert_eq!(component("other.rs"), "other");
+    }
+
     #[test]
     fn test_stress() {
         let bus = Bus::new();
@@ -0,0 +1,613 @@
+//! Cgroups (v2): the services with `resources` limits run in their own cgroup, created below the
+//! cgroup of Horust, where the limits are applied.
+
+use std::ffi::c_void;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::sys::stat::{self, SFlag};
+use nix::unistd::{self, Pid};
+
+use crate::horust::formats::{
+    systemd_unit_name, Cpus, DeviceRule, IoMax, KillMode, Resources, Service,
+};
+
+/// Horust moves itself into this child of its cgroup: once the controllers are enabled for the
+/// children of a cgroup, it can't have processes of its own.
+const SUPERVISOR_CGROUP: &str = "init.scope";
+
+/// Sets up the cgroup of each of the services with `resources` limits, or killed by cgroup.
+/// If it fails, they will fail to spawn.
+pub(crate) fn setup(services: &mut [Service]) {
+    if !services.iter().any(needs_cgroup) {
+        return;
+    }
+    let mut controllers: Vec<&str> = services
+        .iter()
+        .flat_map(|service| controllers(&service.resources))
+        .collect();
+    controllers.sort_unstable();
+    controllers.dedup();
+    let base = match enable_controllers(&controllers) {
+        Ok(base) => base,
+        Err(error) => {
+            error!(
+                "Failed setting up the cgroups, the services with resource limits won't start: {:?}",
+                error
+            );
+            return;
+        }
+    };
+    for service in services.iter_mut() {
+        if needs_cgroup(service) {
+            service.cgroup = Some(base.join(systemd_unit_name(&service.name)));
+        }
+    }
+}
+
+/// Removes the cgroups of the services, once all their processes are gone.
+pub(crate) fn cleanup(services: &[Service]) {
+    for cgroup in services.iter().filter_map(|s| s.cgroup.as_ref()) {
+        if let Err(error) = fs::remove_dir(cgroup) {
+            debug!("Failed removing {}: {}", cgroup.display(), error);
+        }
+    }
+}
+
+/// Applies the limits of `service` to its cgroup (creating it if needed), and opens its
+/// `cgroup.procs` for the child process to join it.
+pub(crate) fn prepare(service: &Service) -> Result<Option<OwnedFd>> {
+    if !needs_cgroup(service) {
+        return Ok(None);
+    }
+    let cgroup = service
+        .cgroup
+        .as_ref()
+        .with_context(|| format!("No cgroup was set up for {}", service.name))?;
+    fs::create_dir_all(cgroup)
+        .with_context(|| format!("Failed creating cgroup {}", cgroup.display()))?;
+    for (file, value) in limits(&service.resources)? {
+        fs::write(cgroup.join(file), &value)
+            .with_context(|| format!("Failed writing `{}` in {}", value, file))?;
+    }
+    restrict_devices(cgroup, &service.resources.devices)
+        .context("Failed restricting the access to the devices")?;
+    // Opened with O_CLOEXEC, so that the service doesn't inherit it.
+    let procs = fs::OpenOptions::new()
+        .write(true)
+        .open(cgroup.join("cgroup.procs"))?;
+    Ok(Some(procs.into()))
+}
+
+/// Moves the calling process into the cgroup of `procs`. To be called in the child, after the fork.
+pub(crate) fn join(procs: &OwnedFd) -> Result<(), Errno> {
+    // 0 is the writing process.
+    unistd::write(procs, b"0").map(drop)
+}
+
+/// The processes in the cgroup of the service, if it has one.
+pub(crate) fn pids(service: &Service) -> Option<Vec<Pid>> {
+    let cgroup = service.cgroup.as_ref()?;
+    let procs = fs::read_to_string(cgroup.join("cgroup.procs")).ok()?;
+    Some(
+        procs
+            .lines()
+            .filter_map(|pid| pid.parse().ok())
+            .map(Pid::from_raw)
+            .collect(),
+    )
+}
+
+/// How many processes of the service were killed by the OOM killer, if it has a cgroup.
+pub(crate) fn oom_kills(service: &Service) -> Option<u64> {
+    let cgroup = service.cgroup.as_ref()?;
+    let events = fs::read_to_string(cgroup.join("memory.events")).ok()?;
+    parse_oom_kills(&events)
+}
+
+/// Freezes (or thaws) all the processes in `cgroup`, including the descendants which might have
+/// left the process group of the service.
+pub(crate) fn freeze(cgroup: &Path, frozen: bool) -> Result<()> {
+    let value = if frozen { "1" } else { "0" };
+    fs::write(cgroup.join("cgroup.freeze"), value).with_context(|| {
+        format!(
+            "Failed writing `{}` in {}/cgroup.freeze",
+            value,
+            cgroup.display()
+        )
+    })
+}
+
+fn parse_oom_kills(memory_events: &str) -> Option<u64> {
+    memory_events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+fn needs_cgroup(service: &Service) -> bool {
+    !service.resources.is_empty() || service.termination.kill_mode == KillMode::Cgroup
+}
+
+/// The controllers required by `resources`.
+fn controllers(resources: &Resources) -> Vec<&'static str> {
+    let mut controllers = vec![];
+    if !resources.io_max.is_empty() {
+        controllers.push("io");
+    }
+    if resources.memory_max.is_some() || resources.memory_swap_max.is_some() {
+        controllers.push("memory");
+    }
+    if resources.cpu_max.is_some() {
+        controllers.push("cpu");
+    }
+    if resources.cpuset_cpus.is_some() {
+        controllers.push("cpuset");
+    }
+    controllers
+}
+
+/// The interface files of the cgroup, and the values to write in them.
+fn limits(resources: &Resources) -> Result<Vec<(&'static str, String)>> {
+    // A line per device, as they can't be written all at once.
+    let mut limits = resources
+        .io_max
+        .iter()
+        .map(|io_max| {
+            Ok((
+                "io.max",
+                io_max_line(io_max, device_number(&io_max.device)?),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(memory_max) = resources.memory_max {
+        limits.push(("memory.max", memory_max.to_string()));
+        // The OOM killer kills the whole service, rather than leaving it half working.
+        limits.push(("memory.oom.group", "1".to_string()));
+    }
+    if let Some(memory_swap_max) = resources.memory_swap_max {
+        limits.push(("memory.swap.max", memory_swap_max.to_string()));
+    }
+    if let Some(cpus) = resources.cpu_max {
+        limits.push(("cpu.max", cpu_max_line(cpus, resources.cpu_period)));
+    }
+    if let Some(cpuset_cpus) = &resources.cpuset_cpus {
+        limits.push(("cpuset.cpus", cpuset_cpus.clone()));
+    }
+    Ok(limits)
+}
+
+/// The quota and the period, in microseconds.
+fn cpu_max_line(cpus: Cpus, period: Duration) -> String {
+    format!("{} {}", cpus.quota(period).as_micros(), period.as_micros())
+}
+
+/// The unset limits are reset, in case they were set by a previous configuration.
+fn io_max_line(io_max: &IoMax, (major, minor): (u64, u64)) -> String {
+    let limit = |limit: Option<u64>| limit.map_or("max".to_string(), |limit| limit.to_string());
+    format!(
+        "{}:{} rbps={} wbps={} riops={} wiops={}",
+        major,
+        minor,
+        limit(io_max.rbps),
+        limit(io_max.wbps),
+        limit(io_max.riops),
+        limit(io_max.wiops)
+    )
+}
+
+fn device_number(device: &Path) -> Result<(u64, u64)> {
+    let stat =
+        stat::stat(device).with_context(|| format!("Invalid device {}", device.display()))?;
+    if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFBLK {
+        bail!("{} is not a block device", device.display());
+    }
+    Ok((stat::major(stat.st_rdev), stat::minor(stat.st_rdev)))
+}
+
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+const BPF_DEVCG_ACC_READ: i32 = 2;
+const BPF_DEVCG_ACC_WRITE: i32 = 4;
+
+/// The pseudo-devices always allowed, like with `DevicePolicy=closed` in systemd:
+/// `/dev/null`, `/dev/zero`, `/dev/full`, `/dev/random`, `/dev/urandom`, `/dev/tty`, `/dev/ptmx`
+/// and the pseudo-terminals in `/dev/pts`.
+fn pseudo_devices() -> Vec<DeviceAccess> {
+    const RWM: i32 = BPF_DEVCG_ACC_READ | BPF_DEVCG_ACC_WRITE | BPF_DEVCG_ACC_MKNOD;
+    const RW: i32 = BPF_DEVCG_ACC_READ | BPF_DEVCG_ACC_WRITE;
+    let char = |major, minor, access| DeviceAccess {
+        kind: BPF_DEVCG_DEV_CHAR,
+        major: Some(major),
+        minor,
+        access,
+    };
+    vec![
+        char(1, Some(3), RWM),
+        char(1, Some(5), RWM),
+        char(1, Some(7), RWM),
+        char(1, Some(8), RWM),
+        char(1, Some(9), RWM),
+        char(5, Some(0), RWM),
+        char(5, Some(2), RW),
+        char(136, None, RW),
+    ]
+}
+
+/// A device (or any of a major number, if `minor` is `None`) and the access allowed to it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct DeviceAccess {
+    kind: i32,
+    major: Option<i32>,
+    minor: Option<i32>,
+    access: i32,
+}
+
+impl DeviceAccess {
+    fn new(rule: &DeviceRule) -> Result<Self> {
+        let path = rule.path.display();
+        let stat = stat::stat(&rule.path).with_context(|| format!("Invalid device {}", path))?;
+        let kind = match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+            SFlag::S_IFBLK => BPF_DEVCG_DEV_BLOCK,
+            SFlag::S_IFCHR => BPF_DEVCG_DEV_CHAR,
+            _ => bail!("{} is not a device", path),
+        };
+        let access = [
+            (rule.read, BPF_DEVCG_ACC_READ),
+            (rule.write, BPF_DEVCG_ACC_WRITE),
+            (rule.mknod, BPF_DEVCG_ACC_MKNOD),
+        ]
+        .iter()
+        .filter(|(allowed, _)| *allowed)
+        .fold(0, |access, (_, bit)| access | bit);
+        Ok(Self {
+            kind,
+            major: Some(stat::major(stat.st_rdev) as i32),
+            minor: Some(stat::minor(stat.st_rdev) as i32),
+            access,
+        })
+    }
+}
+
+/// An eBPF instruction (`struct bpf_insn`).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct BpfInsn {
+    code: u8,
+    /// The destination register in the low nibble, the source one in the high nibble.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Self {
+            code,
+            regs: src << 4 | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+const BPF_LDX_W: u8 = 0x61;
+const BPF_AND32_K: u8 = 0x54;
+const BPF_RSH32_K: u8 = 0x74;
+const BPF_MOV32_X: u8 = 0xbc;
+const BPF_MOV64_K: u8 = 0xb7;
+const BPF_JNE_K: u8 = 0x55;
+const BPF_JNE_X: u8 = 0x5d;
+const BPF_EXIT: u8 = 0x95;
+
+/// A `BPF_PROG_TYPE_CGROUP_DEVICE` program, allowing the access to the devices in `rules` only.
+/// It's called with a `struct bpf_cgroup_dev_ctx` (`access_type`, `major`, `minor`), and returns 1
+/// to allow the access.
+fn device_program(rules: &[DeviceAccess]) -> Vec<BpfInsn> {
+    let mut program = vec![
+        // r2 = the type of device, r3 = the requested access, r4 = major, r5 = minor.
+        BpfInsn::new(BPF_LDX_W, 2, 1, 0, 0),
+        BpfInsn::new(BPF_AND32_K, 2, 0, 0, 0xffff),
+        BpfInsn::new(BPF_LDX_W, 3, 1, 0, 0),
+        BpfInsn::new(BPF_RSH32_K, 3, 0, 0, 16),
+        BpfInsn::new(BPF_LDX_W, 4, 1, 4, 0),
+        BpfInsn::new(BPF_LDX_W, 5, 1, 8, 0),
+    ];
+    for rule in rules {
+        // Each check jumps to the next rule if it doesn't match.
+        let mut checks = vec![
+            (BPF_JNE_K, 2, 0, rule.kind),
+            (BPF_MOV32_X, 1, 3, 0),
+            (BPF_AND32_K, 1, 0, rule.access),
+            // The requested access must be a subset of the allowed one.
+            (BPF_JNE_X, 1, 3, 0),
+        ];
+        checks.extend(rule.major.map(|major| (BPF_JNE_K, 4, 0, major)));
+        checks.extend(rule.minor.map(|minor| (BPF_JNE_K, 5, 0, minor)));
+        // The checks, then r0 = 1 and exit.
+        let length = checks.len() + 2;
+        for (index, (code, dst, src, imm)) in checks.into_iter().enumerate() {
+            let off = match code {
+                BPF_JNE_K | BPF_JNE_X => (length - index - 1) as i16,
+                _ => 0,
+            };
+            program.push(BpfInsn::new(code, dst, src, off, imm));
+        }
+        program.push(BpfInsn::new(BPF_MOV64_K, 0, 0, 0, 1));
+        program.push(BpfInsn::new(BPF_EXIT, 0, 0, 0, 0));
+    }
+    program.push(BpfInsn::new(BPF_MOV64_K, 0, 0, 0, 0));
+    program.push(BpfInsn::new(BPF_EXIT, 0, 0, 0, 0));
+    program
+}
+
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_DETACH: libc::c_long = 9;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+
+/// The `BPF_PROG_LOAD` part of `union bpf_attr`.
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// The `BPF_PROG_ATTACH` and `BPF_PROG_DETACH` part of `union bpf_attr`.
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+    replace_bpf_fd: u32,
+}
+
+fn bpf<T>(command: libc::c_long, attr: &mut T) -> Result<libc::c_long, Errno> {
+    // Safety: `attr` is the part of `union bpf_attr` for `command`, and outlives the call.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            command,
+            attr as *mut T as *mut c_void,
+            std::mem::size_of::<T>(),
+        )
+    };
+    Errno::result(result)
+}
+
+/// Attaches to `cgroup` a program allowing the access to `devices` only (besides the
+/// pseudo-devices), replacing the one attached before. If `devices` is empty, it's detached.
+fn restrict_devices(cgroup: &Path, devices: &[DeviceRule]) -> Result<()> {
+    let cgroup_dir = fs::File::open(cgroup)?;
+    let mut attach = BpfProgAttachAttr {
+        target_fd: cgroup_dir.as_raw_fd() as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        ..Default::default()
+    };
+    if devices.is_empty() {
+        // Fails if none was attached.
+        let _ = bpf(BPF_PROG_DETACH, &mut attach);
+        return Ok(());
+    }
+    let mut rules = pseudo_devices();
+    for device in devices {
+        rules.push(DeviceAccess::new(device)?);
+    }
+    let program = device_program(&rules);
+    let license = b"GPL\0";
+    let mut name = [0; 16];
+    name[..10].copy_from_slice(b"horust_dev");
+    let mut load = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        prog_name: name,
+        expected_attach_type: BPF_CGROUP_DEVICE,
+        ..Default::default()
+    };
+    let fd = bpf(BPF_PROG_LOAD, &mut load).context("Failed loading the BPF program")?;
+    // Safety: the syscall returned a new file descriptor.
+    let program_fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    attach.attach_bpf_fd = program_fd.as_raw_fd() as u32;
+    // The cgroup keeps the program loaded, once attached.
+    bpf(BPF_PROG_ATTACH, &mut attach)
+        .with_context(|| format!("Failed attaching the BPF program to {}", cgroup.display()))?;
+    Ok(())
+}
+
+/// Moves Horust into a child of its cgroup, and enables `controllers` for the cgroups of the
+/// services. Returns the cgroup of Horust, where those are created.
+fn enable_controllers(controllers: &[&str]) -> Result<PathBuf> {
+    let base = own_cgroup()?;
+    let available = fs::read_to_string(base.join("cgroup.controllers"))?;
+    for controller in controllers {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            bail!(
+                "The {} controller is not available in {}",
+                controller,
+                base.display()
+            );
+        }
+    }
+    let supervisor = base.join(SUPERVISOR_CGROUP);
+    fs::create_dir_all(&supervisor)?;
+    fs::write(
+        supervisor.join("cgroup.procs"),
+        std::process::id().to_string(),
+    )
+    .context("Failed moving Horust into its own cgroup")?;
+    if controllers.is_empty() {
+        return Ok(base);
+    }
+    let enabled: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+    fs::write(base.join("cgroup.subtree_control"), enabled.join(" ")).with_context(|| {
+        format!(
+            "Failed enabling the controllers in {}, it might contain other processes",
+            base.display()
+        )
+    })?;
+    Ok(base)
+}
+
+/// The cgroup of Horust, which contains the cgroups of the services.
+pub(crate) fn own_cgroup() -> Result<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mount_point = cgroup2_mount_point(&mountinfo).context("cgroup2 is not mounted")?;
+    let own_cgroup = fs::read_to_string("/proc/self/cgroup")?;
+    let own_cgroup = own_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .context("Horust is not in a cgroup v2")?;
+    // Horust might have moved itself already, in `enable_controllers`.
+    let own_cgroup = own_cgroup
+        .strip_suffix(SUPERVISOR_CGROUP)
+        .unwrap_or(own_cgroup);
+    Ok(match own_cgroup.trim_matches('/') {
+        "" => mount_point,
+        own_cgroup => mount_point.join(own_cgroup),
+    })
+}
+
+/// Finds the mount point of the cgroup2 filesystem, in the content of `/proc/self/mountinfo`.
+fn cgroup2_mount_point(mountinfo: &str) -> Option<PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        if filesystem.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use std::time::Duration;
+
+    use crate::horust::cgroups::{
+        cgroup2_mount_point, cpu_max_line, device_number, device_program, freeze, io_max_line,
+        parse_oom_kills, BpfInsn, DeviceAccess, BPF_DEVCG_ACC_READ, BPF_DEVCG_DEV_CHAR, BPF_EXIT,
+        BPF_JNE_K, BPF_MOV64_K,
+    };
+    use crate::horust::formats::{Cpus, DeviceRule, IoMax};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_cgroup2_mount_point() {
+        let mountinfo = "\
+25 30 0:23 / /sys/fs/cgroup ro,nosuid,nodev,noexec shared:9 - tmpfs tmpfs ro,mode=755
+26 25 0:24 / /sys/fs/cgroup/unified rw,nosuid,nodev,noexec,relatime shared:10 - cgroup2 cgroup2 rw
+27 25 0:25 / /sys/fs/cgroup/cpu rw,relatime shared:11 - cgroup cgroup rw,cpu";
+        assert_eq!(
+            cgroup2_mount_point(mountinfo),
+            Some(PathBuf::from("/sys/fs/cgroup/unified"))
+        );
+        assert_eq!(cgroup2_mount_point(""), None);
+    }
+
+    #[test]
+    fn test_io_max_line() {
+        let io_max = IoMax {
+            device: "/dev/sda".into(),
+            rbps: Some(10_000_000),
+            wbps: None,
+            riops: None,
+            wiops: Some(100),
+        };
+        assert_eq!(
+            io_max_line(&io_max, (8, 0)),
+            "8:0 rbps=10000000 wbps=max riops=max wiops=100"
+        );
+        device_number("/dev/null".as_ref()).unwrap_err();
+        device_number("/no/such/device".as_ref()).unwrap_err();
+    }
+
+    #[test]
+    fn test_device_program() {
+        let rule: DeviceRule = "/dev/null r".parse().unwrap();
+        let null = DeviceAccess::new(&rule).unwrap();
+        assert_eq!(
+            null,
+            DeviceAccess {
+                kind: BPF_DEVCG_DEV_CHAR,
+                major: Some(1),
+                minor: Some(3),
+                access: BPF_DEVCG_ACC_READ,
+            }
+        );
+        DeviceAccess::new(&"/tmp".parse().unwrap()).unwrap_err();
+
+        let any_minor = DeviceAccess {
+            minor: None,
+            ..null
+        };
+        let program = device_program(&[null, any_minor]);
+        // The prologue, 8 instructions for the first rule, 7 for the second one, and the denial.
+        assert_eq!(program.len(), 6 + 8 + 7 + 2);
+        let allow = [
+            BpfInsn::new(BPF_MOV64_K, 0, 0, 0, 1),
+            BpfInsn::new(BPF_EXIT, 0, 0, 0, 0),
+        ];
+        assert_eq!(program[12..14], allow);
+        assert_eq!(program[19..21], allow);
+        assert_eq!(program[21], BpfInsn::new(BPF_MOV64_K, 0, 0, 0, 0));
+        // Every check of a rule jumps to the next one.
+        for (index, insn) in program.iter().enumerate() {
+            if insn.code == BPF_JNE_K {
+                let target = index + 1 + insn.off as usize;
+                assert!(target == 14 || target == 21, "{}: {:?}", index, insn);
+            }
+        }
+        assert_eq!(program[18], BpfInsn::new(BPF_JNE_K, 4, 0, 2, 1));
+    }
+
+    #[test]
+    fn test_cpu_max_line() {
+        let period = Duration::from_millis(100);
+        assert_eq!(cpu_max_line(Cpus(500), period), "50000 100000");
+        assert_eq!(cpu_max_line(Cpus(2000), period), "200000 100000");
+        let cpus: Cpus = 0.25.try_into().unwrap();
+        assert_eq!(cpu_max_line(cpus, Duration::from_secs(1)), "250000 1000000");
+        Cpus::try_from(0.0).unwrap_err();
+        Cpus::try_from(f64::NAN).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_oom_kills() {
+        let memory_events = "low 0\nhigh 0\nmax 12\noom 2\noom_kill 2\noom_group_kill 1\n";
+        assert_eq!(parse_oom_kills(memory_events), Some(2));
+        assert_eq!(parse_oom_kills("low 0\n"), None);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let cgroup = TempDir::new("cgroup").unwrap();
+        let read = || std::fs::read_to_string(cgroup.path().join("cgroup.freeze")).unwrap();
+        freeze(cgroup.path(), true).unwrap();
+        assert_eq!(read(), "1");
+        freeze(cgroup.path(), false).unwrap();
+        assert_eq!(read(), "0");
+        freeze("/no/such/cgroup".as_ref(), true).unwrap_err();
+    }
+}
@@ -0,0 +1,189 @@
+//! The time as seen by Horust's components: the one of the system, or a simulated one which only
+//! moves forward when told to (see `simulation`). All the delays, timeouts and intervals are
+//! measured with a `Clock`, so that they can be tested without actually waiting for them.
+
+use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crossbeam::channel::{self, Receiver};
+
+pub(crate) trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    /// The wall-clock time, for what depends on the time of day.
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+    /// A channel which receives the time once, after `duration`.
+    fn after(&self, duration: Duration) -> Receiver<Instant>;
+    /// A channel which receives the time every `duration`. Missed ticks are dropped.
+    fn tick(&self, duration: Duration) -> Receiver<Instant>;
+}
+
+/// The clock of the system.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+
+    fn after(&self, duration: Duration) -> Receiver<Instant> {
+        channel::after(duration)
+    }
+
+    fn tick(&self, duration: Duration) -> Receiver<Instant> {
+        channel::tick(duration)
+    }
+}
+
+/// A clock which starts at the time it's created, and only moves forward by `advance` or
+/// `sleep` (which returns immediately). Its timers fire as the time passes over them.
+#[cfg(any(test, feature = "simulation"))]
+#[derive(Debug)]
+pub(crate) struct SimulatedClock {
+    start: Instant,
+    start_time: SystemTime,
+    elapsed: std::sync::Mutex<Duration>,
+    timers: std::sync::Mutex<Vec<Timer>>,
+}
+
+#[cfg(any(test, feature = "simulation"))]
+#[derive(Debug)]
+struct Timer {
+    at: Instant,
+    /// Set for the tickers.
+    every: Option<Duration>,
+    sender: channel::Sender<Instant>,
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl SimulatedClock {
+    pub(crate) fn new() -> Self {
+        Self::starting_at(SystemTime::now())
+    }
+
+    /// A clock whose wall-clock time starts at `start_time`.
+    pub(crate) fn starting_at(start_time: SystemTime) -> Self {
+        Self {
+            start: Instant::now(),
+            start_time,
+            elapsed: Default::default(),
+            timers: Default::default(),
+        }
+    }
+
+    pub(crate) fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+        self.fire();
+    }
+
+    /// Fires the timers which are due, dropping the ones which are done or nobody is waiting for.
+    fn fire(&self) {
+        let now = self.now();
+        self.timers.lock().unwrap().retain_mut(|timer| {
+            if timer.at > now {
+                return true;
+            }
+            if let Err(channel::TrySendError::Disconnected(_)) = timer.sender.try_send(now) {
+                return false;
+            }
+            match timer.every {
+                Some(every) => {
+                    while timer.at <= now {
+                        timer.at += every;
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    fn timer(&self, duration: Duration, every: Option<Duration>) -> Receiver<Instant> {
+        let (sender, receiver) = channel::bounded(1);
+        self.timers.lock().unwrap().push(Timer {
+            at: self.now() + duration,
+            every,
+            sender,
+        });
+        self.fire();
+        receiver
+    }
+
+    /// How much time has passed since the clock was created.
+    pub(crate) fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_time + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+
+    fn after(&self, duration: Duration) -> Receiver<Instant> {
+        self.timer(duration, None)
+    }
+
+    fn tick(&self, duration: Duration) -> Receiver<Instant> {
+        // A zero interval would never let the time pass.
+        self.timer(duration, Some(duration.max(Duration::from_nanos(1))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::horust::clock::{Clock, SimulatedClock};
+
+    #[test]
+    fn test_simulated_clock() {
+        let clock = SimulatedClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.sleep(Duration::from_secs(3600));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(clock.now() - start, Duration::from_millis(3_600_001));
+        assert_eq!(clock.elapsed(), Duration::from_millis(3_600_001));
+    }
+
+    #[test]
+    fn test_simulated_timers() {
+        let clock = SimulatedClock::new();
+        let after = clock.after(Duration::from_secs(2));
+        let tick = clock.tick(Duration::from_secs(1));
+        assert!(after.try_recv().is_err());
+        assert!(tick.try_recv().is_err());
+        clock.advance(Duration::from_secs(1));
+        assert!(after.try_recv().is_err());
+        assert_eq!(tick.try_recv(), Ok(clock.now()));
+        // Missed ticks are dropped.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(after.try_recv(), Ok(clock.now()));
+        assert_eq!(tick.try_recv(), Ok(clock.now()));
+        assert!(tick.try_recv().is_err());
+        assert_eq!(clock.after(Duration::ZERO).try_recv(), Ok(clock.now()));
+        drop(tick);
+        clock.advance(Duration::from_secs(1));
+        assert!(clock.timers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+//! Audit log of the control socket: every request is appended to a file as a line of JSON,
+//! with who has sent it, when, and its result.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use nix::sys::socket::{getsockopt, sockopt};
+use serde::Serialize;
+
+use crate::horust::control::protocol::Request;
+
+#[derive(Debug, Clone)]
+pub(crate) struct AuditLog {
+    file: Arc<Mutex<File>>,
+}
+
+/// The process on the other side of a connection to the control socket.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+    /// The supplementary groups of the process, if they could be read.
+    #[serde(skip)]
+    pub groups: Vec<u32>,
+}
+
+impl Peer {
+    /// Reads the credentials of the process which has connected `stream`.
+    pub(crate) fn of(stream: &UnixStream) -> Result<Self> {
+        let credentials = getsockopt(stream, sockopt::PeerCredentials)?;
+        let groups = fs::read_to_string(format!("/proc/{}/status", credentials.pid()))
+            .map(|status| supplementary_groups(&status, credentials.uid()))
+            .unwrap_or_default();
+        Ok(Peer {
+            uid: credentials.uid(),
+            gid: credentials.gid(),
+            pid: credentials.pid(),
+            groups,
+        })
+    }
+
+    /// The primary group, followed by the supplementary ones.
+    pub(crate) fn gids(&self) -> Vec<u32> {
+        let mut gids = vec![self.gid];
+        gids.extend(&self.groups);
+        gids
+    }
+}
+
+/// The `Groups:` of the `/proc/<pid>/status` of a process, if its effective uid is `uid`: otherwise
+/// the pid has been reused by another process since it has connected.
+fn supplementary_groups(status: &str, uid: u32) -> Vec<u32> {
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|values| values.split_whitespace().filter_map(|v| v.parse().ok()))
+    };
+    // Real, effective, saved and filesystem uids.
+    let effective_uid = field("Uid:").and_then(|mut uids| uids.nth(1));
+    if effective_uid != Some(uid) {
+        return vec![];
+    }
+    field("Groups:").map(Iterator::collect).unwrap_or_default()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Record<'a> {
+    time: String,
+    #[serde(flatten)]
+    peer: Option<&'a Peer>,
+    #[serde(flatten)]
+    request: &'a Request,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it (readable only by its owner) if needed.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed opening audit log: {}", path.display()))?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Appends a record of `request`, sent by `peer`, which has ended with `result`.
+    pub(crate) fn record<T>(&self, peer: Option<&Peer>, request: &Request, result: &Result<T>) {
+        let record = Record {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            peer,
+            request,
+            result: if result.is_ok() { "ok" } else { "error" },
+            error: result.as_ref().err().map(|error| format!("{:#}", error)),
+        };
+        let mut line = serde_json::to_vec(&record).expect("Serializable audit record");
+        line.push(b'\n');
+        // A single write, so that the records of concurrent connections don't interleave.
+        if let Err(error) = self.file.lock().unwrap().write_all(&line) {
+            error!("Failed writing audit log: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixStream;
+
+    use anyhow::anyhow;
+
+    use crate::horust::control::audit::{supplementary_groups, AuditLog, Peer};
+    use crate::horust::control::protocol::Request;
+
+    #[test]
+    fn test_audit_log() -> anyhow::Result<()> {
+        let tempdir = tempdir::TempDir::new("audit")?;
+        let path = tempdir.path().join("audit.log");
+        let audit_log = AuditLog::open(&path)?;
+        let (stream, _other) = UnixStream::pair()?;
+        let peer = Peer::of(&stream)?;
+        assert_eq!(peer.pid, std::process::id() as i32);
+
+        let exec = Request::Exec {
+            service: "a.toml".into(),
+        };
+        audit_log.record(Some(&peer), &exec, &Ok(()));
+        audit_log.record::<()>(None, &exec, &Err(anyhow!("Service not found: a.toml")));
+        // Reopening appends to the existing records.
+        AuditLog::open(&path)?.record(Some(&peer), &exec, &Ok(()));
+
+        let content = std::fs::read_to_string(&path)?;
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["uid"], peer.uid);
+        assert_eq!(lines[0]["request"], "exec");
+        assert_eq!(lines[0]["service"], "a.toml");
+        assert_eq!(lines[0]["result"], "ok");
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[1]["result"], "error");
+        assert_eq!(lines[1]["error"], "Service not found: a.toml");
+        assert!(lines[1].get("uid").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_supplementary_groups() {
+        let status = "Name:\tbash\nUid:\t1000\t1000\t1000\t1000\nGid:\t100\t100\t100\t100\nGroups:\t10 27 100 \n";
+        assert_eq!(supplementary_groups(status, 1000), vec![10, 27, 100]);
+        // Another process has reused the pid.
+        assert!(supplementary_groups(status, 0).is_empty());
+        let status = "Uid:\t0\t0\t0\t0\nGroups:\n";
+        assert!(supplementary_groups(status, 0).is_empty());
+    }
+}
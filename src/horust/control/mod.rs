@@ -0,0 +1,883 @@
+//! The control socket: allows inspecting and interacting with a running Horust instance, for
+//! instance via `horustctl`.
+//! This component keeps track of the state of the services by listening to the bus, and serves
+//! each connection on a separate thread.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::{self, Pid};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{
+    Blocker, ControlAccess, Event, GlobalEnvironment, Group, HealthcheckOutput, HorustConfig,
+    Limits, PausedBy, Restart, Service, ServiceName, ServiceStatus, User,
+};
+use crate::horust::history;
+use crate::horust::output::Outputs;
+use crate::horust::plan::Plan;
+use crate::horust::supervisor;
+use crate::horust::templates;
+use audit::{AuditLog, Peer};
+use protocol::{read_message, write_message, ExecContext, Request, Response, Selection, Transient};
+
+mod audit;
+pub mod protocol;
+
+/// How often the bus and the socket are polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What is known about a service, as seen from the bus.
+#[derive(Debug, Clone)]
+struct ServiceState {
+    service: Arc<Service>,
+    pid: Option<Pid>,
+    status: ServiceStatus,
+    healthcheck: Option<HealthcheckOutput>,
+    /// Its processes are stopped.
+    paused: bool,
+    /// Why the last spawn has failed, until a process is spawned.
+    spawn_error: Option<String>,
+    /// Why it isn't running, according to the supervisor.
+    not_running: Vec<Blocker>,
+}
+
+impl ServiceState {
+    fn new(service: Arc<Service>) -> Self {
+        let status = if service.enabled {
+            ServiceStatus::Initial
+        } else {
+            ServiceStatus::Disabled
+        };
+        Self {
+            service,
+            pid: None,
+            status,
+            healthcheck: None,
+            paused: false,
+            spawn_error: None,
+            not_running: vec![],
+        }
+    }
+}
+
+type State = Arc<Mutex<HashMap<ServiceName, ServiceState>>>;
+
+/// What is shared by all the connections.
+#[derive(Debug, Clone)]
+struct Server {
+    state: State,
+    /// Where the requests to the supervisor are sent.
+    bus: Arc<BusConnector<Event>>,
+    outputs: Outputs,
+    clock: Arc<dyn Clock>,
+    audit_log: Option<AuditLog>,
+    access: Option<ControlAccess>,
+    /// Where the number of instances of the scaled templates is persisted.
+    state_dir: PathBuf,
+    /// The journal of the run history, if it's enabled.
+    history: Option<PathBuf>,
+    /// Set for the transient services, like for the others.
+    environment: Option<GlobalEnvironment>,
+}
+
+/// Binds the control socket configured in `config` and serves it in a new thread, until Horust
+/// shuts down. If an audit log is configured, all the requests are recorded there.
+pub(crate) fn spawn(
+    bus: BusConnector<Event>,
+    services: Vec<Arc<Service>>,
+    outputs: Outputs,
+    clock: Arc<dyn Clock>,
+    config: &HorustConfig,
+) -> Result<()> {
+    let socket_path = config
+        .control_socket
+        .clone()
+        .context("The control socket is not configured")?;
+    let audit_log = config
+        .audit_log
+        .as_deref()
+        .map(AuditLog::open)
+        .transpose()?;
+    let listener = bind(&socket_path, config)?;
+    listener.set_nonblocking(true)?;
+    let state = services
+        .into_iter()
+        .map(|service| (service.name.clone(), ServiceState::new(service)))
+        .collect();
+    let server = Server {
+        state: Arc::new(Mutex::new(state)),
+        bus: Arc::new(bus),
+        outputs,
+        clock,
+        audit_log,
+        access: config.control_access.clone(),
+        state_dir: config.state_dir(),
+        history: config.history_path(),
+        environment: config.environment.clone(),
+    };
+    thread::spawn(move || {
+        run(listener, server);
+        let _ = fs::remove_file(&socket_path);
+    });
+    Ok(())
+}
+
+/// The control socket couldn't be given its configured owner, group or mode. Unlike the other
+/// errors, Horust doesn't start: the socket is shared on purpose, but not as it was asked.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed applying the ownership of the control socket: {0:#}")]
+pub(crate) struct OwnershipError(anyhow::Error);
+
+/// Binds the socket in a private (0700) directory next to `socket_path`, where it's given its
+/// ownership, and then moves it to `socket_path`: no client can connect to it before.
+fn bind(socket_path: &Path, config: &HorustConfig) -> Result<UnixListener> {
+    let parent = match socket_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private_dir = unistd::mkdtemp(&parent.join(".horust-XXXXXX"))
+        .with_context(|| format!("Failed creating a directory in: {}", parent.display()))?;
+    let private_path = private_dir.join("sock");
+    let bound = UnixListener::bind(&private_path)
+        .with_context(|| format!("Failed binding control socket: {}", socket_path.display()))
+        .and_then(|listener| {
+            set_ownership(&private_path, config).map_err(OwnershipError)?;
+            // A stale socket left by a previous run is replaced.
+            fs::rename(&private_path, socket_path).with_context(|| {
+                format!("Failed moving control socket to: {}", socket_path.display())
+            })?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    bound
+}
+
+/// Applies the configured owner, group and mode to the socket.
+fn set_ownership(socket_path: &Path, config: &HorustConfig) -> Result<()> {
+    let owner = config
+        .control_socket_owner
+        .as_ref()
+        .map(User::get_uid)
+        .transpose()?;
+    let group = config
+        .control_socket_group
+        .as_ref()
+        .map(Group::get_gid)
+        .transpose()?;
+    if owner.is_some() || group.is_some() {
+        unistd::chown(socket_path, owner, group)
+            .with_context(|| format!("Failed changing the owner of: {}", socket_path.display()))?;
+    }
+    if let Some(mode) = config.control_socket_mode {
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed changing the mode of: {}", socket_path.display()))?;
+    }
+    Ok(())
+}
+
+fn run(listener: UnixListener, server: Server) {
+    loop {
+        for ev in server.bus.try_get_events() {
+            if let Event::ShuttingDownInitiated(_) = ev {
+                return;
+            }
+            apply_event(&mut server.state.lock().unwrap(), ev);
+        }
+        loop {
+            match listener.accept() {
+                Ok((stream, _addr)) => {
+                    let server = server.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = handle_connection(stream, &server) {
+                            debug!("Control connection error: {:?}", error);
+                        }
+                    });
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    error!("Control socket error: {}", error);
+                    break;
+                }
+            }
+        }
+        server.clock.sleep(POLL_INTERVAL);
+    }
+}
+
+fn apply_event(state: &mut HashMap<ServiceName, ServiceState>, ev: Event) {
+    match ev {
+        Event::PidChanged(s_name, pid) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.pid = Some(pid);
+                service_state.spawn_error = None;
+            }
+        }
+        Event::SpawnFailed(s_name, reason) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.spawn_error = Some(reason);
+            }
+        }
+        Event::ServiceExited(s_name, _exit_reason) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.pid = None;
+            }
+        }
+        Event::StatusChanged(s_name, status) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.status = status;
+            }
+        }
+        Event::HealthcheckOutput(s_name, output) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.healthcheck = Some(output);
+            }
+        }
+        Event::Paused(s_name, paused) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.paused = paused;
+            }
+        }
+        Event::ServiceChanged(service) => {
+            if let Some(service_state) = state.get_mut(&service.name) {
+                service_state.service = service;
+            }
+        }
+        Event::ServiceAdded(service) => {
+            state
+                .entry(service.name.clone())
+                .or_insert_with(|| ServiceState::new(service));
+        }
+        Event::NotRunning(s_name, blockers) => {
+            if let Some(service_state) = state.get_mut(&s_name) {
+                service_state.not_running = blockers;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn handle_connection(stream: UnixStream, server: &Server) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    let peer = Peer::of(&stream)
+        .map_err(|error| debug!("Failed reading the peer credentials: {:?}", error))
+        .ok();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(request) = read_message::<_, Request>(&mut reader)? {
+        debug!("Control request: {:?}", request);
+        let authorized = authorize(server.access.as_ref(), peer.as_ref(), &request);
+        if let Request::Attach { service } = &request {
+            // The connection is used only for streaming the output from now on.
+            let service = authorized.and_then(|_| {
+                let state = server.state.lock().unwrap();
+                let service = &get_service(&state, service)?.service;
+                if !server.outputs.captures(service) {
+                    bail!(
+                        "The output of {} isn't read by Horust: set `attachable = true`",
+                        service.name
+                    );
+                }
+                Ok(service.name.clone())
+            });
+            if let Some(audit_log) = &server.audit_log {
+                audit_log.record(peer.as_ref(), &request, &service);
+            }
+            return match service {
+                Ok(service) => attach(&mut writer, &service, &server.outputs),
+                Err(error) => write_message(&mut writer, &error_response(error)),
+            };
+        }
+        let result = authorized.and_then(|_| handle_request(request.clone(), server));
+        if let Some(audit_log) = &server.audit_log {
+            audit_log.record(peer.as_ref(), &request, &result);
+        }
+        let response = result.unwrap_or_else(error_response);
+        write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+/// Checks whether `peer` is allowed to send `request`. Everyone is, if `access` is not set, except
+/// for `run`: the transient services run as Horust's user, so only that user can start them.
+fn authorize(access: Option<&ControlAccess>, peer: Option<&Peer>, request: &Request) -> Result<()> {
+    let owner = nix::unistd::getuid().as_raw();
+    if matches!(request, Request::Run(_)) && peer.is_none_or(|peer| peer.uid != owner) {
+        bail!(
+            "Permission denied: only uid {} can send {} requests",
+            owner,
+            request.name()
+        );
+    }
+    let Some(access) = access else {
+        return Ok(());
+    };
+    let Some(peer) = peer else {
+        bail!("Permission denied: unknown peer credentials");
+    };
+    // Horust's own user can always control it.
+    let is_owner = peer.uid == owner;
+    if is_owner || access.allows(peer.uid, &peer.gids(), request.is_read_only()) {
+        Ok(())
+    } else {
+        bail!(
+            "Permission denied: uid {} (gid {}) can't send {} requests",
+            peer.uid,
+            peer.gid,
+            request.name()
+        )
+    }
+}
+
+fn error_response(error: anyhow::Error) -> Response {
+    Response::Error {
+        message: format!("{:#}", error),
+    }
+}
+
+fn handle_request(request: Request, server: &Server) -> Result<Response> {
+    let mut state = server.state.lock().unwrap();
+    match request {
+        Request::Exec { service } => {
+            let service_state = get_service(&state, &service)?;
+            let (service, pid) = (service_state.service.clone(), service_state.pid);
+            // Computing the environment might run commands: the others can't wait for them.
+            drop(state);
+            Ok(Response::Exec(exec_context(
+                &service,
+                pid,
+                server.clock.as_ref(),
+            )?))
+        }
+        Request::Attach { .. } => bail!("Attach is handled by the connection"),
+        Request::Status { service, tags } => {
+            let mut services: Vec<&ServiceState> = match service {
+                Some(service) => vec![get_service(&state, &service)?],
+                None => state.values().collect(),
+            };
+            services.retain(|service_state| service_state.service.has_any_tag(&tags));
+            services.sort_by(|a, b| a.service.name.cmp(&b.service.name));
+            let services = services.into_iter().map(status_of).collect();
+            Ok(Response::Status { services })
+        }
+        Request::Start(selection) => {
+            let services = select(&state, &selection)?;
+            // The ones with a timer are started again once they've finished, like by their timer.
+            let waiting = |s: &ServiceState| {
+                s.status == ServiceStatus::Initial
+                    || (s.service.timer.is_some()
+                        && matches!(
+                            s.status,
+                            ServiceStatus::Finished | ServiceStatus::FinishedFailed
+                        ))
+            };
+            for name in applicable(
+                &state,
+                &selection,
+                services,
+                waiting,
+                "waiting to be started",
+            )? {
+                server.bus.send_event(Event::Start(name));
+            }
+            Ok(Response::Accepted)
+        }
+        Request::Stop(selection) => {
+            let services = select(&state, &selection)?;
+            let up = |s: &ServiceState| {
+                matches!(
+                    s.status,
+                    ServiceStatus::Initial
+                        | ServiceStatus::Starting
+                        | ServiceStatus::Started
+                        | ServiceStatus::Running
+                )
+            };
+            let services = applicable(&state, &selection, services, up, "running or starting")?;
+            server.bus.send_event(Event::Stop(services));
+            Ok(Response::Accepted)
+        }
+        Request::Reload => {
+            server.bus.send_event(Event::Reload);
+            Ok(Response::Accepted)
+        }
+        Request::Switch { service } => {
+            let service_state = get_service(&state, &service)?;
+            if !service_state.service.variant {
+                bail!("Service {} is not a variant", service_state.service.name);
+            }
+            if service_state.status == ServiceStatus::Disabled {
+                bail!("Service {} is disabled", service_state.service.name);
+            }
+            let name = service_state.service.name.clone();
+            server.bus.send_event(Event::Switch(name));
+            Ok(Response::Accepted)
+        }
+        Request::Restart {
+            selection,
+            max_unavailable,
+        } => {
+            if max_unavailable == 0 {
+                bail!("The max unavailable services must be at least 1");
+            }
+            let services = select(&state, &selection)?;
+            let running = |s: &ServiceState| {
+                matches!(s.status, ServiceStatus::Started | ServiceStatus::Running)
+            };
+            let services = applicable(&state, &selection, services, running, "running")?;
+            server
+                .bus
+                .send_event(Event::Restart(services, max_unavailable));
+            Ok(Response::Accepted)
+        }
+        Request::Scale {
+            service,
+            instances,
+            persist,
+        } => {
+            let template = service.strip_suffix('@').unwrap_or(&service);
+            let max = state
+                .values()
+                .filter_map(|s| s.service.scaled_instance())
+                .filter(|(instance_of, _)| *instance_of == template)
+                .count();
+            if max == 0 {
+                bail!("No scaled template: {}", service);
+            }
+            if instances > max {
+                bail!("{}@ can be scaled up to {} instances", template, max);
+            }
+            if persist {
+                templates::persist(&server.state_dir, template, instances)?;
+            }
+            server
+                .bus
+                .send_event(Event::Scale(template.to_string(), instances));
+            Ok(Response::Accepted)
+        }
+        Request::Clear { service } => {
+            let service_state = get_service(&state, &service)?;
+            if service_state.status != ServiceStatus::Broken {
+                bail!(
+                    "Service {} is {}, not broken",
+                    service_state.service.name,
+                    service_state.status
+                );
+            }
+            let name = service_state.service.name.clone();
+            server.bus.send_event(Event::Clear(name));
+            Ok(Response::Accepted)
+        }
+        Request::Pause { service } => {
+            let service_state = get_service(&state, &service)?;
+            if service_state.pid.is_none() {
+                bail!(
+                    "Service {} is {}, not running",
+                    service_state.service.name,
+                    service_state.status
+                );
+            }
+            let name = service_state.service.name.clone();
+            server
+                .bus
+                .send_event(Event::Pause(name, PausedBy::Operator));
+            Ok(Response::Accepted)
+        }
+        Request::Resume { service } => {
+            let service_state = get_service(&state, &service)?;
+            if !service_state.paused {
+                bail!("Service {} is not paused", service_state.service.name);
+            }
+            let name = service_state.service.name.clone();
+            server
+                .bus
+                .send_event(Event::Resume(name, PausedBy::Operator));
+            Ok(Response::Accepted)
+        }
+        Request::History {
+            service,
+            since,
+            until,
+        } => {
+            let path = server
+                .history
+                .as_deref()
+                .context("The run history is not enabled")?;
+            // The journal is kept across the runs of Horust, so it can hold services which are
+            // not configured anymore.
+            let service = service.map(|service| match get_service(&state, &service) {
+                Ok(service_state) => service_state.service.name.clone(),
+                Err(_) => service,
+            });
+            let runs = history::read(path)
+                .with_context(|| format!("Failed reading the run history: {}", path.display()))?
+                .into_iter()
+                .filter(|run| service.as_ref().is_none_or(|name| run.service == *name))
+                .filter(|run| since.is_none_or(|since| run.ended >= since))
+                .filter(|run| until.is_none_or(|until| run.ended <= until))
+                .collect();
+            Ok(Response::History { runs })
+        }
+        Request::Run(transient) => {
+            if get_service(&state, &transient.name).is_ok() {
+                bail!("Service {} already exists", transient.name);
+            }
+            let service = transient_service(transient, server.environment.clone())?;
+            let service = Arc::new(service);
+            // Right away, so that another service can't be added with the same name meanwhile.
+            state.insert(service.name.clone(), ServiceState::new(service.clone()));
+            server.bus.send_event(Event::ServiceAdded(service));
+            Ok(Response::Accepted)
+        }
+        Request::WhyNotRunning { service } => {
+            let service_state = get_service(&state, &service)?;
+            Ok(Response::WhyNotRunning {
+                service: service_state.service.name.clone(),
+                status: service_state.status.clone(),
+                reasons: why_not_running(service_state, server.clock.now()),
+            })
+        }
+    }
+}
+
+/// The reasons why the service isn't running, with how long is left of the ones which are over by
+/// themselves. Followed by why its last spawn has failed, if it has.
+fn why_not_running(service_state: &ServiceState, now: Instant) -> Vec<String> {
+    if service_state.status == ServiceStatus::Running {
+        return vec![];
+    }
+    let mut reasons: Vec<String> = service_state
+        .not_running
+        .iter()
+        .map(|blocker| match blocker.until {
+            Some(until) => {
+                // Rounded up to the second.
+                let left = until.saturating_duration_since(now);
+                let left = Duration::from_secs(left.as_secs() + u64::from(left.subsec_nanos() > 0));
+                format!(
+                    "{} ({} left)",
+                    blocker.reason,
+                    humantime::format_duration(left)
+                )
+            }
+            None => blocker.reason.clone(),
+        })
+        .collect();
+    if let Some(spawn_error) = &service_state.spawn_error {
+        reasons.push(format!("its last spawn has failed: {}", spawn_error));
+    }
+    if reasons.is_empty() {
+        reasons.push("not known yet".to_string());
+    }
+    reasons
+}
+
+/// The definition of the `transient` service, with the defaults of the services. It runs as Horust's
+/// user (the only one allowed to send `run`), rather than the default `root`.
+fn transient_service(
+    transient: Transient,
+    environment: Option<GlobalEnvironment>,
+) -> Result<Service> {
+    if transient.name.is_empty() || transient.name.contains('/') {
+        bail!("Invalid service name: {:?}", transient.name);
+    }
+    if transient.command.is_empty() {
+        bail!("Missing command");
+    }
+    let command =
+        shlex::try_join(transient.command.iter().map(String::as_str)).context("Invalid command")?;
+    let mut service = Service::from_command(command);
+    service.name = transient.name;
+    service.user = nix::unistd::getuid().into();
+    if let Some(working_directory) = transient.working_directory {
+        service.working_directory = working_directory;
+    }
+    service.restart = Restart {
+        strategy: transient.restart,
+        attempts: transient.attempts,
+        ..Default::default()
+    };
+    service.limits = Limits {
+        wall_time: transient.wall_time,
+        cpu_time: transient.cpu_time,
+    };
+    service.environment.global = environment;
+    Ok(service)
+}
+
+fn status_of(service_state: &ServiceState) -> protocol::ServiceState {
+    protocol::ServiceState {
+        name: service_state.service.name.clone(),
+        description: service_state.service.description.clone(),
+        tags: service_state.service.tags.clone(),
+        status: service_state.status.clone(),
+        pid: service_state.pid.map(Pid::as_raw),
+        healthcheck: service_state.healthcheck.clone(),
+        paused: service_state.paused,
+        spawn_error: service_state.spawn_error.clone(),
+    }
+}
+
+/// Forwards the output of `service` to `writer`, until the client goes away.
+fn attach(writer: &mut UnixStream, service: &str, outputs: &Outputs) -> Result<()> {
+    let subscription = outputs.subscribe(service);
+    write_message(writer, &Response::Attached)?;
+    for output in subscription {
+        let response = Response::Output {
+            stream: output.stream,
+            data: output.data,
+        };
+        write_message(writer, &response)?;
+    }
+    Ok(())
+}
+
+/// The selected services, in starting order: each one after the services it starts after.
+fn select(
+    state: &HashMap<ServiceName, ServiceState>,
+    selection: &Selection,
+) -> Result<Vec<ServiceName>> {
+    let mut selected = BTreeSet::new();
+    let names = |matches: &dyn Fn(&Service) -> bool| -> Vec<ServiceName> {
+        state
+            .values()
+            .filter(|s| matches(&s.service))
+            .map(|s| s.service.name.clone())
+            .collect()
+    };
+    match selection
+        .service
+        .as_deref()
+        .map(|s| (s, s.strip_suffix('@')))
+    {
+        Some((service, Some(template))) => {
+            let instances = names(&|s| s.instance().is_some_and(|(name, _)| name == template));
+            if instances.is_empty() {
+                bail!("No instances of: {}", service);
+            }
+            selected.extend(instances);
+        }
+        Some((service, None)) => {
+            selected.insert(get_service(state, service)?.service.name.clone());
+        }
+        None => (),
+    }
+    for target in &selection.targets {
+        let with_suffix = format!("{}.toml", target);
+        let named = names(&|s| s.is_named(target) || s.is_named(&with_suffix));
+        if named.is_empty() {
+            bail!("No services named, or providing: {}", target);
+        }
+        selected.extend(named);
+    }
+    if !selection.tags.is_empty() {
+        let tagged = names(&|s| !s.tags.is_empty() && s.has_any_tag(&selection.tags));
+        if tagged.is_empty() {
+            bail!("No services tagged: {}", selection.tags.join(", "));
+        }
+        selected.extend(tagged);
+    }
+    if selected.is_empty() {
+        bail!("No services selected: give a service, a target or a tag");
+    }
+    let services: Vec<Service> = state.values().map(|s| (*s.service).clone()).collect();
+    let plan = Plan::new(&services);
+    let order: Vec<&ServiceName> = plan.waves.iter().flatten().map(|s| &s.service).collect();
+    let mut selected: Vec<ServiceName> = selected.into_iter().collect();
+    // The services which are never started (e.g. disabled) go last.
+    selected.sort_by_key(|name| order.iter().position(|s| *s == name).unwrap_or(order.len()));
+    Ok(selected)
+}
+
+/// The selected services which `applies` to. It has to apply to a service selected by its name,
+/// while the others (e.g. selected by a tag) are skipped.
+fn applicable(
+    state: &HashMap<ServiceName, ServiceState>,
+    selection: &Selection,
+    services: Vec<ServiceName>,
+    applies: impl Fn(&ServiceState) -> bool,
+    what: &str,
+) -> Result<Vec<ServiceName>> {
+    let (applicable, skipped): (Vec<ServiceName>, Vec<ServiceName>) =
+        services.into_iter().partition(|name| applies(&state[name]));
+    match skipped.first() {
+        Some(name) if selection.is_single() => {
+            let service_state = &state[name];
+            bail!(
+                "Service {} is {}, not {}",
+                service_state.service.name,
+                service_state.status,
+                what
+            );
+        }
+        _ if applicable.is_empty() => bail!("None of the selected services is {}", what),
+        _ => Ok(applicable),
+    }
+}
+
+fn get_service<'a>(
+    state: &'a HashMap<ServiceName, ServiceState>,
+    name: &str,
+) -> Result<&'a ServiceState> {
+    state
+        .get(name)
+        .or_else(|| state.get(&format!("{}.toml", name)))
+        .with_context(|| format!("Service not found: {}", name))
+}
+
+/// How the commands are run in the context of the service: with the environment of its running
+/// process, including the variables computed when it was spawned.
+fn exec_context(service: &Service, pid: Option<Pid>, clock: &dyn Clock) -> Result<ExecContext> {
+    let spawned = pid.and_then(|_| supervisor::spawned_environment(&service.name));
+    let environment = match spawned {
+        Some(environment) => environment,
+        // The one it would be spawned with now.
+        None => supervisor::environment(service, clock)?,
+    };
+    Ok(ExecContext {
+        uid: service.uid()?.as_raw(),
+        gid: service.gid().map(|gid| gid.as_raw()),
+        environment,
+        working_directory: service.working_directory.clone(),
+        pid: pid.map(Pid::as_raw),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use nix::unistd::Pid;
+
+    use crate::horust::control::audit::Peer;
+    use crate::horust::control::protocol::{Request, Transient};
+    use crate::horust::control::{apply_event, authorize, get_service, ServiceState};
+    use crate::horust::formats::{
+        ControlAccess, Event, ExitReason, HealthcheckOutput, RestartStrategy, Service,
+        ServiceStatus,
+    };
+
+    #[test]
+    fn test_authorize() {
+        let exec = Request::Exec {
+            service: "a".into(),
+        };
+        let attach = Request::Attach {
+            service: "a".into(),
+        };
+        let status = Request::Status {
+            service: None,
+            tags: vec![],
+        };
+        let peer = |uid, gid, groups: &[u32]| Peer {
+            uid,
+            gid,
+            pid: 1234,
+            groups: groups.to_vec(),
+        };
+        let access = ControlAccess {
+            uids: vec![1000],
+            gids: vec![50],
+            read_only_uids: vec![2000],
+            read_only_gids: vec![60],
+        };
+        let authorized =
+            |peer: Peer, request| authorize(Some(&access), Some(&peer), request).is_ok();
+        assert!(authorized(peer(1000, 1, &[]), &exec));
+        assert!(authorized(peer(3000, 50, &[]), &exec));
+        assert!(authorized(peer(2000, 1, &[]), &status));
+        assert!(authorized(peer(3000, 60, &[]), &status));
+        assert!(!authorized(peer(2000, 1, &[]), &exec));
+        assert!(!authorized(peer(3000, 1, &[]), &status));
+        assert!(authorize(Some(&access), None, &status).is_err());
+        // The supplementary groups are matched as well.
+        assert!(authorized(peer(3000, 1, &[7, 50]), &exec));
+        assert!(authorized(peer(3000, 1, &[60]), &status));
+        assert!(!authorized(peer(3000, 1, &[60]), &exec));
+        // The output might have secrets: attaching needs full access.
+        assert!(authorized(peer(1000, 1, &[]), &attach));
+        assert!(!authorized(peer(2000, 1, &[]), &attach));
+        let owner = nix::unistd::getuid().as_raw();
+        assert!(authorized(peer(owner, 1, &[]), &exec));
+
+        let error = authorize(Some(&access), Some(&peer(2000, 1, &[])), &exec).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Permission denied: uid 2000 (gid 1) can't send exec requests"
+        );
+        assert!(authorize(None, None, &exec).is_ok());
+
+        // Only Horust's user can run transient services, whatever the access.
+        let run = Request::Run(Transient {
+            name: "job".into(),
+            command: vec!["/bin/true".into()],
+            working_directory: None,
+            restart: RestartStrategy::Never,
+            attempts: 0,
+            wall_time: None,
+            cpu_time: None,
+        });
+        assert!(authorize(None, Some(&peer(owner, 1, &[])), &run).is_ok());
+        assert!(authorized(peer(owner, 1, &[]), &run));
+        assert!(!authorized(peer(1000, 1, &[]), &run));
+        let error = authorize(None, Some(&peer(owner + 1, 1, &[])), &run).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Permission denied: only uid {} can send run requests",
+                owner
+            )
+        );
+        assert!(authorize(None, None, &run).is_err());
+    }
+
+    #[test]
+    fn test_apply_event() {
+        let service = Service::from_name("a.toml");
+        let mut state = HashMap::new();
+        state.insert(service.name.clone(), ServiceState::new(service.into()));
+        apply_event(&mut state, Event::PidChanged("a.toml".into(), Pid::this()));
+        assert_eq!(get_service(&state, "a").unwrap().pid, Some(Pid::this()));
+
+        apply_event(
+            &mut state,
+            Event::ServiceExited("a.toml".into(), ExitReason::Code(0)),
+        );
+        assert_eq!(get_service(&state, "a.toml").unwrap().pid, None);
+        get_service(&state, "b").unwrap_err();
+
+        let reason = "Program \"a\" not found in any of the PATH directories";
+        apply_event(
+            &mut state,
+            Event::SpawnFailed("a.toml".into(), reason.into()),
+        );
+        let spawn_error = &get_service(&state, "a").unwrap().spawn_error;
+        assert_eq!(spawn_error.as_deref(), Some(reason));
+        apply_event(&mut state, Event::PidChanged("a.toml".into(), Pid::this()));
+        assert_eq!(get_service(&state, "a").unwrap().spawn_error, None);
+
+        apply_event(
+            &mut state,
+            Event::StatusChanged("a.toml".into(), ServiceStatus::Running),
+        );
+        let output = HealthcheckOutput::new(vec!["file-path: missing".into()], Duration::ZERO);
+        apply_event(
+            &mut state,
+            Event::HealthcheckOutput("a.toml".into(), output.clone()),
+        );
+        let service_state = get_service(&state, "a").unwrap();
+        assert_eq!(service_state.status, ServiceStatus::Running);
+        assert_eq!(service_state.healthcheck, Some(output));
+    }
+}
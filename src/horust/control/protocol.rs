@@ -0,0 +1,363 @@
+//! Messages exchanged on the control socket.
+//! Every message is serialized as a single line of JSON: the client sends a `Request`, and
+//! Horust answers with a `Response`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::horust::formats::{HealthcheckOutput, RestartStrategy, ServiceName, ServiceStatus};
+pub use crate::horust::output::Stream;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum Request {
+    /// Get the context needed to run a command like `service` does.
+    Exec { service: ServiceName },
+    /// Stream the output of `service`, until the client disconnects.
+    Attach { service: ServiceName },
+    /// Get the state of `service`, or of all the services if not set. With `tags`, only of the
+    /// services having any of them.
+    Status {
+        service: Option<ServiceName>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+    },
+    /// Start the selected services which are waiting to be started (e.g. `auto-start` is false).
+    Start(Selection),
+    /// Stop the selected services, each one after the selected services which start after it.
+    /// They're not started again until they're asked to start.
+    Stop(Selection),
+    /// Reload the definitions of the services, restarting the ones which have changed.
+    Reload,
+    /// Switch to the variant `service`, stopping the other variants of its template once it's
+    /// running.
+    Switch { service: ServiceName },
+    /// Restart the selected services in starting order, at most `max_unavailable` at a time.
+    Restart {
+        selection: Selection,
+        max_unavailable: usize,
+    },
+    /// Scale the template `service` (like `worker@`) to `instances`: the instances beyond are
+    /// stopped, and the others started. If `persist`, it's kept when Horust restarts.
+    Scale {
+        service: String,
+        instances: usize,
+        persist: bool,
+    },
+    /// Reset the broken `service`, and start it again.
+    Clear { service: ServiceName },
+    /// Stop the processes of `service` (SIGSTOP), until it's resumed.
+    Pause { service: ServiceName },
+    /// Continue the processes of the paused `service` (SIGCONT).
+    Resume { service: ServiceName },
+    /// Get the runs in the run history of `service` (or of all the services, if not set) which
+    /// have ended between `since` and `until`.
+    History {
+        service: Option<ServiceName>,
+        #[serde(default, with = "humantime_serde")]
+        since: Option<SystemTime>,
+        #[serde(default, with = "humantime_serde")]
+        until: Option<SystemTime>,
+    },
+    /// Add a transient service, and start it.
+    Run(Transient),
+    /// Explain why `service` isn't running.
+    WhyNotRunning { service: ServiceName },
+}
+
+impl Request {
+    /// Read-only requests don't change anything, nor run anything.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Request::Exec { .. }
+            | Request::Start(_)
+            | Request::Stop(_)
+            | Request::Reload
+            | Request::Switch { .. }
+            | Request::Restart { .. }
+            | Request::Scale { .. }
+            | Request::Clear { .. }
+            | Request::Pause { .. }
+            | Request::Resume { .. }
+            | Request::Run(_)
+            // The output of the services might include secrets.
+            | Request::Attach { .. } => false,
+            Request::Status { .. }
+            | Request::History { .. }
+            | Request::WhyNotRunning { .. } => true,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Exec { .. } => "exec",
+            Request::Attach { .. } => "attach",
+            Request::Status { .. } => "status",
+            Request::Start(_) => "start",
+            Request::Stop(_) => "stop",
+            Request::Reload => "reload",
+            Request::Switch { .. } => "switch",
+            Request::Restart { .. } => "restart",
+            Request::Scale { .. } => "scale",
+            Request::Clear { .. } => "clear",
+            Request::Pause { .. } => "pause",
+            Request::Resume { .. } => "resume",
+            Request::History { .. } => "history",
+            Request::Run(_) => "run",
+            Request::WhyNotRunning { .. } => "why-not-running",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum Response {
+    Exec(ExecContext),
+    /// Followed by an `Output` for every chunk of output read from the service.
+    Attached,
+    Output {
+        stream: Stream,
+        data: Vec<u8>,
+    },
+    /// Sorted by service name.
+    Status {
+        services: Vec<ServiceState>,
+    },
+    /// Oldest first.
+    History {
+        runs: Vec<Run>,
+    },
+    /// Why the service isn't running: no reasons if it is.
+    WhyNotRunning {
+        service: ServiceName,
+        status: ServiceStatus,
+        reasons: Vec<String>,
+    },
+    /// The request was accepted, and is being carried out.
+    Accepted,
+    Error {
+        message: String,
+    },
+}
+
+/// The services a request applies to: all the ones selected by any of the fields.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Selection {
+    /// A service, or a template followed by `@` (like `app@`) for all its instances.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<ServiceName>,
+    /// The services named, or providing one of these names (see `provides`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    /// The services having any of these tags.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Selection {
+    /// Selects only `service`.
+    pub fn service(service: impl Into<ServiceName>) -> Self {
+        Self {
+            service: Some(service.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Only a service is selected by its name, not a group of them.
+    pub fn is_single(&self) -> bool {
+        self.targets.is_empty()
+            && self.tags.is_empty()
+            && self.service.as_ref().is_some_and(|s| !s.ends_with('@'))
+    }
+}
+
+/// The state of a service, as seen by the control socket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServiceState {
+    pub name: ServiceName,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub status: ServiceStatus,
+    pub pid: Option<i32>,
+    /// The outcome of the last run of the healthchecks, if the service has any.
+    pub healthcheck: Option<HealthcheckOutput>,
+    /// Its processes are stopped, see `Request::Pause`.
+    #[serde(default)]
+    pub paused: bool,
+    /// Why its process couldn't be spawned, the last time it was tried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spawn_error: Option<String>,
+}
+
+/// A run of a service, from its spawn until its process has exited, as recorded in the run history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Run {
+    pub service: ServiceName,
+    /// How many times the service was spawned, since Horust has started.
+    pub generation: u32,
+    pub pid: i32,
+    #[serde(with = "humantime_serde")]
+    pub started: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub ended: SystemTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// The name of the signal which has killed it, e.g. `SIGKILL`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    pub failed: bool,
+}
+
+/// A service which isn't defined in a file, but added while Horust is running. It's kept until
+/// Horust exits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Transient {
+    pub name: ServiceName,
+    /// The program, followed by its arguments.
+    pub command: Vec<String>,
+    /// Horust's working directory, if not set.
+    #[serde(default)]
+    pub working_directory: Option<PathBuf>,
+    /// Like the `restart` section of the services.
+    pub restart: RestartStrategy,
+    pub attempts: u32,
+    /// Like the `limits` section of the services.
+    #[serde(default, with = "humantime_serde")]
+    pub wall_time: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub cpu_time: Option<Duration>,
+}
+
+/// How the processes of a service are run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExecContext {
+    pub uid: u32,
+    /// Set if the service doesn't run with the primary group of Horust's user.
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Environment variables, as `KEY=VALUE`.
+    pub environment: Vec<String>,
+    pub working_directory: PathBuf,
+    /// Pid of the service, if it's currently running. Used for joining its cgroup and namespaces.
+    pub pid: Option<i32>,
+}
+
+/// Sends `request` to the control socket at `socket`, and waits for the response.
+/// A `Response::Error` is turned into an `Err`.
+pub fn send_request(socket: &Path, request: &Request) -> Result<Response> {
+    Connection::connect(socket)?.request(request)
+}
+
+/// A connection to the control socket, for requests which are answered by more than one response.
+pub struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Connection {
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let writer = UnixStream::connect(socket)
+            .with_context(|| format!("Failed connecting to: {}", socket.display()))?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    /// Sends `request`, and waits for the first response.
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request)?;
+        self.next_response()?
+            .context("Connection closed without a response")
+    }
+
+    /// Waits for the next response. Returns `None` if the connection was closed.
+    pub fn next_response(&mut self) -> Result<Option<Response>> {
+        match read_message(&mut self.reader)? {
+            Some(Response::Error { message }) => bail!(message),
+            response => Ok(response),
+        }
+    }
+}
+
+pub(crate) fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+/// Reads the next message. Returns `None` if the stream was closed.
+pub(crate) fn read_message<R: BufRead, T: for<'de> Deserialize<'de>>(
+    reader: &mut R,
+) -> Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let message = serde_json::from_str(&line).context("Invalid message")?;
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::horust::control::protocol::{
+        read_message, write_message, Request, Response, Selection,
+    };
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = vec![];
+        let request = Request::Exec {
+            service: "db.toml".into(),
+        };
+        write_message(&mut buf, &request).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            "{\"request\":\"exec\",\"service\":\"db.toml\"}\n"
+        );
+        let mut reader = Cursor::new(buf);
+        let received: Option<Request> = read_message(&mut reader).unwrap();
+        assert_eq!(received, Some(request));
+        let received: Option<Request> = read_message(&mut reader).unwrap();
+        assert_eq!(received, None);
+
+        // A single service is selected like before the tags and the targets.
+        let mut reader = Cursor::new(b"{\"request\":\"start\",\"service\":\"db\"}\n".to_vec());
+        let received: Option<Request> = read_message(&mut reader).unwrap();
+        assert_eq!(received, Some(Request::Start(Selection::service("db"))));
+        let mut buf = vec![];
+        let stop = Request::Stop(Selection {
+            tags: vec!["workers".into()],
+            ..Default::default()
+        });
+        write_message(&mut buf, &stop).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            "{\"request\":\"stop\",\"tags\":[\"workers\"]}\n"
+        );
+
+        let mut reader = Cursor::new(b"{\"response\":\"error\",\"message\":\"boom\"}\n".to_vec());
+        let received: Option<Response> = read_message(&mut reader).unwrap();
+        assert_eq!(
+            received,
+            Some(Response::Error {
+                message: "boom".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Dynamic users: services with `user = "dynamic"` run with a uid (and gid) which isn't used by
+//! anything else. The allocations are kept in a state file, so that a service gets the same uid
+//! across restarts of Horust (and the files it has written stay accessible).
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::{self, Gid, Uid};
+
+use crate::horust::formats::{DynamicUser, Service, ServiceName};
+
+/// The uids (and gids) reserved for the dynamic users, the same range used by systemd.
+const DYNAMIC_UIDS: std::ops::RangeInclusive<u32> = 61184..=65519;
+const STATE_FILE: &str = "dynamic-users.json";
+
+type Allocations = BTreeMap<ServiceName, u32>;
+
+/// Allocates a uid to each of the services with a dynamic user, and creates their home directory
+/// in `runtime_dir`. If the allocation fails, the service will fail to spawn.
+pub(crate) fn allocate(services: &mut [Service], state_dir: &Path, runtime_dir: &Path) {
+    if !services.iter().any(Service::has_dynamic_user) {
+        return;
+    }
+    let state_file = state_dir.join(STATE_FILE);
+    let mut allocations: Allocations = fs::read_to_string(&state_file)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    let mut used: HashSet<u32> = HashSet::new();
+    for service in services.iter_mut().filter(|s| s.has_dynamic_user()) {
+        let result = allocate_uid(&service.name, &mut allocations, &mut used)
+            .and_then(|uid| create_home(&service.name, uid, runtime_dir));
+        match result {
+            Ok(dynamic_user) => {
+                if service.working_directory == Service::default_working_directory() {
+                    service.working_directory = dynamic_user.home.clone();
+                }
+                service.dynamic_user = Some(dynamic_user);
+            }
+            Err(error) => error!(
+                "{}: failed allocating a dynamic user: {:?}",
+                service.name, error
+            ),
+        }
+    }
+    let saved = fs::create_dir_all(state_dir)
+        .and_then(|_| fs::write(&state_file, serde_json::to_string(&allocations).unwrap()));
+    if let Err(error) = saved {
+        error!("Failed saving {}: {}", state_file.display(), error);
+    }
+}
+
+/// Removes the home directories of the dynamic users.
+pub(crate) fn cleanup(services: &[Service]) {
+    for dynamic_user in services.iter().filter_map(|s| s.dynamic_user.as_ref()) {
+        if let Err(error) = fs::remove_dir_all(&dynamic_user.home) {
+            debug!("Failed removing {}: {}", dynamic_user.home.display(), error);
+        }
+    }
+}
+
+/// The uid previously allocated to `service` if it's still free, otherwise the first free one.
+fn allocate_uid(
+    service: &ServiceName,
+    allocations: &mut Allocations,
+    used: &mut HashSet<u32>,
+) -> Result<u32> {
+    let allocated: HashSet<u32> = allocations
+        .iter()
+        .filter(|(name, _uid)| *name != service)
+        .map(|(_name, uid)| *uid)
+        .collect();
+    let is_free =
+        |uid: &u32| !used.contains(uid) && !allocated.contains(uid) && is_unused_by_system(*uid);
+    let uid = match allocations.get(service).copied().filter(is_free) {
+        Some(uid) => uid,
+        None => DYNAMIC_UIDS
+            .clone()
+            .find(is_free)
+            .context("No free uid left for dynamic users")?,
+    };
+    used.insert(uid);
+    allocations.insert(service.clone(), uid);
+    Ok(uid)
+}
+
+/// Not used by any user or group of the system.
+fn is_unused_by_system(id: u32) -> bool {
+    matches!(unistd::User::from_uid(Uid::from_raw(id)), Ok(None))
+        && matches!(unistd::Group::from_gid(Gid::from_raw(id)), Ok(None))
+}
+
+fn create_home(service: &ServiceName, uid: u32, runtime_dir: &Path) -> Result<DynamicUser> {
+    let name = format!(
+        "horust-{}",
+        service.strip_suffix(".toml").unwrap_or(service)
+    );
+    if name.contains('/') {
+        bail!("Invalid user name: {}", name);
+    }
+    let home: PathBuf = runtime_dir.join(&name);
+    // Nothing is kept from the previous runs.
+    if home.exists() {
+        fs::remove_dir_all(&home)?;
+    }
+    fs::create_dir_all(&home).with_context(|| format!("Failed creating {}", home.display()))?;
+    fs::set_permissions(&home, fs::Permissions::from_mode(0o700))?;
+    unistd::chown(&home, Some(Uid::from_raw(uid)), Some(Gid::from_raw(uid)))?;
+    Ok(DynamicUser { uid, name, home })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::horust::dynamic_users::{allocate_uid, Allocations, DYNAMIC_UIDS};
+
+    #[test]
+    fn test_allocate_uid() {
+        let first = *DYNAMIC_UIDS.start();
+        let mut allocations = Allocations::new();
+        allocations.insert("b".into(), first + 10);
+        allocations.insert("gone".into(), first);
+        let mut used = HashSet::new();
+        // Allocations of other services are not reused, even if they're gone.
+        assert_eq!(
+            allocate_uid(&"a".into(), &mut allocations, &mut used).unwrap(),
+            first + 1
+        );
+        // Stable across runs.
+        assert_eq!(
+            allocate_uid(&"b".into(), &mut allocations, &mut used).unwrap(),
+            first + 10
+        );
+        assert_eq!(
+            allocate_uid(&"a".into(), &mut allocations, &mut HashSet::new()).unwrap(),
+            first + 1
+        );
+        assert_eq!(allocations.get("a"), Some(&(first + 1)));
+    }
+}
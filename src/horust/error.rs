@@ -16,12 +16,57 @@ fn validation_errors(errors: &[ValidationError]) -> String {
         .join("\n")
 }
 
+/// Names are case sensitive: the hint for a name which differs only in case.
+fn did_you_mean(similar: &Option<String>) -> String {
+    match similar {
+        Some(similar) => format!(" Names are case sensitive: did you mean '{}'?", similar),
+        None => String::new(),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ValidationError {
     #[error(
-        "Service '{before}', should start after '{after}', but there is no service with such name."
+        "Service '{before}', should start after '{after}', but there is no service with such name.{}",
+        did_you_mean(.similar)
     )]
-    MissingDependency { before: String, after: String },
+    MissingDependency {
+        before: String,
+        after: String,
+        /// A name which differs only in case.
+        similar: Option<String>,
+    },
+    #[error("Service '{service}' has a start-delay range whose min is greater than its max.")]
+    InvalidStartDelay { service: String },
     #[error("Command is defined, but it is empty for service: {service}")]
     CommandEmpty { service: String },
+    #[error("Service '{service}' has a command which can't be executed: {reason}.")]
+    CommandNotExecutable { service: String, reason: String },
+    #[error("Service '{service}' has an invalid hostname: '{hostname}'.")]
+    InvalidHostname { service: String, hostname: String },
+    #[error("Service '{service}' has remain-after-exit, but it's not a oneshot.")]
+    RemainAfterExitNotOneshot { service: String },
+    #[error("Service '{service}' is lazy, but it has no socket to listen on.")]
+    LazyWithoutSocket { service: String },
+    #[error("Service '{service}' restarts start-first, so its kill-mode can't be cgroup: both processes would be stopped.")]
+    StartFirstWithCgroupKillMode { service: String },
+    #[error("Service '{service}' is a variant, but its name has no instance (like 'app@blue').")]
+    VariantWithoutInstance { service: String },
+    #[error("More than one variant of '{template}' is auto-started, the others need auto-start = false.")]
+    MultipleAutoStartedVariants { template: String },
+    #[error("Service '{service}' has a scale, but it's not a template (like 'worker@').")]
+    ScaleWithoutTemplate { service: String },
+    #[error("Service '{service}' has instances, but it's not a template (like 'app@').")]
+    InstancesWithoutTemplate { service: String },
+    #[error("The template '{template}' starts more instances than its scale.max.")]
+    ScaleAboveMax { template: String },
+}
+
+/// Likely mistakes, which don't keep Horust from starting: they're only logged.
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationWarning {
+    #[error("Service '{service}' should start after itself, so it would never start.")]
+    SelfDependency { service: String },
+    #[error("Service '{service}' isn't started automatically (auto-start is off, or it's lazy), and no service starts after it: it's only started on demand.")]
+    UnreachableService { service: String },
 }
@@ -0,0 +1,471 @@
+//! Calendar expressions, like systemd's `OnCalendar=`: `Mon..Fri 02:30`, `*-*-01 00:00:00`,
+//! `*:0/15` or `daily`. They're made of optional weekdays, a date (`[year-]month-day`) and a time
+//! (`hour:minute[:second]`), in local time unless `UTC` or an offset (like `+01:00`) follows.
+//! Each of the components is `*`, a value, a range `a..b`, a repetition `a/step` (or `*/step`,
+//! `a..b/step`), or a comma separated list of them.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use super::service::{local_utc_offset, parse_time_of_day};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+/// The calendar repeats itself every 400 years: nothing matches later if nothing matches before.
+const DAYS_SEARCHED: i64 = 146_097;
+const WEEKDAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// When a calendar expression triggers.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct CalendarExpression {
+    /// As written.
+    expression: String,
+    /// Monday is 0.
+    weekdays: Field,
+    years: Field,
+    months: Field,
+    days: Field,
+    hours: Field,
+    minutes: Field,
+    seconds: Field,
+    /// Offset from UTC in seconds, local time if `None`.
+    utc_offset: Option<i32>,
+}
+
+impl CalendarExpression {
+    /// The first time strictly after `after` when the expression triggers, if it ever does again.
+    pub fn next_trigger(&self, after: SystemTime) -> Option<SystemTime> {
+        let after = after
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let utc_offset = self
+            .utc_offset
+            .map_or_else(|| local_utc_offset(after), i64::from);
+        let next = self.next_wall_time(after + utc_offset)? - utc_offset;
+        // The local offset might have changed in between, e.g. with daylight saving time.
+        let next = match self.utc_offset {
+            Some(_) => next,
+            None => next + utc_offset - local_utc_offset(next),
+        };
+        Some(UNIX_EPOCH + Duration::from_secs(next.max(0) as u64))
+    }
+
+    /// The first time strictly after `wall`, in seconds since the epoch of the wall clock.
+    fn next_wall_time(&self, wall: i64) -> Option<i64> {
+        let start = wall + 1;
+        let first_day = start.div_euclid(SECS_PER_DAY);
+        for day in first_day..first_day + DAYS_SEARCHED {
+            let from = if day == first_day {
+                start.rem_euclid(SECS_PER_DAY) as u32
+            } else {
+                0
+            };
+            let (year, month, day_of_month) = civil_from_days(day);
+            // 1970-01-01 was a Thursday.
+            let weekday = (day + 3).rem_euclid(7) as u32;
+            if self.years.contains(year)
+                && self.months.contains(month)
+                && self.days.contains(day_of_month)
+                && self.weekdays.contains(weekday)
+            {
+                if let Some(time) = self.first_time(from) {
+                    return Some(day * SECS_PER_DAY + i64::from(time));
+                }
+            }
+        }
+        None
+    }
+
+    /// The first time of the day, in seconds since midnight, from `from` onwards.
+    fn first_time(&self, from: u32) -> Option<u32> {
+        for hour in (0..24).filter(|hour| self.hours.contains(*hour)) {
+            if (hour + 1) * 3600 <= from {
+                continue;
+            }
+            for minute in (0..60).filter(|minute| self.minutes.contains(*minute)) {
+                if hour * 3600 + (minute + 1) * 60 <= from {
+                    continue;
+                }
+                let time = (0..60)
+                    .filter(|second| self.seconds.contains(*second))
+                    .map(|second| hour * 3600 + minute * 60 + second)
+                    .find(|time| *time >= from);
+                if time.is_some() {
+                    return time;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl FromStr for CalendarExpression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens: Vec<&str> = s.split_whitespace().collect();
+        let shorthand = match tokens.first().map(|token| token.to_lowercase()).as_deref() {
+            Some("minutely") => Some("*-*-* *:*:00"),
+            Some("hourly") => Some("*-*-* *:00:00"),
+            Some("daily") => Some("*-*-* 00:00:00"),
+            Some("weekly") => Some("Mon *-*-* 00:00:00"),
+            Some("monthly") => Some("*-*-01 00:00:00"),
+            Some("yearly" | "annually") => Some("*-01-01 00:00:00"),
+            _ => None,
+        };
+        if let Some(shorthand) = shorthand {
+            tokens.splice(0..1, shorthand.split_whitespace());
+        }
+        if tokens.is_empty() {
+            bail!("Empty calendar expression");
+        }
+        let utc_offset = match tokens.last() {
+            Some(&"UTC") => {
+                tokens.pop();
+                Some(0)
+            }
+            Some(offset) if offset.starts_with('+') || offset.starts_with('-') => {
+                let sign = if offset.starts_with('+') { 1 } else { -1 };
+                let offset = parse_time_of_day(&offset[1..])
+                    .with_context(|| format!("Invalid timezone: {}", offset))?;
+                tokens.pop();
+                Some(sign * offset as i32 * 60)
+            }
+            _ => None,
+        };
+        let mut tokens = tokens.into_iter().peekable();
+        let weekdays = match tokens.next_if(|token| token.starts_with(char::is_alphabetic)) {
+            Some(weekdays) => parse_weekdays(weekdays)?,
+            None => Field::any(0, 6),
+        };
+        let (years, months, days) = match tokens.next_if(|token| token.contains('-')) {
+            Some(date) => parse_date(date)?,
+            None => (Field::any(1970, 9999), Field::any(1, 12), Field::any(1, 31)),
+        };
+        let (hours, minutes, seconds) = match tokens.next_if(|token| token.contains(':')) {
+            Some(time) => parse_time(time)?,
+            None => (Field::value(0), Field::value(0), Field::value(0)),
+        };
+        if let Some(token) = tokens.next() {
+            bail!(
+                "Unexpected {} in {}, expected [weekdays] [[year-]month-day] [hour:minute[:second]] [UTC|+HH:MM]",
+                token,
+                s
+            );
+        }
+        Ok(CalendarExpression {
+            expression: s.trim().to_string(),
+            weekdays,
+            years,
+            months,
+            days,
+            hours,
+            minutes,
+            seconds,
+            utc_offset,
+        })
+    }
+}
+
+impl TryFrom<String> for CalendarExpression {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CalendarExpression> for String {
+    fn from(expression: CalendarExpression) -> Self {
+        expression.expression
+    }
+}
+
+impl Display for CalendarExpression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+/// The values of a component, like `1..5/2,10`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Field(Vec<Values>);
+
+/// From `start` to `end` (inclusive), every `step`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Values {
+    start: u32,
+    end: u32,
+    step: u32,
+}
+
+impl Field {
+    fn any(min: u32, max: u32) -> Self {
+        Field(vec![Values {
+            start: min,
+            end: max,
+            step: 1,
+        }])
+    }
+
+    fn value(value: u32) -> Self {
+        Self::any(value, value)
+    }
+
+    fn contains(&self, value: u32) -> bool {
+        self.0.iter().any(|values| {
+            values.start <= value
+                && value <= values.end
+                && (value - values.start).is_multiple_of(values.step)
+        })
+    }
+
+    /// Parses a component of a date or a time, with values from `min` to `max`.
+    fn parse(field: &str, min: u32, max: u32) -> Result<Self> {
+        let value = |value: &str| -> Result<u32> {
+            let value: u32 = value
+                .parse()
+                .with_context(|| format!("Invalid value: {}", value))?;
+            if !(min..=max).contains(&value) {
+                bail!("{} is out of range, expected {}..{}", value, min, max);
+            }
+            Ok(value)
+        };
+        let values = field
+            .split(',')
+            .map(|values| {
+                let (range, step) = match values.split_once('/') {
+                    Some((range, step)) => (range, Some(step)),
+                    None => (values, None),
+                };
+                let (start, end) = match range.split_once("..") {
+                    _ if range == "*" => (min, max),
+                    Some((start, end)) => (value(start)?, value(end)?),
+                    // A repetition goes on until the end.
+                    None if step.is_some() => (value(range)?, max),
+                    None => (value(range)?, value(range)?),
+                };
+                if start > end {
+                    bail!("Invalid range: {}", range);
+                }
+                let step = match step {
+                    Some(step) => step
+                        .parse()
+                        .ok()
+                        .filter(|step| *step > 0)
+                        .with_context(|| format!("Invalid repetition: {}", step))?,
+                    None => 1,
+                };
+                Ok(Values { start, end, step })
+            })
+            .collect::<Result<_>>()
+            .with_context(|| format!("Invalid component: {}", field))?;
+        Ok(Field(values))
+    }
+}
+
+/// Parses weekdays like `Mon..Fri,Sun`: the names can be abbreviated to three letters.
+fn parse_weekdays(weekdays: &str) -> Result<Field> {
+    let weekday = |name: &str| -> Result<u32> {
+        let name = name.to_lowercase();
+        WEEKDAYS
+            .iter()
+            .position(|weekday| name.len() >= 3 && weekday.starts_with(&name))
+            .map(|position| position as u32)
+            .with_context(|| format!("Invalid weekday: {}", name))
+    };
+    let values = weekdays
+        .split(',')
+        .map(|range| {
+            let (start, end) = match range.split_once("..") {
+                Some((start, end)) => (weekday(start)?, weekday(end)?),
+                None => (weekday(range)?, weekday(range)?),
+            };
+            if start > end {
+                bail!("Invalid range of weekdays: {}", range);
+            }
+            Ok(Values {
+                start,
+                end,
+                step: 1,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Field(values))
+}
+
+/// Parses `[year-]month-day`.
+fn parse_date(date: &str) -> Result<(Field, Field, Field)> {
+    let components: Vec<&str> = date.split('-').collect();
+    let (year, month, day) = match components.as_slice() {
+        [year, month, day] => (*year, *month, *day),
+        [month, day] => ("*", *month, *day),
+        _ => bail!("Invalid date: {}, expected [year-]month-day", date),
+    };
+    Ok((
+        Field::parse(year, 1970, 9999)?,
+        Field::parse(month, 1, 12)?,
+        Field::parse(day, 1, 31)?,
+    ))
+}
+
+/// Parses `hour:minute[:second]`.
+fn parse_time(time: &str) -> Result<(Field, Field, Field)> {
+    let components: Vec<&str> = time.split(':').collect();
+    let (hour, minute, second) = match components.as_slice() {
+        [hour, minute, second] => (*hour, *minute, *second),
+        [hour, minute] => (*hour, *minute, "0"),
+        _ => bail!("Invalid time: {}, expected hour:minute[:second]", time),
+    };
+    Ok((
+        Field::parse(hour, 0, 23)?,
+        Field::parse(minute, 0, 59)?,
+        Field::parse(second, 0, 59)?,
+    ))
+}
+
+/// The year, month and day of `days` since the epoch, in the proleptic Gregorian calendar.
+/// From http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (u32, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year.max(0) as u32, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::horust::formats::calendar::civil_from_days;
+    use crate::horust::formats::CalendarExpression;
+
+    /// The time at `date` (`YYYY-MM-DD HH:MM:SS`), in UTC.
+    fn utc(date: &str) -> SystemTime {
+        let days = (0..)
+            .find(|days| {
+                let (year, month, day) = civil_from_days(*days);
+                format!("{:04}-{:02}-{:02}", year, month, day) == date[..10]
+            })
+            .unwrap();
+        let time: Vec<u64> = date[11..].split(':').map(|c| c.parse().unwrap()).collect();
+        UNIX_EPOCH
+            + Duration::from_secs(days as u64 * 86400 + time[0] * 3600 + time[1] * 60 + time[2])
+    }
+
+    fn next(expression: &str, after: &str) -> Option<SystemTime> {
+        let expression: CalendarExpression = expression.parse().unwrap();
+        expression.next_trigger(utc(after))
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_875), (2024, 6, 1));
+    }
+
+    #[test]
+    fn test_parse() {
+        let expression: CalendarExpression = "Mon..Fri 02:30".parse().unwrap();
+        assert_eq!(expression.to_string(), "Mon..Fri 02:30");
+        assert_eq!(expression.utc_offset, None);
+        for expression in [
+            "*-*-01 00:00:00",
+            "daily",
+            "Weekly",
+            "Sat,Sun *-*-* 10:00 UTC",
+            "2024-02-29 12:00:00 +01:00",
+            "*:0/15",
+            "*-1..6/2-1 8..18/2:00",
+            "Monday 00:00",
+        ] {
+            expression.parse::<CalendarExpression>().unwrap();
+        }
+        let expression: CalendarExpression = "03:00 -05:30".parse().unwrap();
+        assert_eq!(expression.utc_offset, Some(-(5 * 3600 + 30 * 60)));
+
+        let error = |expression: &str| {
+            format!(
+                "{:#}",
+                expression.parse::<CalendarExpression>().unwrap_err()
+            )
+        };
+        assert!(error("").contains("Empty calendar expression"));
+        assert!(error("Mo 02:30").contains("Invalid weekday: mo"));
+        assert!(error("Fri..Mon 02:30").contains("Invalid range of weekdays"));
+        assert!(error("*-13-01").contains("13 is out of range"));
+        assert!(error("*-*-* 24:00").contains("24 is out of range"));
+        assert!(error("*-*-* 10..08:00").contains("Invalid range: 10..08"));
+        assert!(error("*:0/0").contains("Invalid repetition: 0"));
+        assert!(error("2024-*-*-01").contains("Invalid date"));
+        assert!(error("02:30 Mon").contains("Unexpected Mon"));
+        assert!(error("02:30 +25:00").contains("Invalid timezone"));
+    }
+
+    #[test]
+    fn test_next_trigger() {
+        // A Saturday.
+        let after = "2024-06-01 12:00:00";
+        assert_eq!(
+            next("Mon..Fri 02:30 UTC", after),
+            Some(utc("2024-06-03 02:30:00"))
+        );
+        assert_eq!(
+            next("Sat,Sun 10:00 UTC", after),
+            Some(utc("2024-06-02 10:00:00"))
+        );
+        assert_eq!(
+            next("*-*-01 00:00:00 UTC", after),
+            Some(utc("2024-07-01 00:00:00"))
+        );
+        assert_eq!(next("daily UTC", after), Some(utc("2024-06-02 00:00:00")));
+        assert_eq!(next("yearly UTC", after), Some(utc("2025-01-01 00:00:00")));
+        assert_eq!(
+            next("*:0/15 UTC", "2024-06-01 12:07:30"),
+            Some(utc("2024-06-01 12:15:00"))
+        );
+        assert_eq!(
+            next("*-*-* *:*:0/20 UTC", "2024-06-01 23:59:45"),
+            Some(utc("2024-06-02 00:00:00"))
+        );
+        // Strictly after.
+        assert_eq!(
+            next("*-*-* 12:00:00 UTC", after),
+            Some(utc("2024-06-02 12:00:00"))
+        );
+        // Only on leap years.
+        assert_eq!(
+            next("*-02-29 UTC", "2024-03-01 00:00:00"),
+            Some(utc("2028-02-29 00:00:00"))
+        );
+        // Offset from UTC.
+        assert_eq!(
+            next("*-*-* 02:30 +02:00", after),
+            Some(utc("2024-06-02 00:30:00"))
+        );
+        // Never again.
+        assert_eq!(next("2020-*-* UTC", after), None);
+        assert_eq!(next("*-02-30 UTC", after), None);
+    }
+}
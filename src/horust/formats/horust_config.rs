@@ -1,43 +1,846 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use nix::unistd;
 use serde::{Deserialize, Serialize};
 
+use crate::horust::formats::{
+    ExitReason, GlobalEnvironment, Group, RunReport, ServiceReport, ServiceStatus, User,
+};
+
+const DEFAULT_STATE_DIR: &str = "/var/lib/horust";
+const DEFAULT_RUNTIME_DIR: &str = "/run/horust";
+/// The system-wide directory of Horust's configuration and services.
+const SYSTEM_CONFIG_DIR: &str = "/etc/horust";
+
 // TODO: this should be an optional
 // otherwise we wouldn't know if it was set to false on the commandline. Maybe. Because it's a flag.
 
-#[derive(Debug, clap::Parser, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, clap::Parser, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", default)]
 pub struct HorustConfig {
     #[clap(long)]
+    #[serde(alias = "unsuccessful_exit_finished_failed")]
     /// Exits with an unsuccessful exit code if any process is in FinishedFailed state
     pub unsuccessful_exit_finished_failed: bool,
+
+    #[clap(long)]
+    /// Path of the control socket used by `horustctl`. If not set, the control socket is disabled.
+    pub control_socket: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Owner of the control socket, as a username or uid. Default: the user running Horust.
+    pub control_socket_owner: Option<User>,
+
+    #[clap(long)]
+    /// Group of the control socket, as a group name or gid.
+    pub control_socket_group: Option<Group>,
+
+    #[clap(long, value_parser = parse_mode)]
+    /// Permissions of the control socket, in octal (e.g. `660`). Connecting requires write permission.
+    pub control_socket_mode: Option<u32>,
+
+    #[clap(long)]
+    /// File where every request received on the control socket is appended, with who sent it,
+    /// when, and its result. The control socket is disabled if it can't be opened.
+    pub audit_log: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Directory where Horust keeps its state across runs. Default: `/var/lib/horust`.
+    pub state_dir: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Directory for the files which only last while Horust is running. Default: `/run/horust`.
+    pub runtime_dir: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Drop Horust's privileges to this `user[:group]` after its setup (e.g. binding the control
+    /// socket), before starting the services. The group defaults to the primary group of the user.
+    pub run_as: Option<RunAs>,
+
+    #[clap(long = "user-services")]
+    /// Users whose services (in `~/.config/horust/services`) are loaded too, and run as them.
+    /// Can be repeated.
+    pub user_services: Vec<User>,
+
+    #[clap(long = "mask")]
+    /// Services which are loaded but never started, e.g. `--mask web.toml`. Can be repeated.
+    pub mask: Vec<String>,
+
+    #[clap(skip)]
+    /// Who can send requests to the control socket, besides Horust's own user.
+    /// If not set, anyone able to connect to the socket.
+    pub control_access: Option<ControlAccess>,
+
+    #[clap(long)]
+    /// Template prepended to the output lines of the services, e.g. `"{time} [{service}:{pid}] "`.
+    /// Services can override it with their own `log-prefix`.
+    pub log_prefix: Option<String>,
+
+    #[clap(long, value_enum)]
+    /// How the exit code of Horust is computed from the outcome of the services. Default: `flag`.
+    pub exit_code_strategy: Option<ExitCodeStrategy>,
+
+    #[clap(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    /// Deadline of a graceful shutdown, e.g. `2m`: the services still running after it are killed,
+    /// regardless of their own `termination.wait`. Default: none.
+    pub shutdown_timeout: Option<Duration>,
+
+    #[clap(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    /// Deadline for all the services to be up after Horust has started, e.g. `5m`: once it's over,
+    /// the `boot-timeout-action` is taken. Default: none.
+    pub boot_timeout: Option<Duration>,
+
+    #[clap(long, value_enum)]
+    /// What to do if the services aren't all up by the `boot-timeout`. Default: `shutdown`.
+    pub boot_timeout_action: Option<BootTimeoutAction>,
+
+    #[clap(long)]
+    /// Command run once all the services are up, e.g. to register the host in a load balancer.
+    pub on_system_ready: Option<String>,
+
+    #[clap(long)]
+    /// File of supervision policies, e.g. `on worker failed 3 times: start fallback`. Default: none.
+    pub policies: Option<PathBuf>,
+
+    #[clap(long)]
+    /// OTLP/HTTP endpoint the lifecycle spans of the services are exported to, e.g. `http://localhost:4318`.
+    pub otlp_endpoint: Option<String>,
+
+    #[clap(long)]
+    /// Diagnostic mode: every event published on the bus is written to this file, with its
+    /// sequence number, timestamp and source component.
+    pub trace_events: Option<PathBuf>,
+
+    #[clap(skip)]
+    /// Exit codes used for each class of failure.
+    pub exit_codes: ExitCodes,
+
+    #[clap(skip)]
+    /// Monitors the pressure stall information, pausing some services while a resource is under
+    /// pressure.
+    pub pressure: Option<PressureMonitor>,
+
+    #[clap(skip)]
+    /// Environment variables set for all the services.
+    pub environment: Option<GlobalEnvironment>,
+
+    #[clap(skip)]
+    /// Executables invoked on the lifecycle events of the services.
+    pub plugins: Option<Plugins>,
+
+    #[clap(skip)]
+    /// Journal of the runs of the services, kept across the runs of Horust.
+    pub history: Option<RunHistory>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitCodeStrategy {
+    /// Exits with 101 if some service has failed and `unsuccessful-exit-finished-failed` is set.
+    Flag,
+    /// Exits with the exit code of the first service which has failed.
+    FirstFailed,
+    /// Like `first-failed`, but only services with `failure.critical` are considered.
+    Critical,
+    /// Exits with the code in `exit-codes` for how the first failed service has failed.
+    ByClass,
+}
+
+/// What to do if the services aren't all up by the `boot-timeout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootTimeoutAction {
+    /// Stops all the services gracefully, and exits with `exit-codes.boot-timeout`.
+    #[default]
+    Shutdown,
+    /// Kills all the services, and exits with `exit-codes.boot-timeout`.
+    Kill,
+    /// Only logs the services which aren't up yet.
+    Warn,
+}
+
+/// Exit codes for each class of failure.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ExitCodes {
+    /// The service has exited with an unsuccessful exit code (or failed its healthchecks).
+    pub exited: i32,
+    /// The service was killed by a signal.
+    pub killed: i32,
+    /// The service couldn't be spawned.
+    pub spawn_failed: i32,
+    /// The services weren't all up by the `boot-timeout`.
+    pub boot_timeout: i32,
+}
+
+/// The user (and group) Horust runs as, after dropping its privileges.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RunAs {
+    pub user: User,
+    pub group: Option<Group>,
+}
+
+impl RunAs {
+    /// Resolves the uid and gid to switch to.
+    pub fn credentials(&self) -> Result<(unistd::Uid, unistd::Gid)> {
+        let gid = match &self.group {
+            Some(group) => group.get_gid()?,
+            None => self.user.get_raw_user()?.gid,
+        };
+        Ok((self.user.get_uid()?, gid))
+    }
+
+    /// Checks that Horust can switch to this user: it must exist, and Horust must be root
+    /// (or already running as it).
+    pub fn check(&self) -> Result<()> {
+        let (uid, gid) = self.credentials()?;
+        let is_root = unistd::geteuid().is_root();
+        if !is_root && (uid != unistd::geteuid() || gid != unistd::getegid()) {
+            bail!("Only root can switch to {}", self);
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RunAs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        if user.is_empty() || group.is_some_and(str::is_empty) {
+            bail!("Invalid user: `{}`, expected `user` or `user:group`", s);
+        }
+        Ok(RunAs {
+            user: user.parse()?,
+            group: group.map(str::parse).transpose()?,
+        })
+    }
+}
+
+impl TryFrom<String> for RunAs {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<RunAs> for String {
+    fn from(run_as: RunAs) -> Self {
+        run_as.to_string()
+    }
+}
+
+impl fmt::Display for RunAs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.user)?;
+        if let Some(group) = &self.group {
+            write!(f, ":{}", group)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("Invalid mode: {}, expected an octal number like 660", mode))
+}
+
+/// The users and groups allowed to send requests to the control socket.
+/// Groups are matched against the primary group of the client's process.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ControlAccess {
+    /// Can send any request.
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    /// Can send only read-only requests, like `status`.
+    pub read_only_uids: Vec<u32>,
+    pub read_only_gids: Vec<u32>,
+}
+
+impl ControlAccess {
+    /// Whether the user `uid`, member of `gids` (its primary and supplementary groups), can send
+    /// a request.
+    pub fn allows(&self, uid: u32, gids: &[u32], read_only: bool) -> bool {
+        let full = self.uids.contains(&uid) || gids.iter().any(|gid| self.gids.contains(gid));
+        let read_only_access = self.read_only_uids.contains(&uid)
+            || gids.iter().any(|gid| self.read_only_gids.contains(gid));
+        full || (read_only && read_only_access)
+    }
+}
+
+/// Thresholds on the pressure stall information (PSI): the share of time, in percent, in which
+/// some task was stalled waiting for the resource, averaged over the last 10 seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PressureMonitor {
+    #[serde(default)]
+    pub source: PressureSource,
+    /// How often the pressure is read.
+    #[serde(
+        default = "PressureMonitor::default_interval",
+        with = "humantime_serde"
+    )]
+    pub interval: Duration,
+    pub cpu: Option<f64>,
+    pub memory: Option<f64>,
+    pub io: Option<f64>,
+    /// Services paused while any resource is over its threshold, and resumed once all are back
+    /// below it.
+    #[serde(default)]
+    pub pause: Vec<String>,
+}
+
+impl PressureMonitor {
+    fn default_interval() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    /// The monitored resources, with their thresholds.
+    pub fn thresholds(&self) -> Vec<(PressureResource, f64)> {
+        [
+            (PressureResource::Cpu, self.cpu),
+            (PressureResource::Memory, self.memory),
+            (PressureResource::Io, self.io),
+        ]
+        .into_iter()
+        .filter_map(|(resource, threshold)| Some((resource, threshold?)))
+        .collect()
+    }
+}
+
+/// The executables in `dir` are invoked on the lifecycle `events`, with the event as JSON on their
+/// stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Plugins {
+    pub dir: PathBuf,
+    #[serde(default = "Plugins::default_events")]
+    pub events: Vec<PluginEvent>,
+    /// How long each invocation is allowed to run, before being killed.
+    #[serde(default = "Plugins::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Plugins {
+    fn default_events() -> Vec<PluginEvent> {
+        vec![
+            PluginEvent::ServiceStarted,
+            PluginEvent::ServiceFailed,
+            PluginEvent::HealthcheckFlapped,
+            PluginEvent::SystemReady,
+            PluginEvent::ShuttingDown,
+        ]
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+/// Every run of a service is appended to the journal at `path`, as a line of JSON. Once it's larger
+/// than `max-size`, the oldest runs are dropped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct RunHistory {
+    /// Default: `history.jsonl` in the `state-dir`.
+    pub path: Option<PathBuf>,
+    #[serde(deserialize_with = "to_bytes")]
+    pub max_size: u64,
+}
+
+impl Default for RunHistory {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_size: 10_000_000,
+        }
+    }
+}
+
+/// Either a number of bytes, or a size like "10 MB".
+fn to_bytes<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Human(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Human(size) => bytefmt::parse(size).map_err(serde::de::Error::custom),
+    }
+}
+
+/// The lifecycle events the plugins can be invoked on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PluginEvent {
+    /// The service has a pid.
+    ServiceStarted,
+    /// The service has failed (it might be restarted).
+    ServiceFailed,
+    /// The healthchecks of the service have started failing, or passing again.
+    HealthcheckFlapped,
+    /// All the services are up, for the first time.
+    SystemReady,
+    /// Horust is shutting down all the services.
+    ShuttingDown,
+}
+
+/// Where the pressure is read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PressureSource {
+    /// The whole system, from `/proc/pressure`.
+    #[default]
+    System,
+    /// The cgroup of Horust, including its services.
+    Cgroup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PressureResource {
+    Cpu,
+    Memory,
+    Io,
+}
+
+impl fmt::Display for PressureResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PressureResource::Cpu => "cpu",
+            PressureResource::Memory => "memory",
+            PressureResource::Io => "io",
+        })
+    }
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        Self {
+            exited: 1,
+            killed: 2,
+            spawn_failed: 3,
+            boot_timeout: 1,
+        }
+    }
+}
+
+/// Where the value of each option of the configuration comes from, by its dotted key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigOrigins(BTreeMap<String, String>);
+
+impl ConfigOrigins {
+    const DEFAULT: &'static str = "default";
+    const COMMAND_LINE: &'static str = "command line";
+
+    /// The origin of `key`, or of the closest section containing it.
+    pub fn get(&self, key: &str) -> &str {
+        let mut key = key;
+        loop {
+            if let Some(origin) = self.0.get(key) {
+                return origin;
+            }
+            match key.rsplit_once('.') {
+                Some((parent, _)) => key = parent,
+                None => return Self::DEFAULT,
+            }
+        }
+    }
+
+    /// Records `origin` for all the values in `table`, under `prefix`.
+    fn record(&mut self, prefix: &str, table: &toml::Table, origin: &str) {
+        for (key, value) in table {
+            let key = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            match value {
+                toml::Value::Table(table) => self.record(&key, table, origin),
+                _ => {
+                    self.0.insert(key, origin.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// The directory of the configuration of the user, `$XDG_CONFIG_HOME/horust` (by default in
+/// `~/.config`), if it can be found.
+fn user_config_dir() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("horust"))
+}
+
+/// Merges `layer` into `table`: the sections are merged key by key, the other values replaced.
+fn merge_tables(table: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(layer)) => {
+                merge_tables(table, layer)
+            }
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
 }
 
 impl HorustConfig {
+    /// The config files loaded by default, from the lowest to the highest precedence:
+    /// `/etc/horust/horust.toml` and `$XDG_CONFIG_HOME/horust/horust.toml`.
+    pub fn config_search_paths() -> Vec<PathBuf> {
+        std::iter::once(PathBuf::from(SYSTEM_CONFIG_DIR))
+            .chain(user_config_dir())
+            .map(|dir| dir.join("horust.toml"))
+            .collect()
+    }
+
+    /// The services directories loaded by default: `/etc/horust/services`, and
+    /// `$XDG_CONFIG_HOME/horust/services` if it exists. The services in the latter replace the
+    /// ones with the same name in the former.
+    pub fn services_search_paths() -> Vec<PathBuf> {
+        let user_services = user_config_dir()
+            .map(|dir| dir.join("services"))
+            .filter(|dir| dir.is_dir());
+        std::iter::once(PathBuf::from(SYSTEM_CONFIG_DIR).join("services"))
+            .chain(user_services)
+            .collect()
+    }
+
     /// Load the config file, and handles the merge with the options defined in the cmdline.
     /// Cmdline defined values have precedence over config based values.
     pub fn load_and_merge(cmd_line: &HorustConfig, path: &Path) -> Result<Self> {
-        let config_file: HorustConfig = if path.exists() {
+        Self::load_layers(cmd_line, &[path.to_path_buf()]).map(|(config, _origins)| config)
+    }
+
+    /// Like `load_and_merge`, with the config files in `paths` merged in order: a value in a file
+    /// overrides the one of the previous files. The missing files are skipped.
+    /// Also returns where each value comes from.
+    pub fn load_layers(
+        cmd_line: &HorustConfig,
+        paths: &[PathBuf],
+    ) -> Result<(Self, ConfigOrigins)> {
+        let mut origins = ConfigOrigins::default();
+        let mut table = toml::Table::new();
+        for path in paths.iter().filter(|path| path.exists()) {
             let content = std::fs::read_to_string(path)?;
-            toml::from_str(&content)?
-        } else {
-            Default::default()
-        };
+            let layer: toml::Table = toml::from_str(&content)
+                .with_context(|| format!("Invalid config file {}", path.display()))?;
+            // Reports the invalid values of each file on its own.
+            toml::Value::Table(layer.clone())
+                .try_into::<HorustConfig>()
+                .with_context(|| format!("Invalid config file {}", path.display()))?;
+            origins.record("", &layer, &path.display().to_string());
+            merge_tables(&mut table, layer);
+        }
+        let config_file: HorustConfig = toml::Value::Table(table).try_into()?;
+        let config = Self::merge(cmd_line, config_file);
+
+        let defaults = toml::Table::try_from(HorustConfig::default())?;
+        let cmd_line = toml::Table::try_from(cmd_line)?;
+        for (key, value) in cmd_line {
+            if defaults.get(&key) != Some(&value) {
+                origins
+                    .0
+                    .insert(key, ConfigOrigins::COMMAND_LINE.to_string());
+            }
+        }
+        Ok((config, origins))
+    }
+
+    /// The effective configuration in TOML, with the origin of each value in a comment.
+    pub fn show(&self, origins: &ConfigOrigins) -> Result<String> {
+        fn show_table(
+            out: &mut String,
+            prefix: &str,
+            table: &toml::Table,
+            origins: &ConfigOrigins,
+        ) -> fmt::Result {
+            let key_of = |key: &str| {
+                if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", prefix, key)
+                }
+            };
+            for (key, value) in table.iter().filter(|(_, value)| !value.is_table()) {
+                let origin = origins.get(&key_of(key));
+                writeln!(out, "{} = {} # {}", key, value, origin)?;
+            }
+            for (key, value) in table {
+                if let toml::Value::Table(section) = value {
+                    writeln!(out, "\n[{}]", key_of(key))?;
+                    show_table(out, &key_of(key), section, origins)?;
+                }
+            }
+            Ok(())
+        }
+        let mut out = String::new();
+        show_table(&mut out, "", &toml::Table::try_from(self)?, origins)?;
+        Ok(out)
+    }
 
+    fn merge(cmd_line: &HorustConfig, config_file: HorustConfig) -> Self {
         let unsuccessful_exit_finished_failed = cmd_line.unsuccessful_exit_finished_failed
             || config_file.unsuccessful_exit_finished_failed;
 
-        Ok(HorustConfig {
+        HorustConfig {
             unsuccessful_exit_finished_failed,
-        })
+            control_socket: cmd_line
+                .control_socket
+                .clone()
+                .or(config_file.control_socket),
+            control_socket_owner: cmd_line
+                .control_socket_owner
+                .clone()
+                .or(config_file.control_socket_owner),
+            control_socket_group: cmd_line
+                .control_socket_group
+                .clone()
+                .or(config_file.control_socket_group),
+            control_socket_mode: cmd_line
+                .control_socket_mode
+                .or(config_file.control_socket_mode),
+            audit_log: cmd_line.audit_log.clone().or(config_file.audit_log),
+            state_dir: cmd_line.state_dir.clone().or(config_file.state_dir),
+            runtime_dir: cmd_line.runtime_dir.clone().or(config_file.runtime_dir),
+            run_as: cmd_line.run_as.clone().or(config_file.run_as),
+            user_services: if cmd_line.user_services.is_empty() {
+                config_file.user_services
+            } else {
+                cmd_line.user_services.clone()
+            },
+            mask: if cmd_line.mask.is_empty() {
+                config_file.mask
+            } else {
+                cmd_line.mask.clone()
+            },
+            control_access: config_file.control_access,
+            log_prefix: cmd_line.log_prefix.clone().or(config_file.log_prefix),
+            exit_code_strategy: cmd_line
+                .exit_code_strategy
+                .or(config_file.exit_code_strategy),
+            shutdown_timeout: cmd_line.shutdown_timeout.or(config_file.shutdown_timeout),
+            boot_timeout: cmd_line.boot_timeout.or(config_file.boot_timeout),
+            boot_timeout_action: cmd_line
+                .boot_timeout_action
+                .or(config_file.boot_timeout_action),
+            on_system_ready: cmd_line
+                .on_system_ready
+                .clone()
+                .or(config_file.on_system_ready),
+            policies: cmd_line.policies.clone().or(config_file.policies),
+            otlp_endpoint: cmd_line.otlp_endpoint.clone().or(config_file.otlp_endpoint),
+            trace_events: cmd_line.trace_events.clone().or(config_file.trace_events),
+            exit_codes: config_file.exit_codes,
+            pressure: config_file.pressure,
+            environment: config_file.environment,
+            plugins: config_file.plugins,
+            history: config_file.history,
+        }
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR))
+    }
+
+    pub fn runtime_dir(&self) -> PathBuf {
+        self.runtime_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_RUNTIME_DIR))
+    }
+
+    /// The path of the run history journal, if it's enabled.
+    pub fn history_path(&self) -> Option<PathBuf> {
+        let history = self.history.as_ref()?;
+        Some(
+            history
+                .path
+                .clone()
+                .unwrap_or_else(|| self.state_dir().join("history.jsonl")),
+        )
+    }
+
+    /// The exit code of Horust, after running the services.
+    pub fn exit_code(&self, report: &RunReport) -> i32 {
+        if report.boot_timed_out {
+            return self.exit_codes.boot_timeout;
+        }
+        let first_failed = |critical_only: bool| {
+            report
+                .services
+                .iter()
+                .filter(|service| service.status == ServiceStatus::FinishedFailed)
+                .filter(|service| !critical_only || service.critical)
+                .min_by_key(|service| service.failed_at)
+        };
+        match self.exit_code_strategy.unwrap_or(ExitCodeStrategy::Flag) {
+            ExitCodeStrategy::Flag => {
+                let any_failed = first_failed(false).is_some();
+                if any_failed && self.unsuccessful_exit_finished_failed {
+                    101
+                } else {
+                    0
+                }
+            }
+            ExitCodeStrategy::FirstFailed => first_failed(false).map_or(0, |s| self.code_of(s)),
+            ExitCodeStrategy::Critical => first_failed(true).map_or(0, |s| self.code_of(s)),
+            ExitCodeStrategy::ByClass => first_failed(false).map_or(0, |s| self.class_of(s)),
+        }
+    }
+
+    /// The exit code of the failed `service`: signal deaths are reported like shells do.
+    fn code_of(&self, service: &ServiceReport) -> i32 {
+        match service.last_exit {
+            // Failed healthchecks might end with a successful exit code.
+            Some(ExitReason::Code(code)) if code != 0 => code,
+            Some(ExitReason::Signal { signal, .. }) => 128 + signal as i32,
+            _ => self.class_of(service),
+        }
+    }
+
+    fn class_of(&self, service: &ServiceReport) -> i32 {
+        match service.last_exit {
+            None => self.exit_codes.spawn_failed,
+            Some(ExitReason::Code(_)) => self.exit_codes.exited,
+            Some(ExitReason::Signal { .. }) => self.exit_codes.killed,
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
     use anyhow::Result;
+    use nix::sys::signal::Signal;
+
+    use crate::horust::formats::horust_config::parse_mode;
+    use crate::horust::formats::{
+        BootTimeoutAction, ExitCodeStrategy, ExitReason, Group, PluginEvent, PressureResource,
+        PressureSource, RunAs, ServiceReport, ServiceStatus, User,
+    };
+    use crate::horust::{HorustConfig, RunReport};
+
+    fn failed(
+        name: &str,
+        last_exit: Option<ExitReason>,
+        critical: bool,
+        at: Instant,
+    ) -> ServiceReport {
+        ServiceReport {
+            name: name.into(),
+            description: None,
+            tags: vec![],
+            status: ServiceStatus::FinishedFailed,
+            spawns: 1,
+            last_exit,
+            runtime: Duration::ZERO,
+            last_output: vec![],
+            critical,
+            failed_at: Some(at),
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let now = Instant::now();
+        let killed = ExitReason::Signal {
+            signal: Signal::SIGKILL,
+            core_dumped: false,
+        };
+        let report = RunReport {
+            services: vec![
+                failed(
+                    "a",
+                    Some(ExitReason::Code(7)),
+                    false,
+                    now + Duration::from_secs(1),
+                ),
+                failed("b", Some(killed), true, now + Duration::from_secs(2)),
+                failed("c", None, false, now + Duration::from_secs(3)),
+            ],
+            boot_timed_out: false,
+        };
+        let exit_code = |strategy| {
+            let config = HorustConfig {
+                exit_code_strategy: Some(strategy),
+                ..Default::default()
+            };
+            config.exit_code(&report)
+        };
+        assert_eq!(exit_code(ExitCodeStrategy::Flag), 0);
+        assert_eq!(exit_code(ExitCodeStrategy::FirstFailed), 7);
+        assert_eq!(exit_code(ExitCodeStrategy::Critical), 137);
+        assert_eq!(exit_code(ExitCodeStrategy::ByClass), 1);
+        let config = HorustConfig {
+            unsuccessful_exit_finished_failed: true,
+            ..Default::default()
+        };
+        assert_eq!(config.exit_code(&report), 101);
+        let report = RunReport {
+            services: vec![],
+            boot_timed_out: false,
+        };
+        assert_eq!(config.exit_code(&report), 0);
+        let report = RunReport {
+            boot_timed_out: true,
+            ..report
+        };
+        assert_eq!(config.exit_code(&report), 1);
+    }
+    #[test]
+    fn test_run_as() {
+        let run_as: RunAs = "nobody:1000".parse().unwrap();
+        assert_eq!(run_as.user, User::Name("nobody".into()));
+        assert_eq!(run_as.group, Some(Group::Gid(1000)));
+        assert_eq!(run_as.to_string(), "nobody:1000");
+        let run_as: RunAs = "0".parse().unwrap();
+        assert_eq!(run_as.user, User::Uid(0));
+        assert_eq!(run_as.group, None);
+        assert_eq!(run_as.credentials().unwrap().0.as_raw(), 0);
+        "".parse::<RunAs>().unwrap_err();
+        "nobody:".parse::<RunAs>().unwrap_err();
+        ":wheel".parse::<RunAs>().unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        parse_mode("rw-rw----").unwrap_err();
+        parse_mode("99").unwrap_err();
+        parse_mode("17777").unwrap_err();
+    }
 
-    use crate::horust::HorustConfig;
     #[test]
     fn test_load_and_merge() -> Result<()> {
         let tempdir = tempdir::TempDir::new("load-and-merge")?;
@@ -45,8 +848,99 @@ mod test {
         std::fs::write(&config_path, "Not a toml file :( ")?;
         let config = HorustConfig {
             unsuccessful_exit_finished_failed: true,
+            ..Default::default()
         };
         HorustConfig::load_and_merge(&config, &config_path).unwrap_err();
+
+        std::fs::write(
+            &config_path,
+            "control-socket = \"/run/horust.sock\"\nexit-code-strategy = \"by-class\"\ncontrol-socket-group = \"wheel\"\ncontrol-socket-mode = 0o660\nshutdown-timeout = \"2m\"\nboot-timeout = \"5m\"\nboot-timeout-action = \"kill\"\non-system-ready = \"touch /run/ready\"\npolicies = \"/etc/horust/policies\"\n[exit-codes]\nkilled = 9\n[control-access]\nread-only-gids = [4]\n[pressure]\nmemory = 40.5\npause = [\"batch.toml\"]\n[plugins]\ndir = \"/etc/horust/plugins\"\nevents = [\"service-failed\"]\n[history]\nmax-size = \"1 MB\"",
+        )?;
+        let merged = HorustConfig::load_and_merge(&config, &config_path)?;
+        assert!(merged.unsuccessful_exit_finished_failed);
+        assert_eq!(merged.control_socket, Some("/run/horust.sock".into()));
+        assert_eq!(merged.exit_code_strategy, Some(ExitCodeStrategy::ByClass));
+        assert_eq!(merged.exit_codes.killed, 9);
+        assert_eq!(merged.exit_codes.exited, 1);
+        assert_eq!(
+            merged.control_socket_group,
+            Some(Group::Name("wheel".into()))
+        );
+        assert_eq!(merged.control_socket_mode, Some(0o660));
+        assert_eq!(merged.shutdown_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(merged.boot_timeout, Some(Duration::from_secs(300)));
+        assert_eq!(merged.boot_timeout_action, Some(BootTimeoutAction::Kill));
+        assert_eq!(merged.on_system_ready, Some("touch /run/ready".into()));
+        assert_eq!(merged.policies, Some("/etc/horust/policies".into()));
+        assert_eq!(merged.history.as_ref().unwrap().max_size, 1_000_000);
+        assert_eq!(
+            merged.history_path(),
+            Some(PathBuf::from("/var/lib/horust/history.jsonl"))
+        );
+        let control_access = merged.control_access.unwrap();
+        assert_eq!(control_access.read_only_gids, vec![4]);
+        assert!(control_access.uids.is_empty());
+        let pressure = merged.pressure.unwrap();
+        assert_eq!(pressure.source, PressureSource::System);
+        assert_eq!(pressure.interval, Duration::from_secs(1));
+        assert_eq!(
+            pressure.thresholds(),
+            vec![(PressureResource::Memory, 40.5)]
+        );
+        assert_eq!(pressure.pause, vec!["batch.toml".to_string()]);
+        let plugins = merged.plugins.unwrap();
+        assert_eq!(plugins.dir, PathBuf::from("/etc/horust/plugins"));
+        assert_eq!(plugins.events, vec![PluginEvent::ServiceFailed]);
+        assert_eq!(plugins.timeout, Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_layers() -> Result<()> {
+        let tempdir = tempdir::TempDir::new("load-layers")?;
+        let system = tempdir.path().join("system.toml");
+        let user = tempdir.path().join("user.toml");
+        std::fs::write(
+            &system,
+            "control-socket = \"/run/horust.sock\"\n[exit-codes]\nkilled = 9\nexited = 2",
+        )?;
+        std::fs::write(&user, "[exit-codes]\nkilled = 10")?;
+        let cmd_line = HorustConfig {
+            unsuccessful_exit_finished_failed: true,
+            ..Default::default()
+        };
+        let paths = [
+            system.clone(),
+            tempdir.path().join("missing.toml"),
+            user.clone(),
+        ];
+        let (config, origins) = HorustConfig::load_layers(&cmd_line, &paths)?;
+        assert!(config.unsuccessful_exit_finished_failed);
+        assert_eq!(config.control_socket, Some("/run/horust.sock".into()));
+        assert_eq!(config.exit_codes.killed, 10);
+        assert_eq!(config.exit_codes.exited, 2);
+
+        let system = system.display().to_string();
+        assert_eq!(origins.get("control-socket"), system);
+        assert_eq!(origins.get("exit-codes.exited"), system);
+        assert_eq!(origins.get("exit-codes.killed"), user.display().to_string());
+        assert_eq!(
+            origins.get("unsuccessful-exit-finished-failed"),
+            "command line"
+        );
+        assert_eq!(origins.get("exit-codes.signaled"), "default");
+        assert_eq!(origins.get("state-dir"), "default");
+
+        let shown = config.show(&origins)?;
+        assert!(shown.contains("unsuccessful-exit-finished-failed = true # command line\n"));
+        assert!(shown.contains(&format!(
+            "\n[exit-codes]\nboot-timeout = 1 # default\nexited = 2 # {}\n",
+            system
+        )));
+
+        std::fs::write(&user, "[exit-codes]\nkilled = \"nine\"")?;
+        let err = HorustConfig::load_layers(&cmd_line, &paths).unwrap_err();
+        assert!(format!("{:#}", err).contains("user.toml"));
         Ok(())
     }
 }
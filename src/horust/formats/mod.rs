@@ -1,10 +1,25 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::Signal;
 use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
 
-pub use horust_config::HorustConfig;
+pub use calendar::CalendarExpression;
+pub use horust_config::{
+    BootTimeoutAction, ConfigOrigins, ControlAccess, ExitCodeStrategy, ExitCodes, HorustConfig,
+    PluginEvent, Plugins, PressureMonitor, PressureResource, PressureSource, RunAs,
+};
 pub use service::*;
+pub use service_override::ServiceOverride;
+pub use systemd_unit::{systemd_unit_name, to_systemd_unit};
 
+mod calendar;
 mod horust_config;
 mod service;
+mod service_override;
+mod systemd_unit;
 
 #[derive(Clone, Debug, Copy, PartialEq, Eq)]
 pub enum ShuttingDown {
@@ -12,6 +27,15 @@ pub enum ShuttingDown {
     Forcefully,
 }
 
+/// Who has paused a service: it's only resumed once none of them wants it paused anymore.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PausedBy {
+    /// The pressure monitoring, while a resource is under pressure.
+    Pressure,
+    /// An operator, via `horustctl pause`.
+    Operator,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     PidChanged(ServiceName, Pid),
@@ -20,15 +44,89 @@ pub enum Event {
     StatusUpdate(ServiceName, ServiceStatus),
     // This event represents a status change.
     StatusChanged(ServiceName, ServiceStatus),
-    ServiceExited(ServiceName, i32),
+    ServiceExited(ServiceName, ExitReason),
     ForceKill(ServiceName),
     Kill(ServiceName),
-    SpawnFailed(ServiceName),
+    // The process couldn't be spawned, and why.
+    SpawnFailed(ServiceName, String),
     Run(ServiceName),
     ShuttingDownInitiated(ShuttingDown),
     HealthCheck(ServiceName, HealthinessStatus),
-    // TODO: to allow changes of service at supervisor:
-    //ServiceCreated(ServiceHandler)
+    // What the last run of the healthchecks of the service has reported.
+    HealthcheckOutput(ServiceName, HealthcheckOutput),
+    // The pre-stop hook of the service has completed.
+    PreStopDone(ServiceName),
+    // The service has been running for longer than its `max-runtime`, and it's going to be restarted.
+    Recycle(ServiceName),
+    // The process of the service has been killed by the OOM killer.
+    OomKilled(ServiceName),
+    // The pressure on the resource has gone over its threshold (true), or back below it (false).
+    PressureChanged(PressureResource, bool),
+    // Stops the process of the service, until it's resumed by whom has paused it.
+    Pause(ServiceName, PausedBy),
+    Resume(ServiceName, PausedBy),
+    // The process of the service was stopped (true), or continued (false).
+    Paused(ServiceName, bool),
+    // SIGCHLD was received: some child processes have exited, and can be reaped.
+    ChildrenExited,
+    // A connection came in on a socket of the lazy service, so it can be started.
+    SocketActivated(ServiceName),
+    // The service was asked to start, e.g. via the control socket.
+    Start(ServiceName),
+    // The notify service has sent `READY=1` on its notify socket.
+    NotifiedReady(ServiceName),
+    // The service isn't ready within its `start-timeout`, and it's going to be killed.
+    StartTimeout(ServiceName),
+    // The service has run for longer than its `limits.wall-time`, and it's going to be killed.
+    WallTimeout(ServiceName),
+    // A path watched by the service has changed.
+    PathChanged(ServiceName),
+    // The definitions of the services are reloaded from their files.
+    Reload,
+    // The definition of the service has changed, once reloaded.
+    ServiceChanged(Arc<Service>),
+    // The transient service is added, and started (see `horustctl run`).
+    ServiceAdded(Arc<Service>),
+    // The process replaced by a start-first restart has to be stopped (or killed, if it's late).
+    StopReplaced(ServiceName),
+    // The process replaced by a start-first restart has exited.
+    ReplacedExited(ServiceName, ExitReason),
+    // The variant is switched to: it's started, and the other variants of its template are stopped
+    // once it's running.
+    Switch(ServiceName),
+    // The services are restarted, at most this many at a time (see `horustctl restart`).
+    Restart(Vec<ServiceName>, usize),
+    // The services are stopped, each one once the services among them which start after it are
+    // down (see `horustctl stop`). They wait to be started again.
+    Stop(Vec<ServiceName>),
+    // The template is scaled to this many instances: the ones beyond are stopped, and the others
+    // are started (see `horustctl scale`).
+    Scale(String, usize),
+    // The broken service is reset, and started again (see `horustctl clear`).
+    Clear(ServiceName),
+    // All the services are up, for the first time since Horust has started.
+    SystemReady,
+    // A condition of the service isn't met yet, so it's not spawned (see `conditions`).
+    ConditionsNotMet(ServiceName, String),
+    // Why the service isn't running, sent whenever it changes: empty once it's running.
+    NotRunning(ServiceName, Vec<Blocker>),
+}
+
+/// A reason why a service isn't running, see `horustctl why-not-running`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blocker {
+    pub reason: String,
+    /// When it's over by itself, e.g. the backoff before the next restart.
+    pub until: Option<Instant>,
+}
+
+impl Blocker {
+    pub(crate) fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+            until: None,
+        }
+    }
 }
 
 impl Event {
@@ -41,26 +139,177 @@ impl Event {
     pub(crate) fn new_status_update(service_name: &str, status: ServiceStatus) -> Self {
         Self::StatusUpdate(service_name.to_string(), status)
     }
-    pub(crate) fn new_service_exited(service_name: ServiceName, exit_status: i32) -> Self {
-        Self::ServiceExited(service_name, exit_status)
+    pub(crate) fn new_service_exited(service_name: ServiceName, exit_reason: ExitReason) -> Self {
+        Self::ServiceExited(service_name, exit_reason)
     }
     pub(crate) fn new_force_kill(service_name: &str) -> Self {
         Self::ForceKill(service_name.to_string())
     }
 }
 
+/// How a process has terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// It has exited with this exit code.
+    Code(i32),
+    /// It has been killed by a signal.
+    Signal { signal: Signal, core_dumped: bool },
+}
+
+impl ExitReason {
+    /// The exit code, if the process wasn't killed by a signal.
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            ExitReason::Code(code) => Some(*code),
+            ExitReason::Signal { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Code(code) => write!(f, "exit code {}", code),
+            ExitReason::Signal {
+                signal,
+                core_dumped,
+            } => {
+                write!(f, "killed by {}", signal)?;
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExitStatus {
     Successful,
     SomeServiceFailed,
 }
 
+/// The outcome of a run of Horust, returned by `Horust::run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    /// Sorted by service name.
+    pub services: Vec<ServiceReport>,
+    /// The services weren't all up by the `boot-timeout`.
+    pub boot_timed_out: bool,
+}
+
+impl RunReport {
+    pub fn exit_status(&self) -> ExitStatus {
+        if self.failed().next().is_some() {
+            ExitStatus::SomeServiceFailed
+        } else {
+            ExitStatus::Successful
+        }
+    }
+
+    /// The services which have finished failed.
+    pub fn failed(&self) -> impl Iterator<Item = &ServiceReport> {
+        self.services
+            .iter()
+            .filter(|service| service.status == ServiceStatus::FinishedFailed)
+    }
+
+    pub fn service(&self, name: &str) -> Option<&ServiceReport> {
+        self.services.iter().find(|service| service.name == name)
+    }
+}
+
+/// What happened to a service during a run of Horust.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceReport {
+    pub name: ServiceName,
+    /// See `Service::description`.
+    pub description: Option<String>,
+    /// See `Service::tags`.
+    pub tags: Vec<String>,
+    /// The final status.
+    pub status: ServiceStatus,
+    /// How many times the service was spawned.
+    pub spawns: u32,
+    /// How the last process of the service has terminated, if it was ever spawned.
+    pub last_exit: Option<ExitReason>,
+    /// Sum of the time spent running by all the processes of the service.
+    pub runtime: Duration,
+    /// The last lines of output, if the service has failed (see `failure.output-lines`).
+    pub last_output: Vec<String>,
+    /// See `failure.critical`.
+    pub critical: bool,
+    /// When the service has failed for the last time, if ever.
+    pub failed_at: Option<Instant>,
+}
+
+impl ServiceReport {
+    /// How many times the service was restarted.
+    pub fn restarts(&self) -> u32 {
+        self.spawns.saturating_sub(1)
+    }
+}
+
+/// E.g. `backend (The API) [critical, db]: exit code 1`.
+impl fmt::Display for ServiceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(description) = &self.description {
+            write!(f, " ({})", description)?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, " [{}]", self.tags.join(", "))?;
+        }
+        match &self.last_exit {
+            Some(last_exit) => write!(f, ": {}", last_exit),
+            None => write!(f, ": {}", self.status),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HealthinessStatus {
     Healthy,
     Unhealthy,
 }
 
+/// The outcome of a run of the healthchecks of a service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthcheckOutput {
+    pub healthy: bool,
+    /// What the failed checks have reported (e.g. the HTTP status, or the error), at most
+    /// `HealthcheckOutput::MAX_LEN` bytes. Empty if healthy.
+    pub output: String,
+    /// How long the checks took, in milliseconds.
+    pub latency_ms: u64,
+}
+
+impl HealthcheckOutput {
+    pub const MAX_LEN: usize = 4096;
+
+    pub fn new(errors: Vec<String>, latency: Duration) -> Self {
+        let mut output = errors.join("\n");
+        if output.len() > Self::MAX_LEN {
+            let mut end = Self::MAX_LEN;
+            while !output.is_char_boundary(end) {
+                end -= 1;
+            }
+            output.truncate(end);
+        }
+        Self {
+            healthy: errors.is_empty(),
+            output,
+            latency_ms: latency.as_millis() as u64,
+        }
+    }
+
+    pub fn status(&self) -> HealthinessStatus {
+        self.healthy.into()
+    }
+}
+
 impl From<bool> for HealthinessStatus {
     fn from(check: bool) -> Self {
         if check {
@@ -70,3 +319,64 @@ impl From<bool> for HealthinessStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use nix::sys::signal::Signal;
+
+    use std::time::Duration;
+
+    use crate::horust::formats::{
+        ExitReason, HealthcheckOutput, HealthinessStatus, ServiceReport, ServiceStatus,
+    };
+
+    #[test]
+    fn test_exit_reason() {
+        assert_eq!(ExitReason::Code(3).to_string(), "exit code 3");
+        assert_eq!(ExitReason::Code(3).code(), Some(3));
+        let segfault = ExitReason::Signal {
+            signal: Signal::SIGSEGV,
+            core_dumped: true,
+        };
+        assert_eq!(segfault.to_string(), "killed by SIGSEGV (core dumped)");
+        assert_eq!(segfault.code(), None);
+    }
+
+    #[test]
+    fn test_service_report() {
+        let mut report = ServiceReport {
+            name: "backend".into(),
+            description: Some("The API".into()),
+            tags: vec!["critical".into(), "db".into()],
+            status: ServiceStatus::FinishedFailed,
+            spawns: 2,
+            last_exit: Some(ExitReason::Code(1)),
+            runtime: Duration::ZERO,
+            last_output: vec![],
+            critical: false,
+            failed_at: None,
+        };
+        assert_eq!(
+            report.to_string(),
+            "backend (The API) [critical, db]: exit code 1"
+        );
+        report.description = None;
+        report.tags = vec![];
+        report.last_exit = None;
+        assert_eq!(report.to_string(), "backend: FinishedFailed");
+    }
+
+    #[test]
+    fn test_healthcheck_output() {
+        let output = HealthcheckOutput::new(vec![], Duration::from_micros(2500));
+        assert_eq!(output.status(), HealthinessStatus::Healthy);
+        assert_eq!(output.output, "");
+        assert_eq!(output.latency_ms, 2);
+
+        let errors = vec!["file-path: missing".into(), "é".repeat(3000)];
+        let output = HealthcheckOutput::new(errors, Duration::ZERO);
+        assert_eq!(output.status(), HealthinessStatus::Unhealthy);
+        assert!(output.output.starts_with("file-path: missing\né"));
+        assert!(output.output.len() <= HealthcheckOutput::MAX_LEN);
+    }
+}
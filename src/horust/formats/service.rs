@@ -1,18 +1,23 @@
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fmt::{Debug, Formatter};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, os::fd::RawFd};
 
-use anyhow::{Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use nix::sys::signal::Signal;
 use nix::unistd;
+use rand::Rng;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::horust::error::{ValidationError, ValidationErrors};
+use crate::horust::error::{ValidationError, ValidationErrors, ValidationWarning};
+use crate::horust::formats::{CalendarExpression, ServiceOverride};
 
 pub fn get_sample_service() -> &'static str {
     include_str!("../../../example_services/sample_service.toml")
@@ -27,6 +32,40 @@ pub struct Service {
     pub name: ServiceName,
     #[serde()]
     pub command: String,
+    /// What to do if the command can't be executed when the services are loaded.
+    #[serde(default)]
+    pub command_check: CommandCheck,
+    /// What the service is for, shown by the tooling (e.g. `horustctl status`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Free-form labels, like `critical` or `db`, for selecting the services in the tooling.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default = "Service::default_enabled")]
+    /// Disabled services are loaded (e.g. for the status), but never started.
+    pub enabled: bool,
+    /// If false, the service is only started on request (e.g. via the control socket), or when a
+    /// service which starts after it is going to be started.
+    #[serde(default = "Service::default_auto_start")]
+    pub auto_start: bool,
+    /// The service is one of the alternative variants of its template (like `app@blue` and
+    /// `app@green` for `app`): only one of them runs at a time, until another one is switched to.
+    #[serde(default)]
+    pub variant: bool,
+    /// Only for templates (like `worker@.toml`): they're instantiated as `worker@1.toml` up to
+    /// `worker@<max>.toml`, and only some of the instances are started (see `horustctl scale`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Scale>,
+    /// Only for templates: their instances by name (like `blue` for `app@blue.toml`), with what
+    /// they override of the template. Unless it's scaled, the template is instantiated as these.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub instances: BTreeMap<String, Instance>,
+    /// When the service is considered ready, for the services which start after it.
+    #[serde(default, rename = "type")]
+    pub service_type: ServiceType,
+    /// A oneshot service which has exited successfully stays `Running`, instead of `Finished`.
+    #[serde(default)]
+    pub remain_after_exit: bool,
     #[serde(default)]
     pub user: User,
     #[serde(default = "Service::default_working_directory")]
@@ -37,10 +76,37 @@ pub struct Service {
     pub stdout_rotate_size: u64,
     #[serde(default = "Service::default_stderr_log")]
     pub stderr: LogOutput,
+    /// Template prepended to the lines written to Horust's stdout or stderr, overriding the
+    /// global `log-prefix`.
+    #[serde(default)]
+    pub log_prefix: Option<String>,
+    /// The output is read by Horust, so that it can be streamed with `horustctl attach`.
+    #[serde(default)]
+    pub attachable: bool,
+    #[serde(default)]
+    pub start_delay: StartDelay,
+    /// After running for this long, the service is gracefully restarted.
     #[serde(default, with = "humantime_serde")]
-    pub start_delay: Duration,
+    pub max_runtime: Option<Duration>,
+    /// If the service isn't ready this long after being spawned, it's killed and counts as failed.
+    #[serde(default, with = "humantime_serde")]
+    pub start_timeout: Option<Duration>,
+    /// The service is restarted when any of these files (or directories) change.
+    #[serde(default)]
+    pub watch: Vec<PathBuf>,
+    /// If set, the service is sent this signal when a watched path changes, instead of being
+    /// restarted.
     #[serde(default)]
-    pub start_after: Vec<ServiceName>,
+    pub reload_signal: Option<TerminationSignal>,
+    /// If the definition has changed once the services are reloaded, the service is restarted.
+    /// Otherwise, the new definition is only used from its next start.
+    #[serde(default = "Service::default_reload_on_change")]
+    pub reload_on_change: bool,
+    #[serde(default)]
+    pub start_after: Vec<Dependency>,
+    /// Other names this service can be referred to by, in `start_after`.
+    #[serde(default)]
+    pub provides: Vec<ServiceName>,
     #[serde()]
     pub signal_rewrite: Option<String>,
     #[serde(default)]
@@ -50,16 +116,95 @@ pub struct Service {
     #[serde(default)]
     pub failure: Failure,
     #[serde(default)]
+    pub on_crash: OnCrash,
+    #[serde(default)]
     pub environment: Environment,
     #[serde(default)]
     pub termination: Termination,
+    #[serde(default)]
+    pub conditions: Conditions,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    #[serde(default)]
+    pub resources: Resources,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub socket: Socket,
+    /// If not set, the core dump limit of Horust is inherited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub core_dumps: Option<CoreDumps>,
+    /// Where a bundle is collected for post-incident analysis, when the service is killed by a
+    /// signal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crash_artifacts: Option<CrashArtifacts>,
+    /// If set, the service isn't started with the others, but every time its timer triggers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timer: Option<Timer>,
+    /// Allocated by Horust, if `user` is `"dynamic"`.
+    #[serde(skip)]
+    pub dynamic_user: Option<DynamicUser>,
+    /// Set up by Horust, if the service has `resources` limits.
+    #[serde(skip)]
+    pub cgroup: Option<PathBuf>,
+    /// Bound by Horust, if the service is a `notify` one.
+    #[serde(skip)]
+    pub notify_socket: Option<PathBuf>,
+}
+
+/// What to do if the command of a service can't be executed when the services are loaded: e.g.
+/// its program isn't found, or it's not executable.
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandCheck {
+    /// The services aren't loaded.
+    #[default]
+    Error,
+    /// Only logs it, e.g. if the program is installed by another service at boot.
+    Warn,
+}
+
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceType {
+    /// Ready once its process is running, and its healthchecks pass.
+    #[default]
+    Simple,
+    /// A job, ready once its process has exited successfully. Healthchecks are not used.
+    Oneshot,
+    /// Ready once it has sent `READY=1` on its `NOTIFY_SOCKET`, and its healthchecks pass.
+    Notify,
+}
+
+/// The `user` of the services running with a dynamic user.
+pub const DYNAMIC_USER: &str = "dynamic";
+
+/// A user allocated to a service, which doesn't exist in the system.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DynamicUser {
+    /// Used as gid as well.
+    pub uid: u32,
+    pub name: String,
+    pub home: PathBuf,
 }
 
 impl Service {
-    fn default_working_directory() -> PathBuf {
+    pub(crate) fn default_working_directory() -> PathBuf {
         env::current_dir().unwrap()
     }
 
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_auto_start() -> bool {
+        true
+    }
+
+    fn default_reload_on_change() -> bool {
+        true
+    }
+
     fn default_stdout_log() -> LogOutput {
         LogOutput::Stdout
     }
@@ -80,13 +225,168 @@ impl Service {
         let postconfig = shellexpand::full(&preconfig)?;
         Ok(toml::from_str::<Service>(&postconfig)?)
     }
+
+    /// Like `from_file`, applying the `overrides` targeting the service. Its name is needed to
+    /// find them, if it's not set in the file.
+    pub fn from_file_with_overrides<P>(
+        path: &P,
+        default_name: &str,
+        overrides: &[ServiceOverride],
+    ) -> Result<Self>
+    where
+        P: AsRef<Path> + ?Sized + AsRef<OsStr> + Debug,
+    {
+        let preconfig = std::fs::read_to_string(path)?;
+        let postconfig = shellexpand::full(&preconfig)?;
+        let mut service: toml::Table = toml::from_str(&postconfig)?;
+        let name = match service.get("name") {
+            Some(toml::Value::String(name)) if !name.is_empty() => name.clone(),
+            _ => default_name.to_string(),
+        };
+        for service_override in overrides {
+            service_override.apply(&name, &mut service)?;
+        }
+        Ok(toml::Value::Table(service).try_into()?)
+    }
     /// Creates the environment K=V variables, used for exec into the new process.
     /// User defined environment variables overwrite the predefined values.
     pub fn get_environment(&self) -> Result<Vec<String>> {
-        Ok(self.environment.get_environment(
-            self.user.clone().get_name()?,
-            self.user.clone().get_home()?.display().to_string(),
-        ))
+        let (user_name, user_home) = match &self.dynamic_user {
+            Some(dynamic_user) => (dynamic_user.name.clone(), dynamic_user.home.clone()),
+            None => (self.user.get_name()?, self.user.get_home()?),
+        };
+        let mut environment = self
+            .environment
+            .get_environment(user_name, user_home.display().to_string())?;
+        if let Some((_, instance)) = self.instance() {
+            if !self.environment.additional.contains_key("HORUST_INSTANCE") {
+                environment.push(format!("HORUST_INSTANCE={}", instance));
+            }
+        }
+        if let Some(hostname) = self.hostname() {
+            if !self.environment.additional.contains_key("HOSTNAME") {
+                environment.retain(|kv| !kv.starts_with("HOSTNAME="));
+                environment.push(format!("HOSTNAME={}", hostname));
+            }
+        }
+        Ok(environment)
+    }
+
+    /// The name of the service without its `.toml` extension, split at the first `@` into the
+    /// name of its template and its instance: like `app` and `blue`, for `app@blue.toml`. None if
+    /// it's not an instance of a template.
+    pub fn instance(&self) -> Option<(&str, &str)> {
+        let name = self.name.strip_suffix(".toml").unwrap_or(&self.name);
+        name.split_once('@')
+    }
+
+    /// The template and the index of an instance of a scaled template, like `("worker", 2)` for
+    /// `worker@2.toml`.
+    pub fn scaled_instance(&self) -> Option<(&str, usize)> {
+        self.scale.as_ref()?;
+        let (template, instance) = self.instance()?;
+        Some((template, instance.parse().ok()?))
+    }
+
+    /// True if it's a template (like `worker@.toml`) to be instantiated, because it's scaled or it
+    /// lists its instances.
+    pub fn is_template(&self) -> bool {
+        let is_instantiated = self.scale.is_some() || !self.instances.is_empty();
+        is_instantiated && matches!(self.instance(), Some((_, "")))
+    }
+
+    /// The instance `instance` of this template, like `worker@2.toml` for `worker@.toml`, with
+    /// what it overrides in `instances`.
+    pub fn instantiate(&self, instance: &str) -> Service {
+        let name = match self.name.strip_suffix(".toml") {
+            Some(template) => format!("{}{}.toml", template, instance),
+            None => format!("{}{}", self.name, instance),
+        };
+        let mut service = Service {
+            name,
+            instances: BTreeMap::new(),
+            ..self.clone()
+        };
+        if let Some(overrides) = self.instances.get(instance) {
+            if let Some(command) = &overrides.command {
+                service.command = command.clone();
+            }
+            if let Some(working_directory) = &overrides.working_directory {
+                service.working_directory = working_directory.clone();
+            }
+            service
+                .environment
+                .additional
+                .extend(overrides.environment.clone());
+        }
+        service
+    }
+
+    /// The hostname of the service (`sandbox.hostname`), with the specifiers replaced.
+    pub fn hostname(&self) -> Option<String> {
+        let template = self.sandbox.hostname.as_ref()?;
+        let name = self.name.strip_suffix(".toml").unwrap_or(&self.name);
+        let instance = self.instance().map(|(_, instance)| instance);
+        let mut hostname = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                hostname.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => hostname.push_str(name),
+                Some('i') => hostname.push_str(instance.unwrap_or_default()),
+                Some(other) => hostname.push(other),
+                None => hostname.push('%'),
+            }
+        }
+        Some(hostname)
+    }
+
+    pub fn has_dynamic_user(&self) -> bool {
+        matches!(&self.user, User::Name(name) if name == DYNAMIC_USER)
+    }
+
+    /// The uid the processes of the service run as.
+    pub(crate) fn uid(&self) -> Result<unistd::Uid> {
+        match &self.dynamic_user {
+            Some(dynamic_user) => Ok(unistd::Uid::from_raw(dynamic_user.uid)),
+            None if self.has_dynamic_user() => {
+                Err(anyhow!("No dynamic user was allocated to {}", self.name))
+            }
+            None => self.user.get_uid(),
+        }
+    }
+
+    /// The gid the processes of the service run as, if it has to be changed.
+    pub(crate) fn gid(&self) -> Option<unistd::Gid> {
+        self.dynamic_user
+            .as_ref()
+            .map(|dynamic_user| unistd::Gid::from_raw(dynamic_user.uid))
+    }
+
+    /// The services listed in `start_after`.
+    pub fn start_after_services(&self) -> Vec<&ServiceName> {
+        self.start_after
+            .iter()
+            .filter_map(|dependency| match dependency {
+                Dependency::Service(name) | Dependency::Detailed { service: name, .. } => {
+                    Some(name)
+                }
+                Dependency::Tcp { .. } => None,
+            })
+            .collect()
+    }
+
+    /// True if `name` refers to this service, either by its name or by one of `provides`.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.provides.iter().any(|provided| provided == name)
+    }
+
+    /// True if the service has any of `tags`, or if `tags` is empty.
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        tags.is_empty() || self.tags.iter().any(|tag| tags.contains(tag))
     }
 
     /// Wrapper for single command horust run
@@ -103,20 +403,50 @@ impl Default for Service {
     fn default() -> Self {
         Self {
             name: "".to_owned(),
+            description: None,
+            tags: Vec::new(),
             start_after: Default::default(),
+            provides: Default::default(),
             working_directory: env::current_dir().unwrap(),
             stdout: Default::default(),
             stdout_rotate_size: 0,
             stderr: Default::default(),
+            log_prefix: None,
+            attachable: false,
             user: Default::default(),
             restart: Default::default(),
-            start_delay: Duration::from_secs(0),
+            start_delay: Default::default(),
+            max_runtime: None,
+            start_timeout: None,
+            watch: Default::default(),
+            reload_signal: None,
+            reload_on_change: true,
             command: "command".to_string(),
+            command_check: Default::default(),
+            enabled: true,
+            auto_start: true,
+            variant: false,
+            scale: None,
+            instances: Default::default(),
+            service_type: Default::default(),
+            remain_after_exit: false,
             healthiness: Default::default(),
             signal_rewrite: None,
             environment: Default::default(),
             failure: Default::default(),
+            on_crash: Default::default(),
             termination: Default::default(),
+            conditions: Default::default(),
+            sandbox: Default::default(),
+            resources: Default::default(),
+            limits: Default::default(),
+            socket: Default::default(),
+            core_dumps: None,
+            crash_artifacts: None,
+            timer: None,
+            dynamic_user: None,
+            cgroup: None,
+            notify_socket: None,
         }
     }
 }
@@ -136,14 +466,32 @@ pub enum LogOutput {
     #[default]
     Stdout,
     Path(PathBuf),
+    /// Written both to the file, and to the same stream of Horust.
+    Tee(PathBuf),
     Pipe(RawFd),
 }
 
+/// The table form of `LogOutput`: `{ file = "/var/log/app.log", also-inherit = true }`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct LogFile {
+    file: PathBuf,
+    #[serde(default, alias = "also_inherit")]
+    also_inherit: bool,
+}
+
 impl Serialize for LogOutput {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
+        if let LogOutput::Tee(path) = self {
+            let log_file = LogFile {
+                file: path.clone(),
+                also_inherit: true,
+            };
+            return log_file.serialize(serializer);
+        }
         let as_string: String = self.clone().into();
         serializer.serialize_str(as_string.as_str())
     }
@@ -154,7 +502,7 @@ impl<'de> Deserialize<'de> for LogOutput {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_str(LogOutputVisitor)
+        deserializer.deserialize_any(LogOutputVisitor)
     }
 }
 
@@ -164,7 +512,7 @@ impl<'de> Visitor<'de> for LogOutputVisitor {
     type Value = LogOutput;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        formatter.write_str("a string with 'STDOUT', 'STDERR', or a full path. All as `String`s ")
+        formatter.write_str("a string with 'STDOUT', 'STDERR', or a full path. All as `String`s, or a table with `file` and `also-inherit`")
     }
     fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
     where
@@ -172,6 +520,17 @@ impl<'de> Visitor<'de> for LogOutputVisitor {
     {
         Ok(LogOutput::from(value))
     }
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        let log_file = LogFile::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        Ok(if log_file.also_inherit {
+            LogOutput::Tee(log_file.file)
+        } else {
+            LogOutput::Path(log_file.file)
+        })
+    }
 }
 
 impl From<String> for LogOutput {
@@ -186,7 +545,7 @@ impl From<LogOutput> for String {
         match l {
             Stdout => "STDOUT".to_string(),
             Stderr => "STDERR".to_string(),
-            Path(path) => {
+            Path(path) | Tee(path) => {
                 let path = path.display();
                 path.to_string()
             }
@@ -205,18 +564,287 @@ impl From<&str> for LogOutput {
     }
 }
 
-#[derive(Serialize, Clone, Default, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Environment {
     #[serde(default)]
     pub keep_env: bool,
     #[serde(default)]
     pub re_export: Vec<String>,
+    #[serde(default)]
+    /// Variables of Horust's environment passed to the service, when `keep_env` is false.
+    /// `*` and `?` match any sequence of characters and any single character.
+    pub keep: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    /// Shell scripts like `/etc/profile.d/app.sh`, whose variable assignments are parsed (they're
+    /// not run) every time the service is spawned, as a login shell would have set them.
+    pub source: Vec<PathBuf>,
+    #[serde(default)]
+    /// Files of `KEY=VALUE` lines, read every time the service is spawned.
+    pub files: Vec<EnvironmentFile>,
+    #[serde(default)]
+    pub additional: HashMap<String, String>,
+    #[serde(default = "default_override")]
+    /// If false, the `additional` variables only set the ones which are not set yet.
+    pub additional_override: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Exported as `TZ`, e.g. `Europe/Rome`. Overrides the one of Horust's configuration.
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Exported as `LANG`, e.g. `en_US.UTF-8`. Overrides the one of Horust's configuration.
+    pub locale: Option<String>,
+    #[serde(default)]
+    /// Variables whose value is the output of a command, run every time the service is spawned.
+    pub from_command: HashMap<String, String>,
+    #[serde(
+        default = "Environment::default_from_command_timeout",
+        with = "humantime_serde"
+    )]
+    /// How long each of the `from_command` commands is allowed to run.
+    pub from_command_timeout: Duration,
+    #[serde(default, deserialize_with = "one_or_many")]
+    /// Variables fetched from a secrets provider (like Vault or SSM) when the service is spawned.
+    pub from_provider: Vec<SecretProvider>,
+    #[serde(skip)]
+    /// The `environment` of Horust's configuration, shared by all the services.
+    pub global: Option<GlobalEnvironment>,
+}
+
+/// A command which prints a secret, like `vault kv get -field=pw secret/db`.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SecretProvider {
+    pub command: String,
+    /// The variable set to the output of the command.
+    pub var: String,
+    #[serde(default = "SecretProvider::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// How long the secret is reused for, when the service is spawned again.
+    #[serde(default = "SecretProvider::default_cache", with = "humantime_serde")]
+    pub cache: Duration,
+    #[serde(default = "default_override", rename = "override")]
+    /// If false, the secret is only fetched if the variable is not set yet.
+    pub overrides: bool,
+}
+
+impl SecretProvider {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_cache() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+}
+
+/// Variables set for all the services, in Horust's configuration.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GlobalEnvironment {
     #[serde(default)]
     pub additional: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Exported as `TZ`, unless it's in `additional`.
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Exported as `LANG`, unless it's in `additional`.
+    pub locale: Option<String>,
+    #[serde(default = "default_override", rename = "override")]
+    /// If false, only the variables which are not set yet are set.
+    pub overrides: bool,
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum EnvironmentFile {
+    Path(PathBuf),
+    Options {
+        path: PathBuf,
+        #[serde(default = "default_override", rename = "override")]
+        /// If false, only the variables which are not set yet are set.
+        overrides: bool,
+    },
+}
+
+fn default_override() -> bool {
+    true
+}
+
+impl EnvironmentFile {
+    pub fn path(&self) -> &Path {
+        match self {
+            EnvironmentFile::Path(path) | EnvironmentFile::Options { path, .. } => path,
+        }
+    }
+
+    fn overrides(&self) -> bool {
+        match self {
+            EnvironmentFile::Path(_) => true,
+            EnvironmentFile::Options { overrides, .. } => *overrides,
+        }
+    }
+
+    fn read(&self) -> Result<HashMap<String, String>> {
+        let content = std::fs::read_to_string(self.path())
+            .with_context(|| format!("Failed reading {}", self.path().display()))?;
+        parse_environment_file(&content)
+            .with_context(|| format!("Invalid environment file {}", self.path().display()))
+    }
+}
+
+/// Parses `KEY=VALUE` lines, skipping the empty ones and the comments. An `export ` prefix and the
+/// quotes around the value are removed.
+fn parse_environment_file(content: &str) -> Result<HashMap<String, String>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Expected KEY=VALUE, got: {}", line))?;
+            let key = key.trim();
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                bail!("Invalid variable name: {}", key);
+            }
+            let value = value.trim();
+            let value = ['"', '\'']
+                .into_iter()
+                .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote))
+                .unwrap_or(value);
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The variables exported for the `timezone` and `locale` settings.
+fn timezone_and_locale(
+    timezone: &Option<String>,
+    locale: &Option<String>,
+) -> HashMap<String, String> {
+    let timezone = timezone.iter().map(|timezone| ("TZ", timezone));
+    let locale = locale.iter().map(|locale| ("LANG", locale));
+    timezone
+        .chain(locale)
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect()
+}
+
+/// Sets the variables assigned by the shell script `content`, like `export PATH="$PATH:/opt/bin"`
+/// or `LANG=C.UTF-8`, expanding their `$VAR` and `${VAR}` references. The script is not run, so
+/// the other lines (like conditionals, or command substitutions) are skipped.
+fn source_shell_script(content: &str, environment: &mut HashMap<String, String>) {
+    for line in content.lines().map(str::trim) {
+        let assignment = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        match parse_assignment(assignment, environment) {
+            Some((key, value)) => {
+                environment.insert(key, value);
+            }
+            None if line.is_empty() || line.starts_with('#') => (),
+            None => debug!("Skipped, not a variable assignment: {}", line),
+        }
+    }
+}
+
+/// A `KEY=VALUE` assignment of a shell script, with its value unquoted and expanded.
+fn parse_assignment(line: &str, environment: &HashMap<String, String>) -> Option<(String, String)> {
+    let (key, rest) = line.split_once('=')?;
+    let is_name = |name: &str| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if !is_name(key) {
+        return None;
+    }
+    let mut value = String::new();
+    let mut quote = None;
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => value.push(c),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() || c == ';' => break,
+            // Within double quotes, only these are escaped.
+            (Some('"'), '\\') => match chars.next_if(|c| matches!(c, '$' | '`' | '"' | '\\')) {
+                Some(escaped) => value.push(escaped),
+                None => value.push('\\'),
+            },
+            (_, '\\') => value.push(chars.next()?),
+            (_, '$') => {
+                let name: String = if chars.next_if_eq(&'{').is_some() {
+                    let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    // Like `${VAR:-default}`, which isn't supported.
+                    if !is_name(&name) {
+                        return None;
+                    }
+                    name
+                } else {
+                    std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_'))
+                        .collect()
+                };
+                match name.as_str() {
+                    // Like `$(command)`.
+                    "" if chars.peek() == Some(&'(') => return None,
+                    "" => value.push('$'),
+                    name => value.push_str(environment.get(name).map_or("", String::as_str)),
+                }
+            }
+            (_, '`') => return None,
+            (_, c) => value.push(c),
+        }
+    }
+    // Unterminated quotes (a value on multiple lines), or more than an assignment on the line.
+    let rest: String = chars.collect();
+    let rest = rest.trim_start();
+    if quote.is_some() || !(rest.is_empty() || rest.starts_with('#')) {
+        return None;
+    }
+    Some((key.to_string(), value))
+}
+
+/// Adds the variables of a source to `environment`. Unless it `overrides`, the variables which are
+/// already set are left as they are.
+fn merge_environment(
+    environment: &mut HashMap<String, String>,
+    source: HashMap<String, String>,
+    overrides: bool,
+) {
+    if overrides {
+        environment.extend(source);
+    } else {
+        for (key, value) in source {
+            environment.entry(key).or_insert(value);
+        }
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            keep_env: false,
+            re_export: Vec::new(),
+            keep: Vec::new(),
+            source: Vec::new(),
+            files: Vec::new(),
+            additional: HashMap::new(),
+            additional_override: true,
+            timezone: None,
+            locale: None,
+            from_command: HashMap::new(),
+            from_command_timeout: Self::default_from_command_timeout(),
+            from_provider: Vec::new(),
+            global: None,
+        }
+    }
 }
 
 impl Environment {
+    fn default_from_command_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
     fn get_hostname_val() -> String {
         let hostname_path = "/etc/hostname";
         let localhost = "localhost".to_string();
@@ -228,14 +856,18 @@ impl Environment {
     }
 
     /// Create the environment K=V variables, used for exec into the new process.
-    /// User defined environment variables overwrite the predefined variables.
-    pub(crate) fn get_environment(&self, user_name: String, user_home: String) -> Vec<String> {
-        let mut initial: HashMap<String, String> = self
-            .keep_env
-            .then(|| std::env::vars().collect())
-            .unwrap_or_default();
-
-        let mut additional = self.additional.clone();
+    /// From the lowest to the highest precedence: the kept variables of Horust's environment, the
+    /// predefined variables, `re_export`, the global environment, `files` and `additional`.
+    pub(crate) fn get_environment(
+        &self,
+        user_name: String,
+        user_home: String,
+    ) -> Result<Vec<String>> {
+        let mut initial: HashMap<String, String> = std::env::vars()
+            .filter(|(key, _value)| {
+                self.keep_env || self.keep.iter().any(|pattern| glob_match(pattern, key))
+            })
+            .collect();
 
         let get_env = |name: &str, default: &str| {
             (
@@ -276,24 +908,507 @@ impl Environment {
         // If a variable is re_export, then it has precedence over initial + env.
         initial.extend(re_export);
 
+        if let Some(global) = &self.global {
+            let mut additional = timezone_and_locale(&global.timezone, &global.locale);
+            additional.extend(global.additional.clone());
+            merge_environment(&mut initial, additional, global.overrides);
+        }
+        for path in &self.source {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed reading {}", path.display()))?;
+            source_shell_script(&content, &mut initial);
+        }
+        for file in &self.files {
+            merge_environment(&mut initial, file.read()?, file.overrides());
+        }
+
         // Finally, additional has the higher precedence:
-        initial.into_iter().for_each(|(k, v)| {
-            additional.entry(k).or_insert(v);
-        });
+        initial.extend(timezone_and_locale(&self.timezone, &self.locale));
+        merge_environment(
+            &mut initial,
+            self.additional.clone(),
+            self.additional_override,
+        );
 
         // This is the suitable format for `exec`
-        additional
+        Ok(initial
             .into_iter()
             .map(|(k, v)| format!("{}={}", k, v))
-            .collect()
+            .collect())
+    }
+}
+
+/// Matches `name` against `pattern`, where `*` matches any sequence of characters and `?` any
+/// single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Where to resume after a mismatch: the last `*` seen, and the name position it's matching up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    // The `*` swallows one more character.
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// An entry of `start_after`.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum Dependency {
+    /// Another service of this Horust instance.
+    Service(ServiceName),
+    /// Another service, with options. An `optional` dependency doesn't block the service if it
+    /// failed, or if it didn't start within `timeout` (if any).
+    Detailed {
+        service: ServiceName,
+        #[serde(default)]
+        optional: bool,
+        #[serde(
+            default,
+            with = "humantime_serde",
+            skip_serializing_if = "Option::is_none"
+        )]
+        timeout: Option<Duration>,
+    },
+    /// An endpoint accepting TCP connections, e.g. `redis:6379`. Once `timeout` (if any) has
+    /// passed without being able to connect, the service fails.
+    Tcp {
+        tcp: String,
+        #[serde(
+            default,
+            with = "humantime_serde",
+            skip_serializing_if = "Option::is_none"
+        )]
+        timeout: Option<Duration>,
+    },
+}
+
+impl From<&str> for Dependency {
+    fn from(name: &str) -> Self {
+        Dependency::Service(name.to_string())
     }
 }
 
+/// Conditions which must be met before spawning the service.
+#[derive(Serialize, Clone, Default, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Conditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkCondition>,
+    /// Files (or sockets, directories) which must exist, e.g. dropped in a shared volume by
+    /// another container.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<PathBuf>,
+    /// How long to wait for the `paths`, before the service fails to spawn.
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub paths_timeout: Option<Duration>,
+}
+
+/// Restrictions applied to the processes of the service (Linux only).
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Sandbox {
+    /// Mount `read_only_paths` read-only, in a mount namespace of the service.
+    #[serde(default)]
+    pub protect_system: bool,
+    #[serde(default = "Sandbox::default_read_only_paths")]
+    pub read_only_paths: Vec<PathBuf>,
+    /// Exceptions to `read_only_paths`, which stay writable.
+    #[serde(default)]
+    pub read_write_paths: Vec<PathBuf>,
+    /// Run the service as the init (pid 1) of its own PID namespace, with its own `/proc`.
+    #[serde(default)]
+    pub pid_namespace: bool,
+    /// Set in a UTS namespace of the service. `%n` is replaced by the name of the service,
+    /// and `%i` by its instance (e.g. `1` in `worker@1.toml`).
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+impl Sandbox {
+    fn default_read_only_paths() -> Vec<PathBuf> {
+        vec!["/usr".into(), "/etc".into()]
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            protect_system: false,
+            read_only_paths: Self::default_read_only_paths(),
+            read_write_paths: vec![],
+            pid_namespace: false,
+            hostname: None,
+        }
+    }
+}
+
+/// Listening sockets bound by Horust, and passed to the processes of the service (socket
+/// activation): they're file descriptors 3, 4, ..., in the order of `listen`.
+#[derive(Serialize, Clone, Deserialize, Default, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Socket {
+    #[serde(default)]
+    pub listen: Vec<ListenAddress>,
+    /// The service is only spawned once the first connection comes in.
+    #[serde(default)]
+    pub lazy: bool,
+}
+
+/// Where a socket listens: a TCP address like `127.0.0.1:8080`, or the path of a Unix socket.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(ListenAddress::Unix(s.into()));
+        }
+        s.parse().map(ListenAddress::Tcp).with_context(|| {
+            format!(
+                "Invalid listen address: {}, expected e.g. 127.0.0.1:8080 or /run/service.sock",
+                s
+            )
+        })
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddress> for String {
+    fn from(address: ListenAddress) -> Self {
+        address.to_string()
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Limits applied to the cgroup (v2) of the service (Linux only).
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Resources {
+    #[serde(default)]
+    pub io_max: Vec<IoMax>,
+    #[serde(
+        default,
+        deserialize_with = "to_bytes_limit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub memory_max: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "to_bytes_limit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub memory_swap_max: Option<u64>,
+    /// The CPU time the service can use in each `cpu_period`, as a number of CPUs (e.g. `0.5`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_max: Option<Cpus>,
+    #[serde(default = "Resources::default_cpu_period", with = "humantime_serde")]
+    pub cpu_period: Duration,
+    /// The CPUs the service can run on, e.g. `"0-1,4"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_cpus: Option<String>,
+    /// If not empty, the only device nodes the service can access (besides the pseudo-devices
+    /// like `/dev/null`), e.g. `"/dev/net/tun rw"`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceRule>,
+}
+
+impl Resources {
+    fn default_cpu_period() -> Duration {
+        Duration::from_millis(100)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl Default for Resources {
+    fn default() -> Self {
+        Self {
+            io_max: vec![],
+            memory_max: None,
+            memory_swap_max: None,
+            cpu_max: None,
+            cpu_period: Self::default_cpu_period(),
+            cpuset_cpus: None,
+            devices: vec![],
+        }
+    }
+}
+
+/// Limits on how long the processes of the service can run.
+#[derive(Serialize, Clone, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Limits {
+    /// The CPU time the process can use (RLIMIT_CPU): it's then sent SIGXCPU, and SIGKILL after
+    /// the termination `wait`.
+    #[serde(default, with = "humantime_serde")]
+    pub cpu_time: Option<Duration>,
+    /// How long the process can run, before it's killed with the termination sequence and counts
+    /// as failed.
+    #[serde(default, with = "humantime_serde")]
+    pub wall_time: Option<Duration>,
+}
+
+/// A number of CPUs, kept in thousandths of a CPU.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct Cpus(pub u64);
+
+impl Cpus {
+    /// The CPU time which can be used in `period`.
+    pub fn quota(&self, period: Duration) -> Duration {
+        period * self.0 as u32 / 1000
+    }
+}
+
+impl TryFrom<f64> for Cpus {
+    type Error = String;
+
+    fn try_from(cpus: f64) -> Result<Self, Self::Error> {
+        if !(0.001..=u32::MAX as f64 / 1000.0).contains(&cpus) {
+            return Err(format!("Invalid number of CPUs: {}", cpus));
+        }
+        Ok(Cpus((cpus * 1000.0).round() as u64))
+    }
+}
+
+impl From<Cpus> for f64 {
+    fn from(cpus: Cpus) -> Self {
+        cpus.0 as f64 / 1000.0
+    }
+}
+
+/// A device node the service can access, like `"/dev/net/tun rw"`: the access is any of `r`
+/// (read), `w` (write) and `m` (mknod), all of them if it's missing.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct DeviceRule {
+    pub path: PathBuf,
+    pub read: bool,
+    pub write: bool,
+    pub mknod: bool,
+}
+
+impl FromStr for DeviceRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (path, access) = match (parts.next(), parts.next().unwrap_or("rwm"), parts.next()) {
+            (Some(path), access, None) if path.starts_with('/') => (path, access),
+            _ => bail!("Invalid device: {}, expected e.g. \"/dev/net/tun rw\"", s),
+        };
+        if access.is_empty() || !access.chars().all(|c| "rwm".contains(c)) {
+            bail!(
+                "Invalid access to {}: {}, expected any of r, w and m",
+                path,
+                access
+            );
+        }
+        Ok(DeviceRule {
+            path: path.into(),
+            read: access.contains('r'),
+            write: access.contains('w'),
+            mknod: access.contains('m'),
+        })
+    }
+}
+
+impl TryFrom<String> for DeviceRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DeviceRule> for String {
+    fn from(rule: DeviceRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl Display for DeviceRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let access: String = [(self.read, 'r'), (self.write, 'w'), (self.mknod, 'm')]
+            .iter()
+            .filter(|(allowed, _)| *allowed)
+            .map(|(_, c)| c)
+            .collect();
+        write!(f, "{} {}", self.path.display(), access)
+    }
+}
+
+/// Bandwidth (bytes per second) and IOPS limits on a block device. `None` is unlimited.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct IoMax {
+    pub device: PathBuf,
+    #[serde(
+        default,
+        deserialize_with = "to_bytes_limit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rbps: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "to_bytes_limit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub wbps: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub riops: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wiops: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum NetworkCondition {
+    /// At least a network interface (other than loopback) is up and has an address.
+    State(NetworkState),
+    /// The host name can be resolved.
+    Dns { dns: String },
+    /// An http GET to the url is successful.
+    Url { url: String },
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NetworkState {
+    Online,
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum CoreDumps {
+    /// No core dumps are written.
+    State(CoreDumpsState),
+    Enabled {
+        /// Maximum size of a core dump, in bytes. `None` is unlimited.
+        #[serde(
+            default,
+            deserialize_with = "str_to_core_limit",
+            serialize_with = "core_limit_to_str"
+        )]
+        limit: Option<u64>,
+        /// Where the core dumps are moved to, after the service is killed by a signal.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoreDumpsState {
+    Disabled,
+}
+
+impl CoreDumps {
+    /// The RLIMIT_CORE to set, `None` is unlimited.
+    pub fn limit(&self) -> Option<u64> {
+        match self {
+            CoreDumps::State(CoreDumpsState::Disabled) => Some(0),
+            CoreDumps::Enabled { limit, .. } => *limit,
+        }
+    }
+}
+
+/// Where the crash bundles of a service are collected, see `Service::crash_artifacts`.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CrashArtifacts {
+    /// The bundles are collected in `<dir>/<service>`.
+    pub dir: PathBuf,
+    /// How many bundles of the service are kept: the oldest ones are removed.
+    #[serde(default = "CrashArtifacts::default_keep")]
+    pub keep: usize,
+}
+
+impl CrashArtifacts {
+    fn default_keep() -> usize {
+        5
+    }
+}
+
+/// When a service is started, see `Service::timer`.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Timer {
+    /// The service is started whenever this triggers, unless it's still running by then.
+    pub on_calendar: CalendarExpression,
+    /// If it should have triggered while Horust was down, it triggers once Horust starts.
+    #[serde(default)]
+    pub persistent: bool,
+    /// How late it can trigger, so that it's coalesced with the other timers due meanwhile.
+    #[serde(default, with = "humantime_serde")]
+    pub accuracy: Duration,
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Healthiness {
     pub http_endpoint: Option<String>,
     pub file_path: Option<PathBuf>,
+    /// Host name which has to be resolvable, e.g. once the service is published in service discovery.
+    pub dns_name: Option<String>,
+    /// If set, `dns_name` has to resolve to this address too.
+    pub dns_address: Option<IpAddr>,
+    /// Executable run on every check: it's sent a JSON request on its stdin, and answers whether
+    /// the service is healthy with a JSON response on its stdout.
+    pub external: Option<String>,
+    /// How long the `external` check is allowed to run, before it counts as failed.
+    #[serde(
+        default = "Healthiness::default_external_timeout",
+        with = "humantime_serde"
+    )]
+    pub external_timeout: Duration,
     #[serde(default = "Healthiness::default_max_failed")]
     // todo: use an u32
     pub max_failed: i32,
@@ -304,8 +1419,15 @@ impl Healthiness {
         3
     }
 
+    fn default_external_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
     pub(crate) fn has_any_check_defined(&self) -> bool {
-        self.http_endpoint.is_some() || self.file_path.is_some()
+        self.http_endpoint.is_some()
+            || self.file_path.is_some()
+            || self.dns_name.is_some()
+            || self.external.is_some()
     }
 }
 
@@ -314,6 +1436,10 @@ impl Default for Healthiness {
         Self {
             http_endpoint: None,
             file_path: None,
+            dns_name: None,
+            dns_address: None,
+            external: None,
+            external_timeout: Self::default_external_timeout(),
             max_failed: 3,
         }
     }
@@ -352,22 +1478,80 @@ impl User {
         }
     }
 
-    fn get_raw_user(&self) -> Result<unistd::User> {
+    pub(crate) fn get_raw_user(&self) -> Result<unistd::User> {
         let uid = self.get_uid()?;
         let user =
             unistd::User::from_uid(uid)?.with_context(|| format!("User `{}` not found", uid))?;
         Ok(user)
     }
 
-    fn get_home(&self) -> Result<PathBuf> {
+    pub(crate) fn get_home(&self) -> Result<PathBuf> {
         Ok(self.get_raw_user()?.dir)
     }
 
-    fn get_name(&self) -> Result<String> {
+    pub(crate) fn get_name(&self) -> Result<String> {
         Ok(self.get_raw_user()?.name)
     }
 }
 
+impl Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            User::Uid(uid) => write!(f, "{}", uid),
+            User::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for User {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse()
+            .map_or_else(|_| User::Name(s.to_string()), User::Uid))
+    }
+}
+
+/// A group in the system.
+/// It can be either a gid or a group name (available in /etc/group)
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum Group {
+    Gid(u32),
+    Name(String),
+}
+
+impl Group {
+    pub(crate) fn get_gid(&self) -> Result<unistd::Gid> {
+        match &self {
+            Group::Name(name) => {
+                let group = unistd::Group::from_name(name)?
+                    .with_context(|| format!("Group `{}` not found", name))?;
+                Ok(group.gid)
+            }
+            Group::Gid(gid) => Ok(unistd::Gid::from_raw(*gid)),
+        }
+    }
+}
+
+impl Display for Group {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Group::Gid(gid) => write!(f, "{}", gid),
+            Group::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for Group {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse()
+            .map_or_else(|_| Group::Name(s.to_string()), Group::Gid))
+    }
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq, Hash, Default)]
 pub enum ServiceStatus {
     /// The service will be started asap
@@ -380,12 +1564,17 @@ pub enum ServiceStatus {
     InKilling,
     /// A successfully exited service.
     Success,
+    /// The service is disabled (or masked), and won't be started.
+    Disabled,
     /// A finished service has done it's job and won't be restarted.
     Finished,
     /// A failed, finished service won't be restarted.
     FinishedFailed,
     /// A Failed service might be restarted if the restart policy demands so.
     Failed,
+    /// The restart attempts of a crash-looping service are over: it's retried once after its
+    /// `restart.cooldown`, or once cleared (see `horustctl clear`).
+    Broken,
     /// This is the initial state: A service in Initial state is marked to be runnable:
     /// it will be run as soon as possible.
     #[default]
@@ -404,10 +1593,70 @@ impl std::fmt::Display for ServiceStatus {
             ServiceStatus::Started => "Started",
             ServiceStatus::Starting => "Starting",
             ServiceStatus::Success => "Success",
+            ServiceStatus::Disabled => "Disabled",
+            ServiceStatus::Broken => "Broken",
         })
     }
 }
 
+/// How long to wait before spawning the service.
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum StartDelay {
+    Fixed(#[serde(with = "humantime_serde")] Duration),
+    /// A random delay between `min` and `max`, picked at every start.
+    Range {
+        #[serde(with = "humantime_serde")]
+        min: Duration,
+        #[serde(with = "humantime_serde")]
+        max: Duration,
+    },
+}
+
+impl StartDelay {
+    /// The delay to use for the next start.
+    pub fn pick(&self) -> Duration {
+        match *self {
+            StartDelay::Fixed(delay) => delay,
+            StartDelay::Range { min, max } if min < max => rand::thread_rng().gen_range(min..=max),
+            StartDelay::Range { min, .. } => min,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            StartDelay::Fixed(delay) => delay.is_zero(),
+            StartDelay::Range { max, .. } => max.is_zero(),
+        }
+    }
+}
+
+impl Default for StartDelay {
+    fn default() -> Self {
+        StartDelay::Fixed(Duration::ZERO)
+    }
+}
+
+impl From<Duration> for StartDelay {
+    fn from(delay: Duration) -> Self {
+        StartDelay::Fixed(delay)
+    }
+}
+
+impl Display for StartDelay {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartDelay::Fixed(delay) => write!(f, "{}", humantime::format_duration(*delay)),
+            StartDelay::Range { min, max } => write!(
+                f,
+                "{} to {}",
+                humantime::format_duration(*min),
+                humantime::format_duration(*max)
+            ),
+        }
+    }
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Restart {
@@ -417,6 +1666,66 @@ pub struct Restart {
     pub backoff: Duration,
     #[serde(default = "default_attempts")]
     pub attempts: u32,
+    /// Restarts which are not caused by a failure (and recycles) are deferred to this window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<TimeWindow>,
+    /// Used instead, after the service was killed by the OOM killer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_oom: Option<OomRestart>,
+    /// Whether the new process is started before stopping the old one, on the restarts which are
+    /// not caused by a failure.
+    #[serde(default)]
+    pub order: RestartOrder,
+    /// Once the attempts are over, the service is Broken instead of giving up on it (whatever the
+    /// strategy): it's retried once after this long.
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cooldown: Option<Duration>,
+}
+
+/// How many instances of a template are started, see `Service::scale`.
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Scale {
+    /// How many instances are started, until scaled at runtime.
+    #[serde(default = "Scale::default_instances")]
+    pub instances: usize,
+    /// How many instances it can be scaled up to.
+    pub max: usize,
+}
+
+impl Scale {
+    fn default_instances() -> usize {
+        1
+    }
+}
+
+/// What an instance overrides of its template, see `Service::instances`.
+#[derive(Serialize, Clone, Deserialize, Default, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Instance {
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub working_directory: Option<PathBuf>,
+    /// Added to the `environment.additional` of the template.
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+}
+
+/// How a service is restarted after being killed by the OOM killer, with its own attempts.
+#[derive(Serialize, Clone, Deserialize, Default, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OomRestart {
+    #[serde(default)]
+    pub strategy: RestartStrategy,
+    #[serde(default, with = "humantime_serde")]
+    pub backoff: Duration,
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
 }
 
 fn default_attempts() -> u32 {
@@ -429,10 +1738,155 @@ impl Default for Restart {
             strategy: Default::default(),
             backoff: Duration::from_secs(0),
             attempts: default_attempts(),
+            window: None,
+            on_oom: None,
+            order: Default::default(),
+            cooldown: None,
+        }
+    }
+}
+
+/// A daily time window, like `02:00-04:00`. Times are local, unless an offset from UTC (like
+/// `+01:00`) or `UTC` follows. The window might span midnight, like `23:00-01:00`.
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+    /// Offset from UTC in seconds, local time if `None`.
+    utc_offset: Option<i32>,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let utc_offset = self
+            .utc_offset
+            .map_or_else(|| local_utc_offset(secs), i64::from);
+        let minute = ((secs + utc_offset).rem_euclid(24 * 60 * 60) / 60) as u32;
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+extern "C" {
+    // Not exposed by the libc crate.
+    fn tzset();
+}
+
+/// Reads the local timezone (from `TZ`, or `/etc/localtime`) once: unlike `localtime`,
+/// `localtime_r` isn't required to. Called before Horust spawns its threads.
+pub(crate) fn init_local_timezone() {
+    // SAFETY: no other thread of Horust can be changing the environment.
+    unsafe { tzset() }
+}
+
+/// Offset from UTC of the local timezone at `secs` since the epoch.
+/// The timezone has been read by `init_local_timezone`.
+pub(crate) fn local_utc_offset(secs: i64) -> i64 {
+    let time = secs as libc::time_t;
+    // SAFETY: tm is plain old data, and localtime_r is thread safe.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff
+}
+
+/// Parses `HH:MM` into the minutes since midnight.
+pub(crate) fn parse_time_of_day(time: &str) -> Result<u32> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .with_context(|| format!("Invalid time: {}, expected HH:MM", time))?;
+    let (hours, minutes): (u32, u32) = (hours.parse()?, minutes.parse()?);
+    if hours >= 24 || minutes >= 60 {
+        anyhow::bail!("Invalid time: {}", time);
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for TimeWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, timezone) = match s.trim().split_once(' ') {
+            Some((range, timezone)) => (range, Some(timezone.trim())),
+            None => (s.trim(), None),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .with_context(|| format!("Invalid window: {}, expected e.g. 02:00-04:00", s))?;
+        let (start, end) = (parse_time_of_day(start)?, parse_time_of_day(end)?);
+        if start == end {
+            anyhow::bail!("The window {} is empty", s);
+        }
+        let utc_offset = match timezone {
+            None => None,
+            Some("UTC") => Some(0),
+            Some(offset) => {
+                let sign = match offset.chars().next() {
+                    Some('+') => 1,
+                    Some('-') => -1,
+                    _ => anyhow::bail!("Invalid timezone: {}, expected UTC or e.g. +01:00", offset),
+                };
+                Some(sign * parse_time_of_day(&offset[1..])? as i32 * 60)
+            }
+        };
+        Ok(TimeWindow {
+            start,
+            end,
+            utc_offset,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl Display for TimeWindow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hh_mm = |minutes: u32| format!("{:02}:{:02}", minutes / 60, minutes % 60);
+        write!(f, "{}-{}", hh_mm(self.start), hh_mm(self.end))?;
+        match self.utc_offset {
+            None => Ok(()),
+            Some(0) => write!(f, " UTC"),
+            Some(offset) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                write!(f, " {}{}", sign, hh_mm(offset.unsigned_abs() / 60))
+            }
         }
     }
 }
 
+/// The order of the two halves of a restart which is not caused by a failure.
+#[derive(Serialize, Clone, Copy, Deserialize, Default, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartOrder {
+    /// The process is stopped, and then a new one is started.
+    #[default]
+    StopFirst,
+    /// A new process is started, and the old one is only stopped once the new one is running.
+    StartFirst,
+}
+
 #[derive(Serialize, Clone, Deserialize, Default, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub enum RestartStrategy {
@@ -465,6 +1919,12 @@ pub struct Failure {
     #[serde(default = "Failure::default_successful_exit_code")]
     pub successful_exit_code: Vec<i32>,
     pub strategy: FailureStrategy,
+    /// How many of the last lines of output are reported when the service fails.
+    #[serde(default)]
+    pub output_lines: usize,
+    /// Considered by the `critical` exit code strategy.
+    #[serde(default)]
+    pub critical: bool,
 }
 
 impl Failure {
@@ -486,6 +1946,49 @@ impl Default for Failure {
         Failure {
             successful_exit_code: Self::default_successful_exit_code(),
             strategy: FailureStrategy::Ignore,
+            output_lines: 0,
+            critical: false,
+        }
+    }
+}
+
+/// What is run when the service keeps crashing, i.e. its processes are killed by a signal.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OnCrash {
+    /// Run against the crashed process, e.g. for grabbing its stack trace from the core dump.
+    pub debug_command: Option<String>,
+    /// The `debug_command` is run once the service has crashed this many times within `window`.
+    #[serde(default = "OnCrash::default_after")]
+    pub after: usize,
+    #[serde(default = "OnCrash::default_window", with = "humantime_serde")]
+    pub window: Duration,
+    /// How long the `debug_command` is allowed to run, before it's killed.
+    #[serde(default = "OnCrash::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl OnCrash {
+    fn default_after() -> usize {
+        1
+    }
+
+    fn default_window() -> Duration {
+        Duration::from_secs(10 * 60)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl Default for OnCrash {
+    fn default() -> Self {
+        Self {
+            debug_command: None,
+            after: Self::default_after(),
+            window: Self::default_window(),
+            timeout: Self::default_timeout(),
         }
     }
 }
@@ -519,6 +2022,28 @@ pub struct Termination {
     #[serde(default = "Vec::new")]
     // Will kill this service if any of the services in Vec are failed
     pub die_if_failed: Vec<ServiceName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Run before sending the termination signal, e.g. to let load balancers drain the traffic.
+    pub pre_stop: Option<PreStop>,
+    #[serde(default)]
+    /// Which processes of the service receive the termination signal.
+    pub kill_mode: KillMode,
+    #[serde(default)]
+    /// Send a SIGCONT after the termination signal, so that stopped processes can handle it.
+    pub send_sigcont: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KillMode {
+    /// Only the main process.
+    #[default]
+    Process,
+    /// The process group of the main process, which includes its children unless they have
+    /// created their own group.
+    ProcessGroup,
+    /// All the processes in the cgroup of the service, even the detached ones.
+    Cgroup,
 }
 
 impl Termination {
@@ -527,12 +2052,39 @@ impl Termination {
     }
 }
 
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum PreStop {
+    /// Run a command with the same user, environment and working directory of the service.
+    #[serde(rename_all = "kebab-case")]
+    Command {
+        command: String,
+        #[serde(default = "PreStop::default_timeout", with = "humantime_serde")]
+        /// The command is killed if it's still running after this time.
+        timeout: Duration,
+    },
+    /// Just wait for the given time.
+    Sleep {
+        #[serde(with = "humantime_serde")]
+        sleep: Duration,
+    },
+}
+
+impl PreStop {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
 impl Default for Termination {
     fn default() -> Self {
         Termination {
             signal: Default::default(),
             wait: Self::default_wait(),
             die_if_failed: Vec::new(),
+            pre_stop: None,
+            kill_mode: Default::default(),
+            send_sigcont: false,
         }
     }
 }
@@ -619,28 +2171,121 @@ impl From<TerminationSignal> for Signal {
 /// TODO: if redirect output is file, check it exists and permissions.
 pub fn validate(services: Vec<Service>) -> Result<Vec<Service>, ValidationErrors> {
     let mut errors = vec![];
+    // The names that can be depended on: of the services, and the ones they provide.
+    let names: HashSet<&str> = services
+        .iter()
+        .flat_map(|s| std::iter::once(&s.name).chain(&s.provides))
+        .map(String::as_str)
+        .collect();
     services.iter().for_each(|service| {
         if service.command.is_empty() {
             errors.push(ValidationError::CommandEmpty {
                 service: service.name.clone(),
             });
         }
+        if let Some(hostname) = service.hostname() {
+            if !is_valid_hostname(&hostname) {
+                errors.push(ValidationError::InvalidHostname {
+                    service: service.name.clone(),
+                    hostname,
+                });
+            }
+        }
+        if service.remain_after_exit && service.service_type != ServiceType::Oneshot {
+            errors.push(ValidationError::RemainAfterExitNotOneshot {
+                service: service.name.clone(),
+            });
+        }
+        if service.socket.lazy && service.socket.listen.is_empty() {
+            errors.push(ValidationError::LazyWithoutSocket {
+                service: service.name.clone(),
+            });
+        }
+        if service.variant && service.instance().is_none() {
+            errors.push(ValidationError::VariantWithoutInstance {
+                service: service.name.clone(),
+            });
+        }
+        if service.scale.is_some() && service.instance().is_none() {
+            errors.push(ValidationError::ScaleWithoutTemplate {
+                service: service.name.clone(),
+            });
+        }
+        if !service.instances.is_empty() {
+            errors.push(ValidationError::InstancesWithoutTemplate {
+                service: service.name.clone(),
+            });
+        }
+        if service.restart.order == RestartOrder::StartFirst
+            && service.termination.kill_mode == KillMode::Cgroup
+        {
+            errors.push(ValidationError::StartFirstWithCgroupKillMode {
+                service: service.name.clone(),
+            });
+        }
+        if let StartDelay::Range { min, max } = service.start_delay {
+            if min > max {
+                errors.push(ValidationError::InvalidStartDelay {
+                    service: service.name.clone(),
+                });
+            }
+        }
         if !service.start_after.is_empty() {
             debug!(
                 "Checking if all depedencies of '{}' exists, deps: {:?}",
                 service.name, service.start_after
             );
         }
-        service.start_after.iter().for_each(|name| {
-            let passed = services.iter().any(|s| s.name == *name);
-            if !passed {
-                errors.push(ValidationError::MissingDependency {
-                    before: name.into(),
-                    after: service.name.clone(),
-                });
+        service.start_after_services().into_iter().for_each(|name| {
+            // Depending on itself is reported by `lint`.
+            if service.is_named(name) || names.contains(name.as_str()) {
+                return;
             }
+            let similar = names.iter().find(|known| known.eq_ignore_ascii_case(name));
+            errors.push(ValidationError::MissingDependency {
+                before: name.clone(),
+                after: service.name.clone(),
+                similar: similar.map(|similar| similar.to_string()),
+            });
         });
     });
+    // Only one variant of a template can run at boot, the others are switched to.
+    let mut started_variants: HashMap<&str, usize> = HashMap::new();
+    services
+        .iter()
+        .filter(|service| service.variant && service.enabled && service.auto_start)
+        .filter_map(|service| service.instance())
+        .for_each(|(template, _)| *started_variants.entry(template).or_default() += 1);
+    let mut templates: Vec<&str> = started_variants
+        .into_iter()
+        .filter(|(_, started)| *started > 1)
+        .map(|(template, _)| template)
+        .collect();
+    templates.sort();
+    errors.extend(templates.into_iter().map(|template| {
+        ValidationError::MultipleAutoStartedVariants {
+            template: template.to_string(),
+        }
+    }));
+    let mut scaled_above_max: Vec<&str> = services
+        .iter()
+        .filter(|service| {
+            service
+                .scale
+                .is_some_and(|scale| scale.instances > scale.max)
+        })
+        .filter_map(|service| service.instance())
+        .map(|(template, _)| template)
+        .collect();
+    scaled_above_max.sort();
+    scaled_above_max.dedup();
+    errors.extend(
+        scaled_above_max
+            .into_iter()
+            .map(|template| ValidationError::ScaleAboveMax {
+                template: template.to_string(),
+            }),
+    );
     if errors.is_empty() {
         Ok(services)
     } else {
@@ -648,6 +2293,155 @@ pub fn validate(services: Vec<Service>) -> Result<Vec<Service>, ValidationErrors
     }
 }
 
+/// Logs the likely mistakes in the definitions of `services`, which are valid nonetheless.
+pub fn lint(services: &[Service]) -> Vec<ValidationWarning> {
+    let mut warnings = vec![];
+    for service in services {
+        for name in service.start_after_services() {
+            if service.is_named(name) {
+                warnings.push(ValidationWarning::SelfDependency {
+                    service: service.name.clone(),
+                });
+            }
+        }
+    }
+    // The variants are switched to instead, and the services with a timer started once it triggers.
+    let unreachable = services.iter().filter(|service| {
+        service.enabled
+            && (!service.auto_start || service.socket.lazy)
+            && !service.variant
+            && service.timer.is_none()
+            && !services.iter().any(|dependent| {
+                dependent
+                    .start_after_services()
+                    .into_iter()
+                    .any(|name| !dependent.is_named(name) && service.is_named(name))
+            })
+    });
+    warnings.extend(
+        unreachable.map(|service| ValidationWarning::UnreachableService {
+            service: service.name.clone(),
+        }),
+    );
+    for warning in &warnings {
+        warn!("{}", warning);
+    }
+    warnings
+}
+
+/// Checks that the commands of the enabled services can be executed: their program is found
+/// (on the `PATH` if it has no `/`) and it's executable, and so is its interpreter if it's a
+/// script. The services with `command-check = "warn"` are only logged.
+pub fn check_commands(services: &[Service]) -> Result<(), ValidationErrors> {
+    let mut errors = vec![];
+    for service in services.iter().filter(|service| service.enabled) {
+        let Err(reason) = check_command(service) else {
+            continue;
+        };
+        match service.command_check {
+            CommandCheck::Error => errors.push(ValidationError::CommandNotExecutable {
+                service: service.name.clone(),
+                reason,
+            }),
+            CommandCheck::Warn => warn!(
+                "{}: the command can't be executed (yet): {}",
+                service.name, reason
+            ),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors::new(errors))
+    }
+}
+
+/// Why the command of `service` can't be executed, like its spawn would find out.
+fn check_command(service: &Service) -> Result<(), String> {
+    let chunks = shlex::split(&service.command).ok_or("its quoting is invalid")?;
+    // Empty commands are reported by `validate`.
+    let Some(program) = chunks.first() else {
+        return Ok(());
+    };
+    let path = if program.contains('/') {
+        service.working_directory.join(program)
+    } else {
+        find_on_path(service, program)?
+    };
+    check_executable(&path)?;
+    let Some((interpreter, arguments)) = interpreter(&path) else {
+        return Ok(());
+    };
+    let check_interpreter = || {
+        check_executable(&interpreter)?;
+        // `#!/usr/bin/env python3` runs the python3 found on the PATH.
+        if interpreter.file_name() == Some(OsStr::new("env")) {
+            let program = arguments
+                .iter()
+                .find(|argument| !argument.starts_with('-') && !argument.contains('='));
+            if let Some(program) = program {
+                check_executable(&find_on_path(service, program)?)?;
+            }
+        }
+        Ok(())
+    };
+    check_interpreter()
+        .map_err(|reason: String| format!("the interpreter of {}: {}", path.display(), reason))
+}
+
+/// Where `program` is found on the PATH of `service`.
+fn find_on_path(service: &Service, program: &str) -> Result<PathBuf, String> {
+    // The PATH of the service, if its environment can already be built.
+    let path_var = service
+        .get_environment()
+        .ok()
+        .and_then(|environment| {
+            environment
+                .into_iter()
+                .find_map(|kv| kv.strip_prefix("PATH=").map(OsString::from))
+        })
+        .or_else(|| env::var_os("PATH"));
+    path_var
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|path| path.join(program))
+        .find(|path| path.is_file())
+        .ok_or_else(|| format!("{} not found in any of the PATH directories", program))
+}
+
+fn check_executable(path: &Path) -> Result<(), String> {
+    let metadata =
+        std::fs::metadata(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path.display()));
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(format!("{} is not executable", path.display()));
+    }
+    Ok(())
+}
+
+/// The interpreter of the script at `path` and its arguments, from its `#!` line.
+fn interpreter(path: &Path) -> Option<(PathBuf, Vec<String>)> {
+    let mut head = [0u8; 256];
+    let read = std::fs::File::open(path).ok()?.read(&mut head).ok()?;
+    let line = head[..read]
+        .strip_prefix(b"#!")?
+        .split(|b| *b == b'\n')
+        .next()?;
+    let mut chunks = std::str::from_utf8(line).ok()?.split_whitespace();
+    let interpreter = PathBuf::from(chunks.next()?);
+    Some((interpreter, chunks.map(String::from).collect()))
+}
+
+/// At most 64 bytes (`HOST_NAME_MAX`), of letters, digits, `-` and `.`.
+fn is_valid_hostname(hostname: &str) -> bool {
+    (1..=64).contains(&hostname.len())
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
 fn str_to_bytes<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: Deserializer<'de>,
@@ -656,14 +2450,85 @@ where
     bytefmt::parse(s).map_err(de::Error::custom)
 }
 
+/// Either a single table, or an array of them.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+/// Either a number of bytes, or a size like "10 MB".
+fn to_bytes_limit<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Human(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Human(size) => bytefmt::parse(size).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Either "unlimited", or a size like "1 GB".
+fn str_to_core_limit<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    if s == "unlimited" {
+        return Ok(None);
+    }
+    bytefmt::parse(s).map(Some).map_err(de::Error::custom)
+}
+
+fn core_limit_to_str<S>(limit: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match limit {
+        Some(limit) => serializer.serialize_str(&limit.to_string()),
+        None => serializer.serialize_str("unlimited"),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::os::unix::fs::PermissionsExt;
     use std::str::FromStr;
-    use std::time::Duration;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use std::collections::HashMap;
+
+    use tempdir::TempDir;
 
+    use crate::horust::formats::service::{
+        glob_match, parse_environment_file, source_shell_script,
+    };
     use crate::horust::formats::{
-        validate, Environment, Failure, FailureStrategy, Healthiness, Restart, RestartStrategy,
-        Service, Termination, TerminationSignal::TERM,
+        check_commands, lint, validate, CommandCheck, Conditions, CoreDumps, Cpus, CrashArtifacts,
+        Dependency, DeviceRule, Environment, EnvironmentFile, Failure, FailureStrategy,
+        GlobalEnvironment, Healthiness, IoMax, KillMode, Limits, ListenAddress, LogOutput,
+        NetworkCondition, NetworkState, OnCrash, OomRestart, PreStop, Resources, Restart,
+        RestartOrder, RestartStrategy, Sandbox, Scale, SecretProvider, Service, ServiceType,
+        Socket, StartDelay, Termination,
+        TerminationSignal::{HUP, TERM},
+        TimeWindow, Timer,
     };
     use crate::horust::get_sample_service;
 
@@ -687,40 +2552,178 @@ mod test {
         let expected = Service {
             name: "".to_string(),
             command: "/bin/bash -c \'echo hello world\'".to_string(),
+            command_check: CommandCheck::Error,
+            description: Some("Prints a greeting".to_string()),
+            tags: vec!["critical".to_string(), "demo".to_string()],
+            enabled: true,
+            auto_start: true,
+            variant: false,
+            scale: None,
+            instances: Default::default(),
+            service_type: ServiceType::Simple,
+            remain_after_exit: false,
             user: super::User::Name(current_user_name),
             environment: Environment {
                 keep_env: false,
                 re_export: vec!["PATH".to_string(), "DB_PASS".to_string()],
+                keep: vec!["HTTP_PROXY".to_string(), "AWS_*".to_string()],
+                source: vec!["/etc/profile.d/app.sh".into()],
+                files: vec![
+                    EnvironmentFile::Path("/etc/horust/common.env".into()),
+                    EnvironmentFile::Options {
+                        path: "/etc/horust/defaults.env".into(),
+                        overrides: false,
+                    },
+                ],
                 additional: vec![("key".to_string(), "value".to_string())]
                     .into_iter()
                     .collect(),
+                additional_override: true,
+                timezone: Some("Europe/Rome".to_string()),
+                locale: Some("en_US.UTF-8".to_string()),
+                from_command: vec![("GIT_SHA".to_string(), "git rev-parse HEAD".to_string())]
+                    .into_iter()
+                    .collect(),
+                from_command_timeout: Duration::from_secs(5),
+                from_provider: vec![SecretProvider {
+                    command: "vault kv get -field=pw secret/db".into(),
+                    var: "DB_PASSWORD".into(),
+                    timeout: Duration::from_secs(10),
+                    cache: Duration::from_secs(60 * 60),
+                    overrides: true,
+                }],
+                global: None,
             },
             working_directory: "/tmp/".into(),
             stdout: "/var/logs/hello_world_svc/stdout.log".into(),
             stdout_rotate_size: 100_000_000,
             stderr: "STDERR".into(),
-            start_delay: Duration::from_secs(2),
-            start_after: vec!["database".into(), "backend.toml".into()],
+            log_prefix: Some("{time} [{service}:{pid}] ".into()),
+            attachable: true,
+            start_delay: Duration::from_secs(2).into(),
+            max_runtime: Some(Duration::from_secs(6 * 60 * 60)),
+            start_timeout: Some(Duration::from_secs(30)),
+            watch: vec!["/etc/hello_world_svc/config.yaml".into()],
+            reload_signal: Some(HUP),
+            reload_on_change: true,
+            start_after: vec![
+                "database".into(),
+                "backend.toml".into(),
+                Dependency::Detailed {
+                    service: "templating.toml".into(),
+                    optional: true,
+                    timeout: Some(Duration::from_secs(30)),
+                },
+                Dependency::Tcp {
+                    tcp: "redis:6379".into(),
+                    timeout: Some(Duration::from_secs(60)),
+                },
+            ],
+            provides: vec!["webserver".into()],
             restart: Restart {
                 strategy: RestartStrategy::Never,
                 backoff: Duration::from_millis(0),
                 attempts: 0,
+                window: Some("02:00-04:00 +01:00".parse().unwrap()),
+                on_oom: Some(OomRestart {
+                    strategy: RestartStrategy::OnFailure,
+                    backoff: Duration::from_secs(10),
+                    attempts: 0,
+                }),
+                order: RestartOrder::StartFirst,
+                cooldown: Some(Duration::from_secs(5 * 60)),
             },
             healthiness: Healthiness {
                 http_endpoint: Some("http://localhost:8080/healthcheck".into()),
                 file_path: Some("/var/myservice/up".into()),
+                dns_name: Some("myservice.service.consul".into()),
+                dns_address: Some("10.0.0.5".parse().unwrap()),
+                external: Some("/usr/local/bin/check-cloud-dependency --bucket assets".into()),
+                external_timeout: Duration::from_secs(10),
                 ..Default::default()
             },
             signal_rewrite: None,
             failure: Failure {
                 successful_exit_code: vec![0, 1, 255],
                 strategy: FailureStrategy::Ignore,
+                output_lines: 20,
+                critical: true,
+            },
+            on_crash: OnCrash {
+                debug_command: Some("coredumpctl info {pid}".into()),
+                after: 3,
+                window: Duration::from_secs(10 * 60),
+                timeout: Duration::from_secs(60),
             },
             termination: Termination {
                 signal: TERM,
                 wait: Duration::from_secs(10),
                 die_if_failed: vec!["db.toml".into()],
+                pre_stop: Some(PreStop::Sleep {
+                    sleep: Duration::from_secs(5),
+                }),
+                kill_mode: KillMode::ProcessGroup,
+                send_sigcont: true,
+            },
+            conditions: Conditions {
+                network: Some(NetworkCondition::State(NetworkState::Online)),
+                paths: vec!["/run/db/db.sock".into()],
+                paths_timeout: Some(Duration::from_secs(60)),
+            },
+            sandbox: Sandbox {
+                protect_system: true,
+                read_only_paths: vec!["/usr".into(), "/etc".into(), "/opt".into()],
+                read_write_paths: vec!["/etc/myservice".into()],
+                pid_namespace: true,
+                hostname: Some("myservice-%i".into()),
+            },
+            resources: Resources {
+                io_max: vec![IoMax {
+                    device: "/dev/sda".into(),
+                    rbps: Some(10_000_000),
+                    wbps: Some(5_000_000),
+                    riops: None,
+                    wiops: Some(1000),
+                }],
+                memory_max: Some(512_000_000),
+                memory_swap_max: Some(0),
+                cpu_max: Some(Cpus(500)),
+                cpu_period: Duration::from_millis(100),
+                cpuset_cpus: Some("0-1".into()),
+                devices: vec![DeviceRule {
+                    path: "/dev/net/tun".into(),
+                    read: true,
+                    write: true,
+                    mknod: false,
+                }],
+            },
+            limits: Limits {
+                cpu_time: Some(Duration::from_secs(300)),
+                wall_time: Some(Duration::from_secs(3600)),
             },
+            socket: Socket {
+                listen: vec![
+                    ListenAddress::Tcp("127.0.0.1:8080".parse().unwrap()),
+                    ListenAddress::Unix("/run/myservice.sock".into()),
+                ],
+                lazy: true,
+            },
+            core_dumps: Some(CoreDumps::Enabled {
+                limit: None,
+                dir: Some("/cores".into()),
+            }),
+            crash_artifacts: Some(CrashArtifacts {
+                dir: "/var/lib/horust/crashes".into(),
+                keep: 10,
+            }),
+            timer: Some(Timer {
+                on_calendar: "Mon..Fri 02:30".parse().unwrap(),
+                persistent: true,
+                accuracy: Duration::from_secs(60),
+            }),
+            dynamic_user: None,
+            cgroup: None,
+            notify_socket: None,
         };
 
         let service =
@@ -756,21 +2759,559 @@ working-directory = "/tmp/"
         assert!(Service::from_str(&cfg).is_ok());
     }
 
+    #[test]
+    fn test_pre_stop() {
+        let parse = |pre_stop: &str| {
+            let cfg = format!(
+                "command = \"/bin/true\"\n[termination]\npre-stop = {}",
+                pre_stop
+            );
+            Service::from_str(&cfg).map(|service| service.termination.pre_stop)
+        };
+        assert_eq!(
+            parse(r#"{ sleep = "3s" }"#).unwrap(),
+            Some(PreStop::Sleep {
+                sleep: Duration::from_secs(3)
+            })
+        );
+        assert_eq!(
+            parse(r#"{ command = "/bin/drain --now" }"#).unwrap(),
+            Some(PreStop::Command {
+                command: "/bin/drain --now".into(),
+                timeout: Duration::from_secs(30),
+            })
+        );
+        assert_eq!(
+            parse(r#"{ command = "/bin/drain", timeout = "1m" }"#).unwrap(),
+            Some(PreStop::Command {
+                command: "/bin/drain".into(),
+                timeout: Duration::from_secs(60),
+            })
+        );
+        parse(r#"{ wait = "3s" }"#).unwrap_err();
+    }
+
+    #[test]
+    fn test_network_condition() {
+        let parse = |network: &str| {
+            let cfg = format!(
+                "command = \"/bin/true\"\n[conditions]\nnetwork = {}",
+                network
+            );
+            Service::from_str(&cfg).map(|service| service.conditions.network)
+        };
+        assert_eq!(
+            parse(r#""online""#).unwrap(),
+            Some(NetworkCondition::State(NetworkState::Online))
+        );
+        assert_eq!(
+            parse(r#"{ dns = "db.internal" }"#).unwrap(),
+            Some(NetworkCondition::Dns {
+                dns: "db.internal".into()
+            })
+        );
+        assert_eq!(
+            parse(r#"{ url = "http://db.internal/ready" }"#).unwrap(),
+            Some(NetworkCondition::Url {
+                url: "http://db.internal/ready".into()
+            })
+        );
+        parse(r#""offline""#).unwrap_err();
+    }
+
+    #[test]
+    fn test_log_output_table() {
+        let service: Service = toml::from_str(
+            r#"command = "a"
+stdout = { file = "/tmp/out.log", also-inherit = true }
+stderr = { file = "/tmp/err.log" }"#,
+        )
+        .unwrap();
+        assert_eq!(service.stdout, LogOutput::Tee("/tmp/out.log".into()));
+        assert_eq!(service.stderr, LogOutput::Path("/tmp/err.log".into()));
+        let serialized = toml::to_string(&service).unwrap();
+        let deserialized: Service = toml::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.stdout, service.stdout);
+    }
+
+    #[test]
+    fn test_core_dumps() {
+        let parse = |core_dumps: &str| -> Option<CoreDumps> {
+            toml::from_str::<Service>(&format!("command = \"a\"\ncore-dumps = {}", core_dumps))
+                .unwrap()
+                .core_dumps
+        };
+        assert_eq!(parse("\"disabled\"").unwrap().limit(), Some(0));
+        assert_eq!(parse("{ limit = \"1 KB\" }").unwrap().limit(), Some(1000));
+        assert_eq!(parse("{}").unwrap().limit(), None);
+        toml::from_str::<Service>("command = \"a\"\ncore-dumps = \"enabled\"").unwrap_err();
+    }
+
+    #[test]
+    fn test_from_provider() {
+        let parse = |from_provider: &str| -> Vec<SecretProvider> {
+            toml::from_str::<Service>(&format!(
+                "command = \"a\"\n[environment]\nfrom-provider = {}",
+                from_provider
+            ))
+            .unwrap()
+            .environment
+            .from_provider
+        };
+        let providers = parse(r#"{ command = "get-secret db", var = "DB_PASSWORD" }"#);
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].var, "DB_PASSWORD");
+        assert_eq!(providers[0].cache, Duration::from_secs(5 * 60));
+        let providers = parse(
+            r#"[{ command = "get-secret db", var = "DB_PASSWORD", cache = "0s" },
+                { command = "get-secret api", var = "API_KEY", timeout = "1s" }]"#,
+        );
+        assert_eq!(providers.len(), 2);
+        assert_eq!(providers[0].cache, Duration::ZERO);
+        assert_eq!(providers[1].timeout, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_start_delay() {
+        let parse = |start_delay: &str| {
+            let cfg = format!("command = \"/bin/true\"\nstart-delay = {}", start_delay);
+            Service::from_str(&cfg).map(|service| service.start_delay)
+        };
+        assert_eq!(
+            parse("\"2s\"").unwrap(),
+            StartDelay::Fixed(Duration::from_secs(2))
+        );
+        let range = parse("{ min = \"1s\", max = \"10s\" }").unwrap();
+        assert_eq!(range.to_string(), "1s to 10s");
+        for _ in 0..100 {
+            let delay = range.pick();
+            assert!(Duration::from_secs(1) <= delay && delay <= Duration::from_secs(10));
+        }
+        parse("{ min = \"1s\" }").unwrap_err();
+
+        let mut service = Service::from_name("a");
+        service.start_delay = StartDelay::Range {
+            min: Duration::from_secs(2),
+            max: Duration::from_secs(1),
+        };
+        validate(vec![service]).unwrap_err();
+    }
+
+    #[test]
+    fn test_time_window() {
+        let at = |hh: u64, mm: u64| UNIX_EPOCH + Duration::from_secs(hh * 3600 + mm * 60);
+        let window: TimeWindow = "02:00-04:00 UTC".parse().unwrap();
+        assert!(!window.contains(at(1, 59)));
+        assert!(window.contains(at(2, 0)));
+        assert!(window.contains(at(3, 59)));
+        assert!(!window.contains(at(4, 0)));
+
+        // 23:30-00:30 in +01:00 is 22:30-23:30 in UTC.
+        let window: TimeWindow = "23:30-00:30 +01:00".parse().unwrap();
+        assert_eq!(window.to_string(), "23:30-00:30 +01:00");
+        assert!(!window.contains(at(22, 29)));
+        assert!(window.contains(at(22, 30)));
+        assert!(window.contains(at(23, 29)));
+        assert!(!window.contains(at(23, 30)));
+
+        for invalid in ["02:00", "02:00-02:00", "25:00-04:00", "02:00-04:00 CET"] {
+            invalid.parse::<TimeWindow>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_environment_precedence() -> anyhow::Result<()> {
+        let tempdir = tempdir::TempDir::new("environment")?;
+        let file = tempdir.path().join("service.env");
+        std::fs::write(
+            &file,
+            "FROM_FILE=file\nGLOBAL=file\nADDITIONAL=file\nUSER=file\n",
+        )?;
+        let defaults = tempdir.path().join("defaults.env");
+        std::fs::write(
+            &defaults,
+            "DEFAULT=defaults\nFROM_FILE=defaults\nCARGO_MANIFEST_DIR=defaults\n",
+        )?;
+        let environment = Environment {
+            keep: vec!["CARGO_*".into()],
+            files: vec![
+                EnvironmentFile::Path(file),
+                EnvironmentFile::Options {
+                    path: defaults,
+                    overrides: false,
+                },
+            ],
+            additional: hashmap! {"ADDITIONAL".into() => "additional".into()},
+            global: Some(GlobalEnvironment {
+                additional: hashmap! {
+                    "GLOBAL".into() => "global".into(),
+                    "HOME".into() => "global".into(),
+                },
+                timezone: None,
+                locale: None,
+                overrides: false,
+            }),
+            ..Default::default()
+        };
+        let environment: HashMap<String, String> = environment
+            .get_environment("user".into(), "/home/user".into())?
+            .into_iter()
+            .map(|kv| {
+                let (k, v) = kv.split_once('=').unwrap();
+                (k.to_string(), v.to_string())
+            })
+            .collect();
+        let get = |key: &str| environment.get(key).map(String::as_str);
+        // The global environment doesn't override the predefined variables, the files do.
+        assert_eq!(get("HOME"), Some("/home/user"));
+        assert_eq!(get("USER"), Some("file"));
+        assert_eq!(get("GLOBAL"), Some("file"));
+        assert_eq!(get("FROM_FILE"), Some("file"));
+        assert_eq!(get("DEFAULT"), Some("defaults"));
+        assert_eq!(get("ADDITIONAL"), Some("additional"));
+        assert_eq!(get("CARGO_MANIFEST_DIR"), Some(env!("CARGO_MANIFEST_DIR")));
+
+        let missing = Environment {
+            files: vec![EnvironmentFile::Path(tempdir.path().join("missing.env"))],
+            ..Default::default()
+        };
+        missing
+            .get_environment("user".into(), "/home/user".into())
+            .unwrap_err();
+
+        // Without overriding, the additional variables are only defaults.
+        let defaults = Environment {
+            files: vec![EnvironmentFile::Path(tempdir.path().join("service.env"))],
+            additional: hashmap! {
+                "FROM_FILE".into() => "additional".into(),
+                "DEFAULT".into() => "additional".into(),
+            },
+            additional_override: false,
+            ..Default::default()
+        };
+        let environment = defaults.get_environment("user".into(), "/home/user".into())?;
+        assert!(environment.contains(&"FROM_FILE=file".to_string()));
+        assert!(environment.contains(&"DEFAULT=additional".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timezone_and_locale() -> anyhow::Result<()> {
+        let global = GlobalEnvironment {
+            additional: hashmap! {"LANG".into() => "C.UTF-8".into()},
+            timezone: Some("UTC".into()),
+            locale: Some("en_US.UTF-8".into()),
+            overrides: true,
+        };
+        let get_environment =
+            |environment: Environment| -> anyhow::Result<HashMap<String, String>> {
+                Ok(environment
+                    .get_environment("user".into(), "/home/user".into())?
+                    .into_iter()
+                    .map(|kv| {
+                        let (k, v) = kv.split_once('=').unwrap();
+                        (k.to_string(), v.to_string())
+                    })
+                    .collect())
+            };
+        // The global additional variables win over the global settings.
+        let environment = get_environment(Environment {
+            global: Some(global.clone()),
+            ..Default::default()
+        })?;
+        assert_eq!(environment.get("TZ").map(String::as_str), Some("UTC"));
+        assert_eq!(environment.get("LANG").map(String::as_str), Some("C.UTF-8"));
+
+        // The service settings win over the global ones, its additional variables win over both.
+        let environment = get_environment(Environment {
+            timezone: Some("Europe/Rome".into()),
+            locale: Some("it_IT.UTF-8".into()),
+            additional: hashmap! {"TZ".into() => "America/New_York".into()},
+            global: Some(global),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            environment.get("TZ").map(String::as_str),
+            Some("America/New_York")
+        );
+        assert_eq!(
+            environment.get("LANG").map(String::as_str),
+            Some("it_IT.UTF-8")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_environment_file() {
+        let content = "# comment\n\nA=1\nexport B = \"two words\"\nC='x=y'\nD=\n";
+        let environment = parse_environment_file(content).unwrap();
+        assert_eq!(
+            environment,
+            hashmap! {
+                "A".to_string() => "1".to_string(),
+                "B".to_string() => "two words".to_string(),
+                "C".to_string() => "x=y".to_string(),
+                "D".to_string() => "".to_string(),
+            }
+        );
+        parse_environment_file("NO_VALUE").unwrap_err();
+        parse_environment_file("A B=1").unwrap_err();
+    }
+
+    #[test]
+    fn test_source_shell_script() {
+        let content = r#"# /etc/profile.d/app.sh
+export APP_HOME=/opt/app
+export PATH="$PATH:${APP_HOME}/bin"
+LANG=C.UTF-8 # comment
+QUOTED='$APP_HOME "x"'; 
+ESCAPED="\$HOME \d" MISSING="[$UNSET]"
+if [ -d "$APP_HOME/lib" ]; then
+    export LD_LIBRARY_PATH=$APP_HOME/lib
+fi
+VERSION=$(cat /opt/app/VERSION)
+DEFAULT=${LEVEL:-info}
+"#;
+        let mut environment = hashmap! { "PATH".to_string() => "/usr/bin".to_string() };
+        source_shell_script(content, &mut environment);
+        assert_eq!(
+            environment,
+            hashmap! {
+                "PATH".to_string() => "/usr/bin:/opt/app/bin".to_string(),
+                "APP_HOME".to_string() => "/opt/app".to_string(),
+                "LANG".to_string() => "C.UTF-8".to_string(),
+                "QUOTED".to_string() => "$APP_HOME \"x\"".to_string(),
+                // Not run, so regardless of the condition.
+                "LD_LIBRARY_PATH".to_string() => "/opt/app/lib".to_string(),
+            }
+        );
+        let mut environment = HashMap::new();
+        source_shell_script(r#"A="\$HOME \d" B="[$UNSET]""#, &mut environment);
+        assert!(environment.is_empty());
+        source_shell_script(r#"A="\$HOME \d[$UNSET]""#, &mut environment);
+        assert_eq!(environment["A"], r"$HOME \d[]");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("HTTP_PROXY", "HTTP_PROXY"));
+        assert!(!glob_match("HTTP_PROXY", "HTTPS_PROXY"));
+        assert!(glob_match("AWS_*", "AWS_"));
+        assert!(glob_match("AWS_*", "AWS_SECRET_ACCESS_KEY"));
+        assert!(!glob_match("AWS_*", "MY_AWS_KEY"));
+        assert!(glob_match("*_PROXY", "NO_PROXY"));
+        assert!(glob_match("*_A*_*", "X_AB_AC_D"));
+        assert!(glob_match("LC_???", "LC_ALL"));
+        assert!(!glob_match("LC_???", "LC_CTYPE"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("", "A"));
+    }
+
+    #[test]
+    fn test_hostname() {
+        let mut service = Service::from_name("worker@3.toml");
+        assert_eq!(service.hostname(), None);
+        service.sandbox.hostname = Some("%n-%i.%%".into());
+        assert_eq!(service.hostname().unwrap(), "worker@3-3.%");
+        service.sandbox.hostname = Some("worker-%i".into());
+        assert_eq!(service.hostname().unwrap(), "worker-3");
+        let environment = service.get_environment().unwrap();
+        assert!(environment.contains(&"HOSTNAME=worker-3".to_string()));
+        assert!(environment.contains(&"HORUST_INSTANCE=3".to_string()));
+        validate(vec![service.clone()]).unwrap();
+
+        service.sandbox.hostname = Some("%n".into());
+        let error = validate(vec![service]).unwrap_err().to_string();
+        assert!(error.contains("invalid hostname: 'worker@3'"), "{}", error);
+    }
+
+    #[test]
+    fn test_listen_address() {
+        let address: ListenAddress = "[::1]:8080".parse().unwrap();
+        assert_eq!(address, ListenAddress::Tcp("[::1]:8080".parse().unwrap()));
+        let address: ListenAddress = "/run/admin.sock".parse().unwrap();
+        assert_eq!(address, ListenAddress::Unix("/run/admin.sock".into()));
+        assert_eq!(address.to_string(), "/run/admin.sock");
+        assert!("localhost:8080".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn test_device_rule() {
+        let rule: DeviceRule = "/dev/net/tun rw".parse().unwrap();
+        assert_eq!(
+            rule,
+            DeviceRule {
+                path: "/dev/net/tun".into(),
+                read: true,
+                write: true,
+                mknod: false,
+            }
+        );
+        let rule: DeviceRule = "/dev/fuse".parse().unwrap();
+        assert_eq!(rule.to_string(), "/dev/fuse rwm");
+        assert!("/dev/fuse rx".parse::<DeviceRule>().is_err());
+        assert!("fuse rw".parse::<DeviceRule>().is_err());
+        assert!("/dev/fuse r w".parse::<DeviceRule>().is_err());
+    }
+
+    #[test]
+    fn test_lint() {
+        let warnings = |services: &[Service]| -> Vec<String> {
+            lint(services).iter().map(ToString::to_string).collect()
+        };
+        // Service depends on itself:
+        let services = vec![Service::start_after("a", vec!["a"])];
+        let services = validate(services).unwrap();
+        assert_eq!(
+            warnings(&services),
+            vec!["Service 'a' should start after itself, so it would never start."]
+        );
+
+        // Not started automatically, and nothing starts after it:
+        let mut maintenance = Service::from_name("maintenance");
+        maintenance.auto_start = false;
+        let mut lazy = Service::from_name("admin");
+        lazy.socket.lazy = true;
+        assert_eq!(
+            warnings(&[maintenance.clone(), lazy]),
+            vec![
+                "Service 'maintenance' isn't started automatically (auto-start is off, or it's lazy), and no service starts after it: it's only started on demand.",
+                "Service 'admin' isn't started automatically (auto-start is off, or it's lazy), and no service starts after it: it's only started on demand.",
+            ]
+        );
+
+        let services = vec![maintenance, Service::start_after("a", vec!["maintenance"])];
+        assert!(lint(&services).is_empty());
+    }
+
     #[test]
     fn test_validate() {
         // Service does not exists:
         let services = vec![Service::start_after("a", vec!["b"])];
         validate(services).unwrap_err();
 
+        // Names differ only in case:
+        let services = vec![
+            Service::from_name("Database"),
+            Service::start_after("a", vec!["database"]),
+        ];
+        let error = validate(services).unwrap_err().to_string();
+        assert!(error.contains("did you mean 'Database'?"), "{}", error);
+
         // Command is empty:
         let services = vec![Service::from_command("".into())];
         validate(services).unwrap_err();
 
+        // Lazy, without a socket:
+        let mut lazy = Service::from_name("admin");
+        lazy.socket.lazy = true;
+        let error = validate(vec![lazy]).unwrap_err().to_string();
+        assert!(error.contains("'admin' is lazy"), "{}", error);
+
+        // Remain after exit, but not a oneshot:
+        let mut remaining = Service::from_name("setup");
+        remaining.remain_after_exit = true;
+        let error = validate(vec![remaining]).unwrap_err().to_string();
+        assert!(error.contains("'setup' has remain-after-exit"), "{}", error);
+
+        // Variants, all of them auto-started:
+        let variant = |name: &str, auto_start: bool| {
+            let mut variant = Service::from_name(name);
+            variant.variant = true;
+            variant.auto_start = auto_start;
+            variant
+        };
+        let services = vec![
+            variant("app@blue.toml", true),
+            variant("app@green.toml", true),
+        ];
+        let error = validate(services).unwrap_err().to_string();
+        assert!(
+            error.contains("variant of 'app' is auto-started"),
+            "{}",
+            error
+        );
+        let error = validate(vec![variant("app.toml", false)])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'app.toml' is a variant"), "{}", error);
+        let services = vec![
+            variant("app@blue.toml", true),
+            variant("app@green.toml", false),
+        ];
+        validate(services).expect("Validation failed");
+
+        // Scaled templates:
+        let scaled = |name: &str, instances: usize| {
+            let mut service = Service::from_name(name);
+            service.scale = Some(Scale { instances, max: 2 });
+            service
+        };
+        let error = validate(vec![scaled("worker.toml", 1)])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'worker.toml' has a scale"), "{}", error);
+        let services = vec![scaled("worker@1.toml", 3), scaled("worker@2.toml", 3)];
+        let error = validate(services).unwrap_err().to_string();
+        assert_eq!(error.matches("'worker' starts more instances").count(), 1);
+        let services = vec![scaled("worker@1.toml", 2), scaled("worker@2.toml", 2)];
+        validate(services).expect("Validation failed");
+        let mut listed = Service::from_name("worker.toml");
+        listed.instances.insert("a".into(), Default::default());
+        let error = validate(vec![listed]).unwrap_err().to_string();
+        assert!(error.contains("'worker.toml' has instances"), "{}", error);
+
         // Should pass validation:
         let services = vec![
             Service::from_name("b"),
             Service::start_after("a", vec!["b"]),
         ];
         validate(services).expect("Validation failed");
+
+        // Dependency on a provided name:
+        let mut provider = Service::from_name("c");
+        provider.provides = vec!["b".into()];
+        let services = vec![provider, Service::start_after("a", vec!["b"])];
+        validate(services).expect("Validation failed");
+    }
+    #[test]
+    fn test_check_commands() {
+        let dir = TempDir::new("check-commands").unwrap();
+        let script = |name: &str, content: &str, mode: u32| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+        script("ok.sh", "#!/bin/sh\ntrue\n", 0o755);
+        script("not-executable.sh", "#!/bin/sh\ntrue\n", 0o644);
+        script("no-interpreter.sh", "#!/missing/sh -e\ntrue\n", 0o755);
+        script("env.sh", "#!/usr/bin/env -S sh -e\ntrue\n", 0o755);
+        script("env-missing.sh", "#!/usr/bin/env missing-sh\ntrue\n", 0o755);
+        let service = |command: &str| {
+            let mut service = Service::from_name("a");
+            service.command = command.into();
+            service.working_directory = dir.path().into();
+            service
+        };
+        let error = |command: &str| check_commands(&[service(command)]).unwrap_err().to_string();
+
+        check_commands(&[
+            service("sh -c true"),
+            service("./ok.sh --flag"),
+            service("./env.sh"),
+        ])
+        .unwrap();
+        assert!(error("missing-program").contains("missing-program not found"));
+        assert!(error("/missing/program").contains("/missing/program: No such file"));
+        assert!(error("./not-executable.sh").contains("not-executable.sh is not executable"));
+        assert!(error("./no-interpreter.sh").contains("the interpreter of"));
+        assert!(error("./env-missing.sh").contains("missing-sh not found"));
+        assert!(error("sh -c 'true").contains("its quoting is invalid"));
+
+        let mut lax = service("missing-program");
+        lax.command_check = CommandCheck::Warn;
+        let mut disabled = service("missing-program");
+        disabled.enabled = false;
+        check_commands(&[lax, disabled]).unwrap();
     }
 }
@@ -0,0 +1,190 @@
+//! Overrides of single fields of the services, given on the command line as
+//! `<service>.<key>=<value>`, e.g. `web.restart.strategy=never`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use toml::{Table, Value};
+
+/// The options of the environment section: any other key is an additional variable, so that
+/// `web.environment.PORT=9090` is short for `web.environment.additional.PORT=9090`.
+const ENVIRONMENT_OPTIONS: [&str; 12] = [
+    "keep-env",
+    "re-export",
+    "keep",
+    "source",
+    "files",
+    "additional",
+    "additional-override",
+    "timezone",
+    "locale",
+    "from-command",
+    "from-command-timeout",
+    "from-provider",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceOverride {
+    /// The name of the service, followed by the dotted path of the key.
+    path: String,
+    value: String,
+}
+
+impl ServiceOverride {
+    /// True if this overrides a field of the service named `service_name`.
+    pub fn targets(&self, service_name: &str) -> bool {
+        self.key(service_name).is_some()
+    }
+
+    /// The path of the key in the service, if it targets it. The service can be referred to with
+    /// or without the `.toml` suffix.
+    fn key(&self, service_name: &str) -> Option<&str> {
+        let stem = service_name.strip_suffix(".toml").unwrap_or(service_name);
+        [service_name, stem]
+            .into_iter()
+            .find_map(|name| self.path.strip_prefix(name)?.strip_prefix('.'))
+            .filter(|key| !key.is_empty())
+    }
+
+    /// Sets the value in the content of the service file, if the override targets it.
+    pub(crate) fn apply(&self, service_name: &str, service: &mut Table) -> Result<()> {
+        let Some(key) = self.key(service_name) else {
+            return Ok(());
+        };
+        let mut keys: Vec<&str> = key.split('.').collect();
+        if keys.len() == 2 && keys[0] == "environment" && !ENVIRONMENT_OPTIONS.contains(&keys[1]) {
+            keys.insert(1, "additional");
+        }
+        let value = if keys.starts_with(&["environment", "additional"]) {
+            // Environment variables are always strings, e.g. `PORT=9090`.
+            Value::String(self.value.clone())
+        } else {
+            parse_value(&self.value)
+        };
+        let (last, parents) = keys.split_last().unwrap();
+        let mut table = service;
+        for parent in parents {
+            table = table
+                .entry(parent.to_string())
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .with_context(|| format!("Can't override {}: {} is not a section", self, parent))?;
+        }
+        table.insert(last.to_string(), value);
+        Ok(())
+    }
+}
+
+/// The value as TOML, e.g. a number or a boolean. Otherwise, it's a string.
+fn parse_value(value: &str) -> Value {
+    format!("value = {}", value)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+impl FromStr for ServiceOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((path, value)) = s.split_once('=') else {
+            bail!("Invalid override: {}, expected <service>.<key>=<value>", s);
+        };
+        if !path.contains('.') {
+            bail!("Invalid override: {}, expected <service>.<key>=<value>", s);
+        }
+        Ok(Self {
+            path: path.trim().to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ServiceOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.path, self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use toml::{Table, Value};
+
+    use crate::horust::formats::service_override::ENVIRONMENT_OPTIONS;
+    use crate::horust::formats::{Environment, ServiceOverride};
+
+    #[test]
+    fn test_environment_options() {
+        // All the fields of the section, so that none of them is taken for a variable.
+        let environment = Environment {
+            timezone: Some("UTC".into()),
+            locale: Some("C".into()),
+            ..Default::default()
+        };
+        let fields: BTreeSet<String> = Table::try_from(environment)
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        let options: BTreeSet<String> = ENVIRONMENT_OPTIONS.iter().map(|o| o.to_string()).collect();
+        assert_eq!(fields, options);
+    }
+
+    #[test]
+    fn test_service_override() {
+        let mut service: Table = "command = \"/bin/web\"\n[restart]\nattempts = 1"
+            .parse()
+            .unwrap();
+        let apply = |service: &mut Table, name: &str, service_override: &str| {
+            service_override
+                .parse::<ServiceOverride>()
+                .unwrap()
+                .apply(name, service)
+        };
+        apply(&mut service, "web.toml", "web.restart.strategy=never").unwrap();
+        apply(&mut service, "web.toml", "web.toml.restart.attempts=3").unwrap();
+        apply(&mut service, "web.toml", "web.environment.PORT=9090").unwrap();
+        apply(&mut service, "web.toml", "web.environment.keep-env=true").unwrap();
+        apply(
+            &mut service,
+            "web.toml",
+            "web.environment.source=/etc/profile",
+        )
+        .unwrap();
+        apply(
+            &mut service,
+            "web.toml",
+            r#"web.environment.from-provider={ command = "vault read db", var = "DB_PASS" }"#,
+        )
+        .unwrap();
+        apply(&mut service, "web.toml", "web.command=/bin/web --debug").unwrap();
+        // Not the target of the override.
+        apply(&mut service, "api.toml", "web.enabled=false").unwrap();
+        let expected: Table = r#"
+command = "/bin/web --debug"
+[restart]
+attempts = 3
+strategy = "never"
+[environment]
+additional = { PORT = "9090" }
+keep-env = true
+source = "/etc/profile"
+from-provider = { command = "vault read db", var = "DB_PASS" }
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(service, expected);
+
+        apply(&mut service, "web.toml", "web.command.path=/bin/web").unwrap_err();
+        assert!("web=1".parse::<ServiceOverride>().is_err());
+        assert!("web.enabled".parse::<ServiceOverride>().is_err());
+        let service_override: ServiceOverride = "web.enabled=false".parse().unwrap();
+        assert!(service_override.targets("web.toml"));
+        assert!(!service_override.targets("webapp.toml"));
+        assert_eq!(super::parse_value("1.5"), Value::Float(1.5));
+    }
+}
@@ -0,0 +1,453 @@
+//! Conversion of Horust services into systemd `.service` unit files.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::horust::formats::{
+    KillMode, LogOutput, RestartStrategy, Service, ServiceType, TerminationSignal, User,
+};
+
+/// Name of the systemd unit generated for `service_name`, e.g. `database.toml` => `database.service`.
+pub fn systemd_unit_name(service_name: &str) -> String {
+    let name = service_name.strip_suffix(".toml").unwrap_or(service_name);
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || ":-_.\\@".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.service", name)
+}
+
+/// Renders `service` as the content of a systemd `.service` unit.
+pub fn to_systemd_unit(service: &Service) -> String {
+    let mut unit = format!("# Generated by horust from service: {}\n", service.name);
+
+    unit.push_str("[Unit]\n");
+    let description = match &service.description {
+        Some(description) => description.clone(),
+        None => format!("Horust service {}", service.name),
+    };
+    entry(&mut unit, "Description", &literal(&description));
+    // TCP endpoints in `start_after` have no equivalent, and are dropped.
+    let start_after = service.start_after_services();
+    if !start_after.is_empty() {
+        let after = start_after
+            .iter()
+            .map(|name| systemd_unit_name(name))
+            .collect::<Vec<_>>()
+            .join(" ");
+        entry(&mut unit, "Wants", &after);
+        entry(&mut unit, "After", &after);
+    }
+
+    unit.push_str("\n[Service]\n");
+    match service.service_type {
+        ServiceType::Simple => (),
+        ServiceType::Oneshot => {
+            entry(&mut unit, "Type", "oneshot");
+            if service.remain_after_exit {
+                entry(&mut unit, "RemainAfterExit", "yes");
+            }
+        }
+        ServiceType::Notify => entry(&mut unit, "Type", "notify"),
+    }
+    if let Some(start_timeout) = service.start_timeout {
+        entry(
+            &mut unit,
+            "TimeoutStartSec",
+            &format!("{}ms", start_timeout.as_millis()),
+        );
+    }
+    // Unlike systemd, Horust runs the command as it is: its variables were expanded when loaded.
+    entry(
+        &mut unit,
+        "ExecStart",
+        &literal(&service.command).replace('$', "$$"),
+    );
+    // systemd has no equivalent of `watch`, a `.path` unit would be needed.
+    if let Some(reload_signal) = service.reload_signal {
+        entry(
+            &mut unit,
+            "ExecReload",
+            &format!("/bin/kill -{:?} $MAINPID", reload_signal),
+        );
+    }
+    if service.has_dynamic_user() {
+        entry(&mut unit, "DynamicUser", "yes");
+    } else if service.user != User::default() {
+        match &service.user {
+            User::Name(name) => entry(&mut unit, "User", &literal(name)),
+            User::Uid(uid) => entry(&mut unit, "User", &uid.to_string()),
+        }
+    }
+    entry(
+        &mut unit,
+        "WorkingDirectory",
+        &literal(&service.working_directory.display().to_string()),
+    );
+    let mut environment = service.environment.additional.iter().collect::<Vec<_>>();
+    environment.sort();
+    for (key, value) in environment {
+        entry(
+            &mut unit,
+            "Environment",
+            &quote(&literal(&format!("{}={}", key, value))),
+        );
+    }
+    // Unlike in Horust, the variables of the files always override the previous ones.
+    for file in &service.environment.files {
+        entry(
+            &mut unit,
+            "EnvironmentFile",
+            &literal(&file.path().display().to_string()),
+        );
+    }
+    // systemd doesn't support patterns in `PassEnvironment`.
+    let pass_environment = service
+        .environment
+        .re_export
+        .iter()
+        .chain(
+            service
+                .environment
+                .keep
+                .iter()
+                .filter(|key| !key.contains(['*', '?'])),
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+    if !pass_environment.is_empty() {
+        entry(&mut unit, "PassEnvironment", &pass_environment.join(" "));
+    }
+    if let Some(output) = standard_output(&service.stdout) {
+        entry(&mut unit, "StandardOutput", &output);
+    }
+    if let Some(output) = standard_output(&service.stderr) {
+        entry(&mut unit, "StandardError", &output);
+    }
+    let restart = match service.restart.strategy {
+        RestartStrategy::Always => "always",
+        RestartStrategy::OnFailure => "on-failure",
+        RestartStrategy::Never => "no",
+    };
+    entry(&mut unit, "Restart", restart);
+    if !service.restart.backoff.is_zero() {
+        entry(
+            &mut unit,
+            "RestartSec",
+            &format!("{}ms", service.restart.backoff.as_millis()),
+        );
+    }
+    // Zero is always considered successful by systemd.
+    let success_codes = service
+        .failure
+        .successful_exit_code
+        .iter()
+        .filter(|code| **code != 0)
+        .map(|code| code.to_string())
+        .collect::<Vec<_>>();
+    if !success_codes.is_empty() {
+        entry(&mut unit, "SuccessExitStatus", &success_codes.join(" "));
+    }
+    if service.termination.signal != TerminationSignal::TERM {
+        entry(
+            &mut unit,
+            "KillSignal",
+            &format!("SIG{:?}", service.termination.signal),
+        );
+    }
+    // systemd always sends SIGCONT after the termination signal, and it can't signal only the
+    // process group.
+    let kill_mode = match service.termination.kill_mode {
+        KillMode::Process => "process",
+        KillMode::ProcessGroup | KillMode::Cgroup => "control-group",
+    };
+    entry(&mut unit, "KillMode", kill_mode);
+    entry(
+        &mut unit,
+        "TimeoutStopSec",
+        &format!("{}ms", service.termination.wait.as_millis()),
+    );
+    if service.sandbox.protect_system {
+        let paths = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|path| literal(&path.display().to_string()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        entry(
+            &mut unit,
+            "ReadOnlyPaths",
+            &paths(&service.sandbox.read_only_paths),
+        );
+        if !service.sandbox.read_write_paths.is_empty() {
+            entry(
+                &mut unit,
+                "ReadWritePaths",
+                &paths(&service.sandbox.read_write_paths),
+            );
+        }
+    }
+    if service.sandbox.pid_namespace {
+        entry(&mut unit, "PrivatePIDs", "yes");
+    }
+    for io_max in &service.resources.io_max {
+        let device = io_max.device.display();
+        let limits = [
+            ("IOReadBandwidthMax", io_max.rbps),
+            ("IOWriteBandwidthMax", io_max.wbps),
+            ("IOReadIOPSMax", io_max.riops),
+            ("IOWriteIOPSMax", io_max.wiops),
+        ];
+        for (key, limit) in limits {
+            if let Some(limit) = limit {
+                entry(&mut unit, key, &format!("{} {}", device, limit));
+            }
+        }
+    }
+    if let Some(memory_max) = service.resources.memory_max {
+        entry(&mut unit, "MemoryMax", &memory_max.to_string());
+    }
+    if let Some(memory_swap_max) = service.resources.memory_swap_max {
+        entry(&mut unit, "MemorySwapMax", &memory_swap_max.to_string());
+    }
+    if let Some(cpus) = service.resources.cpu_max {
+        // In percentage of a CPU.
+        entry(&mut unit, "CPUQuota", &format!("{}%", cpus.0 as f64 / 10.0));
+        entry(
+            &mut unit,
+            "CPUQuotaPeriodSec",
+            &format!("{}ms", service.resources.cpu_period.as_millis()),
+        );
+    }
+    if let Some(cpuset_cpus) = &service.resources.cpuset_cpus {
+        entry(&mut unit, "AllowedCPUs", cpuset_cpus);
+    }
+    if !service.resources.devices.is_empty() {
+        entry(&mut unit, "DevicePolicy", "closed");
+        for device in &service.resources.devices {
+            entry(&mut unit, "DeviceAllow", &device.to_string());
+        }
+    }
+    if let Some(core_dumps) = &service.core_dumps {
+        let limit = core_dumps
+            .limit()
+            .map_or_else(|| "infinity".to_string(), |limit| limit.to_string());
+        entry(&mut unit, "LimitCORE", &limit);
+    }
+    if let Some(cpu_time) = service.limits.cpu_time {
+        entry(
+            &mut unit,
+            "LimitCPU",
+            &cpu_time.as_secs().max(1).to_string(),
+        );
+    }
+    if let Some(wall_time) = service.limits.wall_time {
+        entry(
+            &mut unit,
+            "RuntimeMaxSec",
+            &format!("{}ms", wall_time.as_millis()),
+        );
+    }
+
+    // Otherwise the unit is only started on demand, or by the units which want it.
+    if service.auto_start {
+        unit.push_str("\n[Install]\n");
+        entry(&mut unit, "WantedBy", "multi-user.target");
+    }
+    unit
+}
+
+fn entry(unit: &mut String, key: &str, value: &str) {
+    writeln!(unit, "{}={}", key, value).unwrap();
+}
+
+/// Escapes the `%` of a value taken from the service: systemd would take it for a specifier.
+fn literal(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// Maps a Horust log output into a value for `StandardOutput`/`StandardError`.
+fn standard_output(output: &LogOutput) -> Option<String> {
+    match output {
+        LogOutput::Stdout | LogOutput::Stderr | LogOutput::Pipe(_) => None,
+        // systemd can't write to both the journal and a file.
+        LogOutput::Path(path) | LogOutput::Tee(path) => {
+            Some(format!("append:{}", literal(&path.display().to_string())))
+        }
+    }
+}
+
+/// Quotes a value using the systemd unit file syntax.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::horust::formats::systemd_unit::{systemd_unit_name, to_systemd_unit};
+    use crate::horust::formats::{Service, ServiceType, TerminationSignal};
+
+    #[test]
+    fn test_systemd_unit_name() {
+        assert_eq!(systemd_unit_name("database.toml"), "database.service");
+        assert_eq!(systemd_unit_name("my service"), "my_service.service");
+        assert_eq!(systemd_unit_name("worker@1"), "worker@1.service");
+    }
+
+    #[test]
+    fn test_to_systemd_unit() {
+        let service: Service = toml::from_str(
+            r#"name = "backend.toml"
+command = "/usr/bin/backend --port 8080"
+start-after = ["database.toml"]
+user = "nobody"
+working-directory = "/srv"
+stdout = "/var/log/backend.log"
+[restart]
+strategy = "on-failure"
+backoff = "2s"
+[environment]
+re-export = ["PATH"]
+files = ["/etc/backend.env"]
+additional = { MODE = "prod \"eu\"" }
+[failure]
+successful-exit-code = [0, 3]
+strategy = "ignore"
+[termination]
+signal = "INT"
+wait = "10s"
+kill-mode = "cgroup"
+[sandbox]
+protect-system = true
+read-write-paths = ["/etc/backend"]
+pid-namespace = true
+[resources]
+io-max = [{ device = "/dev/sda", rbps = "10 MB", wiops = 100 }]
+memory-max = "1 GB"
+cpu-max = 0.5
+cpuset-cpus = "2-3"
+devices = ["/dev/net/tun rw"]
+[limits]
+cpu-time = "5m"
+wall-time = "1h"
+"#,
+        )
+        .unwrap();
+        let unit = to_systemd_unit(&service);
+        let expected = [
+            "Description=Horust service backend.toml",
+            "Wants=database.service",
+            "After=database.service",
+            "ExecStart=/usr/bin/backend --port 8080",
+            "User=nobody",
+            "WorkingDirectory=/srv",
+            r#"Environment="MODE=prod \"eu\"""#,
+            "EnvironmentFile=/etc/backend.env",
+            "PassEnvironment=PATH",
+            "StandardOutput=append:/var/log/backend.log",
+            "Restart=on-failure",
+            "RestartSec=2000ms",
+            "SuccessExitStatus=3",
+            "KillSignal=SIGINT",
+            "KillMode=control-group",
+            "TimeoutStopSec=10000ms",
+            "ReadOnlyPaths=/usr /etc",
+            "ReadWritePaths=/etc/backend",
+            "PrivatePIDs=yes",
+            "IOReadBandwidthMax=/dev/sda 10000000",
+            "IOWriteIOPSMax=/dev/sda 100",
+            "MemoryMax=1000000000",
+            "CPUQuota=50%",
+            "CPUQuotaPeriodSec=100ms",
+            "AllowedCPUs=2-3",
+            "DevicePolicy=closed",
+            "DeviceAllow=/dev/net/tun rw",
+            "LimitCPU=300",
+            "RuntimeMaxSec=3600000ms",
+        ];
+        for line in expected {
+            assert!(
+                unit.lines().any(|l| l == line),
+                "{} not in:\n{}",
+                line,
+                unit
+            );
+        }
+        assert!(!unit.contains("StandardError"));
+        assert!(unit.contains("WantedBy=multi-user.target"));
+
+        let described = Service {
+            description: Some("The backend API".into()),
+            ..service.clone()
+        };
+        assert!(to_systemd_unit(&described).contains("\nDescription=The backend API\n"));
+
+        let manual = Service {
+            auto_start: false,
+            ..service
+        };
+        assert!(!to_systemd_unit(&manual).contains("[Install]"));
+
+        let job = Service {
+            service_type: ServiceType::Oneshot,
+            remain_after_exit: true,
+            ..manual
+        };
+        let unit = to_systemd_unit(&job);
+        assert!(unit.contains("Type=oneshot\nRemainAfterExit=yes\n"));
+
+        let notify = Service {
+            service_type: ServiceType::Notify,
+            remain_after_exit: false,
+            start_timeout: Some(Duration::from_secs(30)),
+            ..job
+        };
+        let unit = to_systemd_unit(&notify);
+        assert!(unit.contains("Type=notify\nTimeoutStartSec=30000ms\n"));
+
+        let reloaded = Service {
+            reload_signal: Some(TerminationSignal::HUP),
+            ..notify
+        };
+        let unit = to_systemd_unit(&reloaded);
+        assert!(unit.contains("ExecReload=/bin/kill -HUP $MAINPID\n"));
+    }
+
+    #[test]
+    fn test_to_systemd_unit_escaping() {
+        let service: Service = toml::from_str(
+            r#"name = "report.toml"
+command = "/bin/sh -c 'date +%Y-%m-%d; echo $HOME $$'"
+description = "100% of the reports"
+working-directory = "/srv/100%"
+[environment]
+additional = { FORMAT = '%H:%M $USER \ "quoted"' }
+"#,
+        )
+        .unwrap();
+        let unit = to_systemd_unit(&service);
+        let expected = [
+            "Description=100%% of the reports",
+            "ExecStart=/bin/sh -c 'date +%%Y-%%m-%%d; echo $$HOME $$$$'",
+            "WorkingDirectory=/srv/100%%",
+            r#"Environment="FORMAT=%%H:%%M $USER \\ \"quoted\"""#,
+        ];
+        for line in expected {
+            assert!(
+                unit.lines().any(|l| l == line),
+                "{} not in:\n{}",
+                line,
+                unit
+            );
+        }
+    }
+}
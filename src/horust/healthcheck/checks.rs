@@ -1,39 +1,47 @@
+use std::net::ToSocketAddrs;
+use std::process::Stdio;
 use std::time::Duration;
 
+use anyhow::{bail, Context};
 #[cfg(feature = "http-healthcheck")]
 use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
 
-use crate::horust::formats::Healthiness;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Healthiness, Service};
+use crate::horust::subprocess;
 
 const FILE_CHECK: FilePathCheck = FilePathCheck {};
 const HTTP_CHECK: HttpCheck = HttpCheck {};
-const CHECKS: [&dyn Check; 2] = [&FILE_CHECK, &HTTP_CHECK];
+const DNS_CHECK: DnsCheck = DnsCheck {};
+const CHECKS: [&dyn Check; 3] = [&FILE_CHECK, &HTTP_CHECK, &DNS_CHECK];
 
-pub(crate) fn get_checks() -> [&'static dyn Check; 2] {
+pub(crate) fn get_checks() -> [&'static dyn Check; 3] {
     CHECKS
 }
 
 pub(crate) trait Check {
-    fn run(&self, healthiness: &Healthiness) -> bool;
+    /// On failure, returns what the check has reported.
+    fn run(&self, healthiness: &Healthiness) -> Result<(), String>;
     fn prepare(&self, _healtiness: &Healthiness) -> Result<(), std::io::Error> {
         Ok(())
     }
 }
 
 /// HTTP based healthcheck: will send an head request with 1 second timeout, and the test will be
-/// considered failed if the repsonse is anything other than `200`.
+/// considered failed if the repsonse is anything other than `200`. Reports the status, or the error.
 pub(crate) struct HttpCheck;
 
 static HTTP_REQUEST_TIMEOUT: u64 = 1;
 
 impl Check for HttpCheck {
-    fn run(&self, healthiness: &Healthiness) -> bool {
+    fn run(&self, healthiness: &Healthiness) -> Result<(), String> {
         healthiness
             .http_endpoint.as_ref()
             .map(|endpoint| {
                 if cfg!(not(feature = "http-healthcheck")) {
                     error!("There is an http based healthcheck, but horust was built without the http-healthcheck feature (thus it will never pass these checks).");
-                    return false;
+                    return Err("http-endpoint: horust was built without the http-healthcheck feature".into());
                 }
                 #[cfg(feature = "http-healthcheck")]
                     {
@@ -41,22 +49,27 @@ impl Check for HttpCheck {
                             .timeout(Duration::from_secs(HTTP_REQUEST_TIMEOUT))
                             .build().expect("Http client");
                         let resp: Result<reqwest::blocking::Response, reqwest::Error> = client.head(endpoint).send();
-                        resp.map(|resp| resp.status().is_success()).unwrap_or(false)
+                        match resp {
+                            Ok(resp) if resp.status().is_success() => Ok(()),
+                            Ok(resp) => Err(format!("http-endpoint: {} answered {}", endpoint, resp.status())),
+                            Err(error) => Err(format!("http-endpoint: {}", error)),
+                        }
                     }
             })
-            .unwrap_or(true)
+            .unwrap_or(Ok(()))
     }
 }
 
 pub(crate) struct FilePathCheck;
 
 impl Check for FilePathCheck {
-    fn run(&self, healthiness: &Healthiness) -> bool {
-        healthiness
-            .file_path
-            .as_ref()
-            .map(|file_path| file_path.exists())
-            .unwrap_or(true)
+    fn run(&self, healthiness: &Healthiness) -> Result<(), String> {
+        match &healthiness.file_path {
+            Some(file_path) if !file_path.exists() => {
+                Err(format!("file-path: {} doesn't exist", file_path.display()))
+            }
+            _ => Ok(()),
+        }
     }
     fn prepare(&self, healthiness: &Healthiness) -> Result<(), std::io::Error> {
         //TODO: check if user has permissions to remove the file.
@@ -68,3 +81,86 @@ impl Check for FilePathCheck {
             .unwrap_or(Ok(()))
     }
 }
+
+/// DNS based healthcheck: the host name has to be resolvable (with the system's resolver), and
+/// to resolve to the expected address if there is one.
+pub(crate) struct DnsCheck;
+
+impl Check for DnsCheck {
+    fn run(&self, healthiness: &Healthiness) -> Result<(), String> {
+        let Some(dns_name) = &healthiness.dns_name else {
+            return Ok(());
+        };
+        let addrs: Vec<_> = (dns_name.as_str(), 0)
+            .to_socket_addrs()
+            .map_err(|error| format!("dns-name: cannot resolve {}: {}", dns_name, error))?
+            .map(|addr| addr.ip())
+            .collect();
+        match healthiness.dns_address {
+            Some(expected) if !addrs.contains(&expected) => Err(format!(
+                "dns-name: {} resolves to {:?}, not to {}",
+                dns_name, addrs, expected
+            )),
+            None if addrs.is_empty() => Err(format!("dns-name: {} has no address", dns_name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The version of the protocol of the external checks, sent in their request.
+const EXTERNAL_PROTOCOL_VERSION: u32 = 1;
+
+/// Written as JSON on the stdin of the external check.
+#[derive(Serialize)]
+struct ExternalRequest<'a> {
+    version: u32,
+    service: &'a str,
+}
+
+/// Expected as JSON on the stdout of the external check.
+#[derive(Deserialize)]
+struct ExternalResponse {
+    healthy: bool,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// External healthcheck: the `external` executable is run in the context of the service, and tells
+/// whether it's healthy, e.g. by checking the external systems it depends on. Reports the message
+/// of the executable, or why it couldn't tell.
+pub(crate) fn run_external(service: &Service, clock: &dyn Clock) -> Result<(), String> {
+    let Some(command) = &service.healthiness.external else {
+        return Ok(());
+    };
+    match ask_external(service, command, clock) {
+        Ok(ExternalResponse { healthy: true, .. }) => Ok(()),
+        Ok(ExternalResponse { message, .. }) => Err(format!(
+            "external: {}",
+            message.as_deref().unwrap_or("unhealthy")
+        )),
+        Err(error) => Err(format!("external: {:#}", error)),
+    }
+}
+
+fn ask_external(
+    service: &Service,
+    command: &str,
+    clock: &dyn Clock,
+) -> anyhow::Result<ExternalResponse> {
+    let request = ExternalRequest {
+        version: EXTERNAL_PROTOCOL_VERSION,
+        service: &service.name,
+    };
+    let mut input = serde_json::to_vec(&request)?;
+    input.push(b'\n');
+    let mut cmd = subprocess::service_command(service, command)?;
+    cmd.stdout(Stdio::piped());
+    let timeout = service.healthiness.external_timeout;
+    let output = subprocess::run_with_input(&mut cmd, Some(input), timeout, clock)
+        .with_context(|| format!("failed running {}", command))?;
+    if !output.status.success() {
+        bail!("{} has failed: {}", command, output.status);
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("invalid response from {}", command))
+}
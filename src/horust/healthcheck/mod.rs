@@ -1,33 +1,47 @@
 //! If a service has defined an healthchecker, this module will spawn a worker to making sure that
 //! the service is working as supposed to.
 
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
-use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 
 use checks::*;
 
 use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
 use crate::horust::formats::{
-    Event, Healthiness, HealthinessStatus, Service, ServiceName, ServiceStatus,
+    Event, HealthcheckOutput, Healthiness, HealthinessStatus, Service, ServiceName, ServiceStatus,
+    ServiceType,
 };
 
 mod checks;
 
+/// How often the checks of a service are run.
+const CHECK_INTERVAL: Duration = Duration::from_millis(1000);
+
 struct Worker {
-    service: Service,
+    service: Arc<Service>,
     bus: BusConnector<Event>,
     work_done_notifier: Receiver<()>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Worker {
-    fn new(service: Service, bus: BusConnector<Event>, work_done_notifier: Receiver<()>) -> Self {
+    fn new(
+        service: Arc<Service>,
+        bus: BusConnector<Event>,
+        work_done_notifier: Receiver<()>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Worker {
             service,
             bus,
             work_done_notifier,
+            clock,
         }
     }
     pub fn spawn_thread(self) -> JoinHandle<()> {
@@ -35,18 +49,18 @@ impl Worker {
     }
     fn run(self) {
         loop {
-            let status = check_health(&self.service.healthiness);
+            let next_check = self.clock.after(CHECK_INTERVAL);
+            let output = check_health(&self.service, self.clock.as_ref());
             self.bus.send_event(Event::HealthCheck(
                 self.service.name.clone(),
-                status.clone(),
+                output.status(),
             ));
-            match self
-                .work_done_notifier
-                .recv_timeout(Duration::from_millis(1000))
-            {
-                Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
-                _ => (),
-            };
+            self.bus
+                .send_event(Event::HealthcheckOutput(self.service.name.clone(), output));
+            select! {
+                recv(self.work_done_notifier) -> _ => break,
+                recv(next_check) -> _ => (),
+            }
         }
     }
 }
@@ -54,37 +68,37 @@ impl Worker {
 // TODO:
 // * Tunable healthchecks timing in horust's config
 // * If there are no checks to run, just exit the thread. or go sleep until an "service created" event is received.
-pub fn spawn(bus: BusConnector<Event>, services: Vec<Service>) {
+pub(crate) fn spawn(bus: BusConnector<Event>, services: Vec<Arc<Service>>, clock: Arc<dyn Clock>) {
     thread::spawn(move || {
-        run(bus, services);
+        run(bus, services, clock);
     });
 }
 
-/// Returns true if the service is healthy and all checks are passed.
-fn check_health(healthiness: &Healthiness) -> HealthinessStatus {
-    get_checks()
+/// Runs all the checks: the service is healthy if all of them pass.
+fn check_health(service: &Service, clock: &dyn Clock) -> HealthcheckOutput {
+    let start = clock.now();
+    let errors = get_checks()
         .into_iter()
-        .all(|check| check.run(healthiness))
-        .into()
+        .filter_map(|check| check.run(&service.healthiness).err())
+        .chain(run_external(service, clock).err())
+        .collect();
+    HealthcheckOutput::new(errors, clock.now().saturating_duration_since(start))
 }
 
-fn run(bus: BusConnector<Event>, services: Vec<Service>) {
+fn run(bus: BusConnector<Event>, services: Vec<Arc<Service>>, clock: Arc<dyn Clock>) {
     let mut workers = hashmap! {};
-    let get_service = |s_name: &ServiceName| {
-        services
-            .iter()
-            .filter(|sh| sh.name == *s_name)
-            .take(1)
-            .cloned()
-            .collect::<Vec<Service>>()
-            .remove(0)
-    };
+    let mut services: HashMap<ServiceName, Arc<Service>> = services
+        .into_iter()
+        .map(|service| (service.name.clone(), service))
+        .collect();
 
     for ev in bus.iter() {
         match ev {
             Event::StatusChanged(s_name, ServiceStatus::Started) => {
-                let service = get_service(&s_name);
-                if !service.healthiness.has_any_check_defined() {
+                let service = services[&s_name].clone();
+                // The readiness of a oneshot is its successful exit.
+                let is_oneshot = service.service_type == ServiceType::Oneshot;
+                if is_oneshot || !service.healthiness.has_any_check_defined() {
                     bus.send_event(Event::HealthCheck(s_name, HealthinessStatus::Healthy));
                     continue;
                 }
@@ -92,16 +106,21 @@ fn run(bus: BusConnector<Event>, services: Vec<Service>) {
                     stop_worker(sender, handler)
                 }
                 let (worker_notifier, work_done_rcv) = unbounded();
-                let handle = Worker::new(service, bus.join_bus(), work_done_rcv).spawn_thread();
+                let handle = Worker::new(service, bus.join_bus(), work_done_rcv, clock.clone())
+                    .spawn_thread();
                 workers.insert(s_name, (worker_notifier, handle));
             }
-            Event::ServiceExited(s_name, _exit_code) => {
+            Event::ServiceExited(s_name, _exit_reason) => {
                 if let Some((sender, handler)) = workers.remove(&s_name) {
                     stop_worker(sender, handler)
                 } else {
                     warn!("Worker thread for {} not found.", s_name);
                 }
             }
+            // Used from the next start of the service.
+            Event::ServiceChanged(service) | Event::ServiceAdded(service) => {
+                services.insert(service.name.clone(), service);
+            }
             Event::ShuttingDownInitiated(_) => {
                 // Stop all the workers:
                 for (ws, _wh) in workers.values() {
@@ -143,18 +162,29 @@ pub fn prepare_service(healthiness: &Healthiness) -> Result<Vec<()>, std::io::Er
 mod test {
     use std::io::{Read, Write};
     use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
-    use std::sync::mpsc;
+    use std::sync::{mpsc, Arc};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     use anyhow::Result;
+    use crossbeam::channel::unbounded;
     use tempdir::TempDir;
 
-    use crate::horust::formats::{Healthiness, HealthinessStatus};
-    use crate::horust::healthcheck::check_health;
+    use crate::horust::bus::Bus;
+    use crate::horust::clock::{SimulatedClock, SystemClock};
+    use crate::horust::formats::{
+        Event, HealthcheckOutput, Healthiness, HealthinessStatus, Service,
+    };
+    use crate::horust::healthcheck::{check_health, Worker, CHECK_INTERVAL};
 
     fn check_health_w(healthiness: &Healthiness) -> bool {
-        check_health(healthiness) == HealthinessStatus::Healthy
+        check_health_output(healthiness).status() == HealthinessStatus::Healthy
+    }
+
+    fn check_health_output(healthiness: &Healthiness) -> HealthcheckOutput {
+        let mut service = Service::from_name("a");
+        service.healthiness = healthiness.clone();
+        check_health(&service, &SystemClock)
     }
 
     #[test]
@@ -167,6 +197,10 @@ mod test {
             ..Default::default()
         };
         assert!(!check_health_w(&healthiness));
+        assert_eq!(
+            check_health_output(&healthiness).output,
+            format!("file-path: {} doesn't exist", file_path.display())
+        );
         std::fs::write(file_path, "Hello world!")?;
         assert!(check_health_w(&healthiness));
         let healthiness: Healthiness = Default::default();
@@ -174,14 +208,87 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_worker_interval() -> Result<()> {
+        let tempdir = TempDir::new("health")?;
+        let mut service = Service::from_name("a");
+        service.healthiness.file_path = Some(tempdir.path().join("file.txt"));
+        let clock = Arc::new(SimulatedClock::new());
+        let bus = Bus::new();
+        let observer = bus.join_bus();
+        let (_notifier, work_done) = unbounded();
+        Worker::new(service.into(), bus.join_bus(), work_done, clock.clone()).spawn_thread();
+        thread::spawn(move || bus.run());
+
+        let start = Instant::now();
+        let mut outputs = observer
+            .iter()
+            .filter(|ev| matches!(ev, Event::HealthcheckOutput(..)));
+        for _ in 0..10 {
+            // The next check is scheduled before publishing the output of the current one.
+            outputs.next().unwrap();
+            clock.advance(CHECK_INTERVAL);
+        }
+        assert_eq!(clock.elapsed(), CHECK_INTERVAL * 10);
+        assert!(start.elapsed() < CHECK_INTERVAL * 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_healthiness_dns() {
+        let healthiness = |dns_name: &str, dns_address: Option<&str>| Healthiness {
+            dns_name: Some(dns_name.into()),
+            dns_address: dns_address.map(|address| address.parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(check_health_w(&healthiness("localhost", None)));
+        assert!(check_health_w(&healthiness("localhost", Some("127.0.0.1"))));
+        assert!(!check_health_w(&healthiness("localhost", Some("10.0.0.5"))));
+        assert!(!check_health_w(&healthiness("horust.invalid", None)));
+    }
+
+    #[test]
+    fn test_healthiness_external() -> Result<()> {
+        let tempdir = TempDir::new("health")?;
+        let script = tempdir.path().join("check.sh");
+        // Healthy if the request names the service.
+        std::fs::write(
+            &script,
+            r#"read request
+case "$request" in
+  *'"service":"a"'*) echo '{"healthy": true}' ;;
+  *) echo '{"healthy": false, "message": "unknown service"}' ;;
+esac"#,
+        )?;
+        let healthiness = |command: String| Healthiness {
+            external: Some(command),
+            external_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let check = healthiness(format!("/bin/sh {}", script.display()));
+        assert!(check_health_w(&check));
+        let mut service = Service::from_name("b");
+        service.healthiness = check;
+        let output = check_health(&service, &SystemClock);
+        assert_eq!(output.output, "external: unknown service");
+
+        let output = check_health_output(&healthiness("/bin/echo not json".into()));
+        assert!(output.output.starts_with("external: invalid response"));
+        let output = check_health_output(&healthiness("/bin/false".into()));
+        assert!(output.output.starts_with("external: /bin/false has failed"));
+        let output = check_health_output(&healthiness("/bin/sleep 10".into()));
+        assert!(output.output.contains("timed out"), "{}", output.output);
+        Ok(())
+    }
+
     fn handle_request(listener: TcpListener) -> std::io::Result<()> {
         if let Some(stream) = listener.incoming().next() {
             info!("Received request");
             let mut buffer = [0; 512];
             let mut stream = stream?;
-            stream.read(&mut buffer).unwrap();
+            let _ = stream.read(&mut buffer).unwrap();
             let response = b"HTTP/1.1 200 OK\r\n\r\n";
-            stream.write(response).expect("Stream write");
+            stream.write_all(response).expect("Stream write");
         }
         Ok(())
     }
@@ -0,0 +1,205 @@
+//! The run history: every run of a service, from its spawn until its process has exited, is
+//! appended to a journal as a line of JSON. The journal is kept across the runs of Horust, so that
+//! e.g. how often a service has crashed in the last week can be found out. Once it's over its
+//! maximum size, the oldest runs are dropped.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::horust::control::protocol::Run;
+use crate::horust::formats::{ExitReason, ServiceName};
+
+/// Keeps track of the running processes, and appends their runs to the journal once they exit.
+#[derive(Debug)]
+pub(crate) struct History {
+    path: PathBuf,
+    max_size: u64,
+    /// The pid, start time and generation of the running process of each service.
+    running: HashMap<ServiceName, (i32, SystemTime, u32)>,
+    file: Option<File>,
+    size: u64,
+}
+
+impl History {
+    pub(crate) fn new(path: PathBuf, max_size: u64) -> Self {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        let mut history = Self {
+            path,
+            max_size,
+            running: HashMap::new(),
+            file: None,
+            size,
+        };
+        history.trim();
+        history
+    }
+
+    pub(crate) fn spawned(&mut self, service: &ServiceName, pid: i32, generation: u32) {
+        self.running
+            .insert(service.clone(), (pid, SystemTime::now(), generation));
+    }
+
+    pub(crate) fn exited(&mut self, service: &ServiceName, exit_reason: ExitReason, failed: bool) {
+        let Some((pid, started, generation)) = self.running.remove(service) else {
+            return;
+        };
+        let (exit_code, signal) = match exit_reason {
+            ExitReason::Code(code) => (Some(code), None),
+            ExitReason::Signal { signal, .. } => (None, Some(signal.as_str().to_string())),
+        };
+        let run = Run {
+            service: service.clone(),
+            generation,
+            pid,
+            started,
+            ended: SystemTime::now(),
+            exit_code,
+            signal,
+            failed,
+        };
+        if let Err(error) = self.append(&run) {
+            warn!(
+                "Failed writing the run history to {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+        self.trim();
+    }
+
+    fn append(&mut self, run: &Run) -> io::Result<()> {
+        let mut line = serde_json::to_vec(run)?;
+        line.push(b'\n');
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+        file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Drops the oldest runs once the journal is over its maximum size, keeping it at half of it
+    /// so that it's not rewritten after every run.
+    fn trim(&mut self) {
+        if self.size <= self.max_size {
+            return;
+        }
+        match trim(&self.path, self.max_size / 2) {
+            Ok(size) => self.size = size,
+            Err(error) => warn!(
+                "Failed trimming the run history in {}: {}",
+                self.path.display(),
+                error
+            ),
+        }
+        // Reopened, since the journal has been replaced.
+        self.file = None;
+    }
+}
+
+/// Rewrites the journal at `path` with its newest lines which fit in `size`. Returns its new size.
+fn trim(path: &Path, size: u64) -> io::Result<u64> {
+    let content = fs::read_to_string(path)?;
+    let mut kept = 0;
+    let mut start = content.len();
+    for line in content.lines().rev() {
+        let line_size = line.len() as u64 + 1;
+        if kept + line_size > size {
+            break;
+        }
+        kept += line_size;
+        start -= line.len() + 1;
+    }
+    let trimmed = path.with_extension("jsonl.tmp");
+    fs::write(&trimmed, &content[start..])?;
+    fs::rename(&trimmed, path)?;
+    Ok(kept)
+}
+
+/// The runs in the journal at `path`, oldest first. The lines which can't be parsed are skipped.
+pub(crate) fn read(path: &Path) -> io::Result<Vec<Run>> {
+    let content = match fs::read_to_string(path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        content => content?,
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::signal::Signal;
+    use tempdir::TempDir;
+
+    use crate::horust::formats::ExitReason;
+    use crate::horust::history::{read, History};
+
+    #[test]
+    fn test_history() {
+        let tempdir = TempDir::new("history").unwrap();
+        let path = tempdir.path().join("history.jsonl");
+        let mut history = History::new(path.clone(), 1_000);
+        history.spawned(&"web.toml".into(), 10, 1);
+        history.spawned(&"db.toml".into(), 11, 1);
+        // Never spawned.
+        history.exited(&"cron.toml".into(), ExitReason::Code(0), false);
+        let killed = ExitReason::Signal {
+            signal: Signal::SIGSEGV,
+            core_dumped: true,
+        };
+        history.exited(&"web.toml".into(), killed, true);
+        history.exited(&"db.toml".into(), ExitReason::Code(0), false);
+
+        // Kept across the runs of Horust.
+        let mut history = History::new(path.clone(), 1_000);
+        history.spawned(&"web.toml".into(), 12, 1);
+        history.exited(&"web.toml".into(), ExitReason::Code(2), true);
+        let runs = read(&path).unwrap();
+        let summary: Vec<(&str, i32, Option<i32>, Option<&str>)> = runs
+            .iter()
+            .map(|run| {
+                (
+                    run.service.as_str(),
+                    run.pid,
+                    run.exit_code,
+                    run.signal.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("web.toml", 10, None, Some("SIGSEGV")),
+                ("db.toml", 11, Some(0), None),
+                ("web.toml", 12, Some(2), None),
+            ]
+        );
+        assert!(runs[0].failed && !runs[1].failed);
+        assert!(runs[0].started <= runs[0].ended);
+
+        // Only the newest runs are kept, once it's too large.
+        for pid in 0..100 {
+            history.spawned(&"web.toml".into(), pid, pid as u32);
+            history.exited(&"web.toml".into(), ExitReason::Code(1), true);
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() <= 1_000);
+        let runs = read(&path).unwrap();
+        assert_eq!(runs.last().unwrap().pid, 99);
+        assert!(runs.len() > 1);
+        assert_eq!(read(&tempdir.path().join("missing")).unwrap(), vec![]);
+    }
+}
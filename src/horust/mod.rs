@@ -1,34 +1,118 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 #[cfg(target_os = "linux")]
 use libc::{prctl, PR_SET_CHILD_SUBREAPER};
+use nix::unistd;
 
 pub use formats::Event;
 
 use crate::horust::bus::Bus;
-use crate::horust::formats::{validate, Service};
+use crate::horust::clock::{Clock, SystemClock};
+use crate::horust::formats::{
+    check_commands, lint, systemd_unit_name, to_systemd_unit, validate, Service, ServiceName,
+};
+use crate::horust::output::Outputs;
+pub use crate::horust::plan::{Plan, Step};
+use crate::horust::policies::Policy;
+use crate::horust::sockets::Sockets;
+#[cfg(feature = "simulation")]
+pub use crate::horust::supervisor::simulation;
 
-pub use self::formats::{get_sample_service, ExitStatus, HorustConfig};
+pub use self::formats::{
+    get_sample_service, BootTimeoutAction, ConfigOrigins, ControlAccess, ExitCodeStrategy,
+    ExitCodes, ExitReason, ExitStatus, HealthcheckOutput, HorustConfig, RestartStrategy, RunAs,
+    RunReport, ServiceOverride, ServiceReport, ServiceStatus, User,
+};
 
 mod bus;
+mod cgroups;
+mod clock;
+pub mod control;
+mod dynamic_users;
 mod error;
 mod formats;
 mod healthcheck;
+mod history;
+mod notify;
+mod otlp;
+mod output;
+mod plan;
+mod plugins;
+mod policies;
+mod pressure;
+mod reload;
+mod sd_notify;
 mod signal_safe;
+mod sockets;
+mod subprocess;
 mod supervisor;
+mod system_ready;
+mod templates;
+mod timers;
+mod user_services;
+#[cfg(target_os = "linux")]
+mod watcher;
+
+/// How long to wait for the output of the services to be written, before exiting.
+const OUTPUT_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct Horust {
     services: Vec<Service>,
+    config: HorustConfig,
+    /// Where the services were loaded from. None if they can't be reloaded.
+    sources: Option<Sources>,
+    /// From the `policies` file of the config.
+    policies: Vec<Policy>,
+}
+
+/// Where the services were loaded from, for reloading them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Sources {
+    paths: Vec<PathBuf>,
+    overrides: Vec<ServiceOverride>,
+    users: Vec<User>,
+    masked: Vec<ServiceName>,
+}
+
+impl Sources {
+    /// Loads the services again, as they are now.
+    pub(crate) fn load(&self) -> Result<Vec<Service>> {
+        let mut horust = Horust::from_services_dirs_with_overrides(&self.paths, &self.overrides)?;
+        horust.add_user_services(&self.users)?;
+        horust.mask(&self.masked);
+        horust.check_commands()?;
+        Ok(horust.services)
+    }
 }
 
 impl Horust {
     fn new(services: Vec<Service>) -> Self {
-        Horust { services }
+        Horust {
+            services,
+            config: Default::default(),
+            sources: None,
+            policies: vec![],
+        }
+    }
+
+    /// Sets the configuration used when running the services.
+    /// Fails if its policies are invalid.
+    pub fn set_config(&mut self, config: HorustConfig) -> Result<()> {
+        self.policies = match &config.policies {
+            Some(path) => policies::load(path, &self.services)
+                .with_context(|| format!("Invalid policies in {}", path.display()))?,
+            None => vec![],
+        };
+        self.config = config;
+        Ok(())
     }
 
     pub fn get_services(&self) -> &[Service] {
@@ -41,22 +125,122 @@ impl Horust {
     }
 
     /// Create a new horust instance from multiple paths of services.
+    /// The commands aren't checked until `check_commands`, once all the masks are applied.
     pub fn from_services_dirs(paths: &[PathBuf]) -> Result<Self> {
-        let services = paths
+        Self::from_services_dirs_with_overrides(paths, &[])
+    }
+
+    /// Like `from_services_dirs`, with some fields of the services overridden.
+    pub fn from_services_dirs_with_overrides(
+        paths: &[PathBuf],
+        overrides: &[ServiceOverride],
+    ) -> Result<Self> {
+        let mut services: Vec<Service> = Vec::new();
+        let mut positions: HashMap<ServiceName, usize> = HashMap::new();
+        for path in paths {
+            // A service replaces the one with the same name from a previous path.
+            for service in fetch_services(path, overrides)? {
+                match positions.get(&service.name) {
+                    Some(&position) => {
+                        info!("{}: overridden by {}", service.name, path.display());
+                        services[position] = service;
+                    }
+                    None => {
+                        positions.insert(service.name.clone(), services.len());
+                        services.push(service);
+                    }
+                }
+            }
+        }
+        for service_override in overrides {
+            if !services.iter().any(|s| service_override.targets(&s.name)) {
+                bail!("No (valid) service to override with {}", service_override);
+            }
+        }
+
+        let services = validate(templates::expand(services))?;
+        lint(&services);
+        let mut horust = Horust::new(services);
+        let masked: Vec<ServiceName> = paths.iter().flat_map(|path| fetch_masks(path)).collect();
+        horust.mask(&masked);
+        // The mask files are read again when reloading.
+        horust.sources = Some(Sources {
+            paths: paths.to_vec(),
+            overrides: overrides.to_vec(),
+            ..Default::default()
+        });
+        Ok(horust)
+    }
+
+    /// Disables the services named in `masked`, so that they're never started.
+    pub fn mask(&mut self, masked: &[ServiceName]) {
+        if let Some(sources) = &mut self.sources {
+            sources.masked.extend_from_slice(masked);
+        }
+        for name in masked {
+            // Masking a scaled template masks all of its instances.
+            let template = name.strip_suffix(".toml").unwrap_or(name).strip_suffix('@');
+            let mut found = false;
+            for service in self.services.iter_mut().filter(|service| {
+                service.name == *name
+                    || service
+                        .scaled_instance()
+                        .is_some_and(|(instance_of, _)| Some(instance_of) == template)
+            }) {
+                info!("{}: masked", service.name);
+                service.enabled = false;
+                found = true;
+            }
+            if !found {
+                warn!("Cannot mask {}, no such service", name);
+            }
+        }
+    }
+
+    /// Adds the services of each of `users`, found in their `~/.config/horust/services`.
+    /// They are run as their respective user.
+    pub fn add_user_services(&mut self, users: &[User]) -> Result<()> {
+        let mut services = std::mem::take(&mut self.services);
+        for user in users {
+            services.extend(templates::expand(user_services::fetch_user_services(user)?));
+        }
+        self.services = validate(services)?;
+        lint(&self.services);
+        if let Some(sources) = &mut self.sources {
+            sources.users.extend_from_slice(users);
+        }
+        Ok(())
+    }
+
+    /// Checks that the commands of the enabled services can be executed.
+    /// To be called on the final services: the masked ones aren't checked.
+    pub fn check_commands(&self) -> Result<()> {
+        check_commands(&self.services)?;
+        Ok(())
+    }
+
+    /// The order in which the services are going to be started.
+    pub fn plan(&self) -> Plan {
+        Plan::new(&self.services)
+    }
+
+    /// Writes a systemd `.service` unit for each of the services into `out_dir`.
+    /// Returns the paths of the generated unit files.
+    pub fn export_systemd(&self, out_dir: &Path) -> Result<Vec<PathBuf>> {
+        fs::create_dir_all(out_dir)?;
+        self.services
             .iter()
-            .map(|path| fetch_services(path))
-            .flat_map(|result| match result {
-                Ok(vec) => vec.into_iter().map(Ok).collect(),
-                Err(err) => vec![Err(err)],
+            .map(|service| {
+                let path = out_dir.join(systemd_unit_name(&service.name));
+                fs::write(&path, to_systemd_unit(service))?;
+                Ok(path)
             })
-            .collect::<Result<Vec<_>>>()?;
-
-        let services = validate(services)?;
-        Ok(Horust::new(services))
+            .collect()
     }
 
     /// Blocking call, will setup the event loop and the threads and run all the available services.
-    pub fn run(&mut self) -> ExitStatus {
+    /// Returns what happened to each of the services.
+    pub fn run(&mut self) -> RunReport {
         #[cfg(target_os = "linux")]
         unsafe {
             // A subreaper fulfills the role of init(1) for its
@@ -72,28 +256,172 @@ impl Horust {
             // https://man7.org/linux/man-pages/man2/prctl.2.html
             prctl(PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0);
         }
+        formats::init_local_timezone();
         supervisor::init();
+        // As loaded, before being set up for running.
+        let loaded = self.services.clone();
+        templates::restore(&mut self.services, &self.config.state_dir());
+        for service in &mut self.services {
+            service.environment.global = self.config.environment.clone();
+        }
+        dynamic_users::allocate(
+            &mut self.services,
+            &self.config.state_dir(),
+            &self.config.runtime_dir(),
+        );
+        cgroups::setup(&mut self.services);
+        if let Some(run_as) = self.config.run_as.clone() {
+            if let Err(error) = self.run_services_as(&run_as) {
+                panic!("Failed dropping privileges to {}: {:?}", run_as, error);
+            }
+        }
+
+        let notify_sockets = notify::setup(&mut self.services, &self.config.runtime_dir());
 
-        let dispatcher = Bus::new();
+        let mut dispatcher = Bus::new();
+        if let Some(path) = &self.config.trace_events {
+            if let Err(error) = dispatcher.trace_to(path) {
+                error!(
+                    "Events tracing disabled, failed creating {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        }
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let outputs = Outputs::new(self.config.log_prefix.clone(), clock.clone());
         debug!("Services: {:?}", self.services);
+        // The definitions don't change from now on: they're shared by all the components.
+        let services: Vec<Arc<Service>> = self.services.iter().cloned().map(Arc::new).collect();
+        // Bound before dropping the privileges, which privileged ports need.
+        let sockets = Sockets::bind(&services);
+        sockets::spawn_activator(dispatcher.join_bus(), sockets.clone(), &services);
+        notify::spawn(dispatcher.join_bus(), notify_sockets);
+        #[cfg(target_os = "linux")]
+        watcher::spawn(dispatcher.join_bus(), &services, clock.clone());
+        reload::spawn(
+            dispatcher.join_bus(),
+            self.sources.clone(),
+            loaded,
+            services.clone(),
+        );
+        if let Some(plugins) = self.config.plugins.clone() {
+            plugins::spawn(dispatcher.join_bus(), plugins, clock.clone());
+        }
+        policies::spawn(
+            dispatcher.join_bus(),
+            std::mem::take(&mut self.policies),
+            clock.clone(),
+        );
+        if let Some(command) = self.config.on_system_ready.clone() {
+            system_ready::spawn(dispatcher.join_bus(), command, clock.clone());
+        }
+        timers::spawn(
+            dispatcher.join_bus(),
+            &services,
+            clock.clone(),
+            self.config.state_dir(),
+        );
         // Spawn helper threads:
-        healthcheck::spawn(dispatcher.join_bus(), self.services.clone());
-        let handle = supervisor::spawn(dispatcher.join_bus(), self.services.clone());
+        healthcheck::spawn(dispatcher.join_bus(), services.clone(), clock.clone());
+        if let Some(monitor) = self.config.pressure.clone() {
+            pressure::spawn(
+                dispatcher.join_bus(),
+                monitor,
+                &self.services,
+                clock.clone(),
+            );
+        }
+        if self.config.control_socket.is_some() {
+            if let Err(error) = control::spawn(
+                dispatcher.join_bus_with_replay(),
+                services.clone(),
+                outputs.clone(),
+                clock.clone(),
+                &self.config,
+            ) {
+                if error.is::<control::OwnershipError>() {
+                    panic!("{:?}", error);
+                }
+                error!("Control socket disabled: {:?}", error);
+            }
+        }
+        // The setup requiring privileges is done.
+        if let Some(run_as) = &self.config.run_as {
+            if let Err(error) = drop_privileges(run_as) {
+                // Supervising as root would defeat the purpose of `run-as`.
+                panic!("Failed dropping privileges to {}: {:?}", run_as, error);
+            }
+        }
+        let tracer = self.config.otlp_endpoint.clone().map(otlp::Tracer::new);
+        let handle = supervisor::spawn(
+            dispatcher.join_bus(),
+            services,
+            outputs.clone(),
+            tracer,
+            clock,
+            sockets,
+            &self.config,
+        );
         dispatcher.run();
-        handle.join().unwrap()
+        let report = handle.join().unwrap();
+        outputs.flush(OUTPUT_FLUSH_TIMEOUT);
+        dynamic_users::cleanup(&self.services);
+        cgroups::cleanup(&self.services);
+        sockets::cleanup(&self.services);
+        notify::cleanup(&self.services);
+        report
+    }
+
+    /// The services which would have run as Horust's user, are run as the user of `run_as` instead.
+    /// The others can't be started once the privileges are dropped.
+    fn run_services_as(&mut self, run_as: &RunAs) -> Result<()> {
+        let (uid, _gid) = run_as.credentials()?;
+        let previous_uid = unistd::getuid();
+        for service in &mut self.services {
+            if service.has_dynamic_user() {
+                warn!(
+                    "{}: it will fail to start, dynamic users need root",
+                    service.name
+                );
+                continue;
+            }
+            let service_uid = service.user.get_uid()?;
+            if service_uid == previous_uid {
+                service.user = User::Uid(uid.as_raw());
+            } else if service_uid != uid {
+                warn!(
+                    "{}: it will fail to start, it can't run as {} after switching to {}",
+                    service.name, service.user, run_as
+                );
+            }
+        }
+        Ok(())
     }
 }
 
-fn load_service<P>(path: P) -> Result<Service>
+/// Permanently switches Horust to the user and group of `run_as`.
+fn drop_privileges(run_as: &RunAs) -> Result<()> {
+    let (uid, gid) = run_as.credentials()?;
+    if unistd::geteuid().is_root() {
+        unistd::setgroups(&[gid])?;
+    }
+    unistd::setgid(gid)?;
+    unistd::setuid(uid)?;
+    info!("Dropped privileges, running as {}", run_as);
+    Ok(())
+}
+
+fn load_service<P>(path: P, overrides: &[ServiceOverride]) -> Result<Service>
 where
     P: AsRef<Path> + Sized + AsRef<OsStr> + Debug,
     PathBuf: From<P>,
 {
-    let res = Service::from_file(&path);
     let path = PathBuf::from(path);
+    let filename = path.file_name().unwrap().to_str().unwrap().to_owned();
+    let res = Service::from_file_with_overrides(&path, &filename, overrides);
     res.map(|mut service| {
         if service.name.is_empty() {
-            let filename = path.file_name().unwrap().to_str().unwrap().to_owned();
             service.name = filename;
         }
         service
@@ -116,9 +444,27 @@ fn is_toml_file(path: &Path) -> bool {
     path.is_file() && has_toml_extension(path)
 }
 
+/// The services masked in `path` by a `<name>.mask` file, which disables `<name>.toml`.
+fn fetch_masks(path: &Path) -> Vec<ServiceName> {
+    if !path.is_dir() {
+        return vec![];
+    }
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|direntry| direntry.path())
+        .filter(|path| path.extension() == Some(OsStr::new("mask")))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            Some(format!("{}.toml", stem))
+        })
+        .collect()
+}
+
 // TODO: option to decide to not start if the deserialization of any service failed.
 /// Search for *.toml files in path, and deserialize them into Service.
-fn fetch_services(path: &Path) -> Result<Vec<Service>> {
+fn fetch_services(path: &Path, overrides: &[ServiceOverride]) -> Result<Vec<Service>> {
     debug!("Fetching services from: {}", path.display());
 
     let paths = if path.is_file() {
@@ -137,7 +483,7 @@ fn fetch_services(path: &Path) -> Result<Vec<Service>> {
     let services = paths
         .into_iter()
         .filter(|p| is_toml_file(p))
-        .map(load_service)
+        .map(|path| load_service(path, overrides))
         .filter_map(Result::ok)
         .collect::<Vec<Service>>();
     if services.is_empty() {
@@ -189,26 +535,26 @@ mod test {
     fn test_fetch_services() -> io::Result<()> {
         let tempdir = TempDir::new("horust").unwrap();
         // Empty service directory will print a log but it's not an error.
-        assert_eq!(fetch_services(tempdir.path()).unwrap().len(), 0);
+        assert_eq!(fetch_services(tempdir.path(), &[]).unwrap().len(), 0);
         let not_toml_file = tempdir.path().join("not_a_toml.toml");
         fs::write(not_toml_file.clone(), "not really a toml.")?;
 
         // Today Horust filters out invalid toml files.
-        assert_eq!(fetch_services(tempdir.path()).unwrap().len(), 0);
+        assert_eq!(fetch_services(tempdir.path(), &[]).unwrap().len(), 0);
 
         // This is not a directory
-        assert_eq!(fetch_services(&not_toml_file).unwrap().len(), 0);
+        assert_eq!(fetch_services(&not_toml_file, &[]).unwrap().len(), 0);
 
         let tempdir = create_test_dir()?;
         fs::write(tempdir.path().join("not-a-service"), "Hello world")?;
-        let res = fetch_services(tempdir.path()).unwrap();
+        let res = fetch_services(tempdir.path(), &[]).unwrap();
         assert_eq!(res.len(), 2,);
         let mut names: Vec<String> = res.into_iter().map(|serv| serv.name).collect();
         names.sort();
         assert_eq!(vec!["a", "b"], names);
 
         // Load a service from a single file instead of a directory
-        let res = fetch_services(&tempdir.path().join(FIRST_SERVICE_FILENAME)).unwrap();
+        let res = fetch_services(&tempdir.path().join(FIRST_SERVICE_FILENAME), &[]).unwrap();
         assert_eq!(res.len(), 1,);
 
         Ok(())
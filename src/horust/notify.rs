@@ -0,0 +1,196 @@
+//! Readiness notifications of the `notify` services: each of them gets its own datagram socket,
+//! passed in `NOTIFY_SOCKET` like systemd does, and it's only considered running once it has sent
+//! `READY=1` on it (see `sd_notify(3)`).
+
+use std::fs;
+use std::io;
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::unistd;
+
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::{Event, Service, ServiceName, ServiceType};
+
+/// Where the sockets are created, in the runtime directory.
+const NOTIFY_DIR: &str = "notify";
+
+/// How often the listener checks whether Horust is shutting down.
+const LISTENER_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Binds a socket in `runtime_dir` for each of the notify services, and sets their
+/// `notify_socket`. If it can't be bound, the service will fail to spawn.
+pub(crate) fn setup(
+    services: &mut [Service],
+    runtime_dir: &Path,
+) -> Vec<(ServiceName, UnixDatagram)> {
+    services
+        .iter_mut()
+        .filter(|service| service.service_type == ServiceType::Notify)
+        .filter_map(|service| {
+            let path = socket_path(&service.name, runtime_dir);
+            match bind(service, &path) {
+                Ok(socket) => {
+                    service.notify_socket = Some(path);
+                    Some((service.name.clone(), socket))
+                }
+                Err(error) => {
+                    error!(
+                        service = service.name.as_str();
+                        "{}: failed binding the notify socket {}: {}",
+                        service.name,
+                        path.display(),
+                        error
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn socket_path(service: &ServiceName, runtime_dir: &Path) -> PathBuf {
+    let name: String = service
+        .chars()
+        .map(|c| if c == '/' { '_' } else { c })
+        .collect();
+    runtime_dir.join(NOTIFY_DIR).join(format!("{}.sock", name))
+}
+
+/// Binds the socket at `path`, owned by the user of `service` so that it can send to it.
+fn bind(service: &Service, path: &Path) -> io::Result<UnixDatagram> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // A stale socket left by a previous run would make the bind fail.
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let socket = UnixDatagram::bind(path)?;
+    socket.set_nonblocking(true)?;
+    if unistd::geteuid().is_root() {
+        let uid = service.uid().map_err(io::Error::other)?;
+        unistd::chown(path, Some(uid), None)?;
+    }
+    Ok(socket)
+}
+
+/// Removes the sockets of the services, once they have finished.
+pub(crate) fn cleanup(services: &[Service]) {
+    for path in services
+        .iter()
+        .filter_map(|service| service.notify_socket.as_ref())
+    {
+        if let Err(error) = fs::remove_file(path) {
+            debug!("Failed removing {}: {}", path.display(), error);
+        }
+    }
+}
+
+/// Sends `Event::NotifiedReady` whenever a service sends `READY=1` on its socket.
+pub(crate) fn spawn(bus: BusConnector<Event>, sockets: Vec<(ServiceName, UnixDatagram)>) {
+    if sockets.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        if let Err(error) = run(&bus, &sockets) {
+            error!("Readiness notifications disabled: {}", error);
+        }
+    });
+}
+
+fn run(bus: &BusConnector<Event>, sockets: &[(ServiceName, UnixDatagram)]) -> nix::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let shutting_down = bus
+            .try_get_events()
+            .into_iter()
+            .any(|ev| matches!(ev, Event::ShuttingDownInitiated(_)));
+        if shutting_down {
+            return Ok(());
+        }
+        let mut fds: Vec<PollFd> = sockets
+            .iter()
+            .map(|(_, socket)| PollFd::new(socket.as_fd(), PollFlags::POLLIN))
+            .collect();
+        let timeout = PollTimeout::try_from(LISTENER_INTERVAL).unwrap_or(PollTimeout::MAX);
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => (),
+            Err(error) => return Err(error),
+        }
+        let readable: Vec<usize> = fds
+            .iter()
+            .enumerate()
+            .filter(|(_, fd)| fd.revents().is_some_and(|revents| !revents.is_empty()))
+            .map(|(index, _)| index)
+            .collect();
+        for (service, socket) in readable.into_iter().map(|index| &sockets[index]) {
+            while let Ok(len) = socket.recv(&mut buf) {
+                if is_ready(&buf[..len]) {
+                    debug!("{}: notified its readiness", service);
+                    bus.send_event(Event::NotifiedReady(service.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// A notification is made of newline separated assignments, like `READY=1\nSTATUS=Started`.
+fn is_ready(message: &[u8]) -> bool {
+    message
+        .split(|byte| *byte == b'\n')
+        .any(|line| line == b"READY=1")
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::horust::bus::Bus;
+    use crate::horust::formats::{Event, Service, ServiceType, ShuttingDown};
+    use crate::horust::notify::{is_ready, setup, spawn};
+
+    #[test]
+    fn test_is_ready() {
+        assert!(is_ready(b"READY=1"));
+        assert!(is_ready(b"STATUS=Listening\nREADY=1\n"));
+        assert!(!is_ready(b"STATUS=READY=1"));
+        assert!(!is_ready(b"READY=0"));
+    }
+
+    #[test]
+    fn test_notify() {
+        let runtime_dir = TempDir::new("notify").unwrap();
+        let mut notify = Service::from_name("api.toml");
+        notify.service_type = ServiceType::Notify;
+        let mut services = vec![notify, Service::from_name("simple.toml")];
+        let sockets = setup(&mut services, runtime_dir.path());
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(services[1].notify_socket, None);
+        let path = services[0].notify_socket.clone().unwrap();
+        assert_eq!(path, runtime_dir.path().join("notify/api.toml.sock"));
+
+        let bus = Bus::new();
+        let receiver = bus.join_bus();
+        spawn(bus.join_bus(), sockets);
+        std::thread::spawn(move || bus.run());
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(b"STATUS=Starting", &path).unwrap();
+        client.send_to(b"READY=1", &path).unwrap();
+        let ev = receiver
+            .iter()
+            .find(|ev| matches!(ev, Event::NotifiedReady(_)))
+            .unwrap();
+        assert_eq!(ev, Event::NotifiedReady("api.toml".into()));
+        receiver.send_event(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
+        std::thread::sleep(Duration::from_millis(300));
+    }
+}
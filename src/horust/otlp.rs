@@ -0,0 +1,295 @@
+//! Tracing of the lifecycle of the services, exported via OTLP/HTTP (JSON encoding).
+//! Every process of a service is a span (spawn -> exit, with a `ready` event once it's running),
+//! and so are the boot (until all the services are up) and the shutdown of Horust.
+//! All the spans of a Horust instance belong to the same trace.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::horust::formats::{ExitReason, ServiceName, ShuttingDown};
+
+/// Status codes of the spans, as defined by OTLP.
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Span {
+    span_id: [u8; 8],
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    events: Vec<(&'static str, SystemTime)>,
+    failed: bool,
+}
+
+impl Span {
+    fn start(name: String, attributes: Vec<(&'static str, Value)>) -> Self {
+        Span {
+            span_id: rand::thread_rng().gen(),
+            name,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes,
+            events: vec![],
+            failed: false,
+        }
+    }
+
+    fn to_json(&self, trace_id: &[u8; 16]) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+            .collect();
+        let events: Vec<Value> = self
+            .events
+            .iter()
+            .map(|(name, time)| json!({ "name": name, "timeUnixNano": unix_nanos(*time) }))
+            .collect();
+        json!({
+            "traceId": hex(trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            // Internal.
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": attributes,
+            "events": events,
+            "status": { "code": if self.failed { STATUS_ERROR } else { STATUS_OK } },
+        })
+    }
+}
+
+/// Keeps track of the open spans, and sends the closed ones to the exporter.
+#[derive(Debug)]
+pub(crate) struct Tracer {
+    runs: HashMap<ServiceName, Span>,
+    boot: Option<Span>,
+    shutdown: Option<Span>,
+    exporter: Sender<Span>,
+    exporter_done: Option<Receiver<()>>,
+}
+
+impl Tracer {
+    /// Exports the spans to the OTLP/HTTP `endpoint`, e.g. `http://collector:4318`.
+    pub(crate) fn new(endpoint: String) -> Self {
+        let (sender, receiver) = unbounded();
+        let (done_sender, done_receiver) = unbounded();
+        let trace_id = rand::thread_rng().gen();
+        thread::spawn(move || {
+            export(&endpoint, &trace_id, receiver);
+            let _ = done_sender.send(());
+        });
+        let mut tracer = Self::with_exporter(sender);
+        tracer.exporter_done = Some(done_receiver);
+        tracer
+    }
+
+    fn with_exporter(exporter: Sender<Span>) -> Self {
+        Tracer {
+            runs: HashMap::new(),
+            boot: Some(Span::start("boot".into(), vec![])),
+            shutdown: None,
+            exporter,
+            exporter_done: None,
+        }
+    }
+
+    pub(crate) fn spawned(&mut self, service: &ServiceName, pid: i32) {
+        let attributes = vec![
+            ("horust.service.name", json!(service)),
+            ("process.pid", json!(pid)),
+        ];
+        let span = Span::start(format!("run {}", service), attributes);
+        self.runs.insert(service.clone(), span);
+    }
+
+    pub(crate) fn ready(&mut self, service: &ServiceName) {
+        if let Some(span) = self.runs.get_mut(service) {
+            span.events.push(("ready", SystemTime::now()));
+        }
+    }
+
+    pub(crate) fn exited(&mut self, service: &ServiceName, exit_reason: ExitReason, failed: bool) {
+        let Some(mut span) = self.runs.remove(service) else {
+            return;
+        };
+        span.attributes
+            .push(("horust.exit.reason", json!(exit_reason.to_string())));
+        match exit_reason {
+            ExitReason::Code(code) => span.attributes.push(("process.exit.code", json!(code))),
+            ExitReason::Signal { signal, .. } => span
+                .attributes
+                .push(("horust.exit.signal", json!(signal.as_str()))),
+        }
+        span.failed = failed;
+        self.send(span);
+    }
+
+    /// All the services are up.
+    pub(crate) fn booted(&mut self) {
+        if let Some(span) = self.boot.take() {
+            self.send(span);
+        }
+    }
+
+    pub(crate) fn shutting_down(&mut self, shutting_down: ShuttingDown) {
+        if self.shutdown.is_none() {
+            let mode = match shutting_down {
+                ShuttingDown::Gracefully => "gracefully",
+                ShuttingDown::Forcefully => "forcefully",
+            };
+            let attributes = vec![("horust.shutdown", json!(mode))];
+            self.shutdown = Some(Span::start("shutdown".into(), attributes));
+        }
+    }
+
+    /// Closes the remaining spans, and waits up to `timeout` for them to be exported.
+    pub(crate) fn finish(mut self, timeout: Duration) {
+        // Never booted completely.
+        if let Some(mut span) = self.boot.take() {
+            span.failed = true;
+            self.send(span);
+        }
+        if let Some(span) = self.shutdown.take() {
+            self.send(span);
+        }
+        let Tracer {
+            exporter,
+            exporter_done,
+            ..
+        } = self;
+        drop(exporter);
+        if let Some(done) = exporter_done {
+            let _ = done.recv_timeout(timeout);
+        }
+    }
+
+    fn send(&self, mut span: Span) {
+        span.end = SystemTime::now();
+        let _ = self.exporter.send(span);
+    }
+}
+
+/// Builds the body of an OTLP/HTTP export request.
+fn export_request(trace_id: &[u8; 16], spans: &[Span]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|span| span.to_json(trace_id)).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "horust" } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "horust", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// Sends the spans received from `receiver` in batches, until the tracer is dropped.
+fn export(endpoint: &str, trace_id: &[u8; 16], receiver: Receiver<Span>) {
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    while let Ok(span) = receiver.recv() {
+        let mut spans = vec![span];
+        spans.extend(receiver.try_iter());
+        let body = export_request(trace_id, &spans);
+        if let Err(error) = post(&url, &body) {
+            error!(
+                "Failed exporting {} spans to {}: {}",
+                spans.len(),
+                url,
+                error
+            );
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn post(url: &str, body: &Value) -> anyhow::Result<()> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    client.post(url).json(body).send()?.error_for_status()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+fn post(_url: &str, _body: &Value) -> anyhow::Result<()> {
+    anyhow::bail!("horust was built without the otlp feature")
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        // 64 bit integers are encoded as strings.
+        Value::Number(number) if number.is_i64() => json!({ "intValue": number.to_string() }),
+        Value::String(string) => json!({ "stringValue": string }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::channel::unbounded;
+    use nix::sys::signal::Signal;
+
+    use crate::horust::formats::{ExitReason, ShuttingDown};
+    use crate::horust::otlp::{export_request, Tracer};
+
+    #[test]
+    fn test_tracer() {
+        let (sender, receiver) = unbounded();
+        let mut tracer = Tracer::with_exporter(sender);
+        tracer.spawned(&"a".into(), 42);
+        tracer.spawned(&"b".into(), 43);
+        tracer.ready(&"a".into());
+        tracer.booted();
+        tracer.exited(&"a".into(), ExitReason::Code(0), false);
+        tracer.shutting_down(ShuttingDown::Gracefully);
+        let killed = ExitReason::Signal {
+            signal: Signal::SIGKILL,
+            core_dumped: false,
+        };
+        tracer.exited(&"b".into(), killed, true);
+        tracer.finish(Default::default());
+
+        let spans: Vec<_> = receiver.iter().collect();
+        let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+        assert_eq!(names, vec!["boot", "run a", "run b", "shutdown"]);
+
+        let request = export_request(&[1; 16], &spans);
+        let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        let run_a = &spans[1];
+        assert_eq!(run_a["traceId"], "01".repeat(16));
+        assert_eq!(run_a["events"][0]["name"], "ready");
+        assert_eq!(run_a["status"]["code"], 1);
+        assert_eq!(
+            run_a["attributes"][1],
+            serde_json::json!({ "key": "process.pid", "value": { "intValue": "42" } })
+        );
+        let run_b = &spans[2];
+        assert_eq!(run_b["status"]["code"], 2);
+        assert_eq!(
+            run_b["attributes"][3]["value"]["stringValue"],
+            serde_json::json!("SIGKILL")
+        );
+    }
+}
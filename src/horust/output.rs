@@ -0,0 +1,623 @@
+//! Output of the services.
+//! The stdout and stderr of a service are pipes read by Horust only when it's needed: for the log
+//! prefix, the rotation or the `also-inherit` of its files, the tail reported on failure, or the
+//! attached clients. Otherwise the process writes to its destination directly (e.g. to the TTY).
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::unistd::{self, Pid};
+use serde::{Deserialize, Serialize};
+
+use crate::horust::clock::Clock;
+use crate::horust::formats::{LogOutput, Service, ServiceName};
+
+/// How many chunks an attached client can lag behind, before the next ones are dropped for it.
+const SUBSCRIBER_CAPACITY: usize = 256;
+/// The size of the reads of the output.
+const CHUNK_SIZE: usize = 8192;
+/// Longer lines are split in the tail.
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A line of output of a service, including the trailing newline (if any).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct OutputLine {
+    pub(crate) stream: Stream,
+    pub(crate) line: Vec<u8>,
+}
+
+/// Output of a service as it was read, not split into lines.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct OutputChunk {
+    pub(crate) stream: Stream,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Shared between the components interested in the output of the services.
+#[derive(Clone, Debug)]
+pub(crate) struct Outputs {
+    /// Attached clients, by service.
+    subscribers: Arc<Mutex<HashMap<ServiceName, Vec<Sender<OutputChunk>>>>>,
+    /// The last lines of output of the current (or last) process of every service.
+    tails: Arc<Mutex<HashMap<ServiceName, VecDeque<OutputLine>>>>,
+    /// The threads forwarding the output, by service.
+    pumps: Arc<Mutex<HashMap<ServiceName, Vec<JoinHandle<()>>>>>,
+    /// Used for the services without a `log_prefix`.
+    default_log_prefix: Option<String>,
+    /// For the `{time}` of the prefixes, and the waits for the output to be read.
+    clock: Arc<dyn Clock>,
+}
+
+impl Outputs {
+    pub(crate) fn new(default_log_prefix: Option<String>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            subscribers: Default::default(),
+            tails: Default::default(),
+            pumps: Default::default(),
+            default_log_prefix,
+            clock,
+        }
+    }
+
+    /// Whether the output of `service` has to be read by Horust, instead of being written by the
+    /// process to its destination directly.
+    pub(crate) fn captures(&self, service: &Service) -> bool {
+        let inherited = |output: &LogOutput| !matches!(output, LogOutput::Path(_));
+        let tee = |output: &LogOutput| matches!(output, LogOutput::Tee(_));
+        let has_prefix = service.log_prefix.is_some() || self.default_log_prefix.is_some();
+        service.attachable
+            || service.failure.output_lines > 0
+            || (has_prefix && (inherited(&service.stdout) || inherited(&service.stderr)))
+            || tee(&service.stdout)
+            || tee(&service.stderr)
+            || service.stdout_rotate_size > 0
+    }
+
+    /// Receives the output of `service`, until the receiver is dropped. If the receiver lags
+    /// behind, the output is dropped for it rather than buffered.
+    pub(crate) fn subscribe(&self, service: &str) -> Receiver<OutputChunk> {
+        let (sender, receiver) = bounded(SUBSCRIBER_CAPACITY);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(service.to_string())
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    fn publish(&self, service: &str, chunk: &OutputChunk) {
+        if let Some(subscribers) = self.subscribers.lock().unwrap().get_mut(service) {
+            subscribers.retain(|sender| match sender.try_send(chunk.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!("{}: dropped output for a slow client", service);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        }
+    }
+
+    fn push_tail(&self, service: &Service, output: OutputLine) {
+        let max_lines = service.failure.output_lines;
+        if max_lines > 0 {
+            let mut tails = self.tails.lock().unwrap();
+            let tail = tails.entry(service.name.clone()).or_default();
+            if tail.len() == max_lines {
+                tail.pop_front();
+            }
+            tail.push_back(output);
+        }
+    }
+
+    /// Forgets the output of the previous process of `service`, before spawning a new one.
+    pub(crate) fn clear_tail(&self, service: &str) {
+        self.tails.lock().unwrap().remove(service);
+    }
+
+    /// The last lines of output of `service`, up to `failure.output_lines`.
+    /// Waits up to `timeout` for the output of the exited process to be read.
+    pub(crate) fn tail(&self, service: &str, timeout: Duration) -> Vec<OutputLine> {
+        let deadline = self.clock.now() + timeout;
+        let is_pumping = || {
+            self.pumps
+                .lock()
+                .unwrap()
+                .get(service)
+                .is_some_and(|pumps| pumps.iter().any(|pump| !pump.is_finished()))
+        };
+        while self.clock.now() < deadline && is_pumping() {
+            self.clock.sleep(Duration::from_millis(10));
+        }
+        self.tails
+            .lock()
+            .unwrap()
+            .get(service)
+            .map(|tail| tail.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Spawns a thread forwarding the `stdout` and `stderr` of the process `pid` of the service,
+    /// until both are closed.
+    pub(crate) fn pump(&self, service: &Service, pid: Pid, stdout: OwnedFd, stderr: OwnedFd) {
+        let outputs = self.clone();
+        let service = service.clone();
+        let mut sources: Vec<Source> = [(Stream::Stdout, stdout), (Stream::Stderr, stderr)]
+            .into_iter()
+            .map(|(stream, fd)| {
+                let prefix = service
+                    .log_prefix
+                    .as_ref()
+                    .or(self.default_log_prefix.as_ref())
+                    .map(|template| LogPrefix::new(template, &service, pid, stream));
+                Source {
+                    stream,
+                    fd,
+                    destination: Destination::new(&service, stream, prefix),
+                    partial_line: vec![],
+                }
+            })
+            .collect();
+        let name = service.name.clone();
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; CHUNK_SIZE];
+            while !sources.is_empty() {
+                let mut fds: Vec<PollFd> = sources
+                    .iter()
+                    .map(|source| PollFd::new(source.fd.as_fd(), PollFlags::POLLIN))
+                    .collect();
+                match poll(&mut fds, PollTimeout::NONE) {
+                    Ok(_) | Err(Errno::EINTR) => (),
+                    Err(error) => {
+                        error!("{}: failed polling the output: {}", service.name, error);
+                        break;
+                    }
+                }
+                let ready: Vec<bool> = fds.iter().map(|fd| fd.any().unwrap_or(true)).collect();
+                drop(fds);
+                let mut closed = vec![];
+                for (index, source) in sources.iter_mut().enumerate() {
+                    if !ready[index] {
+                        continue;
+                    }
+                    let read = match unistd::read(source.fd.as_raw_fd(), &mut buf) {
+                        Ok(read) => read,
+                        Err(Errno::EINTR | Errno::EAGAIN) => continue,
+                        Err(error) => {
+                            error!("{}: failed reading the output: {}", service.name, error);
+                            0
+                        }
+                    };
+                    if read == 0 {
+                        closed.push(index);
+                        continue;
+                    }
+                    let data = &buf[..read];
+                    let time = outputs.clock.system_time();
+                    if let Err(error) = source.destination.write(data, time) {
+                        debug!("{}: failed writing the output: {}", service.name, error);
+                    }
+                    let chunk = OutputChunk {
+                        stream: source.stream,
+                        data: data.to_vec(),
+                    };
+                    outputs.publish(&service.name, &chunk);
+                    source.split_lines(data, |line| outputs.push_tail(&service, line));
+                }
+                for index in closed.into_iter().rev() {
+                    let source = sources.remove(index);
+                    if !source.partial_line.is_empty() {
+                        let line = OutputLine {
+                            stream: source.stream,
+                            line: source.partial_line,
+                        };
+                        outputs.push_tail(&service, line);
+                    }
+                }
+            }
+        });
+        let mut pumps = self.pumps.lock().unwrap();
+        let pumps = pumps.entry(name).or_default();
+        pumps.retain(|pump| !pump.is_finished());
+        pumps.push(handle);
+    }
+
+    /// Waits up to `timeout` for the output of the exited services to be written.
+    /// Processes which are still running (e.g. daemonized grandchildren) are not waited for.
+    pub(crate) fn flush(&self, timeout: Duration) {
+        let deadline = self.clock.now() + timeout;
+        let mut pumps = self.pumps.lock().unwrap();
+        let is_pumping = |pumps: &HashMap<ServiceName, Vec<JoinHandle<()>>>| {
+            pumps.values().flatten().any(|pump| !pump.is_finished())
+        };
+        while self.clock.now() < deadline && is_pumping(&pumps) {
+            self.clock.sleep(Duration::from_millis(10));
+        }
+        pumps.retain(|_name, pumps| {
+            pumps.retain(|pump| !pump.is_finished());
+            !pumps.is_empty()
+        });
+    }
+}
+
+/// The read end of the stdout or stderr of a process.
+struct Source {
+    stream: Stream,
+    fd: OwnedFd,
+    destination: Destination,
+    /// The last line read, until its newline is.
+    partial_line: Vec<u8>,
+}
+
+impl Source {
+    /// Calls `on_line` with the lines completed by `data`.
+    fn split_lines(&mut self, data: &[u8], mut on_line: impl FnMut(OutputLine)) {
+        for piece in data.split_inclusive(|byte| *byte == b'\n') {
+            self.partial_line.extend_from_slice(piece);
+            if piece.ends_with(b"\n") || self.partial_line.len() >= MAX_LINE_LENGTH {
+                on_line(OutputLine {
+                    stream: self.stream,
+                    line: std::mem::take(&mut self.partial_line),
+                });
+            }
+        }
+    }
+}
+
+/// Where the output of a stream is written to.
+struct Destination {
+    file: Option<RotatingFile>,
+    /// The stream of Horust the output is written to, if any.
+    inherited: Option<Stream>,
+    /// Prepended to the lines written to `inherited`.
+    prefix: Option<LogPrefix>,
+    /// Whether the next byte written to `inherited` starts a line.
+    at_line_start: bool,
+}
+
+impl Destination {
+    fn new(service: &Service, stream: Stream, prefix: Option<LogPrefix>) -> Self {
+        let (output, rotate_size) = match stream {
+            Stream::Stdout => (&service.stdout, service.stdout_rotate_size),
+            Stream::Stderr => (&service.stderr, 0),
+        };
+        let (file, inherited) = match output {
+            LogOutput::Stdout | LogOutput::Pipe(_) => (None, Some(Stream::Stdout)),
+            LogOutput::Stderr => (None, Some(Stream::Stderr)),
+            LogOutput::Path(path) => (Some(RotatingFile::new(path, rotate_size)), None),
+            LogOutput::Tee(path) => (Some(RotatingFile::new(path, rotate_size)), Some(stream)),
+        };
+        Self {
+            file,
+            inherited,
+            prefix,
+            at_line_start: true,
+        }
+    }
+
+    /// Writes `data` as it is, besides the prefix at the start of its lines.
+    fn write(&mut self, data: &[u8], time: SystemTime) -> io::Result<()> {
+        let inherited = match self.inherited {
+            Some(stream) => {
+                let buf = self.prefixed(data, time);
+                match stream {
+                    Stream::Stdout => io::stdout().lock().write_all(&buf),
+                    Stream::Stderr => io::stderr().lock().write_all(&buf),
+                }
+            }
+            None => Ok(()),
+        };
+        let written = match &mut self.file {
+            Some(file) => file.write(data),
+            None => Ok(()),
+        };
+        inherited.and(written)
+    }
+
+    /// `data` with the prefix (rendered at `time`) inserted at the start of its lines.
+    fn prefixed(&mut self, data: &[u8], time: SystemTime) -> Vec<u8> {
+        let Some(prefix) = &self.prefix else {
+            return data.to_vec();
+        };
+        let mut buf = Vec::with_capacity(data.len());
+        for piece in data.split_inclusive(|byte| *byte == b'\n') {
+            if self.at_line_start {
+                buf.extend_from_slice(prefix.render(time).as_bytes());
+            }
+            buf.extend_from_slice(piece);
+            self.at_line_start = piece.ends_with(b"\n");
+        }
+        buf
+    }
+}
+
+/// A prefix template, like `{time} [{service}:{pid}] `. The placeholders are `{time}` (RFC 3339),
+/// `{service}`, `{pid}` and `{stream}`. Only `{time}` changes among the lines of the same process.
+struct LogPrefix {
+    template: String,
+}
+
+impl LogPrefix {
+    fn new(template: &str, service: &Service, pid: Pid, stream: Stream) -> Self {
+        let stream = match stream {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+        let template = template
+            .replace("{service}", &service.name)
+            .replace("{pid}", &pid.to_string())
+            .replace("{stream}", stream);
+        Self { template }
+    }
+
+    fn render(&self, time: SystemTime) -> String {
+        if self.template.contains("{time}") {
+            let time = humantime::format_rfc3339_millis(time).to_string();
+            self.template.replace("{time}", &time)
+        } else {
+            self.template.clone()
+        }
+    }
+}
+
+/// A log file which is rotated after `rotate_size` bytes: `out.log`, `out.log.1`, `out.log.2`...
+/// Lines are never split among two files.
+struct RotatingFile {
+    base_path: PathBuf,
+    /// Zero disables the rotation.
+    rotate_size: u64,
+    file: Option<File>,
+    written: u64,
+    /// The file is rotated only once the current line is complete.
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    fn new(base_path: &Path, rotate_size: u64) -> Self {
+        Self {
+            base_path: base_path.to_path_buf(),
+            rotate_size,
+            file: None,
+            written: 0,
+            at_line_start: true,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        for piece in data.split_inclusive(|byte| *byte == b'\n') {
+            if self.rotate_size > 0 && self.written >= self.rotate_size && self.at_line_start {
+                self.file = None;
+            }
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => {
+                    let path = if self.rotate_size > 0 {
+                        next_chunk_path(&self.base_path)
+                    } else {
+                        self.base_path.clone()
+                    };
+                    debug!("Opening log output: {}", path.display());
+                    self.written = 0;
+                    self.file
+                        .insert(OpenOptions::new().create(true).append(true).open(path)?)
+                }
+            };
+            file.write_all(piece)?;
+            self.written += piece.len() as u64;
+            self.at_line_start = piece.ends_with(b"\n");
+        }
+        Ok(())
+    }
+}
+
+/// The first of `base_path`, `base_path.1`, `base_path.2`... which doesn't exist.
+fn next_chunk_path(base_path: &Path) -> PathBuf {
+    let mut count = 1;
+    let mut path = base_path.to_path_buf();
+    while path.is_file() {
+        path = PathBuf::from(format!("{}.{count}", base_path.display()));
+        count += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::os::fd::OwnedFd;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use nix::unistd::{pipe, Pid};
+    use tempdir::TempDir;
+
+    use crate::horust::clock::{Clock, SimulatedClock, SystemClock};
+    use crate::horust::formats::{LogOutput, Service};
+    use crate::horust::output::{
+        Destination, LogPrefix, OutputChunk, Outputs, RotatingFile, Stream, SUBSCRIBER_CAPACITY,
+    };
+
+    #[test]
+    fn test_log_prefix() {
+        let service = Service::from_name("web.toml");
+        let pid = Pid::from_raw(42);
+        let prefix = LogPrefix::new("{time} [{service}:{pid}] ", &service, pid, Stream::Stdout);
+        assert_eq!(
+            prefix.render(UNIX_EPOCH + Duration::from_millis(1500)),
+            "1970-01-01T00:00:01.500Z [web.toml:42] "
+        );
+        let prefix = LogPrefix::new("{stream} {unknown}: ", &service, pid, Stream::Stderr);
+        assert_eq!(prefix.render(UNIX_EPOCH), "stderr {unknown}: ");
+    }
+
+    #[test]
+    fn test_prefix_across_chunks() {
+        let service = Service::from_name("web");
+        let prefix = LogPrefix::new("[{service}] ", &service, Pid::this(), Stream::Stdout);
+        let mut destination = Destination::new(&service, Stream::Stdout, Some(prefix));
+        let mut prefixed = |data: &[u8]| destination.prefixed(data, UNIX_EPOCH);
+        assert_eq!(prefixed(b"a\nb"), b"[web] a\n[web] b");
+        assert_eq!(prefixed(b"c\n\n"), b"c\n[web] \n");
+        assert_eq!(prefixed(b"\xff\x00"), b"[web] \xff\x00");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let tempdir = TempDir::new("rotating").unwrap();
+        let path = tempdir.path().join("out.log");
+        let mut file = RotatingFile::new(&path, 10);
+        for _ in 0..5 {
+            file.write(b"Hello\n").unwrap();
+        }
+        // A line is never split, even if it's written in several chunks.
+        file.write(b"Hello ").unwrap();
+        file.write(b"world\nHi\n").unwrap();
+        let read = |name: &str| std::fs::read_to_string(tempdir.path().join(name)).unwrap();
+        assert_eq!(read("out.log"), "Hello\nHello\n");
+        assert_eq!(read("out.log.1"), "Hello\nHello\n");
+        assert_eq!(read("out.log.2"), "Hello\nHello world\n");
+        assert_eq!(read("out.log.3"), "Hi\n");
+    }
+
+    fn pipes() -> ((OwnedFd, OwnedFd), (OwnedFd, OwnedFd)) {
+        (pipe().unwrap(), pipe().unwrap())
+    }
+
+    #[test]
+    fn test_pump() {
+        let tempdir = TempDir::new("pump").unwrap();
+        let mut service = Service::from_name("a");
+        service.stdout = LogOutput::Path(tempdir.path().join("out.log"));
+        service.stderr = LogOutput::Path(tempdir.path().join("err.log"));
+        let outputs = Outputs::new(None, Arc::new(SystemClock));
+        let subscriber = outputs.subscribe("a");
+        let ((stdout_read, stdout_write), (stderr_read, stderr_write)) = pipes();
+        outputs.pump(&service, Pid::this(), stdout_read, stderr_read);
+        let mut stderr = std::fs::File::from(stderr_write);
+        let mut stdout = std::fs::File::from(stdout_write);
+        let mut received = vec![];
+        for (stream, data) in [
+            (Stream::Stderr, &b"first\nsec"[..]),
+            (Stream::Stderr, b"ond"),
+            (Stream::Stdout, b"\x00\xff"),
+        ] {
+            match stream {
+                Stream::Stdout => stdout.write_all(data).unwrap(),
+                Stream::Stderr => stderr.write_all(data).unwrap(),
+            }
+            received.push(subscriber.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+        drop((stdout, stderr));
+        outputs.flush(Duration::from_secs(5));
+
+        // The chunks are relayed as they are read, not split into lines.
+        assert_eq!(
+            received,
+            vec![
+                OutputChunk {
+                    stream: Stream::Stderr,
+                    data: b"first\nsec".to_vec()
+                },
+                OutputChunk {
+                    stream: Stream::Stderr,
+                    data: b"ond".to_vec()
+                },
+                OutputChunk {
+                    stream: Stream::Stdout,
+                    data: b"\x00\xff".to_vec()
+                },
+            ]
+        );
+        let content = std::fs::read_to_string(tempdir.path().join("err.log")).unwrap();
+        assert_eq!(content, "first\nsecond");
+        let content = std::fs::read(tempdir.path().join("out.log")).unwrap();
+        assert_eq!(content, b"\x00\xff");
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        let outputs = Outputs::new(None, Arc::new(SystemClock));
+        let slow = outputs.subscribe("a");
+        let gone = outputs.subscribe("a");
+        drop(gone);
+        let chunk = OutputChunk {
+            stream: Stream::Stdout,
+            data: b"x".to_vec(),
+        };
+        // Never blocks: the output which doesn't fit is dropped.
+        for _ in 0..SUBSCRIBER_CAPACITY * 2 {
+            outputs.publish("a", &chunk);
+        }
+        assert_eq!(slow.try_iter().count(), SUBSCRIBER_CAPACITY);
+        outputs.publish("a", &chunk);
+        assert_eq!(slow.try_iter().count(), 1);
+        assert_eq!(outputs.subscribers.lock().unwrap()["a"].len(), 1);
+    }
+
+    #[test]
+    fn test_tail() {
+        let mut service = Service::from_name("a");
+        service.stdout = LogOutput::Path("/dev/null".into());
+        service.stderr = LogOutput::Path("/dev/null".into());
+        service.failure.output_lines = 2;
+        let outputs = Outputs::new(None, Arc::new(SystemClock));
+        let ((stdout_read, stdout_write), (stderr_read, stderr_write)) = pipes();
+        outputs.pump(&service, Pid::this(), stdout_read, stderr_read);
+        drop(stderr_write);
+        let mut stdout = std::fs::File::from(stdout_write);
+        stdout.write_all(b"1\n2\n").unwrap();
+        stdout.write_all(b"3\n4").unwrap();
+        drop(stdout);
+
+        let lines: Vec<Vec<u8>> = outputs
+            .tail("a", Duration::from_secs(5))
+            .into_iter()
+            .map(|output| output.line)
+            .collect();
+        assert_eq!(lines, vec![b"3\n".to_vec(), b"4".to_vec()]);
+        outputs.clear_tail("a");
+        assert!(outputs.tail("a", Duration::ZERO).is_empty());
+
+        // The wait for the output of a process which is still writing is over at the time of the
+        // clock, not after actually waiting.
+        let clock = Arc::new(SimulatedClock::new());
+        let outputs = Outputs::new(None, clock.clone());
+        let ((stdout_read, _stdout_write), (stderr_read, _stderr_write)) = pipes();
+        outputs.pump(&service, Pid::this(), stdout_read, stderr_read);
+        let start = clock.now();
+        assert!(outputs.tail("a", Duration::from_secs(10)).is_empty());
+        assert!(clock.now() >= start + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_captures() {
+        let outputs = Outputs::new(None, Arc::new(SystemClock));
+        let mut service = Service::from_name("a");
+        assert!(!outputs.captures(&service));
+        assert!(
+            Outputs::new(Some("[{service}] ".into()), Arc::new(SystemClock)).captures(&service)
+        );
+        service.attachable = true;
+        assert!(outputs.captures(&service));
+        service.attachable = false;
+        service.failure.output_lines = 1;
+        assert!(outputs.captures(&service));
+    }
+}
@@ -0,0 +1,338 @@
+//! The order in which the services are going to be started.
+//! Services are grouped in waves: the services of a wave start once all the services they're
+//! waiting for (in the previous waves) are running.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::horust::formats::{
+    Dependency, NetworkCondition, NetworkState, Service, ServiceName, ServiceType, StartDelay,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Services grouped by wave, in starting order.
+    pub waves: Vec<Vec<Step>>,
+    /// Services which will never start, because they're part of a dependency cycle.
+    pub never_started: Vec<ServiceName>,
+    /// Services which are disabled or masked. They're not waited for.
+    pub disabled: Vec<ServiceName>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub service: ServiceName,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub start_delay: StartDelay,
+    /// The services to wait for, each with a note about what gates it (if any).
+    pub after: Vec<(ServiceName, Option<String>)>,
+    /// What it waits for, in short: e.g. `database healthy`, or `migrations done`.
+    pub gates: Vec<String>,
+    /// Other conditions checked before spawning the service.
+    pub conditions: Vec<String>,
+}
+
+impl Plan {
+    /// The plan on a single line, e.g. `wave 1: a, b; wave 2: c (after a healthy)`.
+    pub fn summary(&self) -> String {
+        let mut waves: Vec<String> = self
+            .waves
+            .iter()
+            .enumerate()
+            .map(|(index, wave)| {
+                let steps: Vec<String> = wave.iter().map(Step::summary).collect();
+                format!("wave {}: {}", index + 1, steps.join(", "))
+            })
+            .collect();
+        if !self.never_started.is_empty() {
+            waves.push(format!(
+                "never started (dependency cycle): {}",
+                self.never_started.join(", ")
+            ));
+        }
+        waves.join("; ")
+    }
+
+    pub fn new(services: &[Service]) -> Self {
+        let (enabled, disabled): (Vec<Service>, Vec<Service>) = services
+            .iter()
+            .cloned()
+            .partition(|service| service.enabled);
+        let services = enabled.as_slice();
+        let mut waves: HashMap<&str, usize> = HashMap::new();
+        // A dependency is met once all the services providing it are running.
+        let providers = |name: &ServiceName| {
+            let name = name.clone();
+            services.iter().filter(move |s| s.is_named(&name))
+        };
+        loop {
+            let mut assigned = false;
+            for service in services {
+                if waves.contains_key(service.name.as_str()) {
+                    continue;
+                }
+                let wave = service
+                    .start_after_services()
+                    .into_iter()
+                    .flat_map(|name| providers(name))
+                    .map(|provider| waves.get(provider.name.as_str()).map(|wave| wave + 1))
+                    .try_fold(0, |max, wave| wave.map(|wave| max.max(wave)));
+                if let Some(wave) = wave {
+                    waves.insert(&service.name, wave);
+                    assigned = true;
+                }
+            }
+            if !assigned {
+                break;
+            }
+        }
+
+        let mut plan = Plan {
+            waves: vec![vec![]; waves.values().max().map_or(0, |max| max + 1)],
+            never_started: vec![],
+            disabled: disabled.into_iter().map(|service| service.name).collect(),
+        };
+        for service in services {
+            match waves.get(service.name.as_str()) {
+                Some(wave) => plan.waves[*wave].push(Step::new(service, services)),
+                None => plan.never_started.push(service.name.clone()),
+            }
+        }
+        plan.waves
+            .iter_mut()
+            .for_each(|wave| wave.sort_by(|a, b| a.service.cmp(&b.service)));
+        plan.never_started.sort();
+        plan.disabled.sort();
+        plan
+    }
+}
+
+impl Step {
+    /// The service, followed by what it waits for, e.g. `c (after a healthy, tcp cache:6379)`.
+    pub fn summary(&self) -> String {
+        let waits_for: Vec<&str> = self
+            .gates
+            .iter()
+            .chain(&self.conditions)
+            .map(String::as_str)
+            .collect();
+        if waits_for.is_empty() {
+            self.service.clone()
+        } else {
+            format!("{} (after {})", self.service, waits_for.join(", "))
+        }
+    }
+
+    fn new(service: &Service, services: &[Service]) -> Self {
+        let mut after = vec![];
+        let mut gates = vec![];
+        let mut conditions = vec![];
+        for dependency in &service.start_after {
+            let (name, optional, timeout) = match dependency {
+                Dependency::Service(name) => (name, false, None),
+                Dependency::Detailed {
+                    service,
+                    optional,
+                    timeout,
+                } => (service, *optional, *timeout),
+                Dependency::Tcp { tcp, timeout } => {
+                    conditions.push(with_timeout(format!("tcp {}", tcp), *timeout));
+                    continue;
+                }
+            };
+            for provider in services.iter().filter(|s| s.is_named(name)) {
+                let mut notes = vec![];
+                if provider.name != *name {
+                    notes.push(format!("provides {}", name));
+                }
+                if optional {
+                    notes.push(with_timeout("optional".to_string(), timeout));
+                }
+                match provider.service_type {
+                    ServiceType::Simple => (),
+                    ServiceType::Oneshot => notes.push("oneshot, until it exits".to_string()),
+                    ServiceType::Notify => notes.push("notify, until READY=1".to_string()),
+                }
+                let healthiness = &provider.healthiness;
+                if let Some(endpoint) = &healthiness.http_endpoint {
+                    notes.push(format!("healthcheck http {}", endpoint));
+                }
+                if let Some(file_path) = &healthiness.file_path {
+                    notes.push(format!("healthcheck file {}", file_path.display()));
+                }
+                if let Some(external) = &healthiness.external {
+                    notes.push(format!("healthcheck external {}", external));
+                }
+                let notes = (!notes.is_empty()).then(|| notes.join(", "));
+                after.push((provider.name.clone(), notes));
+                gates.push(match gate(provider) {
+                    Some(gate) => format!("{} {}", provider.name, gate),
+                    None => provider.name.clone(),
+                });
+            }
+        }
+        if let Some(network) = &service.conditions.network {
+            conditions.push(match network {
+                NetworkCondition::State(NetworkState::Online) => "network online".to_string(),
+                NetworkCondition::Dns { dns } => format!("dns {}", dns),
+                NetworkCondition::Url { url } => format!("url {}", url),
+            });
+        }
+        let paths_timeout = service.conditions.paths_timeout;
+        for path in &service.conditions.paths {
+            conditions.push(with_timeout(
+                format!("path {}", path.display()),
+                paths_timeout,
+            ));
+        }
+        Step {
+            service: service.name.clone(),
+            description: service.description.clone(),
+            tags: service.tags.clone(),
+            start_delay: service.start_delay,
+            after,
+            gates,
+            conditions,
+        }
+    }
+}
+
+/// What the services starting after `service` wait for, besides its process being up.
+fn gate(service: &Service) -> Option<&'static str> {
+    match service.service_type {
+        ServiceType::Oneshot => Some("done"),
+        ServiceType::Notify => Some("ready"),
+        ServiceType::Simple => {
+            let healthiness = &service.healthiness;
+            let has_healthchecks = healthiness.http_endpoint.is_some()
+                || healthiness.file_path.is_some()
+                || healthiness.external.is_some();
+            has_healthchecks.then_some("healthy")
+        }
+    }
+}
+
+fn with_timeout(condition: String, timeout: Option<Duration>) -> String {
+    match timeout {
+        Some(timeout) => format!(
+            "{} (timeout {})",
+            condition,
+            humantime::format_duration(timeout)
+        ),
+        None => condition,
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, wave) in self.waves.iter().enumerate() {
+            writeln!(f, "Wave {}:", index + 1)?;
+            for step in wave {
+                write!(f, "  {}", step.service)?;
+                if let Some(description) = &step.description {
+                    write!(f, ": {}", description)?;
+                }
+                if !step.tags.is_empty() {
+                    write!(f, " [{}]", step.tags.join(", "))?;
+                }
+                if !step.start_delay.is_zero() {
+                    write!(f, " (start-delay {})", step.start_delay)?;
+                }
+                writeln!(f)?;
+                for (service, notes) in &step.after {
+                    match notes {
+                        Some(notes) => writeln!(f, "    after {} [{}]", service, notes)?,
+                        None => writeln!(f, "    after {}", service)?,
+                    }
+                }
+                for condition in &step.conditions {
+                    writeln!(f, "    waits for {}", condition)?;
+                }
+            }
+        }
+        if !self.never_started.is_empty() {
+            writeln!(f, "Never started (dependency cycle):")?;
+            for service in &self.never_started {
+                writeln!(f, "  {}", service)?;
+            }
+        }
+        if !self.disabled.is_empty() {
+            writeln!(f, "Disabled:")?;
+            for service in &self.disabled {
+                writeln!(f, "  {}", service)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::horust::formats::{Dependency, Service};
+    use crate::horust::plan::Plan;
+
+    #[test]
+    fn test_plan() {
+        let mut database = Service::from_name("database");
+        database.provides = vec!["db".into()];
+        database.healthiness.file_path = Some("/tmp/up".into());
+        let mut backend = Service::start_after("backend", vec!["db"]);
+        backend.start_delay = Duration::from_secs(2).into();
+        backend.description = Some("The API".into());
+        backend.tags = vec!["critical".into(), "web".into()];
+        backend.start_after.push(Dependency::Tcp {
+            tcp: "cache:6379".into(),
+            timeout: None,
+        });
+        backend.conditions.paths = vec!["/run/cache/cache.sock".into()];
+        backend.conditions.paths_timeout = Some(Duration::from_secs(30));
+        let mut legacy = Service::from_name("legacy");
+        legacy.enabled = false;
+        let services = vec![
+            Service::start_after("frontend", vec!["backend", "database", "legacy"]),
+            legacy,
+            backend,
+            database,
+            Service::from_name("metrics"),
+            Service::start_after("a", vec!["b"]),
+            Service::start_after("b", vec!["a"]),
+        ];
+        let plan = Plan::new(&services);
+        let names = |wave: usize| {
+            plan.waves[wave]
+                .iter()
+                .map(|step| step.service.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(plan.waves.len(), 3);
+        assert_eq!(names(0), vec!["database", "metrics"]);
+        assert_eq!(names(1), vec!["backend"]);
+        assert_eq!(names(2), vec!["frontend"]);
+        assert_eq!(plan.never_started, vec!["a", "b"]);
+        assert_eq!(plan.disabled, vec!["legacy"]);
+
+        let expected = "Wave 2:
+  backend: The API [critical, web] (start-delay 2s)
+    after database [provides db, healthcheck file /tmp/up]
+    waits for tcp cache:6379
+    waits for path /run/cache/cache.sock (timeout 30s)
+";
+        assert!(plan.to_string().contains(expected), "{}", plan);
+        assert!(
+            plan.to_string().ends_with("Disabled:\n  legacy\n"),
+            "{}",
+            plan
+        );
+        assert_eq!(
+            plan.summary(),
+            "wave 1: database, metrics; \
+             wave 2: backend (after database healthy, tcp cache:6379, path /run/cache/cache.sock (timeout 30s)); \
+             wave 3: frontend (after backend, database healthy); \
+             never started (dependency cycle): a, b"
+        );
+    }
+}
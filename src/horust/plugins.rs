@@ -0,0 +1,237 @@
+//! Plugins: the executables in the configured directory are invoked on the lifecycle events of the
+//! services, with the event as a line of JSON on their stdin, for site-specific automation (e.g.
+//! paging someone when a service fails). They're invoked one at a time, in name order, and they
+//! don't affect the services: their failures are only logged.
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{
+    Event, HealthinessStatus, PluginEvent, Plugins, ServiceName, ServiceStatus,
+};
+use crate::horust::subprocess;
+
+/// Written as JSON on the stdin of the plugins.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct Payload {
+    event: PluginEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service: Option<ServiceName>,
+    /// Only for `healthcheck-flapped`: whether the healthchecks are passing now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    healthy: Option<bool>,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+}
+
+/// Invokes the plugins on the configured events, until Horust shuts down.
+pub(crate) fn spawn(bus: BusConnector<Event>, plugins: Plugins, clock: Arc<dyn Clock>) {
+    thread::spawn(move || {
+        // The last healthiness of each service, to find out when it flaps.
+        let mut healthiness: HashMap<ServiceName, HealthinessStatus> = HashMap::new();
+        for ev in bus.iter() {
+            let shutting_down = matches!(ev, Event::ShuttingDownInitiated(_));
+            if let Some(payload) = to_payload(&ev, &mut healthiness, clock.system_time()) {
+                if plugins.events.contains(&payload.event) {
+                    invoke(&plugins, &payload, clock.as_ref());
+                }
+            }
+            if shutting_down {
+                break;
+            }
+        }
+    });
+}
+
+fn to_payload(
+    ev: &Event,
+    healthiness: &mut HashMap<ServiceName, HealthinessStatus>,
+    time: SystemTime,
+) -> Option<Payload> {
+    let (event, service, healthy) = match ev {
+        Event::StatusChanged(service, ServiceStatus::Started) => {
+            (PluginEvent::ServiceStarted, Some(service.clone()), None)
+        }
+        Event::StatusChanged(service, ServiceStatus::Failed) => {
+            (PluginEvent::ServiceFailed, Some(service.clone()), None)
+        }
+        Event::HealthCheck(service, status) => {
+            let previous = healthiness.insert(service.clone(), status.clone())?;
+            if previous == *status {
+                return None;
+            }
+            let healthy = *status == HealthinessStatus::Healthy;
+            (
+                PluginEvent::HealthcheckFlapped,
+                Some(service.clone()),
+                Some(healthy),
+            )
+        }
+        // The healthchecks start over with the next process.
+        Event::ServiceExited(service, _) => {
+            healthiness.remove(service);
+            return None;
+        }
+        Event::SystemReady => (PluginEvent::SystemReady, None, None),
+        Event::ShuttingDownInitiated(_) => (PluginEvent::ShuttingDown, None, None),
+        _ => return None,
+    };
+    let timestamp = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Some(Payload {
+        event,
+        service,
+        healthy,
+        timestamp,
+    })
+}
+
+/// The executables in `dir`, in name order.
+fn executables(plugins: &Plugins) -> Vec<PathBuf> {
+    let mut executables: Vec<PathBuf> = fs::read_dir(&plugins.dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|direntry| direntry.path())
+        .filter(|path| {
+            fs::metadata(path).is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        })
+        .collect();
+    executables.sort();
+    executables
+}
+
+fn invoke(plugins: &Plugins, payload: &Payload, clock: &dyn Clock) {
+    let mut input = serde_json::to_vec(payload).unwrap();
+    input.push(b'\n');
+    for executable in executables(plugins) {
+        debug!(
+            "Plugin {}: invoked with {:?}",
+            executable.display(),
+            payload
+        );
+        let mut cmd = Command::new(&executable);
+        cmd.stdout(Stdio::null());
+        match subprocess::run_with_input(&mut cmd, Some(input.clone()), plugins.timeout, clock) {
+            Ok(output) if output.status.success() => (),
+            Ok(output) => warn!(
+                "Plugin {}: has failed: {}",
+                executable.display(),
+                output.status
+            ),
+            Err(error) => warn!("Plugin {}: {}", executable.display(), error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    use tempdir::TempDir;
+
+    use crate::horust::clock::SystemClock;
+    use crate::horust::formats::{
+        Event, ExitReason, HealthinessStatus, PluginEvent, Plugins, ServiceStatus,
+    };
+    use crate::horust::plugins::{invoke, to_payload};
+
+    #[test]
+    fn test_to_payload() {
+        let mut healthiness = HashMap::new();
+        let mut event = |ev: Event| {
+            to_payload(&ev, &mut healthiness, SystemTime::now())
+                .map(|payload| (payload.event, payload.healthy))
+        };
+        assert_eq!(
+            event(Event::new_status_changed("a", ServiceStatus::Started)),
+            Some((PluginEvent::ServiceStarted, None))
+        );
+        assert_eq!(
+            event(Event::new_status_changed("a", ServiceStatus::Running)),
+            None
+        );
+        let health = |status: HealthinessStatus| Event::HealthCheck("a".into(), status);
+        // The first check is not a flap, nor the ones which don't change anything.
+        assert_eq!(event(health(HealthinessStatus::Healthy)), None);
+        assert_eq!(event(health(HealthinessStatus::Healthy)), None);
+        assert_eq!(
+            event(health(HealthinessStatus::Unhealthy)),
+            Some((PluginEvent::HealthcheckFlapped, Some(false)))
+        );
+        assert_eq!(
+            event(Event::new_service_exited("a".into(), ExitReason::Code(1))),
+            None
+        );
+        assert_eq!(event(health(HealthinessStatus::Healthy)), None);
+        assert_eq!(
+            event(Event::new_status_changed("a", ServiceStatus::Failed)),
+            Some((PluginEvent::ServiceFailed, None))
+        );
+        assert_eq!(
+            event(Event::SystemReady),
+            Some((PluginEvent::SystemReady, None))
+        );
+    }
+
+    #[test]
+    fn test_invoke() {
+        let temp_dir = TempDir::new("plugins").unwrap();
+        let dir = temp_dir.path().join("plugins");
+        fs::create_dir(&dir).unwrap();
+        let output = temp_dir.path().join("output");
+        let write = |name: &str, content: String, mode: u32| {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        let append = |name: &str| {
+            format!(
+                "#!/bin/sh\nread event\necho \"{} $event\" >> {}",
+                name,
+                output.display()
+            )
+        };
+        write("b", append("b"), 0o755);
+        write("a", append("a"), 0o755);
+        write("not-executable", append("not-executable"), 0o644);
+        let plugins = Plugins {
+            dir,
+            events: vec![PluginEvent::ServiceFailed],
+            timeout: Duration::from_secs(5),
+        };
+        let ev = Event::new_status_changed("db.toml", ServiceStatus::Failed);
+        let payload = to_payload(&ev, &mut HashMap::new(), SystemTime::now()).unwrap();
+        invoke(&plugins, &payload, &SystemClock);
+        let lines: Vec<String> = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| line.replace(&payload.timestamp.to_string(), "<timestamp>"))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"a {"event":"service-failed","service":"db.toml","timestamp":<timestamp>}"#,
+                r#"b {"event":"service-failed","service":"db.toml","timestamp":<timestamp>}"#,
+            ]
+        );
+    }
+}
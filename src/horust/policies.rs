@@ -0,0 +1,368 @@
+//! Supervision policies: rules reacting to the lifecycle events of the services with control
+//! actions, like `on worker failed 3 times within 10m: stop worker, start fallback`. They're read
+//! from the `policies` file when the services are loaded, one rule per line, and act like `horustctl` would.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Event, HealthinessStatus, Service, ServiceName, ServiceStatus};
+
+/// What a rule reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    /// The service has a pid.
+    Started,
+    Running,
+    /// The service has failed (it might be restarted).
+    Failed,
+    Finished,
+    /// The healthchecks of the service have started passing, or failing.
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Start(ServiceName),
+    Stop(ServiceName),
+    Restart(ServiceName),
+    Clear(ServiceName),
+}
+
+impl Action {
+    /// The event asking the supervisor to act, like the ones of the control socket.
+    fn to_event(&self) -> Event {
+        match self {
+            Action::Start(service) => Event::Start(service.clone()),
+            Action::Stop(service) => Event::Stop(vec![service.clone()]),
+            Action::Restart(service) => Event::Restart(vec![service.clone()], 1),
+            Action::Clear(service) => Event::Clear(service.clone()),
+        }
+    }
+}
+
+/// A rule: once `trigger` has happened to `service` `times` times (within `within`, if set), the
+/// `actions` are taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Policy {
+    /// As written, for the logs.
+    rule: String,
+    service: ServiceName,
+    trigger: Trigger,
+    times: usize,
+    within: Option<Duration>,
+    actions: Vec<Action>,
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rule)
+    }
+}
+
+/// Reads the policies in `path`, referring to `services`.
+pub(crate) fn load(path: &Path, services: &[Service]) -> Result<Vec<Policy>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed reading {}", path.display()))?;
+    parse(&content, services)
+}
+
+/// One rule per line: `on <service> <trigger> [<n> times [within <time>]]: <action> <service>, ...`.
+/// Empty lines and lines starting with `#` are skipped.
+fn parse(content: &str, services: &[Service]) -> Result<Vec<Policy>> {
+    // Like `horustctl`, the `.toml` can be left out.
+    let resolve = |name: &str| -> Result<ServiceName> {
+        [name.to_string(), format!("{}.toml", name)]
+            .into_iter()
+            .find(|candidate| services.iter().any(|service| service.name == *candidate))
+            .with_context(|| format!("Service not found: {}", name))
+    };
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            parse_rule(line, &resolve).with_context(|| format!("Line {}: {}", number, line))
+        })
+        .collect()
+}
+
+fn parse_rule(rule: &str, resolve: &dyn Fn(&str) -> Result<ServiceName>) -> Result<Policy> {
+    let (condition, actions) = rule
+        .split_once(':')
+        .context("Expected `on <service> <event>: <actions>`")?;
+    let mut words = condition.split_whitespace();
+    if words.next() != Some("on") {
+        bail!("Expected `on` at the start of the rule");
+    }
+    let service = resolve(words.next().context("Missing the service")?)?;
+    let trigger = match words.next().context("Missing the event")? {
+        "started" => Trigger::Started,
+        "running" => Trigger::Running,
+        "failed" => Trigger::Failed,
+        "finished" => Trigger::Finished,
+        "healthy" => Trigger::Healthy,
+        "unhealthy" => Trigger::Unhealthy,
+        other => bail!(
+            "Unknown event: {}, expected started, running, failed, finished, healthy or unhealthy",
+            other
+        ),
+    };
+    let rest: Vec<&str> = words.collect();
+    let (times, within) = match rest.as_slice() {
+        [] => (1, None),
+        [times, "times" | "time"] => (parse_times(times)?, None),
+        [times, "times" | "time", "within", within @ ..] if !within.is_empty() => {
+            let within = within.join(" ");
+            let within = humantime::parse_duration(&within)
+                .with_context(|| format!("Invalid time: {}", within))?;
+            (parse_times(times)?, Some(within))
+        }
+        _ => bail!("Expected `<n> times [within <time>]` after the event"),
+    };
+    let actions = actions
+        .split(',')
+        .map(|action| {
+            let (verb, service) = action
+                .trim()
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("Expected `<action> <service>`: {}", action.trim()))?;
+            let service = resolve(service.trim())?;
+            Ok(match verb {
+                "start" => Action::Start(service),
+                "stop" => Action::Stop(service),
+                "restart" => Action::Restart(service),
+                "clear" => Action::Clear(service),
+                other => bail!(
+                    "Unknown action: {}, expected start, stop, restart or clear",
+                    other
+                ),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Policy {
+        rule: rule.to_string(),
+        service,
+        trigger,
+        times,
+        within,
+        actions,
+    })
+}
+
+fn parse_times(times: &str) -> Result<usize> {
+    match times.parse() {
+        Ok(times) if times > 0 => Ok(times),
+        _ => bail!("Invalid number of times: {}", times),
+    }
+}
+
+/// The policies, and what has happened so far.
+#[derive(Debug)]
+struct Policies {
+    policies: Vec<Policy>,
+    /// When the trigger of each policy has happened, since its actions were last taken.
+    occurrences: Vec<VecDeque<Instant>>,
+    /// The last healthiness of each service, to find out when it changes.
+    healthiness: HashMap<ServiceName, HealthinessStatus>,
+}
+
+impl Policies {
+    fn new(policies: Vec<Policy>) -> Self {
+        Self {
+            occurrences: vec![VecDeque::new(); policies.len()],
+            policies,
+            healthiness: HashMap::new(),
+        }
+    }
+
+    /// The actions to take on `ev`, happened at `now`.
+    fn handle(&mut self, ev: &Event, now: Instant) -> Vec<Action> {
+        let (service, trigger) = match ev {
+            Event::StatusChanged(service, status) => match status {
+                ServiceStatus::Started => (service, Trigger::Started),
+                ServiceStatus::Running => (service, Trigger::Running),
+                ServiceStatus::Failed => (service, Trigger::Failed),
+                ServiceStatus::Finished => (service, Trigger::Finished),
+                _ => return vec![],
+            },
+            Event::HealthCheck(service, status) => {
+                if self.healthiness.insert(service.clone(), status.clone()) == Some(status.clone())
+                {
+                    return vec![];
+                }
+                match status {
+                    HealthinessStatus::Healthy => (service, Trigger::Healthy),
+                    HealthinessStatus::Unhealthy => (service, Trigger::Unhealthy),
+                }
+            }
+            // The healthchecks start over with the next process.
+            Event::ServiceExited(service, _) => {
+                self.healthiness.remove(service);
+                return vec![];
+            }
+            _ => return vec![],
+        };
+        let mut actions = vec![];
+        for (policy, occurrences) in self.policies.iter().zip(self.occurrences.iter_mut()) {
+            if policy.service != *service || policy.trigger != trigger {
+                continue;
+            }
+            occurrences.push_back(now);
+            if let Some(within) = policy.within {
+                while occurrences
+                    .front()
+                    .is_some_and(|at| now.saturating_duration_since(*at) > within)
+                {
+                    occurrences.pop_front();
+                }
+            }
+            if occurrences.len() >= policy.times {
+                info!("Policy `{}`: taking its actions", policy);
+                occurrences.clear();
+                actions.extend(policy.actions.iter().cloned());
+            }
+        }
+        actions
+    }
+}
+
+/// Applies the policies to the events on the bus, until Horust shuts down.
+pub(crate) fn spawn(bus: BusConnector<Event>, policies: Vec<Policy>, clock: Arc<dyn Clock>) {
+    if policies.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut policies = Policies::new(policies);
+        for ev in bus.iter() {
+            if matches!(ev, Event::ShuttingDownInitiated(_)) {
+                break;
+            }
+            for action in policies.handle(&ev, clock.now()) {
+                bus.send_event(action.to_event());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::horust::clock::{Clock, SimulatedClock};
+    use crate::horust::formats::{Event, HealthinessStatus, Service, ServiceStatus};
+    use crate::horust::policies::{parse, Action, Policies, Policy, Trigger};
+
+    fn services() -> Vec<Service> {
+        ["a.toml", "b.toml", "c.toml"]
+            .into_iter()
+            .map(Service::from_name)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let content = "
+# If a crashes 3 times, switch to c.
+on a failed 3 times within 10m: stop b.toml, start c
+
+on c.toml unhealthy: restart c
+";
+        let policies = parse(content, &services()).unwrap();
+        assert_eq!(
+            policies,
+            vec![
+                Policy {
+                    rule: "on a failed 3 times within 10m: stop b.toml, start c".into(),
+                    service: "a.toml".into(),
+                    trigger: Trigger::Failed,
+                    times: 3,
+                    within: Some(Duration::from_secs(600)),
+                    actions: vec![
+                        Action::Stop("b.toml".into()),
+                        Action::Start("c.toml".into())
+                    ],
+                },
+                Policy {
+                    rule: "on c.toml unhealthy: restart c".into(),
+                    service: "c.toml".into(),
+                    trigger: Trigger::Unhealthy,
+                    times: 1,
+                    within: None,
+                    actions: vec![Action::Restart("c.toml".into())],
+                },
+            ]
+        );
+
+        let error = |content: &str| format!("{:#}", parse(content, &services()).unwrap_err());
+        assert!(error("on d failed: start c").contains("Service not found: d"));
+        assert!(error("\non a crashed: start c").starts_with("Line 2: on a crashed: start c"));
+        assert!(error("on a failed 0 times: start c").contains("Invalid number of times: 0"));
+        assert!(error("on a failed 2 times within soon: start c").contains("Invalid time: soon"));
+        assert!(error("on a failed: kill c").contains("Unknown action: kill"));
+        assert!(error("on a failed start c").contains("Expected `on <service> <event>"));
+        assert!(error("when a failed: start c").contains("Expected `on`"));
+    }
+
+    #[test]
+    fn test_handle() {
+        let policies = parse(
+            "on a failed 3 times within 1m: stop b, start c\non c healthy: clear a",
+            &services(),
+        )
+        .unwrap();
+        let mut policies = Policies::new(policies);
+        let clock = SimulatedClock::new();
+        let failed = Event::new_status_changed("a.toml", ServiceStatus::Failed);
+        let mut fail = |after: u64| {
+            clock.advance(Duration::from_secs(after));
+            policies.handle(&failed, clock.now())
+        };
+        assert!(fail(0).is_empty());
+        assert!(fail(10).is_empty());
+        // The first failure is over a minute ago.
+        assert!(fail(55).is_empty());
+        assert_eq!(
+            fail(1),
+            vec![
+                Action::Stop("b.toml".into()),
+                Action::Start("c.toml".into())
+            ]
+        );
+        // It starts over.
+        assert!(fail(1).is_empty());
+
+        let mut policies = Policies::new(parse("on c healthy: clear a", &services()).unwrap());
+        let now = clock.now();
+        let health = |status| Event::HealthCheck("c.toml".into(), status);
+        let cleared = vec![Action::Clear("a.toml".into())];
+        assert_eq!(
+            policies.handle(&health(HealthinessStatus::Healthy), now),
+            cleared
+        );
+        // Only when the healthiness changes.
+        assert!(policies
+            .handle(&health(HealthinessStatus::Healthy), now)
+            .is_empty());
+        assert!(policies
+            .handle(&health(HealthinessStatus::Unhealthy), now)
+            .is_empty());
+        assert_eq!(
+            policies.handle(&health(HealthinessStatus::Healthy), now),
+            cleared
+        );
+        assert_eq!(
+            Action::Stop("b.toml".into()).to_event(),
+            Event::Stop(vec!["b.toml".into()])
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Monitors the pressure stall information (PSI) of the system, or of the cgroup of Horust.
+//! When a resource goes over its threshold, the low priority services listed in the monitor are
+//! paused, leaving the resource to the rest of the services.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::cgroups;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{
+    Event, PausedBy, PressureMonitor, PressureResource, PressureSource, Service,
+};
+
+pub(crate) fn spawn(
+    bus: BusConnector<Event>,
+    mut monitor: PressureMonitor,
+    services: &[Service],
+    clock: Arc<dyn Clock>,
+) {
+    monitor.pause.retain(|name| {
+        let exists = services.iter().any(|service| service.name == *name);
+        if !exists {
+            warn!("Pressure monitor: cannot pause {}, no such service", name);
+        }
+        exists
+    });
+    thread::spawn(move || {
+        if let Err(error) = run(bus, monitor, clock.as_ref()) {
+            error!("Pressure monitor disabled: {:?}", error);
+        }
+    });
+}
+
+fn run(bus: BusConnector<Event>, monitor: PressureMonitor, clock: &dyn Clock) -> Result<()> {
+    let dir = match monitor.source {
+        PressureSource::System => PathBuf::from("/proc/pressure"),
+        PressureSource::Cgroup => cgroups::own_cgroup()?,
+    };
+    let path = |resource: PressureResource| match monitor.source {
+        PressureSource::System => dir.join(resource.to_string()),
+        PressureSource::Cgroup => dir.join(format!("{}.pressure", resource)),
+    };
+    for (resource, _threshold) in monitor.thresholds() {
+        fs::metadata(path(resource))
+            .with_context(|| format!("Cannot read {}", path(resource).display()))?;
+    }
+    let mut under_pressure = HashSet::new();
+    loop {
+        let shutting_down = bus
+            .try_get_events()
+            .into_iter()
+            .any(|ev| matches!(ev, Event::ShuttingDownInitiated(_)));
+        if shutting_down {
+            return Ok(());
+        }
+        let was_under_pressure = !under_pressure.is_empty();
+        for (resource, threshold) in monitor.thresholds() {
+            let avg10 = match fs::read_to_string(path(resource)).map(|psi| parse_avg10(&psi)) {
+                Ok(Some(avg10)) => avg10,
+                _ => {
+                    debug!("Failed reading the {} pressure", resource);
+                    continue;
+                }
+            };
+            let high = avg10 >= threshold;
+            if high == under_pressure.contains(&resource) {
+                continue;
+            }
+            if high {
+                warn!(
+                    "The {} pressure is at {}%, over the threshold of {}%",
+                    resource, avg10, threshold
+                );
+                under_pressure.insert(resource);
+            } else {
+                info!("The {} pressure is back to {}%", resource, avg10);
+                under_pressure.remove(&resource);
+            }
+            bus.send_event(Event::PressureChanged(resource, high));
+        }
+        let is_under_pressure = !under_pressure.is_empty();
+        if was_under_pressure != is_under_pressure {
+            for name in &monitor.pause {
+                bus.send_event(if is_under_pressure {
+                    Event::Pause(name.clone(), PausedBy::Pressure)
+                } else {
+                    Event::Resume(name.clone(), PausedBy::Pressure)
+                });
+            }
+        }
+        clock.sleep(monitor.interval);
+    }
+}
+
+/// The share of time some task was stalled in the last 10 seconds, in a PSI file.
+fn parse_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::pressure::parse_avg10;
+
+    #[test]
+    fn test_parse_avg10() {
+        let psi = "some avg10=12.50 avg60=1.15 avg300=0.62 total=282423488\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+        assert_eq!(parse_avg10(psi), Some(12.5));
+        assert_eq!(parse_avg10("full avg10=0.00 avg60=0.00"), None);
+        assert_eq!(parse_avg10(""), None);
+    }
+}
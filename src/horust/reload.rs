@@ -0,0 +1,208 @@
+//! Reloads the definitions of the services from their files, on `Event::Reload` (SIGHUP or
+//! `horustctl reload`). Only the services whose definition has changed are updated, with an
+//! `Event::ServiceChanged`: the supervisor restarts them, unless they have `reload-on-change`
+//! disabled.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::{Event, Service, ServiceName, ServiceType};
+use crate::horust::Sources;
+
+/// Keeps track of the definitions of the services, to find out which ones have changed.
+#[derive(Debug)]
+struct Reloader {
+    sources: Option<Sources>,
+    /// As loaded from the files, before being set up for running.
+    loaded: HashMap<ServiceName, Service>,
+    /// As used by the other components.
+    running: HashMap<ServiceName, Arc<Service>>,
+}
+
+/// Reloads the services every time `Event::Reload` is received. `loaded` are the services as
+/// loaded from `sources`, and `running` as they were set up for running.
+pub(crate) fn spawn(
+    bus: BusConnector<Event>,
+    sources: Option<Sources>,
+    loaded: Vec<Service>,
+    running: Vec<Arc<Service>>,
+) {
+    let mut reloader = Reloader {
+        sources,
+        loaded: loaded
+            .into_iter()
+            .map(|service| (service.name.clone(), service))
+            .collect(),
+        running: running
+            .into_iter()
+            .map(|service| (service.name.clone(), service))
+            .collect(),
+    };
+    thread::spawn(move || {
+        for ev in bus.iter() {
+            match ev {
+                Event::Reload => match reloader.reload() {
+                    Ok(changed) => {
+                        info!("Services reloaded, {} changed.", changed.len());
+                        for service in changed {
+                            bus.send_event(Event::ServiceChanged(service));
+                        }
+                    }
+                    Err(error) => error!("Failed reloading the services: {:?}", error),
+                },
+                Event::ShuttingDownInitiated(_) => break,
+                _ => {}
+            }
+        }
+    });
+}
+
+impl Reloader {
+    /// Loads the services again, and returns the ones whose definition has changed.
+    fn reload(&mut self) -> Result<Vec<Arc<Service>>> {
+        let sources = self
+            .sources
+            .as_ref()
+            .context("The services weren't loaded from services directories")?;
+        let services = sources.load()?;
+        for name in self.loaded.keys() {
+            if !services.iter().any(|service| service.name == *name) {
+                warn!("{}: removed, but it's kept until Horust restarts", name);
+            }
+        }
+        let mut changed = vec![];
+        for service in services {
+            let Some(loaded) = self.loaded.get(&service.name) else {
+                warn!(
+                    "{}: added, but it's ignored until Horust restarts",
+                    service.name
+                );
+                continue;
+            };
+            if service == *loaded {
+                continue;
+            }
+            let not_applied = set_up_at_start(&service, loaded);
+            if !not_applied.is_empty() {
+                warn!(
+                    service = service.name.as_str();
+                    "{}: the changes of {} are only applied once Horust restarts",
+                    service.name,
+                    not_applied.join(", ")
+                );
+            }
+            let running = prepare(service.clone(), loaded, &self.running[&service.name]);
+            let running = Arc::new(running);
+            self.running.insert(service.name.clone(), running.clone());
+            self.loaded.insert(service.name.clone(), service);
+            changed.push(running);
+        }
+        Ok(changed)
+    }
+}
+
+/// The options which have changed from `loaded`, but which are only set up when Horust starts.
+fn set_up_at_start(service: &Service, loaded: &Service) -> Vec<&'static str> {
+    let is_notify = |service: &Service| service.service_type == ServiceType::Notify;
+    [
+        ("enabled", service.enabled != loaded.enabled),
+        ("type", is_notify(service) != is_notify(loaded)),
+        (
+            "user",
+            service.has_dynamic_user() != loaded.has_dynamic_user(),
+        ),
+        ("watch", service.watch != loaded.watch),
+        ("resources", service.resources != loaded.resources),
+        ("socket", service.socket != loaded.socket),
+        ("scale", service.scale != loaded.scale),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(option, _)| option)
+    .collect()
+}
+
+/// Sets up `service` for running like the `running` definition it replaces, which was loaded as
+/// `loaded`.
+fn prepare(mut service: Service, loaded: &Service, running: &Service) -> Service {
+    service.environment.global = running.environment.global.clone();
+    service.dynamic_user = running.dynamic_user.clone();
+    service.cgroup = running.cgroup.clone();
+    service.notify_socket = running.notify_socket.clone();
+    // It might have been changed by `run-as`.
+    if service.user == loaded.user {
+        service.user = running.user.clone();
+    }
+    service
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+
+    use crate::horust::formats::{Service, User};
+    use crate::horust::reload::Reloader;
+    use crate::horust::Horust;
+
+    #[test]
+    fn test_reload() {
+        let temp_dir = TempDir::new("reload").unwrap();
+        // The commands are never run.
+        let write = |name: &str, content: &str| {
+            let content = format!("{}\ncommand-check = \"warn\"", content);
+            fs::write(temp_dir.path().join(name), content).unwrap();
+        };
+        write("api.toml", "command = \"api --port 80\"");
+        write("worker.toml", "command = \"worker\"");
+        let horust = Horust::from_services_dirs(&[temp_dir.path().into()]).unwrap();
+        let loaded = horust.get_services().to_vec();
+        // As set up by `run-as`.
+        let running: Vec<Arc<Service>> = loaded
+            .iter()
+            .cloned()
+            .map(|mut service| {
+                service.user = User::Uid(1000);
+                service.cgroup = Some(PathBuf::from("/sys/fs/cgroup/horust"));
+                Arc::new(service)
+            })
+            .collect();
+        let mut reloader = Reloader {
+            sources: horust.sources.clone(),
+            loaded: loaded.into_iter().map(|s| (s.name.clone(), s)).collect(),
+            running: running.into_iter().map(|s| (s.name.clone(), s)).collect(),
+        };
+        assert!(reloader.reload().unwrap().is_empty());
+
+        write("api.toml", "command = \"api --port 8080\"");
+        write("new.toml", "command = \"new\"");
+        let changed = reloader.reload().unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "api.toml");
+        assert_eq!(changed[0].command, "api --port 8080");
+        assert_eq!(changed[0].user, User::Uid(1000));
+        assert!(changed[0].cgroup.is_some());
+        // Already applied.
+        assert!(reloader.reload().unwrap().is_empty());
+
+        // Nothing is applied if any of the services is invalid.
+        write(
+            "worker.toml",
+            "command = \"worker\"\nstart-after = [\"missing\"]",
+        );
+        assert!(reloader.reload().is_err());
+
+        let mut reloader = Reloader {
+            sources: None,
+            ..reloader
+        };
+        assert!(reloader.reload().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Minimal implementation of the systemd notification protocol (`sd_notify(3)`).
+//!
+//! When Horust itself is run by systemd as a `Type=notify` unit, systemd provides the address
+//! of a datagram socket in the `NOTIFY_SOCKET` environment variable. Horust uses it to
+//! report when all the services are up (`READY=1`) and when it's shutting down (`STOPPING=1`).
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+pub(crate) const READY: &str = "READY=1";
+pub(crate) const STOPPING: &str = "STOPPING=1";
+
+/// Sends `state` to the service manager, if Horust was started with a `NOTIFY_SOCKET`.
+/// It's a no-op otherwise.
+pub(crate) fn notify(state: &str) {
+    if let Ok(socket) = std::env::var(NOTIFY_SOCKET_ENV) {
+        debug!("Notifying service manager on {}: {}", socket, state);
+        if let Err(err) = send_to(&socket, state) {
+            error!("Failed notifying {} to the service manager: {}", state, err);
+        }
+    }
+}
+
+/// Sends `state` to the datagram socket at `socket`. A leading `@` denotes an abstract socket.
+fn send_to(socket: &str, state: &str) -> io::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(abstract_name) => {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            sock.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+
+    use tempdir::TempDir;
+
+    use crate::horust::sd_notify::{send_to, READY};
+
+    #[test]
+    fn test_send_to() {
+        let tempdir = TempDir::new("sd_notify").unwrap();
+        let path = tempdir.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send_to(path.to_str().unwrap(), READY).unwrap();
+        let mut buf = [0; 64];
+        let read = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..read], READY.as_bytes());
+    }
+
+    #[test]
+    fn test_send_to_missing_socket() {
+        let tempdir = TempDir::new("sd_notify").unwrap();
+        let path = tempdir.path().join("missing.sock");
+        send_to(path.to_str().unwrap(), READY).unwrap_err();
+    }
+}
@@ -0,0 +1,174 @@
+//! Socket activation: the listening sockets of the services are bound by Horust, and passed to
+//! their processes. A service can be restarted without refusing the incoming connections, and a
+//! `lazy` one is only spawned once the first connection comes in.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{AsFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::{Event, ListenAddress, Service, ServiceName};
+
+/// The sockets are passed starting from this file descriptor, after stdin, stdout and stderr.
+pub(crate) const LISTEN_FDS_START: RawFd = 3;
+
+/// How often the activator checks whether Horust is shutting down.
+const ACTIVATOR_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The listening sockets of the services, shared between the components.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Sockets {
+    sockets: Arc<HashMap<ServiceName, Vec<OwnedFd>>>,
+}
+
+impl Sockets {
+    /// Binds the sockets of all the `services`. If any of the sockets of a service can't be bound,
+    /// the service has none, and it will fail to spawn.
+    pub(crate) fn bind(services: &[Arc<Service>]) -> Self {
+        let sockets = services
+            .iter()
+            .filter(|service| !service.socket.listen.is_empty())
+            .filter_map(|service| {
+                let sockets = service
+                    .socket
+                    .listen
+                    .iter()
+                    .map(|address| {
+                        bind(address).map_err(|error| {
+                            error!(
+                                service = service.name.as_str();
+                                "{}: failed binding {}: {}",
+                                service.name,
+                                address,
+                                error
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()?;
+                Some((service.name.clone(), sockets))
+            })
+            .collect();
+        Self {
+            sockets: Arc::new(sockets),
+        }
+    }
+
+    /// The sockets of `service`, in the order of its `listen` addresses. None if they couldn't be
+    /// bound.
+    pub(crate) fn get(&self, service: &str) -> Option<&[OwnedFd]> {
+        self.sockets.get(service).map(Vec::as_slice)
+    }
+}
+
+fn bind(address: &ListenAddress) -> io::Result<OwnedFd> {
+    match address {
+        ListenAddress::Tcp(address) => TcpListener::bind(address).map(OwnedFd::from),
+        ListenAddress::Unix(path) => {
+            // A stale socket left by a previous run would make the bind fail.
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            UnixListener::bind(path).map(OwnedFd::from)
+        }
+    }
+}
+
+/// Removes the Unix sockets of the services, once they have finished.
+pub(crate) fn cleanup(services: &[Service]) {
+    let paths = services
+        .iter()
+        .flat_map(|service| &service.socket.listen)
+        .filter_map(|address| match address {
+            ListenAddress::Unix(path) => Some(path),
+            ListenAddress::Tcp(_) => None,
+        });
+    for path in paths {
+        if let Err(error) = fs::remove_file(path) {
+            debug!("Failed removing {}: {}", path.display(), error);
+        }
+    }
+}
+
+/// Sends `Event::SocketActivated` for each of the lazy `services` once a connection comes in on
+/// one of its sockets, or right away if they couldn't be bound.
+pub(crate) fn spawn_activator(
+    bus: BusConnector<Event>,
+    sockets: Sockets,
+    services: &[Arc<Service>],
+) {
+    let lazy: Vec<ServiceName> = services
+        .iter()
+        .filter(|service| service.socket.lazy)
+        .map(|service| service.name.clone())
+        .collect();
+    if lazy.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        if let Err(error) = run_activator(&bus, &sockets, lazy) {
+            error!("Socket activation disabled: {}", error);
+        }
+    });
+}
+
+fn run_activator(
+    bus: &BusConnector<Event>,
+    sockets: &Sockets,
+    mut waiting: Vec<ServiceName>,
+) -> nix::Result<()> {
+    waiting.retain(|service| {
+        let bound = sockets.get(service).is_some();
+        if !bound {
+            bus.send_event(Event::SocketActivated(service.clone()));
+        }
+        bound
+    });
+    while !waiting.is_empty() {
+        let shutting_down = bus
+            .try_get_events()
+            .into_iter()
+            .any(|ev| matches!(ev, Event::ShuttingDownInitiated(_)));
+        if shutting_down {
+            return Ok(());
+        }
+        // The service each socket belongs to.
+        let (owners, mut fds): (Vec<&ServiceName>, Vec<PollFd>) = waiting
+            .iter()
+            .flat_map(|service| {
+                sockets
+                    .get(service)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(move |socket| (service, PollFd::new(socket.as_fd(), PollFlags::POLLIN)))
+            })
+            .unzip();
+        let timeout = PollTimeout::try_from(ACTIVATOR_INTERVAL).unwrap_or(PollTimeout::MAX);
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => (),
+            Err(error) => return Err(error),
+        }
+        let mut activated: Vec<ServiceName> = owners
+            .into_iter()
+            .zip(&fds)
+            .filter(|(_, fd)| fd.revents().is_some_and(|revents| !revents.is_empty()))
+            .map(|(service, _)| service.clone())
+            .collect();
+        activated.dedup();
+        for service in activated {
+            info!(service = service.as_str(); "{}: activated by an incoming connection", service);
+            bus.send_event(Event::SocketActivated(service.clone()));
+            waiting.retain(|waiting| *waiting != service);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+//! Helper processes spawned by Horust itself, for instance to run hooks.
+//!
+//! The reaper collects the exit status of every child of Horust, so the helpers cannot simply
+//! be `wait()`ed. Every helper is registered here while it's running: if the reaper collects it
+//! first, it will hand its exit status over via `reaped`.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, SIGKILL};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+use crate::horust::clock::Clock;
+use crate::horust::formats::Service;
+
+/// Running helpers, and their exit status if they have been collected by the reaper.
+static HELPERS: Mutex<BTreeMap<Pid, Option<ExitStatus>>> = Mutex::new(BTreeMap::new());
+
+/// How often to check if a helper has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Builds a `Command` running `command` with the user, environment and working directory of `service`.
+pub(crate) fn service_command(service: &Service, command: &str) -> Result<Command> {
+    let chunks = shlex::split(command).with_context(|| format!("Invalid command: {}", command))?;
+    let (program, args) = chunks
+        .split_first()
+        .with_context(|| format!("Empty command for service: {}", service.name))?;
+    let environment = service.get_environment()?;
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .env_clear()
+        .envs(environment.iter().filter_map(|kv| kv.split_once('=')))
+        .current_dir(&service.working_directory)
+        .uid(service.uid()?.as_raw());
+    if let Some(gid) = service.gid() {
+        cmd.gid(gid.as_raw());
+    }
+    Ok(cmd)
+}
+
+/// Runs `command` until it exits, killing it (and its process group) after `timeout`.
+/// Only the piped streams of `command` are captured in the returned `Output`.
+pub(crate) fn run(
+    command: &mut Command,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> io::Result<Output> {
+    run_with_input(command, None, timeout, clock)
+}
+
+/// Like `run`, with `input` written to the stdin of `command`, if set.
+pub(crate) fn run_with_input(
+    command: &mut Command,
+    input: Option<Vec<u8>>,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> io::Result<Output> {
+    command.process_group(0);
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let mut child = {
+        let mut helpers = HELPERS.lock().unwrap();
+        let child = command.spawn()?;
+        helpers.insert(Pid::from_raw(child.id() as i32), None);
+        child
+    };
+    let pid = Pid::from_raw(child.id() as i32);
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        // In the background, since the helper might not read all of it.
+        thread::spawn(move || stdin.write_all(&input));
+    }
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let status = wait(pid, timeout, clock);
+    HELPERS.lock().unwrap().remove(&pid);
+    Ok(Output {
+        status: status?,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Called by the reaper with the status of a reaped process which doesn't belong to any service.
+/// Returns false if `pid` isn't a helper either.
+pub(crate) fn reaped(pid: Pid, wait_status: WaitStatus) -> bool {
+    match HELPERS.lock().unwrap().get_mut(&pid) {
+        Some(slot) => {
+            *slot = to_exit_status(wait_status);
+            true
+        }
+        None => false,
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(source: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut source) = source {
+            let _ = source.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Waits for the helper to exit. If the timeout expires, it's killed and an error is returned.
+fn wait(pid: Pid, timeout: Duration, clock: &dyn Clock) -> io::Result<ExitStatus> {
+    let deadline = clock.now() + timeout;
+    let mut timed_out = false;
+    loop {
+        if let Some(status) = try_wait(pid)? {
+            return if timed_out {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Process timed out after {:?}", timeout),
+                ))
+            } else {
+                Ok(status)
+            };
+        }
+        if !timed_out && clock.now() >= deadline {
+            // The helper is the leader of its own process group.
+            let _ = kill(Pid::from_raw(-pid.as_raw()), SIGKILL);
+            timed_out = true;
+        }
+        clock.sleep(POLL_INTERVAL);
+    }
+}
+
+fn try_wait(pid: Pid) -> io::Result<Option<ExitStatus>> {
+    let helpers = HELPERS.lock().unwrap();
+    if let Some(Some(status)) = helpers.get(&pid) {
+        return Ok(Some(*status));
+    }
+    match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+        Ok(wait_status) => Ok(to_exit_status(wait_status)),
+        // It was collected by the reaper, which will soon report its exit status.
+        Err(Errno::ECHILD) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn to_exit_status(wait_status: WaitStatus) -> Option<ExitStatus> {
+    match wait_status {
+        WaitStatus::Exited(_pid, code) => Some(ExitStatus::from_raw(code << 8)),
+        WaitStatus::Signaled(_pid, signal, core_dumped) => Some(ExitStatus::from_raw(
+            signal as i32 | if core_dumped { 0x80 } else { 0 },
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    use crate::horust::clock::SystemClock;
+    use crate::horust::subprocess::{run, run_with_input};
+
+    #[test]
+    fn test_run() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "echo hello; exit 3"])
+            .stdout(Stdio::piped());
+        let output = run(&mut cmd, Duration::from_secs(5), &SystemClock).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"hello\n");
+    }
+
+    #[test]
+    fn test_run_with_input() {
+        let mut cmd = Command::new("/bin/cat");
+        cmd.stdout(Stdio::piped());
+        let input = b"hello".to_vec();
+        let output =
+            run_with_input(&mut cmd, Some(input), Duration::from_secs(5), &SystemClock).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello");
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "sleep 10"]);
+        let start = Instant::now();
+        let err = run(&mut cmd, Duration::from_millis(200), &SystemClock).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,177 @@
+//! How the supervisor acts on the processes of the services: `System` forks and signals real
+//! processes, while the simulation (see `simulation`) only pretends to.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::cgroups;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Event, ExitReason, KillMode, Service};
+use crate::horust::output::Outputs;
+use crate::horust::sockets::Sockets;
+use crate::horust::supervisor::reaper::Reaper;
+use crate::horust::supervisor::repo::Repo;
+use crate::horust::supervisor::service_handler::ServiceHandler;
+use crate::horust::supervisor::{hooks, process_spawner, sandbox, signal_handling};
+
+/// PID 1 is reserved for the init process.
+const INIT_PID: Pid = Pid::from_raw(1);
+
+pub(crate) trait Backend: Debug + Send {
+    /// Spawns a process for `service`, once its start delay and `backoff` have passed. Its pid
+    /// is then published with a `PidChanged` event, or a `SpawnFailed` one if it couldn't start.
+    fn spawn(&mut self, service: Arc<Service>, backoff: Duration, bus: BusConnector<Event>);
+
+    /// Runs the pre-stop hook of `service`, and then publishes a `PreStopDone` event.
+    fn spawn_pre_stop(&mut self, service: Arc<Service>, bus: BusConnector<Event>);
+
+    /// Runs the `on-crash.debug-command` of `service`, against its process `pid` which has
+    /// crashed with `exit_reason`.
+    fn spawn_debug_command(&mut self, service: Arc<Service>, pid: Pid, exit_reason: ExitReason);
+
+    /// Sends `signal` to the processes of the service selected by its `kill-mode`, if it's running.
+    fn send_signal(&mut self, sh: &ServiceHandler, signal: Signal);
+
+    /// Stops (or continues) the processes of the service, with a `SIGSTOP` (or a `SIGCONT`).
+    fn set_paused(&mut self, sh: &ServiceHandler, paused: bool) {
+        let signal = if paused {
+            signal::SIGSTOP
+        } else {
+            signal::SIGCONT
+        };
+        self.send_signal(sh, signal);
+    }
+
+    /// True if `pid` has a handler for `signal`.
+    fn catches_signal(&self, pid: Pid, signal: Signal) -> bool;
+
+    /// Collects the processes which have exited, as `ServiceExited` events.
+    fn reap(&mut self, repo: &Repo) -> Vec<Event>;
+
+    /// All the services have finished.
+    fn finished(&mut self) {}
+}
+
+/// The processes of the services are real ones.
+#[derive(Debug)]
+pub(crate) struct System {
+    /// Where the output of the spawned services is forwarded.
+    outputs: Outputs,
+    clock: Arc<dyn Clock>,
+    /// Passed to the services which use socket activation.
+    sockets: Sockets,
+    reaper: Reaper,
+}
+
+impl System {
+    pub(crate) fn new(outputs: Outputs, clock: Arc<dyn Clock>, sockets: Sockets) -> Self {
+        Self {
+            outputs,
+            clock,
+            sockets,
+            reaper: Reaper::default(),
+        }
+    }
+}
+
+impl Backend for System {
+    fn spawn(&mut self, service: Arc<Service>, backoff: Duration, bus: BusConnector<Event>) {
+        let (outputs, clock) = (self.outputs.clone(), self.clock.clone());
+        let sockets = self.sockets.clone();
+        process_spawner::spawn_fork_exec_handler(service, backoff, bus, outputs, clock, sockets);
+    }
+
+    fn spawn_pre_stop(&mut self, service: Arc<Service>, bus: BusConnector<Event>) {
+        hooks::spawn_pre_stop(service, bus, self.clock.clone());
+    }
+
+    fn spawn_debug_command(&mut self, service: Arc<Service>, pid: Pid, exit_reason: ExitReason) {
+        hooks::spawn_debug_command(service, pid, exit_reason, self.clock.clone());
+    }
+
+    fn send_signal(&mut self, sh: &ServiceHandler, signal: Signal) {
+        let Some(pid) = sh.pid() else {
+            return;
+        };
+        let result = match sh.service().termination.kill_mode {
+            KillMode::Process => signal::kill(pid, signal),
+            // The main process is the leader of its own session and process group.
+            KillMode::ProcessGroup => signal::killpg(pid, signal),
+            KillMode::Cgroup => cgroups::pids(sh.service())
+                .unwrap_or_else(|| vec![pid])
+                .into_iter()
+                .try_for_each(|pid| match signal::kill(pid, signal) {
+                    // It has exited in the meanwhile.
+                    Err(nix::errno::Errno::ESRCH) => Ok(()),
+                    result => result,
+                }),
+        };
+        if let Err(error) = result {
+            match error {
+                // No process or process group can be found corresponding to that specified by pid
+                // It has exited already, so it's fine.
+                nix::errno::Errno::ESRCH => (),
+                _ => error!(
+                    service = sh.name().as_str();
+                    "Error sending {} to the process: {}, service: {}, pid: {:?}",
+                    signal,
+                    error,
+                    sh.name(),
+                    pid,
+                ),
+            }
+        }
+    }
+
+    /// If the service has a cgroup, all of its processes are frozen atomically instead: even the
+    /// ones outside of its `kill-mode`, and those forked while it's being paused.
+    fn set_paused(&mut self, sh: &ServiceHandler, paused: bool) {
+        if let Some(cgroup) = &sh.service().cgroup {
+            match cgroups::freeze(cgroup, paused) {
+                Ok(()) => return,
+                Err(error) => warn!(
+                    service = sh.name().as_str();
+                    "{}: falling back to signals: {:?}",
+                    sh.name(),
+                    error
+                ),
+            }
+        }
+        let signal = if paused {
+            signal::SIGSTOP
+        } else {
+            signal::SIGCONT
+        };
+        self.send_signal(sh, signal);
+    }
+
+    fn catches_signal(&self, pid: Pid, signal: Signal) -> bool {
+        sandbox::catches_signal(pid, signal)
+    }
+
+    fn reap(&mut self, repo: &Repo) -> Vec<Event> {
+        // Nothing to reap until SIGCHLD is received, unless some exits are still unclaimed.
+        if signal_handling::take_sigchld() || self.reaper.has_unclaimed() {
+            self.reaper.run(repo, self.clock.now(), &self.outputs)
+        } else {
+            vec![]
+        }
+    }
+
+    fn finished(&mut self) {
+        signal_handling::stop_sigchld_watcher();
+        // If we're the init system, let's be sure that everything stops before exiting.
+        // TODO: Test (probably via docker).
+        if unistd::getpid() == INIT_PID {
+            let all_processes = Pid::from_raw(-1);
+            let _res = signal::kill(all_processes, signal::SIGTERM);
+            self.clock.sleep(Duration::from_secs(3));
+            let _res = signal::kill(all_processes, signal::SIGKILL);
+        }
+    }
+}
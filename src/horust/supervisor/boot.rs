@@ -0,0 +1,175 @@
+//! The progress of the boot, wave by wave (see `Plan`): which waves are up, and which services
+//! Horust is still waiting for.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::horust::formats::{ServiceName, ServiceStatus};
+use crate::horust::plan::Plan;
+use crate::horust::supervisor::repo::Repo;
+use crate::horust::supervisor::service_handler::ServiceHandler;
+
+#[derive(Debug)]
+pub(crate) struct Boot {
+    /// Reported once, at the first progress.
+    plan: Option<String>,
+    /// The waves which aren't up yet, with their number.
+    waves: VecDeque<(usize, Vec<ServiceName>)>,
+    /// The services of the first wave which weren't up, the last time.
+    waiting: Vec<ServiceName>,
+}
+
+impl Boot {
+    pub(crate) fn new(plan: &Plan) -> Self {
+        let waves: VecDeque<(usize, Vec<ServiceName>)> = plan
+            .waves
+            .iter()
+            .enumerate()
+            .map(|(index, wave)| {
+                let services = wave.iter().map(|step| step.service.clone()).collect();
+                (index + 1, services)
+            })
+            .collect();
+        Self {
+            plan: Some(plan.summary()),
+            waiting: waves
+                .front()
+                .map(|(_, wave)| wave.clone())
+                .unwrap_or_default(),
+            waves,
+        }
+    }
+
+    /// What has happened since the last time: the plan at first, then the services which are up
+    /// in the current wave, and the waves which are up. `elapsed` is the time since the boot.
+    pub(crate) fn progress(&mut self, repo: &Repo, elapsed: Duration) -> Vec<String> {
+        let mut messages: Vec<String> = self
+            .plan
+            .take()
+            .map(|plan| format!("Boot plan: {}", plan))
+            .into_iter()
+            .collect();
+        while let Some((number, wave)) = self.waves.front() {
+            let services = wave.iter().filter_map(|name| repo.services.get(name));
+            let waiting: Vec<&ServiceHandler> = services.clone().filter(|sh| !is_up(sh)).collect();
+            if !waiting.is_empty() {
+                if waiting.len() < self.waiting.len() {
+                    let up: Vec<&str> = self
+                        .waiting
+                        .iter()
+                        .filter(|name| waiting.iter().all(|sh| sh.name() != *name))
+                        .map(String::as_str)
+                        .collect();
+                    let waiting_for: Vec<String> = waiting
+                        .iter()
+                        .map(|sh| format!("{} ({})", sh.name(), sh.status))
+                        .collect();
+                    messages.push(format!(
+                        "Wave {}: {} up, waiting for {}",
+                        number,
+                        up.join(", "),
+                        waiting_for.join(", ")
+                    ));
+                    self.waiting = waiting.iter().map(|sh| sh.name().clone()).collect();
+                }
+                break;
+            }
+            let failed: Vec<&str> = services
+                .filter(|sh| sh.status == ServiceStatus::FinishedFailed)
+                .map(|sh| sh.name().as_str())
+                .collect();
+            let elapsed = Duration::from_millis(elapsed.as_millis() as u64);
+            let mut message = format!(
+                "Wave {} is up, after {}",
+                number,
+                humantime::format_duration(elapsed)
+            );
+            if !failed.is_empty() {
+                message.push_str(&format!(", except the failed: {}", failed.join(", ")));
+            }
+            messages.push(message);
+            self.waves.pop_front();
+            self.waiting = self
+                .waves
+                .front()
+                .map(|(_, wave)| wave.clone())
+                .unwrap_or_default();
+        }
+        messages
+    }
+
+    /// All the waves are up.
+    pub(crate) fn is_done(&self) -> bool {
+        self.waves.is_empty()
+    }
+}
+
+/// The service is running, or done with its job (even if it has failed for good). The services
+/// waiting to be asked to start aren't waited for.
+fn is_up(sh: &ServiceHandler) -> bool {
+    matches!(
+        sh.status,
+        ServiceStatus::Running
+            | ServiceStatus::Finished
+            | ServiceStatus::Success
+            | ServiceStatus::FinishedFailed
+            | ServiceStatus::Disabled
+    ) || sh.is_waiting_for_start()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::horust::bus::Bus;
+    use crate::horust::clock::SystemClock;
+    use crate::horust::formats::{Service, ServiceStatus};
+    use crate::horust::plan::Plan;
+    use crate::horust::supervisor::boot::Boot;
+    use crate::horust::supervisor::repo::Repo;
+
+    #[test]
+    fn test_boot() {
+        let mut database = Service::from_name("database");
+        database.healthiness.file_path = Some("/tmp/up".into());
+        let services = vec![
+            database,
+            Service::from_name("cache"),
+            Service::start_after("api", vec!["cache"]),
+        ];
+        let plan = Plan::new(&services);
+        let mut repo = Repo::new(
+            Bus::new().join_bus(),
+            services.into_iter().map(Arc::new).collect(),
+            Arc::new(SystemClock),
+        );
+        let mut boot = Boot::new(&plan);
+        let second = Duration::from_secs(1);
+
+        assert_eq!(
+            boot.progress(&repo, second),
+            vec!["Boot plan: wave 1: cache, database; wave 2: api (after cache)"]
+        );
+        assert!(boot.progress(&repo, second).is_empty());
+
+        repo.get_mut_sh("cache").status = ServiceStatus::Running;
+        repo.get_mut_sh("database").status = ServiceStatus::Started;
+        assert_eq!(
+            boot.progress(&repo, second),
+            vec!["Wave 1: cache up, waiting for database (Started)"]
+        );
+        assert!(boot.progress(&repo, second).is_empty());
+
+        repo.get_mut_sh("database").status = ServiceStatus::FinishedFailed;
+        repo.get_mut_sh("api").status = ServiceStatus::Running;
+        assert_eq!(
+            boot.progress(&repo, Duration::from_micros(2_500_500)),
+            vec![
+                "Wave 1 is up, after 2s 500ms, except the failed: database",
+                "Wave 2 is up, after 2s 500ms"
+            ]
+        );
+        assert!(boot.is_done());
+    }
+}
@@ -0,0 +1,179 @@
+//! Conditions which must be met before spawning a service: `Service::conditions`, and the
+//! endpoints listed in `Service::start_after`.
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use nix::ifaddrs::getifaddrs;
+use nix::net::if_::InterfaceFlags;
+#[cfg(feature = "http-healthcheck")]
+use reqwest::blocking::Client;
+
+use crate::horust::formats::{Dependency, NetworkCondition, NetworkState, Service};
+
+#[cfg(feature = "http-healthcheck")]
+const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The first condition which is not met.
+#[derive(Debug)]
+pub(crate) struct NotMet {
+    pub(crate) reason: anyhow::Error,
+    /// How long it's allowed to wait for this condition, if there is a limit.
+    pub(crate) timeout: Option<Duration>,
+}
+
+pub(crate) fn check(service: &Service) -> Result<(), NotMet> {
+    if let Some(network) = &service.conditions.network {
+        check_network(network).map_err(|reason| NotMet {
+            reason,
+            timeout: None,
+        })?;
+    }
+    if let Some(path) = service.conditions.paths.iter().find(|path| !path.exists()) {
+        return Err(NotMet {
+            reason: anyhow!("{} doesn't exist", path.display()),
+            timeout: service.conditions.paths_timeout,
+        });
+    }
+    for dependency in &service.start_after {
+        if let Dependency::Tcp { tcp, timeout } = dependency {
+            check_tcp(tcp).map_err(|reason| NotMet {
+                reason,
+                timeout: *timeout,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Succeeds if any of the addresses of `endpoint` accepts a connection.
+fn check_tcp(endpoint: &str) -> Result<()> {
+    let addresses = endpoint
+        .to_socket_addrs()
+        .map_err(|err| anyhow!("Cannot resolve {}: {}", endpoint, err))?;
+    let mut last_error = anyhow!("No addresses found for {}", endpoint);
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, TCP_CONNECT_TIMEOUT) {
+            Ok(_stream) => return Ok(()),
+            Err(err) => last_error = anyhow!("Cannot connect to {}: {}", endpoint, err),
+        }
+    }
+    Err(last_error)
+}
+
+fn check_network(condition: &NetworkCondition) -> Result<()> {
+    match condition {
+        NetworkCondition::State(NetworkState::Online) => {
+            if !is_online()? {
+                bail!("The network is not online");
+            }
+        }
+        NetworkCondition::Dns { dns } => {
+            (dns.as_str(), 0)
+                .to_socket_addrs()
+                .map_err(|err| anyhow!("Cannot resolve {}: {}", dns, err))?;
+        }
+        NetworkCondition::Url { url } => check_url(url)?,
+    }
+    Ok(())
+}
+
+/// True if any interface other than loopback is up, and has an address which is not link-local.
+fn is_online() -> Result<bool> {
+    Ok(getifaddrs()?.any(|interface| {
+        let is_up = interface
+            .flags
+            .contains(InterfaceFlags::IFF_UP | InterfaceFlags::IFF_RUNNING);
+        let is_loopback = interface.flags.contains(InterfaceFlags::IFF_LOOPBACK);
+        let has_address = interface.address.is_some_and(|address| {
+            address.as_sockaddr_in().is_some()
+                || address
+                    .as_sockaddr_in6()
+                    .is_some_and(|addr| addr.ip().segments()[0] & 0xffc0 != 0xfe80)
+        });
+        is_up && !is_loopback && has_address
+    }))
+}
+
+#[cfg(feature = "http-healthcheck")]
+fn check_url(url: &str) -> Result<()> {
+    let client = Client::builder().timeout(HTTP_REQUEST_TIMEOUT).build()?;
+    let status = client.get(url).send()?.status();
+    if !status.is_success() {
+        bail!("{} answered with {}", url, status);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "http-healthcheck"))]
+fn check_url(_url: &str) -> Result<()> {
+    bail!("There is an url based condition, but horust was built without the http-healthcheck feature (thus it will never pass).")
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::horust::formats::{Dependency, NetworkCondition, Service};
+    use crate::horust::supervisor::conditions::check;
+
+    fn dns(host: &str) -> Service {
+        let mut service = Service::from_name("a");
+        service.conditions.network = Some(NetworkCondition::Dns { dns: host.into() });
+        service
+    }
+
+    fn tcp(endpoint: &str) -> Service {
+        let mut service = Service::from_name("a");
+        service.start_after = vec![
+            "b".into(),
+            Dependency::Tcp {
+                tcp: endpoint.into(),
+                timeout: Some(Duration::from_secs(3)),
+            },
+        ];
+        service
+    }
+
+    #[test]
+    fn test_check() {
+        check(&Service::from_name("a")).unwrap();
+        check(&dns("localhost")).unwrap();
+        // `.invalid` is reserved, and never resolves.
+        let not_met = check(&dns("horust.invalid")).unwrap_err();
+        assert_eq!(not_met.timeout, None);
+    }
+
+    #[test]
+    fn test_check_paths() {
+        let tempdir = TempDir::new("conditions").unwrap();
+        let socket = tempdir.path().join("db.sock");
+        let mut service = Service::from_name("a");
+        service.conditions.paths = vec![tempdir.path().into(), socket.clone()];
+        service.conditions.paths_timeout = Some(Duration::from_secs(5));
+        let not_met = check(&service).unwrap_err();
+        assert_eq!(
+            not_met.reason.to_string(),
+            format!("{} doesn't exist", socket.display())
+        );
+        assert_eq!(not_met.timeout, Some(Duration::from_secs(5)));
+        let _listener = UnixListener::bind(&socket).unwrap();
+        check(&service).unwrap();
+    }
+
+    #[test]
+    fn test_check_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        check(&tcp(&endpoint)).unwrap();
+        drop(listener);
+        let not_met = check(&tcp(&endpoint)).unwrap_err();
+        assert_eq!(not_met.timeout, Some(Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,140 @@
+//! Collection of the core dumps of the services, see `Service::core_dumps`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::unistd::Pid;
+
+use crate::horust::formats::{CoreDumps, Service};
+
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+const CORE_USES_PID: &str = "/proc/sys/kernel/core_uses_pid";
+
+/// The `dir` where the core dumps of the service are moved to, if any.
+pub(crate) fn dir(service: &Service) -> Option<&Path> {
+    match &service.core_dumps {
+        Some(CoreDumps::Enabled { dir: Some(dir), .. }) => Some(dir),
+        _ => None,
+    }
+}
+
+/// Where the kernel has written the core dump of `pid`, according to the `core_pattern`.
+pub(crate) fn locate(service: &Service, pid: Pid) -> Option<PathBuf> {
+    let pattern = fs::read_to_string(CORE_PATTERN).unwrap_or_else(|_| "core".into());
+    let uses_pid = fs::read_to_string(CORE_USES_PID).is_ok_and(|value| value.trim() == "1");
+    let path = core_file_path(pattern.trim(), uses_pid, pid, &service.working_directory);
+    if path.is_none() {
+        warn!(
+            "{}: cannot collect the core dump, unsupported core_pattern: {}",
+            service.name,
+            pattern.trim()
+        );
+    }
+    path
+}
+
+/// Moves the core dump of `pid` into the `dir` of its service (if any), as
+/// `<service>.<pid>.<timestamp>.core`. The copy runs in a new thread.
+pub(crate) fn collect(service: &Service, pid: Pid) {
+    let Some(dir) = dir(service) else {
+        return;
+    };
+    let Some(source) = locate(service, pid) else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let target = dir.join(format!("{}.{}.{}.core", service.name, pid, timestamp));
+    let name = service.name.clone();
+    thread::spawn(move || match move_file(&source, &target) {
+        Ok(()) => info!("{}: core dump saved in {}", name, target.display()),
+        Err(error) => error!(
+            "{}: failed collecting the core dump {}: {}",
+            name,
+            source.display(),
+            error
+        ),
+    });
+}
+
+/// Where the kernel writes the core dump of `pid`, according to the `core_pattern`.
+/// Returns `None` if the pattern is not supported, e.g. if it's piped to a program.
+fn core_file_path(pattern: &str, uses_pid: bool, pid: Pid, cwd: &Path) -> Option<PathBuf> {
+    if pattern.starts_with('|') {
+        return None;
+    }
+    let mut path = String::new();
+    let mut has_pid = false;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'p' | 'P' => {
+                has_pid = true;
+                path.push_str(&pid.to_string());
+            }
+            '%' => path.push('%'),
+            _ => return None,
+        }
+    }
+    if uses_pid && !has_pid {
+        path.push_str(&format!(".{}", pid));
+    }
+    Some(cwd.join(path))
+}
+
+pub(crate) fn move_file(source: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Renaming fails if the paths are on different file systems.
+    if fs::rename(source, target).is_err() {
+        fs::copy(source, target)?;
+        fs::remove_file(source)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use nix::unistd::Pid;
+    use tempdir::TempDir;
+
+    use crate::horust::supervisor::core_dumps::{core_file_path, move_file};
+
+    #[test]
+    fn test_core_file_path() {
+        let pid = Pid::from_raw(42);
+        let cwd = Path::new("/srv");
+        let path = |pattern, uses_pid| core_file_path(pattern, uses_pid, pid, cwd);
+        assert_eq!(path("core", false), Some(PathBuf::from("/srv/core")));
+        assert_eq!(path("core", true), Some(PathBuf::from("/srv/core.42")));
+        assert_eq!(
+            path("/cores/core.%p.100%%", true),
+            Some(PathBuf::from("/cores/core.42.100%"))
+        );
+        assert_eq!(path("/cores/core.%e", false), None);
+        assert_eq!(path("|/usr/lib/systemd/systemd-coredump %P", false), None);
+    }
+
+    #[test]
+    fn test_move_file() {
+        let tempdir = TempDir::new("core-dumps").unwrap();
+        let source = tempdir.path().join("core");
+        std::fs::write(&source, "dump").unwrap();
+        let target = tempdir.path().join("cores").join("a.42.0.core");
+        move_file(&source, &target).unwrap();
+        assert!(!source.exists());
+        assert_eq!(std::fs::read_to_string(target).unwrap(), "dump");
+    }
+}
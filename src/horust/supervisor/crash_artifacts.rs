@@ -0,0 +1,219 @@
+//! Collection of the crash bundles of the services, see `Service::crash_artifacts`.
+//! A bundle is a directory `<dir>/<service>/<timestamp>-<pid>`, with:
+//! * `crash.json`: how the process was killed, and the hash of its environment.
+//! * `output.log`: its last lines of output (see `failure.output-lines`).
+//! * `proc-status`: its `/proc/<pid>/status`, as it was before being reaped.
+//! * `core`: its core dump, unless it's moved to the `core-dumps.dir` of the service.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::unistd::Pid;
+use serde::Serialize;
+
+use crate::horust::formats::{ExitReason, Service, ServiceName};
+use crate::horust::output::Outputs;
+use crate::horust::supervisor::core_dumps;
+
+/// How long to wait for the last output of the crashed process to be read.
+const OUTPUT_TAIL_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Summary {
+    service: ServiceName,
+    pid: i32,
+    exit: String,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    /// Hash of the environment the process was started with, for comparing it across crashes
+    /// without exposing it.
+    environment_hash: Option<String>,
+    /// The `core-dumps.dir` where the core dump was moved to, if it's not in the bundle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    core_dump: Option<PathBuf>,
+}
+
+/// Collects the bundle of `pid`, which was killed by a signal, if the service has
+/// `crash-artifacts`. `proc_status` is its `/proc/<pid>/status`. The collection runs in a new
+/// thread.
+pub(crate) fn collect(
+    service: &Service,
+    pid: Pid,
+    exit_reason: ExitReason,
+    proc_status: Option<String>,
+    outputs: &Outputs,
+) {
+    let Some(crash_artifacts) = &service.crash_artifacts else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let service_dir = crash_artifacts.dir.join(&service.name);
+    let bundle = service_dir.join(format!("{}-{}", timestamp, pid));
+    let core_dumped = matches!(
+        exit_reason,
+        ExitReason::Signal {
+            core_dumped: true,
+            ..
+        }
+    );
+    // Moved to the `core-dumps.dir` instead, if the service has one.
+    let (core, core_dump) = match core_dumps::dir(service) {
+        _ if !core_dumped => (None, None),
+        Some(dir) => (None, Some(dir.to_path_buf())),
+        None => (core_dumps::locate(service, pid), None),
+    };
+    let summary = Summary {
+        service: service.name.clone(),
+        pid: pid.as_raw(),
+        exit: exit_reason.to_string(),
+        timestamp,
+        environment_hash: service.get_environment().ok().map(|env| hash(&env)),
+        core_dump,
+    };
+    let keep = crash_artifacts.keep;
+    let outputs = outputs.clone();
+    thread::spawn(move || {
+        let output: Vec<u8> = outputs
+            .tail(&summary.service, OUTPUT_TAIL_TIMEOUT)
+            .into_iter()
+            .flat_map(|output| output.line)
+            .collect();
+        let name = summary.service.clone();
+        let written = write(
+            &bundle,
+            &summary,
+            &output,
+            proc_status.as_deref(),
+            core.as_deref(),
+        )
+        .and_then(|_| prune(&service_dir, keep));
+        match written {
+            Ok(()) => info!(
+                "{}: crash artifacts collected in {}",
+                name,
+                bundle.display()
+            ),
+            Err(error) => error!(
+                "{}: failed collecting the crash artifacts in {}: {}",
+                name,
+                bundle.display(),
+                error
+            ),
+        }
+    });
+}
+
+/// A stable representation of the environment: the variables are sorted first.
+fn hash(environment: &[String]) -> String {
+    let mut environment = environment.to_vec();
+    environment.sort();
+    let mut hasher = DefaultHasher::new();
+    environment.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn write(
+    bundle: &Path,
+    summary: &Summary,
+    output: &[u8],
+    proc_status: Option<&str>,
+    core: Option<&Path>,
+) -> io::Result<()> {
+    fs::create_dir_all(bundle)?;
+    fs::write(
+        bundle.join("crash.json"),
+        serde_json::to_string_pretty(summary)?,
+    )?;
+    fs::write(bundle.join("output.log"), output)?;
+    if let Some(proc_status) = proc_status {
+        fs::write(bundle.join("proc-status"), proc_status)?;
+    }
+    if let Some(core) = core {
+        core_dumps::move_file(core, &bundle.join("core"))?;
+    }
+    Ok(())
+}
+
+/// Removes the oldest bundles in `service_dir`, keeping only the last `keep`.
+fn prune(service_dir: &Path, keep: usize) -> io::Result<()> {
+    let mut bundles: Vec<PathBuf> = fs::read_dir(service_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    // Named after when they were collected.
+    bundles.sort();
+    let excess = bundles.len().saturating_sub(keep);
+    for bundle in &bundles[..excess] {
+        fs::remove_dir_all(bundle)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use crate::horust::supervisor::crash_artifacts::{hash, prune, write, Summary};
+
+    #[test]
+    fn test_hash() {
+        let a = hash(&["A=1".into(), "B=2".into()]);
+        assert_eq!(a, hash(&["B=2".into(), "A=1".into()]));
+        assert_ne!(a, hash(&["A=1".into(), "B=3".into()]));
+    }
+
+    #[test]
+    fn test_write_and_prune() {
+        let tempdir = TempDir::new("crash-artifacts").unwrap();
+        let core = tempdir.path().join("core.42");
+        fs::write(&core, "dump").unwrap();
+        let summary = Summary {
+            service: "crashing".into(),
+            pid: 42,
+            exit: "killed by SIGSEGV (core dumped)".into(),
+            timestamp: 1000,
+            environment_hash: Some("0123456789abcdef".into()),
+            core_dump: None,
+        };
+        let service_dir = tempdir.path().join("crashing");
+        let bundle = service_dir.join("1000-42");
+        write(
+            &bundle,
+            &summary,
+            b"line\n",
+            Some("State:\tZ (zombie)\n"),
+            Some(&core),
+        )
+        .unwrap();
+        let crash = fs::read_to_string(bundle.join("crash.json")).unwrap();
+        assert!(crash.contains(r#""exit": "killed by SIGSEGV (core dumped)""#));
+        assert!(crash.contains(r#""environment-hash": "0123456789abcdef""#));
+        assert_eq!(
+            fs::read_to_string(bundle.join("output.log")).unwrap(),
+            "line\n"
+        );
+        assert!(bundle.join("proc-status").exists());
+        assert_eq!(fs::read_to_string(bundle.join("core")).unwrap(), "dump");
+        assert!(!core.exists());
+
+        for bundle in ["1001-43", "1002-44"] {
+            fs::create_dir(service_dir.join(bundle)).unwrap();
+        }
+        prune(&service_dir, 2).unwrap();
+        assert!(!bundle.exists());
+        assert!(service_dir.join("1001-43").exists());
+        assert!(service_dir.join("1002-44").exists());
+    }
+}
@@ -0,0 +1,233 @@
+//! Why a service isn't running, computed from its state in the repo: see
+//! `horustctl why-not-running`.
+
+use crate::horust::formats::{Blocker, ServiceStatus, ServiceType};
+use crate::horust::supervisor::repo::Repo;
+use crate::horust::supervisor::service_handler::ServiceHandler;
+
+/// The reasons why the service isn't running, empty if it is.
+pub(crate) fn diagnose(repo: &Repo, sh: &ServiceHandler) -> Vec<Blocker> {
+    let service = sh.service();
+    let exited = || {
+        sh.last_exit
+            .map(|exit_reason| format!(" ({})", exit_reason))
+            .unwrap_or_default()
+    };
+    match sh.status {
+        ServiceStatus::Running => vec![],
+        ServiceStatus::Disabled => vec![Blocker::new("it's disabled, or masked")],
+        ServiceStatus::Initial if sh.is_waiting_for_start() => {
+            let reason = if sh.inactive {
+                "it's stopped (or switched away from, or scaled down): waiting to be started"
+            } else if service.timer.is_some() {
+                "it has a timer: waiting for it to trigger"
+            } else if service.socket.lazy {
+                "it's lazy: waiting for a connection on its sockets"
+            } else {
+                "auto-start is off: waiting to be started"
+            };
+            vec![Blocker::new(reason)]
+        }
+        ServiceStatus::Initial => {
+            let blockers: Vec<Blocker> = repo
+                .unmet_dependencies(sh)
+                .into_iter()
+                .map(|dependency| {
+                    let providers: Vec<String> = repo
+                        .get_providers(dependency)
+                        .map(|provider| {
+                            if provider.name() == dependency {
+                                provider.status.to_string()
+                            } else {
+                                format!("{}: {}", provider.name(), provider.status)
+                            }
+                        })
+                        .collect();
+                    Blocker::new(format!(
+                        "waiting for {} ({})",
+                        dependency,
+                        providers.join(", ")
+                    ))
+                })
+                .collect();
+            if blockers.is_empty() {
+                vec![Blocker::new("it's about to be started")]
+            } else {
+                blockers
+            }
+        }
+        ServiceStatus::Starting => {
+            if let Some(reason) = &sh.conditions_not_met {
+                vec![Blocker::new(format!("a condition isn't met: {}", reason))]
+            } else if let Some(until) = sh.backoff_until.filter(|until| *until > repo.now()) {
+                let (_, attempts) = sh.restart_policy();
+                vec![Blocker {
+                    reason: format!("backing off before the restart attempt {}", attempts),
+                    until: Some(until),
+                }]
+            } else {
+                vec![Blocker::new("it's being spawned")]
+            }
+        }
+        ServiceStatus::Started => {
+            let reason = if !sh.is_oneshot() && sh.has_some_failed_healthchecks() {
+                "it's started, waiting for its healthchecks to pass"
+            } else if service.service_type == ServiceType::Notify
+                && !sh.notified_ready
+            {
+                "it's started, waiting for READY=1 on its notify socket"
+            } else if sh.is_oneshot() {
+                "it's a oneshot: running until its job is done"
+            } else {
+                "it's started, about to be running"
+            };
+            vec![Blocker::new(reason)]
+        }
+        ServiceStatus::InKilling if sh.recycling => vec![Blocker::new("it's being restarted")],
+        ServiceStatus::InKilling => vec![Blocker::new("it's being stopped")],
+        ServiceStatus::Success if !sh.recycling && !sh.is_restart_window_open(repo.system_time()) => {
+            vec![Blocker::new(
+                "it has exited successfully: waiting for its restart window",
+            )]
+        }
+        ServiceStatus::Success => vec![Blocker::new("it has exited successfully")],
+        ServiceStatus::Failed => vec![Blocker::new(format!("it has failed{}", exited()))],
+        ServiceStatus::Broken => vec![Blocker {
+            reason: format!(
+                "it's crash-looping (failures in a row: {}): waiting for its cooldown, or to be cleared",
+                sh.crashes
+            ),
+            until: sh
+                .broken_at
+                .zip(service.restart.cooldown)
+                .map(|(broken_at, cooldown)| broken_at + cooldown),
+        }],
+        ServiceStatus::Finished | ServiceStatus::FinishedFailed if sh.is_waiting_for_timer() => {
+            vec![Blocker::new(format!(
+                "it has finished{}: waiting for its timer to trigger again",
+                exited()
+            ))]
+        }
+        ServiceStatus::Finished if sh.is_oneshot() => {
+            vec![Blocker::new("it's a oneshot which has done its job")]
+        }
+        ServiceStatus::Finished => vec![Blocker::new(format!(
+            "it has finished{}, and it's not restarted",
+            exited()
+        ))],
+        ServiceStatus::FinishedFailed => vec![Blocker::new(format!(
+            "it has failed{}, and it's not restarted anymore",
+            exited()
+        ))],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::horust::bus::Bus;
+    use crate::horust::clock::SystemClock;
+    use crate::horust::formats::{Blocker, ExitReason, Service, ServiceStatus, Timer};
+    use crate::horust::supervisor::diagnosis::diagnose;
+    use crate::horust::supervisor::repo::Repo;
+
+    fn reasons(repo: &Repo, name: &str) -> Vec<String> {
+        diagnose(repo, &repo.services[name])
+            .into_iter()
+            .map(|blocker| blocker.reason)
+            .collect()
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let mut disabled = Service::from_name("disabled");
+        disabled.enabled = false;
+        let mut lazy = Service::from_name("lazy");
+        lazy.socket.lazy = true;
+        let mut broken = Service::from_name("broken");
+        broken.restart.cooldown = Some(Duration::from_secs(60));
+        let mut backup = Service::from_name("backup");
+        backup.timer = Some(Timer {
+            on_calendar: "daily".parse().unwrap(),
+            persistent: false,
+            accuracy: Duration::ZERO,
+        });
+        let services = vec![
+            Service::from_name("db"),
+            Service::start_after("api", vec!["db"]),
+            disabled,
+            lazy,
+            broken,
+            backup,
+        ];
+        let mut repo = Repo::new(
+            Bus::new().join_bus(),
+            services.into_iter().map(Arc::new).collect(),
+            Arc::new(SystemClock),
+        );
+
+        assert_eq!(reasons(&repo, "db"), vec!["it's about to be started"]);
+        assert_eq!(reasons(&repo, "api"), vec!["waiting for db (Initial)"]);
+        assert_eq!(reasons(&repo, "disabled"), vec!["it's disabled, or masked"]);
+        assert_eq!(
+            reasons(&repo, "lazy"),
+            vec!["it's lazy: waiting for a connection on its sockets"]
+        );
+        assert_eq!(
+            reasons(&repo, "backup"),
+            vec!["it has a timer: waiting for it to trigger"]
+        );
+
+        let now = repo.now();
+        let db = repo.get_mut_sh("db");
+        db.status = ServiceStatus::Starting;
+        db.restart_attempts = 2;
+        db.backoff_until = Some(now + Duration::from_secs(10));
+        assert_eq!(
+            diagnose(&repo, &repo.services["db"]),
+            vec![Blocker {
+                reason: "backing off before the restart attempt 2".into(),
+                until: Some(now + Duration::from_secs(10)),
+            }]
+        );
+        repo.get_mut_sh("db").conditions_not_met = Some("/tmp/db doesn't exist".into());
+        assert_eq!(
+            reasons(&repo, "db"),
+            vec!["a condition isn't met: /tmp/db doesn't exist"]
+        );
+        assert_eq!(reasons(&repo, "api"), vec!["waiting for db (Starting)"]);
+
+        repo.get_mut_sh("db").status = ServiceStatus::Running;
+        assert!(reasons(&repo, "db").is_empty());
+        assert_eq!(reasons(&repo, "api"), vec!["it's about to be started"]);
+
+        let broken = repo.get_mut_sh("broken");
+        broken.status = ServiceStatus::Broken;
+        broken.crashes = 4;
+        broken.broken_at = Some(now);
+        assert_eq!(
+            diagnose(&repo, &repo.services["broken"]),
+            vec![Blocker {
+                reason: "it's crash-looping (failures in a row: 4): waiting for its cooldown, or to be cleared".into(),
+                until: Some(now + Duration::from_secs(60)),
+            }]
+        );
+        let broken = repo.get_mut_sh("broken");
+        broken.status = ServiceStatus::FinishedFailed;
+        broken.last_exit = Some(ExitReason::Code(3));
+        assert_eq!(
+            reasons(&repo, "broken"),
+            vec!["it has failed (exit code 3), and it's not restarted anymore"]
+        );
+
+        let backup = repo.get_mut_sh("backup");
+        backup.status = ServiceStatus::Finished;
+        backup.last_exit = Some(ExitReason::Code(0));
+        assert_eq!(
+            reasons(&repo, "backup"),
+            vec!["it has finished (exit code 0): waiting for its timer to trigger again"]
+        );
+    }
+}
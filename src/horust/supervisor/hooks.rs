@@ -0,0 +1,117 @@
+//! Hooks run by the supervisor on behalf of a service, outside of its lifecycle.
+
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nix::unistd::Pid;
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Event, ExitReason, PreStop, Service, ShuttingDown};
+use crate::horust::subprocess;
+
+/// Runs the pre-stop hook of `service` in another thread.
+/// Once done (successfully or not), a `PreStopDone` event is sent.
+pub(crate) fn spawn_pre_stop(
+    service: Arc<Service>,
+    bus: BusConnector<Event>,
+    clock: Arc<dyn Clock>,
+) {
+    thread::spawn(move || {
+        match &service.termination.pre_stop {
+            Some(PreStop::Command { command, timeout }) => {
+                debug!("{}: running pre-stop command: {}", service.name, command);
+                let result = subprocess::service_command(&service, command)
+                    .and_then(|mut cmd| Ok(subprocess::run(&mut cmd, *timeout, clock.as_ref())?));
+                match result {
+                    Ok(output) if output.status.success() => (),
+                    Ok(output) => warn!(
+                        "{}: pre-stop command has failed: {}",
+                        service.name, output.status
+                    ),
+                    Err(error) => error!("{}: pre-stop command error: {}", service.name, error),
+                }
+            }
+            Some(PreStop::Sleep { sleep }) => {
+                debug!("{}: pre-stop, sleeping for {:?}", service.name, sleep);
+                sleep_unless_forced(*sleep, &bus, clock.as_ref());
+            }
+            None => (),
+        }
+        bus.send_event(Event::PreStopDone(service.name.clone()));
+    });
+}
+
+/// Runs the `on-crash.debug-command` of `service` in another thread, with `{pid}` replaced by
+/// the pid of the crashed process. Its output is logged.
+pub(crate) fn spawn_debug_command(
+    service: Arc<Service>,
+    pid: Pid,
+    exit_reason: ExitReason,
+    clock: Arc<dyn Clock>,
+) {
+    let Some(command) = &service.on_crash.debug_command else {
+        return;
+    };
+    let command = command.replace("{pid}", &pid.to_string());
+    thread::spawn(move || {
+        info!(
+            service = service.name.as_str();
+            "{}: {}, running the debug command: {}",
+            service.name,
+            exit_reason,
+            command
+        );
+        let result = subprocess::service_command(&service, &command).and_then(|mut cmd| {
+            cmd.env("HORUST_PID", pid.to_string())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            Ok(subprocess::run(
+                &mut cmd,
+                service.on_crash.timeout,
+                clock.as_ref(),
+            )?)
+        });
+        match result {
+            Ok(output) => {
+                let printed = [output.stdout, output.stderr].concat();
+                let lines: Vec<String> = String::from_utf8_lossy(&printed)
+                    .lines()
+                    .map(|line| format!("  | {}", line))
+                    .collect();
+                info!(
+                    service = service.name.as_str();
+                    "{}: debug command has exited with {}:\n{}",
+                    service.name,
+                    output.status,
+                    lines.join("\n")
+                );
+            }
+            Err(error) => error!(
+                service = service.name.as_str();
+                "{}: debug command error: {}",
+                service.name,
+                error
+            ),
+        }
+    });
+}
+
+/// Sleeps for `duration`, returning early if Horust is forcefully shutting down.
+fn sleep_unless_forced(duration: Duration, bus: &BusConnector<Event>, clock: &dyn Clock) {
+    let timeout = clock.after(duration);
+    let ticker = clock.tick(Duration::from_millis(100));
+    let is_forced = |ev: Event| ev == Event::ShuttingDownInitiated(ShuttingDown::Forcefully);
+    loop {
+        select! {
+            recv(ticker) -> _ => {
+                if bus.try_get_events().into_iter().any(is_forced) {
+                    return;
+                }
+            },
+            recv(timeout) -> _ => return,
+        }
+    }
+}
@@ -2,37 +2,97 @@
 //! keeping track of their current state.
 //! It will also reap the dead processes
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::ops::Mul;
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use nix::sys::signal;
-use nix::unistd;
 
+use backend::Backend;
+use boot::Boot;
+pub(crate) use process_spawner::{environment, spawned_environment};
 use repo::Repo;
-use service_handler::ServiceHandler;
+use rollout::{Rollout, Stop};
+use service_handler::{PreStopStatus, ServiceHandler};
 pub(crate) use signal_handling::init;
+use storms::Storms;
 
 use crate::horust::bus::BusConnector;
-use crate::horust::formats::{Event, ExitStatus, Service, ServiceStatus, ShuttingDown};
+use crate::horust::cgroups;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{
+    Blocker, BootTimeoutAction, Event, ExitReason, HorustConfig, RunReport, Service, ServiceName,
+    ServiceStatus, ShuttingDown,
+};
 use crate::horust::healthcheck;
+use crate::horust::history::History;
+use crate::horust::otlp::Tracer;
+use crate::horust::output::Outputs;
+use crate::horust::plan::Plan;
+use crate::horust::sd_notify;
+use crate::horust::sockets::Sockets;
 
+mod backend;
+mod boot;
+mod conditions;
+mod core_dumps;
+mod crash_artifacts;
+mod diagnosis;
+mod hooks;
 mod process_spawner;
 mod reaper;
 mod repo;
+mod rollout;
+mod sandbox;
+mod secrets;
 mod service_handler;
 mod signal_handling;
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
+mod storms;
 
-/// How many pid reap per iteration of the reaper
-const MAX_PROCESS_REAPS_ITERS: u32 = 20;
+/// How long the supervisor waits between two iterations, unless some children exit.
+const LOOP_INTERVAL: Duration = Duration::from_millis(300);
 
-/// PID 1 is reserved for the init process.
-const INIT_PID: unistd::Pid = unistd::Pid::from_raw(1);
+/// How long to wait for the last output of a failed service to be read.
+const OUTPUT_TAIL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long to wait for the pending spans to be exported, before exiting.
+const TRACES_EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
 
 // Spawns and runs this component in a new thread.
-pub fn spawn(bus: BusConnector<Event>, services: Vec<Service>) -> thread::JoinHandle<ExitStatus> {
-    thread::spawn(move || Supervisor::new(bus, services).run())
+pub(crate) fn spawn(
+    bus: BusConnector<Event>,
+    services: Vec<Arc<Service>>,
+    outputs: Outputs,
+    tracer: Option<Tracer>,
+    clock: Arc<dyn Clock>,
+    sockets: Sockets,
+    config: &HorustConfig,
+) -> thread::JoinHandle<RunReport> {
+    let shutdown_timeout = config.shutdown_timeout;
+    let (boot_timeout, boot_timeout_action) = (config.boot_timeout, config.boot_timeout_action);
+    let history = config
+        .history_path()
+        .zip(config.history.as_ref())
+        .map(|(path, history)| History::new(path, history.max_size));
+    thread::spawn(move || {
+        signal_handling::spawn_sigchld_watcher(bus.join_bus());
+        let backend = Box::new(backend::System::new(
+            outputs.clone(),
+            clock.clone(),
+            sockets,
+        ));
+        let mut supervisor = Supervisor::new(bus, services, outputs, tracer, backend, clock);
+        supervisor.shutdown_timeout = shutdown_timeout;
+        supervisor.boot_timeout = boot_timeout;
+        supervisor.boot_timeout_action = boot_timeout_action.unwrap_or_default();
+        supervisor.history = history;
+        supervisor.run()
+    })
 }
 
 #[derive(Clone, Debug, Copy, PartialEq, Eq)]
@@ -46,77 +106,231 @@ pub struct Supervisor {
     /// The system is shutting down, no more services will be spawned.
     status: LifecycleStatus,
     repo: Repo,
+    /// The service manager (if any) was notified that all the services are up.
+    ready_notified: bool,
+    /// Logs the progress of the boot, until all the waves of services are up.
+    boot: Option<Boot>,
+    /// Where the output of the spawned services is forwarded.
+    outputs: Outputs,
+    /// Traces the lifecycle of the services, if an OTLP endpoint is configured.
+    tracer: Option<Tracer>,
+    /// Records the runs of the services, if the run history is enabled.
+    history: Option<History>,
+    /// Spawns, signals and reaps the processes of the services.
+    backend: Box<dyn Backend>,
+    /// The variants being switched to: the other variants are stopped once they're running.
+    switches: Vec<ServiceName>,
+    /// The rolling restarts in progress.
+    rollouts: Vec<Rollout>,
+    /// The bulk stops in progress.
+    stops: Vec<Stop>,
+    /// The services exiting over and over.
+    storms: Storms,
+    /// Why the services aren't running, as last published.
+    diagnoses: HashMap<ServiceName, Vec<Blocker>>,
+    /// How long a graceful shutdown can last, before the remaining services are killed.
+    shutdown_timeout: Option<Duration>,
+    /// When the graceful shutdown in progress turns into a forceful one.
+    shutdown_deadline: Option<Instant>,
+    /// How long the services can take to be all up, since the supervisor has started.
+    boot_timeout: Option<Duration>,
+    /// What to do if they aren't all up by then.
+    boot_timeout_action: BootTimeoutAction,
+    started_at: Instant,
+    /// The services weren't all up by the `boot_timeout`.
+    boot_timed_out: bool,
 }
 
 impl Supervisor {
-    fn new(bus: BusConnector<Event>, services: Vec<Service>) -> Self {
-        let repo = Repo::new(bus, services);
+    fn new(
+        bus: BusConnector<Event>,
+        services: Vec<Arc<Service>>,
+        outputs: Outputs,
+        tracer: Option<Tracer>,
+        backend: Box<dyn Backend>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let started_at = clock.now();
+        let plan = Plan::new(&services.iter().map(|s| (**s).clone()).collect::<Vec<_>>());
+        let repo = Repo::new(bus, services, clock);
         Self {
             repo,
             status: LifecycleStatus::Running,
+            ready_notified: false,
+            boot: Some(Boot::new(&plan)),
+            outputs,
+            tracer,
+            history: None,
+            backend,
+            switches: vec![],
+            rollouts: vec![],
+            stops: vec![],
+            storms: Storms::default(),
+            diagnoses: HashMap::new(),
+            shutdown_timeout: None,
+            shutdown_deadline: None,
+            boot_timeout: None,
+            boot_timeout_action: BootTimeoutAction::default(),
+            started_at,
+            boot_timed_out: false,
         }
     }
 
     /// Handle the events, returns Events (state changes) to be dispatched.
     fn handle_event(&mut self, ev: Event) -> Vec<Event> {
+        let (now, time) = (self.repo.now(), self.repo.system_time());
         match ev {
-            Event::ServiceExited(service_name, exit_code) => {
-                let pid = self.repo.get_sh(&service_name).pid.unwrap();
-                self.repo.remove_pid(pid);
+            Event::ServiceExited(service_name, exit_reason) => {
+                // Missing if the exit was already handled.
+                let pid = self.repo.get_sh(&service_name).pid;
+                if let Some(pid) = pid {
+                    self.repo.remove_pid(pid, &service_name);
+                }
+                let logged = self.storms.exited(&service_name, now);
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 service_handler.shutting_down_start = None;
                 service_handler.pid = None;
+                service_handler.exited = false;
+                service_handler.pre_stop = PreStopStatus::NotRun;
+                service_handler.last_exit = Some(exit_reason);
+                let ran_for = service_handler
+                    .spawned_at
+                    .take()
+                    .map(|spawned_at| now.saturating_duration_since(spawned_at))
+                    .unwrap_or_default();
+                service_handler.runtime += ran_for;
 
-                let has_failed = !service_handler
-                    .service()
-                    .failure
-                    .successful_exit_code
-                    .contains(&exit_code);
+                // Signal deaths are always failures, unless the service was being recycled. So is
+                // being killed for not being ready in time (or running for too long), even if it
+                // exits successfully.
+                let has_failed = service_handler.start_timed_out
+                    || service_handler.wall_timed_out
+                    || (!service_handler.recycling
+                        && !exit_reason.code().is_some_and(|exit_code| {
+                            service_handler
+                                .service()
+                                .failure
+                                .successful_exit_code
+                                .contains(&exit_code)
+                        }));
+
+                // Killed by the OOM killer, if it has killed more processes in its cgroup.
+                let oom_kills = cgroups::oom_kills(service_handler.service());
+                let oom_killed =
+                    has_failed && oom_kills.is_some_and(|kills| kills > service_handler.oom_kills);
+                service_handler.oom_kills = oom_kills.unwrap_or(service_handler.oom_kills);
+                service_handler.oom_killed = oom_killed;
+                service_handler.oom_restart_attempts = if oom_killed {
+                    service_handler.oom_restart_attempts + 1
+                } else {
+                    0
+                };
+                let cooldown = service_handler.service().restart.cooldown;
+                service_handler.crashes = if has_failed && cooldown.is_none_or(|c| ran_for < c) {
+                    service_handler.crashes + 1
+                } else {
+                    0
+                };
+                // Killed by a signal which wasn't sent by Horust.
+                let crashed = matches!(exit_reason, ExitReason::Signal { .. })
+                    && !service_handler.recycling
+                    && service_handler.status != ServiceStatus::InKilling;
+                if let Some(pid) = pid.filter(|_| crashed && service_handler.crashed(now)) {
+                    let service = service_handler.shared_service();
+                    self.backend.spawn_debug_command(service, pid, exit_reason);
+                }
 
                 // If it has failed too quickly, increase service_handler's restart attempts
                 // and check if it has more attempts left.
                 service_handler.restart_attempts += u32::from(
-                    service_handler.has_some_failed_healthchecks()
-                        && service_handler.is_early_state(),
+                    service_handler.start_timed_out
+                        || (service_handler.has_some_failed_healthchecks()
+                            && service_handler.is_early_state()),
                 );
 
                 let new_status = if has_failed
                     || (service_handler.status == ServiceStatus::Running
                         && service_handler.has_some_failed_healthchecks())
                 {
-                    warn!(
-                        "Service: {} has failed, exit code: {}, healthchecks: {} ({:?})",
-                        service_handler.name(),
-                        exit_code,
-                        service_handler.has_some_failed_healthchecks(),
-                        service_handler.healthiness_checks_failed
-                    );
+                    if logged {
+                        warn!(
+                            service = service_name.as_str();
+                            "Service: {} has failed, {}{}, healthchecks: {} ({:?})",
+                            service_handler.name(),
+                            exit_reason,
+                            if oom_killed { " (OOM killed)" } else { "" },
+                            service_handler.has_some_failed_healthchecks(),
+                            service_handler.healthiness_checks_failed
+                        );
+                    }
+                    service_handler.failed_at = Some(now);
+                    service_handler.last_output = self
+                        .outputs
+                        .tail(&service_name, OUTPUT_TAIL_TIMEOUT)
+                        .iter()
+                        .map(|output| {
+                            let line = String::from_utf8_lossy(&output.line);
+                            line.trim_end_matches(['\n', '\r']).to_string()
+                        })
+                        .collect();
+                    if logged && !service_handler.last_output.is_empty() {
+                        let lines: Vec<String> = service_handler
+                            .last_output
+                            .iter()
+                            .map(|line| format!("  | {}", line))
+                            .collect();
+                        error!(
+                            service = service_name.as_str();
+                            "Last output of {} ({}):\n{}",
+                            service_name,
+                            exit_reason,
+                            lines.join("\n")
+                        );
+                    }
                     ServiceStatus::Failed
                 } else {
-                    info!(
-                        "Service: {} successfully exited with: {}.",
-                        service_handler.name(),
-                        exit_code
-                    );
+                    if logged {
+                        info!(
+                            service = service_name.as_str();
+                            "Service: {} successfully exited with: {}.",
+                            service_handler.name(),
+                            exit_reason
+                        );
+                    }
                     ServiceStatus::Success
                 };
-                let (new_sh, new_status) = service_handler.change_status(new_status);
+                let failed = new_status == ServiceStatus::Failed;
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.exited(&service_name, exit_reason, failed);
+                }
+                if let Some(history) = &mut self.history {
+                    history.exited(&service_name, exit_reason, failed);
+                }
+                let (new_sh, new_status) = service_handler.change_status(new_status, now);
                 self.repo.insert_sh_by_name(service_name.clone(), new_sh);
                 debug!(
                     "{}: new status for exited service: {:?}",
                     service_name, new_status
                 );
-                vec![Event::StatusChanged(service_name, new_status)]
+                let mut events = vec![Event::StatusChanged(service_name.clone(), new_status)];
+                if oom_killed {
+                    events.push(Event::OomKilled(service_name));
+                }
+                events
             }
             Event::Run(service_name) if self.repo.get_sh(&service_name).is_initial() => {
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 service_handler.status = ServiceStatus::Starting;
+                service_handler.notified_ready = false;
+                service_handler.start_timed_out = false;
+                service_handler.wall_timed_out = false;
                 let evs = vec![Event::StatusChanged(service_name, ServiceStatus::Starting)];
 
                 let res = healthcheck::prepare_service(&service_handler.service().healthiness);
                 if res.is_err() {
                     //TODO: maybe this is a bit too aggressive.
                     error!(
+                        service = service_handler.name().as_str();
                         "Prepare healthiness checks failed for service: {}, shutting down...",
                         service_handler.name()
                     );
@@ -130,29 +344,50 @@ impl Supervisor {
                         Event::ShuttingDownInitiated(ShuttingDown::Gracefully),
                     ];
                 }
-                let backoff = service_handler
-                    .service()
-                    .restart
-                    .backoff
-                    .mul(service_handler.restart_attempts);
-                process_spawner::spawn_fork_exec_handler(
-                    service_handler.service().clone(),
-                    backoff,
-                    self.repo.bus.join_bus(),
-                );
+                let (restart, attempts) = service_handler.restart_policy();
+                let mut backoff = restart.backoff.mul(attempts);
+                if self.storms.is_raging(service_handler.name()) {
+                    backoff = backoff.max(storms::STORM_BACKOFF);
+                }
+                service_handler.conditions_not_met = None;
+                service_handler.backoff_until = (!backoff.is_zero()).then(|| now + backoff);
+                let service = service_handler.shared_service();
+                let bus = self.repo.bus.join_bus();
+                self.backend.spawn(service, backoff, bus);
                 evs
             }
-            Event::SpawnFailed(s_name) => {
+            Event::ConditionsNotMet(s_name, reason)
+                if self.repo.get_sh(&s_name).status == ServiceStatus::Starting =>
+            {
+                self.repo.get_mut_sh(&s_name).conditions_not_met = Some(reason);
+                vec![]
+            }
+            Event::SpawnFailed(s_name, _reason) => {
                 let service_handler = self.repo.get_mut_sh(&s_name);
+                service_handler.conditions_not_met = None;
+                service_handler.backoff_until = None;
                 service_handler.status = ServiceStatus::Failed;
+                service_handler.crashes += 1;
+                service_handler.failed_at = Some(now);
                 vec![Event::StatusUpdate(s_name, ServiceStatus::Failed)]
             }
             Event::Kill(service_name) => {
                 debug!("Received kill request");
+                let service_handler = self.repo.get_sh(&service_name);
+                if service_handler.is_in_killing() && service_handler.needs_pre_stop() {
+                    // The termination signal will be sent once the hook is done.
+                    let service = service_handler.shared_service();
+                    let bus = self.repo.bus.join_bus();
+                    self.backend.spawn_pre_stop(service, bus);
+                    self.repo.get_mut_sh(&service_name).pre_stop = PreStopStatus::Running;
+                    return vec![];
+                }
                 let service_handler = self.repo.get_mut_sh(&service_name);
-                if service_handler.is_in_killing() {
-                    service_handler.shutting_down_started();
-                    kill(service_handler, None);
+                if service_handler.pre_stop == PreStopStatus::Running {
+                    debug!("{}: pre-stop hook is still running", service_name);
+                } else if service_handler.is_in_killing() {
+                    service_handler.shutting_down_started(now);
+                    kill(self.backend.as_mut(), service_handler, None);
                 } else {
                     debug!(
                         "Cannot send kill request, service was in: {}",
@@ -164,31 +399,401 @@ impl Supervisor {
             Event::ForceKill(service_name) if self.repo.get_sh(&service_name).is_in_killing() => {
                 debug!("Going to forcekill {}", service_name);
                 let service_handler = self.repo.get_mut_sh(&service_name);
-                kill(service_handler, Some(signal::SIGKILL));
+                kill(
+                    self.backend.as_mut(),
+                    service_handler,
+                    Some(signal::SIGKILL),
+                );
+                if service_handler.recycling {
+                    // It will be restarted once it has exited.
+                    return vec![];
+                }
                 service_handler.status = ServiceStatus::Failed;
                 vec![Event::new_status_changed(
                     service_handler.name(),
                     ServiceStatus::Failed,
                 )]
             }
+            Event::Recycle(service_name)
+                if self.repo.get_sh(&service_name).should_recycle(now, time) =>
+            {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                info!(
+                    service = service_name.as_str();
+                    "{}: restarting, max-runtime ({}) is over.",
+                    service_name,
+                    humantime::format_duration(service_handler.service().max_runtime.unwrap())
+                );
+                self.restart(&service_name, now)
+            }
+            Event::StartTimeout(service_name)
+                if self.repo.get_sh(&service_name).has_start_timed_out(now) =>
+            {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                warn!(
+                    service = service_name.as_str();
+                    "{}: not ready after its start-timeout ({}), killing it.",
+                    service_name,
+                    humantime::format_duration(service_handler.service().start_timeout.unwrap())
+                );
+                service_handler.start_timed_out = true;
+                vec![
+                    Event::new_status_update(&service_name, ServiceStatus::InKilling),
+                    Event::Kill(service_name),
+                ]
+            }
+            Event::WallTimeout(service_name)
+                if self.repo.get_sh(&service_name).has_wall_timed_out(now) =>
+            {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                warn!(
+                    service = service_name.as_str();
+                    "{}: still running after its wall-time ({}), killing it.",
+                    service_name,
+                    humantime::format_duration(service_handler.service().limits.wall_time.unwrap())
+                );
+                service_handler.wall_timed_out = true;
+                vec![
+                    Event::new_status_update(&service_name, ServiceStatus::InKilling),
+                    Event::Kill(service_name),
+                ]
+            }
+            Event::PathChanged(service_name) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                // Not started yet, or already restarting: it will read the changed paths anyway.
+                if !service_handler.can_be_restarted() {
+                    return vec![];
+                }
+                match service_handler.service().reload_signal {
+                    Some(_) if service_handler.exited => vec![],
+                    Some(reload_signal) => {
+                        info!(
+                            service = service_name.as_str();
+                            "{}: a watched path has changed, sending SIG{:?}.",
+                            service_name,
+                            reload_signal
+                        );
+                        self.backend
+                            .send_signal(service_handler, reload_signal.into());
+                        vec![]
+                    }
+                    None => {
+                        info!(
+                            service = service_name.as_str();
+                            "{}: a watched path has changed, restarting.",
+                            service_name
+                        );
+                        self.restart(&service_name, now)
+                    }
+                }
+            }
+            Event::ServiceChanged(service) => {
+                let service_name = service.name.clone();
+                let mut service_handler = self.repo.get_sh(&service_name).clone();
+                service_handler.redefine(service);
+                self.repo
+                    .insert_sh_by_name(service_name.clone(), service_handler);
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                if !service_handler.service().reload_on_change
+                    || !service_handler.can_be_restarted()
+                {
+                    info!(
+                        service = service_name.as_str();
+                        "{}: its definition has changed, it's used from its next start.",
+                        service_name
+                    );
+                    return vec![];
+                }
+                info!(
+                    service = service_name.as_str();
+                    "{}: its definition has changed, restarting.",
+                    service_name
+                );
+                self.restart(&service_name, now)
+            }
+            Event::ServiceAdded(service) => {
+                let service_name = service.name.clone();
+                if self.repo.services.contains_key(&service_name) {
+                    warn!(
+                        "{}: not added, there's already such a service.",
+                        service_name
+                    );
+                    return vec![];
+                }
+                info!(
+                    service = service_name.as_str();
+                    "{}: added, running: {}",
+                    service_name,
+                    service.command
+                );
+                let mut service_handler = ServiceHandler::from(service);
+                service_handler.initial_since = Some(now);
+                self.repo.insert_sh_by_name(service_name, service_handler);
+                vec![]
+            }
+            Event::StopReplaced(service_name) => {
+                let lifecycle_status = self.status;
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                if !service_handler.should_stop_replaced(lifecycle_status, now) {
+                    return vec![];
+                }
+                let Some(replaced) = service_handler
+                    .replaced
+                    .as_mut()
+                    .filter(|replaced| !replaced.exited)
+                else {
+                    return vec![];
+                };
+                let signal = if replaced.stopping_since.is_none() {
+                    info!(
+                        service = service_name.as_str();
+                        "{}: stopping the replaced process {}.",
+                        service_name,
+                        replaced.pid
+                    );
+                    replaced.stopping_since = Some(now);
+                    None
+                } else {
+                    debug!(
+                        "Going to forcekill the replaced process of {}",
+                        service_name
+                    );
+                    replaced.killed = true;
+                    Some(signal::SIGKILL)
+                };
+                // Signalled like the service, but with the pid of the replaced process.
+                let pid = replaced.pid;
+                let mut replaced_handler = service_handler.clone();
+                replaced_handler.pid = Some(pid);
+                kill(self.backend.as_mut(), &replaced_handler, signal);
+                vec![]
+            }
+            Event::ReplacedExited(service_name, exit_reason) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                let Some(replaced) = service_handler.replaced.take() else {
+                    return vec![];
+                };
+                if let Some(spawned_at) = replaced.spawned_at {
+                    service_handler.runtime += now.saturating_duration_since(spawned_at);
+                }
+                info!(
+                    service = service_name.as_str();
+                    "{}: the replaced process has exited with: {}.",
+                    service_name,
+                    exit_reason
+                );
+                self.repo.remove_pid(replaced.pid, &service_name);
+                vec![]
+            }
+            Event::Switch(service_name) => {
+                let Some((template, _)) = self.repo.get_sh(&service_name).service().instance()
+                else {
+                    return vec![];
+                };
+                let template = template.to_string();
+                info!(
+                    service = service_name.as_str();
+                    "{}: switching to this variant of {}.",
+                    service_name,
+                    template
+                );
+                // It supersedes the pending switch to another variant, if any.
+                let repo = &self.repo;
+                self.switches
+                    .retain(|switch| !repo.services[switch].is_variant_of(&template));
+                self.switches.push(service_name.clone());
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                service_handler.inactive = false;
+                service_handler.start_requested = true;
+                vec![]
+            }
+            Event::Scale(template, instances) => {
+                info!("Scaling {} to {} instances.", template, instances);
+                let scaled: Vec<(ServiceName, usize)> = self
+                    .repo
+                    .services
+                    .values()
+                    .filter_map(|sh| {
+                        let (instance_of, index) = sh.service().scaled_instance()?;
+                        (instance_of == template).then(|| (sh.name().clone(), index))
+                    })
+                    .collect();
+                let mut events = vec![];
+                for (service_name, index) in scaled {
+                    if index > instances {
+                        let stopping = self.deactivate(&service_name);
+                        if !stopping.is_empty() {
+                            info!(
+                                service = service_name.as_str();
+                                "{}: stopping it, {} was scaled down.",
+                                service_name,
+                                template
+                            );
+                        }
+                        events.extend(stopping);
+                        continue;
+                    }
+                    let service_handler = self.repo.get_mut_sh(&service_name);
+                    if service_handler.inactive {
+                        service_handler.inactive = false;
+                        service_handler.start_requested = true;
+                    }
+                }
+                events
+            }
+            Event::Restart(services, max_unavailable) => {
+                info!(
+                    "Restarting {} services, at most {} at a time.",
+                    services.len(),
+                    max_unavailable
+                );
+                self.rollouts.push(Rollout::new(services, max_unavailable));
+                vec![]
+            }
+            Event::Stop(services) => {
+                info!("Stopping {} services.", services.len());
+                self.stops.push(Stop::new(services));
+                vec![]
+            }
+            Event::NotifiedReady(service_name) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                // Sent by the process, so it might come before it's known to be started.
+                if matches!(
+                    service_handler.status,
+                    ServiceStatus::Starting | ServiceStatus::Started
+                ) {
+                    service_handler.notified_ready = true;
+                }
+                vec![]
+            }
+            Event::PreStopDone(service_name) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                // The service might have exited (and restarted) in the meanwhile.
+                if service_handler.pre_stop == PreStopStatus::Running {
+                    service_handler.pre_stop = PreStopStatus::Done;
+                    if service_handler.is_in_killing() {
+                        service_handler.shutting_down_started(now);
+                        kill(self.backend.as_mut(), service_handler, None);
+                    }
+                }
+                vec![]
+            }
             Event::PidChanged(service_name, pid) => {
                 self.repo.add_pid(pid, service_name.clone());
 
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 service_handler.pid = Some(pid);
+                service_handler.exited = false;
+                service_handler.spawns += 1;
+                service_handler.spawned_at = Some(now);
+                service_handler.conditions_not_met = None;
+                service_handler.backoff_until = None;
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.spawned(&service_name, pid.as_raw());
+                }
+                if let Some(history) = &mut self.history {
+                    history.spawned(&service_name, pid.as_raw(), service_handler.spawns);
+                }
                 if service_handler.is_in_killing() {
                     // Ah! Gotcha!
-                    service_handler.shutting_down_start = Some(Instant::now());
-                    kill(service_handler, None)
+                    service_handler.shutting_down_start = Some(now);
+                    kill(self.backend.as_mut(), service_handler, None)
                 } else {
+                    if service_handler.is_paused() {
+                        // Restarted while paused, it stays paused.
+                        self.backend.set_paused(service_handler, true);
+                    }
                     service_handler.status = ServiceStatus::Started;
                     return vec![Event::StatusChanged(service_name, ServiceStatus::Started)];
                 }
 
                 vec![]
             }
+            Event::Pause(service_name, paused_by) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                if service_handler.is_in_killing() {
+                    return vec![];
+                }
+                let was_paused = service_handler.is_paused();
+                service_handler.paused_by.insert(paused_by);
+                if was_paused {
+                    return vec![];
+                }
+                info!(service = service_name.as_str(); "{}: pausing", service_name);
+                if !service_handler.exited {
+                    self.backend.set_paused(service_handler, true);
+                }
+                vec![Event::Paused(service_name, true)]
+            }
+            Event::Resume(service_name, paused_by) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                if !service_handler.paused_by.remove(&paused_by) {
+                    return vec![];
+                }
+                if service_handler.is_paused() {
+                    info!(
+                        service = service_name.as_str();
+                        "{}: still paused by {:?}",
+                        service_name,
+                        service_handler.paused_by
+                    );
+                    return vec![];
+                }
+                info!(service = service_name.as_str(); "{}: resuming", service_name);
+                if !service_handler.exited {
+                    self.backend.set_paused(service_handler, false);
+                }
+                vec![Event::Paused(service_name, false)]
+            }
+            Event::SocketActivated(service_name) => {
+                // Started by the next iteration, once its dependencies are satisfied.
+                self.repo.get_mut_sh(&service_name).start_requested = true;
+                vec![]
+            }
+            Event::Start(service_name)
+                if self.repo.get_sh(&service_name).is_waiting_for_start() =>
+            {
+                info!(service = service_name.as_str(); "{}: start requested", service_name);
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                service_handler.inactive = false;
+                service_handler.start_requested = true;
+                vec![]
+            }
+            Event::Start(service_name)
+                if self.repo.get_sh(&service_name).is_waiting_for_timer() =>
+            {
+                info!(service = service_name.as_str(); "{}: starting it again.", service_name);
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                service_handler.restart_attempts = 0;
+                service_handler.status = ServiceStatus::Initial;
+                service_handler.initial_since = Some(now);
+                service_handler.start_requested = true;
+                vec![Event::new_status_changed(
+                    &service_name,
+                    ServiceStatus::Initial,
+                )]
+            }
+            Event::Clear(service_name)
+                if self.repo.get_sh(&service_name).status == ServiceStatus::Broken =>
+            {
+                info!(service = service_name.as_str(); "{}: cleared, starting it again.", service_name);
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                service_handler.crashes = 0;
+                service_handler.restart_attempts = 0;
+                service_handler.broken_at = None;
+                service_handler.status = ServiceStatus::Initial;
+                service_handler.initial_since = Some(now);
+                vec![Event::new_status_changed(
+                    &service_name,
+                    ServiceStatus::Initial,
+                )]
+            }
             Event::HealthCheck(s_name, health) => {
                 let sh = self.repo.get_mut_sh(&s_name);
+                if sh.is_paused() {
+                    // A stopped process can't pass the checks, but it isn't failing either.
+                    return vec![];
+                }
                 // Count the failed healthiness checks. The state change producer wll handle states
                 // changes (if they're needed)
                 sh.add_healthcheck_event(health);
@@ -203,16 +808,30 @@ impl Supervisor {
                         warn!("Terminating all services...");
                     }
                 }
+                if self.status == LifecycleStatus::Running {
+                    sd_notify::notify(sd_notify::STOPPING);
+                    self.shutdown_deadline = self.shutdown_timeout.map(|timeout| now + timeout);
+                }
+                if shutting_down == ShuttingDown::Forcefully {
+                    self.shutdown_deadline = None;
+                }
                 self.status = LifecycleStatus::ShuttingDown(shutting_down);
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.shutting_down(shutting_down);
+                }
                 signal_handling::clear_sigtem();
                 vec![]
             }
             Event::StatusUpdate(service_name, new_status) => {
                 let service_handler = self.repo.get_sh(&service_name);
 
-                let (new_sh, new_status) = service_handler.change_status(new_status);
+                let (new_sh, new_status) = service_handler.change_status(new_status, now);
                 if new_status != service_handler.status {
                     self.repo.insert_sh_by_name(service_name.clone(), new_sh);
+                    if let (Some(tracer), ServiceStatus::Running) = (&mut self.tracer, &new_status)
+                    {
+                        tracer.ready(&service_name);
+                    }
                     // this is the only place where the new_status changed is emitted.
                     vec![Event::new_status_changed(&service_name, new_status)]
                 } else {
@@ -230,93 +849,385 @@ impl Supervisor {
         }
     }
 
-    /// Blocking call.
-    /// This function will run the services and reap dead pids.
-    fn run(mut self) -> ExitStatus {
-        while !self.repo.all_have_finished() {
-            // Ingest updates
-            let received_events = self.repo.get_events();
-            debug!("Applying events... {:?}", received_events);
-            match (self.status, signal_handling::is_sigterm_received()) {
-                (LifecycleStatus::Running, true) => {
-                    warn!("1. SIGTERM received");
-                    self.repo
-                        .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
-                }
-                (LifecycleStatus::ShuttingDown(ShuttingDown::Gracefully), true) => {
-                    warn!("2. SIGTERM received");
-                    self.repo
-                        .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Forcefully));
+    /// Restarts the service, which is up, for a reason other than a failure. If it restarts
+    /// start-first, its process is replaced: it's only stopped once the new one is running.
+    /// Otherwise, it's stopped first, and started again once it has exited.
+    fn restart(&mut self, service_name: &ServiceName, now: Instant) -> Vec<Event> {
+        let service_handler = self.repo.get_mut_sh(service_name);
+        if service_handler.can_be_replaced() {
+            service_handler.replace(now);
+            return vec![Event::new_status_changed(
+                service_name,
+                ServiceStatus::Initial,
+            )];
+        }
+        service_handler.recycling = true;
+        vec![
+            Event::new_status_update(service_name, ServiceStatus::InKilling),
+            Event::Kill(service_name.clone()),
+        ]
+    }
+
+    /// Restarts the next services of the rolling restarts, once the previous ones are running.
+    fn progress_rollouts(&mut self) -> Vec<Event> {
+        if self.status != LifecycleStatus::Running {
+            self.rollouts.clear();
+            return vec![];
+        }
+        let now = self.repo.now();
+        let mut events = vec![];
+        let mut rollouts = std::mem::take(&mut self.rollouts);
+        for rollout in &mut rollouts {
+            for service_name in rollout.next(&self.repo) {
+                info!(service = service_name.as_str(); "{}: restarting, as requested.", service_name);
+                events.extend(self.restart(&service_name, now));
+            }
+        }
+        rollouts.retain(|rollout| !rollout.is_done());
+        self.rollouts = rollouts;
+        events
+    }
+
+    /// Logs the waves of services which are up, and what the current one is waiting for.
+    fn progress_boot(&mut self) {
+        let Some(boot) = &mut self.boot else {
+            return;
+        };
+        let elapsed = self.repo.now().saturating_duration_since(self.started_at);
+        for message in boot.progress(&self.repo, elapsed) {
+            info!("{}", message);
+        }
+        if boot.is_done() || self.status != LifecycleStatus::Running {
+            self.boot = None;
+        }
+    }
+
+    /// Publishes why the services aren't running, for the ones where it has changed.
+    fn diagnose(&mut self) -> Vec<Event> {
+        let mut events = vec![];
+        for sh in self.repo.services.values() {
+            let blockers = diagnosis::diagnose(&self.repo, sh);
+            if self.diagnoses.get(sh.name()) != Some(&blockers) {
+                self.diagnoses.insert(sh.name().clone(), blockers.clone());
+                events.push(Event::NotRunning(sh.name().clone(), blockers));
+            }
+        }
+        events
+    }
+
+    /// Stops the next services of the bulk stops, once the ones starting after them are down.
+    fn progress_stops(&mut self) -> Vec<Event> {
+        if self.status != LifecycleStatus::Running {
+            self.stops.clear();
+            return vec![];
+        }
+        let mut events = vec![];
+        let mut stops = std::mem::take(&mut self.stops);
+        for stop in &mut stops {
+            for service_name in stop.next(&self.repo) {
+                info!(service = service_name.as_str(); "{}: stopping, as requested.", service_name);
+                events.extend(self.deactivate(&service_name));
+            }
+        }
+        stops.retain(|stop| !stop.is_done());
+        self.stops = stops;
+        events
+    }
+
+    /// Completes the switches to the variants which are running: the other variants of their
+    /// template are stopped, and they're not started again until they're switched to. The switch
+    /// to a variant which has finished is given up, and the other variants are kept.
+    fn complete_switches(&mut self) -> Vec<Event> {
+        let mut events = vec![];
+        for service_name in std::mem::take(&mut self.switches) {
+            let service_handler = &self.repo.services[&service_name];
+            if !service_handler.is_running() {
+                if service_handler.is_finished()
+                    || service_handler.status == ServiceStatus::FinishedFailed
+                {
+                    warn!(
+                        service = service_name.as_str();
+                        "{}: it has finished, the switch to it is given up.",
+                        service_name
+                    );
+                } else {
+                    self.switches.push(service_name);
                 }
-                _ => {}
+                continue;
             }
-            // Handling of the received events and commands:
-            let produced_events = received_events
-                .into_iter()
-                .flat_map(|ev| self.handle_event(ev))
-                .collect::<Vec<Event>>();
-            debug!("Produced events: {:?}", produced_events);
-            // Producing commands which will be applied in the next iteration
-            let next_evs: Vec<Event> = self
+            let template = match service_handler.service().instance() {
+                Some((template, _)) => template.to_string(),
+                None => continue,
+            };
+            let others: Vec<ServiceName> = self
                 .repo
                 .services
-                .iter()
-                .flat_map(|(_s_name, sh)| sh.next(&self.repo, self.status))
-                .chain(reaper::run(&self.repo, MAX_PROCESS_REAPS_ITERS))
+                .values()
+                .filter(|sh| sh.name() != &service_name && sh.is_variant_of(&template))
+                .map(|sh| sh.name().clone())
                 .collect();
-            debug!("Next evs: {:?}", next_evs);
-            // Dispatch everything via the bus. Since the bus is run by another thread,
-            // the next_evs might not arrive in the next batch, leading to possibly duplicated
-            // commands.
-            produced_events
-                .into_iter()
-                .chain(next_evs)
-                .for_each(|ev| self.repo.send_ev(ev));
-
-            thread::sleep(Duration::from_millis(300));
+            for other in others {
+                let stopping = self.deactivate(&other);
+                if !stopping.is_empty() {
+                    info!(
+                        service = other.as_str();
+                        "{}: stopping it, {} was switched to.",
+                        other,
+                        service_name
+                    );
+                }
+                events.extend(stopping);
+            }
+        }
+        events
+    }
+
+    /// The service isn't started again until it's switched to (or scaled up, or asked to start).
+    /// If it's up, it's stopped: it goes back to Initial once it has exited, and waits there.
+    fn deactivate(&mut self, service_name: &ServiceName) -> Vec<Event> {
+        let service_handler = self.repo.get_mut_sh(service_name);
+        service_handler.inactive = true;
+        service_handler.start_requested = false;
+        let is_up = matches!(
+            service_handler.status,
+            ServiceStatus::Starting | ServiceStatus::Started | ServiceStatus::Running
+        );
+        if !is_up || service_handler.recycling {
+            return vec![];
+        }
+        service_handler.recycling = true;
+        vec![
+            Event::new_status_update(service_name, ServiceStatus::InKilling),
+            Event::Kill(service_name.clone()),
+        ]
+    }
+
+    /// Blocking call.
+    /// This function will run the services and reap dead pids.
+    fn run(mut self) -> RunReport {
+        while !self.has_finished() {
+            self.step();
+            self.repo.wait(LOOP_INTERVAL);
+        }
+        self.finish()
+    }
+
+    /// All the services have finished. While Horust is running, the ones with a timer are
+    /// started again once it triggers, so it keeps running too.
+    fn has_finished(&self) -> bool {
+        self.repo.all_have_finished()
+            && (self.status != LifecycleStatus::Running
+                || self
+                    .repo
+                    .services
+                    .values()
+                    .all(|sh| !sh.is_waiting_for_timer()))
+    }
+
+    /// An iteration of the supervisor: handles the received events, and sends the ones which make
+    /// the services progress.
+    fn step(&mut self) {
+        // Ingest updates
+        let received_events = self.repo.get_events();
+        debug!("Applying events... {:?}", received_events);
+        match (self.status, signal_handling::is_sigterm_received()) {
+            (LifecycleStatus::Running, true) => {
+                warn!("1. SIGTERM received");
+                self.repo
+                    .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
+            }
+            (LifecycleStatus::ShuttingDown(ShuttingDown::Gracefully), true) => {
+                warn!("2. SIGTERM received");
+                self.repo
+                    .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Forcefully));
+            }
+            _ => {}
+        }
+        if self
+            .shutdown_deadline
+            .is_some_and(|deadline| self.repo.now() >= deadline)
+        {
+            warn!(
+                "Shutdown timeout ({}) is over, killing the remaining services.",
+                humantime::format_duration(self.shutdown_timeout.unwrap_or_default())
+            );
+            self.shutdown_deadline = None;
+            self.repo
+                .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Forcefully));
+        }
+        if signal_handling::take_sighup() && self.status == LifecycleStatus::Running {
+            info!("SIGHUP received, reloading the services.");
+            self.repo.send_ev(Event::Reload);
         }
+        // Handling of the received events and commands:
+        let mut produced_events = received_events
+            .into_iter()
+            .flat_map(|ev| self.handle_event(ev))
+            .collect::<Vec<Event>>();
+        produced_events.extend(self.complete_switches());
+        produced_events.extend(self.progress_rollouts());
+        produced_events.extend(self.progress_stops());
+        produced_events.extend(self.diagnose());
+        self.storms.summarize(self.repo.now());
+        debug!("Produced events: {:?}", produced_events);
+        self.progress_boot();
+        if !self.ready_notified
+            && self.status == LifecycleStatus::Running
+            && self.repo.all_have_started()
+        {
+            info!("All services are up.");
+            sd_notify::notify(sd_notify::READY);
+            self.ready_notified = true;
+            self.repo.send_ev(Event::SystemReady);
+            if let Some(tracer) = &mut self.tracer {
+                tracer.booted();
+            }
+        }
+        self.check_boot_timeout();
+        // The exits are handled in the next iteration: meanwhile the reaped processes must not be
+        // signalled anymore, since their pids can be reused.
+        let reaped = self.backend.reap(&self.repo);
+        for ev in &reaped {
+            match ev {
+                Event::ServiceExited(service_name, _) => {
+                    self.repo.get_mut_sh(service_name).exited = true;
+                }
+                Event::ReplacedExited(service_name, _) => {
+                    if let Some(replaced) = &mut self.repo.get_mut_sh(service_name).replaced {
+                        replaced.exited = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Producing commands which will be applied in the next iteration
+        let now = self.repo.now();
+        let ready = match self.status {
+            LifecycleStatus::Running => {
+                self.repo.request_dependencies();
+                self.repo.ready()
+            }
+            LifecycleStatus::ShuttingDown(_) => vec![],
+        };
+        let next_evs: Vec<Event> = ready
+            .into_iter()
+            .map(Event::Run)
+            .chain(
+                self.repo
+                    .active()
+                    .flat_map(|sh| sh.next(&self.repo, self.status)),
+            )
+            .chain(
+                self.repo
+                    .services
+                    .values()
+                    .filter(|sh| sh.should_stop_replaced(self.status, now))
+                    .map(|sh| Event::StopReplaced(sh.name().clone())),
+            )
+            .chain(reaped)
+            .collect();
+        debug!("Next evs: {:?}", next_evs);
+        // Dispatch everything via the bus. Since the bus is run by another thread,
+        // the next_evs might not arrive in the next batch, leading to possibly duplicated
+        // commands.
+        produced_events
+            .into_iter()
+            .chain(next_evs)
+            .for_each(|ev| self.repo.send_ev(ev));
+    }
+
+    /// Takes the `boot_timeout_action`, once the `boot_timeout` is over and some services aren't
+    /// up yet.
+    fn check_boot_timeout(&mut self) {
+        let Some(boot_timeout) = self.boot_timeout else {
+            return;
+        };
+        if self.ready_notified
+            || self.boot_timed_out
+            || self.status != LifecycleStatus::Running
+            || self.repo.now() < self.started_at + boot_timeout
+        {
+            return;
+        }
+        self.boot_timed_out = true;
+        let not_started: Vec<&str> = self
+            .repo
+            .not_started()
+            .map(|sh| sh.name().as_str())
+            .collect();
+        let message = format!(
+            "Boot timeout ({}) is over, these services aren't up yet: {}",
+            humantime::format_duration(boot_timeout),
+            not_started.join(", ")
+        );
+        match self.boot_timeout_action {
+            BootTimeoutAction::Shutdown => {
+                error!("{}. Shutting down.", message);
+                self.repo
+                    .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
+            }
+            BootTimeoutAction::Kill => {
+                error!("{}. Killing all the services.", message);
+                self.repo
+                    .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Forcefully));
+            }
+            BootTimeoutAction::Warn => warn!("{}.", message),
+        }
+    }
 
+    /// All the services have finished: returns the report of the run.
+    fn finish(&mut self) -> RunReport {
         debug!("All services have finished");
-        // If we're the init system, let's be sure that everything stops before exiting.
-        // TODO: Test (probably via docker).
-        if unistd::getpid() == INIT_PID {
-            let all_processes = unistd::Pid::from_raw(-1);
-            let _res = signal::kill(all_processes, signal::SIGTERM);
-            thread::sleep(Duration::from_secs(3));
-            let _res = signal::kill(all_processes, signal::SIGKILL);
+        if self.status == LifecycleStatus::Running {
+            sd_notify::notify(sd_notify::STOPPING);
         }
+        self.backend.finished();
 
         self.repo
             .send_ev(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
-        if self.repo.any_finished_failed() {
-            ExitStatus::SomeServiceFailed
-        } else {
-            ExitStatus::Successful
+        if let Some(tracer) = self.tracer.take() {
+            tracer.finish(TRACES_EXPORT_TIMEOUT);
         }
+        let mut report = self.repo.report();
+        report.boot_timed_out =
+            self.boot_timed_out && self.boot_timeout_action != BootTimeoutAction::Warn;
+        report
     }
 }
 
 /// A Kill wrapper which will send a signal to sh.
 /// It will send the signal set out in the termination section of the service
-fn kill(sh: &ServiceHandler, signal: Option<signal::Signal>) {
-    let signal = signal.unwrap_or_else(|| sh.service().termination.signal.into());
+fn kill(backend: &mut dyn Backend, sh: &ServiceHandler, signal: Option<signal::Signal>) {
+    let mut signal = signal.unwrap_or_else(|| sh.service().termination.signal.into());
     debug!("Going to send {} signal to pid {:?}", signal, sh.pid());
-    if let Some(pid) = sh.pid() {
-        if let Err(error) = signal::kill(pid, signal) {
-            match error {
-                // No process or process group can be found corresponding to that specified by pid
-                // It has exited already, so it's fine.
-                nix::errno::Errno::ESRCH => (),
-                _ => error!(
-                    "Error killing the process: {}, service: {}, pid: {:?}",
-                    error,
-                    sh.name(),
-                    pid,
-                ),
-            }
+    if sh.exited {
+        debug!(
+            "{}: pid {:?} has already exited, not sending {}",
+            sh.name(),
+            sh.pid(),
+            signal
+        );
+    } else if let Some(pid) = sh.pid() {
+        // Otherwise the signal would be discarded, instead of terminating the service.
+        if sh.service().sandbox.pid_namespace && !backend.catches_signal(pid, signal) {
+            debug!(
+                "{}: pid {} doesn't handle {}, as the init of its PID namespace it gets SIGKILL",
+                sh.name(),
+                pid,
+                signal
+            );
+            signal = signal::SIGKILL;
+        }
+        backend.send_signal(sh, signal);
+        // A stopped process would handle the termination signal only once continued.
+        if sh.is_paused() {
+            backend.set_paused(sh, false);
+        } else if sh.service().termination.send_sigcont {
+            backend.send_signal(sh, signal::SIGCONT);
         }
     } else {
         warn!(
+            service = sh.name().as_str();
             "{}: Missing pid to kill but service was in {:?} state.",
             sh.name(),
             sh.status
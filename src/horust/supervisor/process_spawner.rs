@@ -1,60 +1,105 @@
-use std::ffi::{CStr, CString};
-use std::os::unix::io::AsRawFd;
-use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::BorrowedFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{fs::File, io::BufReader};
-use std::{fs::OpenOptions, ops::Add};
-use std::{
-    io::{self, Read},
-    os::fd::OwnedFd,
-};
-
-use anyhow::{anyhow, Context, Result};
-use crossbeam::channel::{after, tick};
+use std::{io, ops::Add, os::fd::OwnedFd};
+
+use anyhow::{anyhow, bail, Context, Result};
 use nix::errno::Errno;
 use nix::fcntl;
+use nix::sys::resource::{getrlimit, setrlimit, Resource, RLIM_INFINITY};
 use nix::unistd;
-use nix::unistd::{fork, ForkResult, Pid, Uid};
+use nix::unistd::{fork, ForkResult, Gid, Pid, Uid};
 
 use crate::horust::bus::BusConnector;
-use crate::horust::formats::{Event, LogOutput, Service};
+use crate::horust::cgroups;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Event, LogOutput, Service, ServiceName, ServiceType};
+use crate::horust::output::Outputs;
 use crate::horust::signal_safe::panic_ssafe;
+use crate::horust::sockets::{Sockets, LISTEN_FDS_START};
+use crate::horust::subprocess;
+use crate::horust::supervisor::conditions;
+use crate::horust::supervisor::sandbox::{self, Namespaces};
+use crate::horust::supervisor::secrets;
+
+/// How often the conditions of a service are checked, until they're met.
+const CONDITIONS_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The environment the last process of each service was spawned with.
+static SPAWNED_ENVIRONMENTS: Mutex<BTreeMap<ServiceName, Vec<String>>> =
+    Mutex::new(BTreeMap::new());
 
-/// Run another thread that will wait for the start delay and handle the fork / exec
+/// The environment the last process of `service` was spawned with, e.g. for running a command
+/// like the service does: the variables of `from-command` and `from-provider` are the same.
+pub(crate) fn spawned_environment(service: &ServiceName) -> Option<Vec<String>> {
+    SPAWNED_ENVIRONMENTS.lock().unwrap().get(service).cloned()
+}
+
+/// Run another thread that will wait for the start delay and the conditions, and handle the fork / exec
 pub(crate) fn spawn_fork_exec_handler(
-    service: Service,
+    service: Arc<Service>,
     backoff: Duration,
     bus: BusConnector<Event>,
+    outputs: Outputs,
+    clock: Arc<dyn Clock>,
+    sockets: Sockets,
 ) {
     std::thread::spawn(move || {
-        let total_sleep = service.start_delay.add(backoff);
-        let timeout = after(total_sleep);
-        let ticker = tick(Duration::from_millis(100));
+        let total_sleep = service.start_delay.pick().add(backoff);
+        let mut timeout = clock.after(total_sleep);
+        let ticker = clock.tick(Duration::from_millis(100));
         debug!("going to sleep: {:?}", total_sleep);
         // If start-delay is very high, this might interfere with the shutdown of the system.
         // the thread will listen for shutdown events from the bus, and will early exit if there is
         // a shuttingdowninitiated event
         let is_shutting_down_ev = |ev: Event| matches!(ev, Event::ShuttingDownInitiated(_));
 
+        let mut waiting_since = None;
         let ev = loop {
+            let mut conditions_met = false;
             select! {
                     recv(ticker) -> _ => {
                         let is_shutting_down = bus.try_get_events().into_iter().any(is_shutting_down_ev);
                         if is_shutting_down {
-                            break Event::SpawnFailed(service.name.clone());
+                            break Event::SpawnFailed(service.name.clone(), "Horust is shutting down".into());
                         }
                     },
-                    recv(timeout) -> _ => break match spawn_process(&service) {
-                            Ok(pid) => {
-                                debug!("Setting pid:{} for service: {}", pid, service.name);
-                                Event::new_pid_changed(service.name.clone(), pid)
-                            }
-                            Err(error) => {
-                                error!("Failed spawning the process: {}", error);
-                                Event::SpawnFailed(service.name)
+                    recv(timeout) -> _ => {
+                        match conditions::check(&service) {
+                            Ok(()) => conditions_met = true,
+                            Err(not_met) => {
+                                let now = clock.now();
+                                let waited = now.saturating_duration_since(*waiting_since.get_or_insert(now));
+                                if not_met.timeout.is_some_and(|timeout| waited >= timeout) {
+                                    error!(service = service.name.as_str(); "{}: gave up waiting: {}", service.name, not_met.reason);
+                                    break Event::SpawnFailed(service.name.clone(), format!("Gave up waiting: {}", not_met.reason));
+                                }
+                                info!(service = service.name.as_str(); "{}: waiting for the conditions: {}", service.name, not_met.reason);
+                                bus.send_event(Event::ConditionsNotMet(service.name.clone(), not_met.reason.to_string()));
+                                timeout = clock.after(CONDITIONS_RETRY_INTERVAL);
                             }
+                        }
                     },
             }
+            if conditions_met {
+                break match spawn_process(&service, &outputs, &sockets, clock.as_ref()) {
+                    Ok(pid) => {
+                        debug!("Setting pid:{} for service: {}", pid, service.name);
+                        Event::new_pid_changed(service.name.clone(), pid)
+                    }
+                    Err(error) => {
+                        error!(service = service.name.as_str(); "Failed spawning the process: {}", error);
+                        Event::SpawnFailed(service.name.clone(), format!("{:#}", error))
+                    }
+                };
+            }
         };
         bus.send_event(ev);
     });
@@ -62,96 +107,248 @@ pub(crate) fn spawn_fork_exec_handler(
 
 /// Produces the execvpe arguments out of a `Service`
 #[inline]
-fn exec_args(service: &Service) -> Result<(CString, Vec<CString>, Vec<CString>)> {
+fn exec_args(
+    service: &Service,
+    clock: &dyn Clock,
+) -> Result<(CString, Vec<CString>, Vec<CString>)> {
     let chunks: Vec<String> =
         shlex::split(&service.command).context(format!("Invalid command: {}", service.command,))?;
-    let program_name = String::from(chunks.first().unwrap());
+    let program_name = chunks.first().context("Empty command")?.clone();
     let to_cstring = |s: Vec<String>| {
         s.into_iter()
             .map(|arg| CString::new(arg).map_err(Into::into))
             .collect::<Result<Vec<_>>>()
     };
     let arg_cstrings = to_cstring(chunks)?;
-    let environment = service.get_environment()?;
-    let env_cstrings = to_cstring(environment)?;
+    let environment = environment(service, clock)?;
     let path = if program_name.contains('/') {
         program_name.to_string()
     } else {
-        find_program(&program_name)?
+        // The one of the service, e.g. extended by `environment.source`.
+        let path_var = environment
+            .iter()
+            .find_map(|kv| kv.strip_prefix("PATH="))
+            .map(OsString::from);
+        find_program(&program_name, path_var)?
     };
+    let env_cstrings = to_cstring(environment)?;
     Ok((CString::new(path)?, arg_cstrings, env_cstrings))
 }
 
+/// The environment of the service, including the variables computed by `environment.from-command`
+/// and the secrets of `environment.from-provider`.
+pub(crate) fn environment(service: &Service, clock: &dyn Clock) -> Result<Vec<String>> {
+    let mut environment = service.get_environment()?;
+    // The socket of Horust's own service manager, e.g. kept by `keep-env`: only Horust notifies
+    // it. The notify services get Horust's socket instead, when spawned.
+    environment.retain(|kv| !kv.starts_with(NOTIFY_SOCKET_PREFIX));
+    fn set(environment: &mut Vec<String>, key: &str, value: String) {
+        environment.retain(|kv| kv.split_once('=').map(|(k, _v)| k) != Some(key));
+        environment.push(format!("{}={}", key, value));
+    }
+    for (key, command) in &service.environment.from_command {
+        let timeout = service.environment.from_command_timeout;
+        let value = environment_from_command(service, command, timeout, clock)
+            .with_context(|| format!("Failed computing environment variable {}", key))?;
+        set(&mut environment, key, value);
+    }
+    for provider in &service.environment.from_provider {
+        let prefix = format!("{}=", provider.var);
+        if !provider.overrides && environment.iter().any(|kv| kv.starts_with(&prefix)) {
+            continue;
+        }
+        let value = secrets::get(&service.name, provider, clock.now(), || {
+            environment_from_command(service, &provider.command, provider.timeout, clock)
+        })
+        .with_context(|| format!("Failed fetching the secret {}", provider.var))?;
+        set(&mut environment, &provider.var, value);
+    }
+    Ok(environment)
+}
+
+/// Runs `command` in the context of the service, and returns its stdout without the trailing newline.
+fn environment_from_command(
+    service: &Service,
+    command: &str,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<String> {
+    let mut cmd = subprocess::service_command(service, command)?;
+    cmd.stdout(Stdio::piped());
+    let output = subprocess::run(&mut cmd, timeout, clock)
+        .with_context(|| format!("Failed running: {}", command))?;
+    if !output.status.success() {
+        bail!("Command `{}` has failed: {}", command, output.status);
+    }
+    let value = String::from_utf8(output.stdout)?;
+    Ok(value.trim_end_matches(['\n', '\r']).to_string())
+}
+
 #[inline]
 fn child_process_main(
     service: &Service,
-    path: CString,
-    cwd: PathBuf,
-    uid: Uid,
-    arg_cptr: Vec<&CStr>,
-    env_cptr: Vec<&CStr>,
+    namespaces: Option<&Namespaces>,
+    cgroup_procs: Option<&OwnedFd>,
+    listen_fds: &mut [RawFd],
+    (path, arg_cptr, env_cptr): (CString, Vec<&CStr>, Vec<&CStr>),
+    (cwd, uid, gid): (PathBuf, Uid, Option<Gid>),
+    error_pipe: RawFd,
 ) {
+    let failed = |message: &str, errno: Errno, status: i32| {
+        child_failed(error_pipe, &service.name, message, errno, status)
+    };
+    // Before anything else, so that all the processes of the service are in its cgroup.
+    if let Some(cgroup_procs) = cgroup_procs {
+        if let Err(errno) = cgroups::join(cgroup_procs) {
+            failed("Failed joining the cgroup", errno, 107);
+        }
+    }
     if let Err(errno) = redirect_output(&service.stdout, LogOutput::Stdout) {
-        panic_ssafe(
-            "child_process_main: Redirect stdout failed.",
-            Some(&service.name),
-            errno,
-            101,
-        );
+        failed("Failed redirecting stdout", errno, 101);
     }
     if let Err(errno) = redirect_output(&service.stderr, LogOutput::Stderr) {
-        panic_ssafe(
-            "child_process_main: Redirect stderr failed.",
-            Some(&service.name),
-            errno,
-            102,
-        );
+        failed("Failed redirecting stderr", errno, 102);
     }
-    if let Err(errno) = exec(path, arg_cptr, env_cptr, uid, cwd) {
-        panic_ssafe(
-            "child_process_main: Failed to exec the new process.",
-            Some(&service.name),
-            errno,
-            103,
-        );
+    let mut listen_pid = [0u8; 32];
+    let mut env_cptr = env_cptr;
+    if !listen_fds.is_empty() {
+        if let Err(errno) = pass_sockets(listen_fds) {
+            failed("Failed passing the sockets", errno, 111);
+        }
+        // The capacity was reserved before forking.
+        env_cptr.push(listen_pid_variable(&mut listen_pid));
+    }
+    if let Some(core_dumps) = &service.core_dumps {
+        if let Err(errno) = set_core_limit(core_dumps.limit()) {
+            failed("Failed setting the core dump limit", errno, 105);
+        }
+    }
+    if let Some(cpu_time) = service.limits.cpu_time {
+        if let Err(errno) = set_cpu_limit(cpu_time, service.termination.wait) {
+            failed("Failed setting the CPU time limit", errno, 108);
+        }
+    }
+    if let Some(namespaces) = namespaces {
+        if let Err(errno) = sandbox::enter(namespaces) {
+            failed("Failed setting up the sandbox", errno, 106);
+        }
+    }
+    if let Err((message, errno)) = exec(path, arg_cptr, env_cptr, uid, gid, cwd) {
+        failed(message, errno, 103);
+    }
+}
+
+/// Reports why the child has failed to the parent, through `error_pipe`: the errno, followed by
+/// `message`. Then exits with `status`, like `panic_ssafe`.
+fn child_failed(error_pipe: RawFd, service: &str, message: &str, errno: Errno, status: i32) {
+    // Nothing else can be done if the parent is gone.
+    let error_pipe = unsafe { BorrowedFd::borrow_raw(error_pipe) };
+    let _ = unistd::write(error_pipe, &(errno as i32).to_ne_bytes());
+    let _ = unistd::write(error_pipe, message.as_bytes());
+    panic_ssafe(message, Some(service), errno, status);
+}
+
+/// Waits until the child has exec'd, or has failed: then it has written why to the error pipe.
+/// Its write end is close-on-exec, so it's closed by a successful exec.
+fn read_child_error(error_pipe: OwnedFd) -> Result<()> {
+    let mut report = vec![];
+    File::from(error_pipe).read_to_end(&mut report)?;
+    if report.is_empty() {
+        return Ok(());
     }
+    let (errno, message) = report.split_at(report.len().min(size_of::<i32>()));
+    let errno = errno
+        .try_into()
+        .map(|errno| Errno::from_raw(i32::from_ne_bytes(errno)))
+        .unwrap_or(Errno::UnknownErrno);
+    bail!("{}: {}", String::from_utf8_lossy(message), errno)
 }
 
 /// Fork the process
-fn spawn_process(service: &Service) -> Result<Pid> {
+fn spawn_process(
+    service: &Service,
+    outputs: &Outputs,
+    sockets: &Sockets,
+    clock: &dyn Clock,
+) -> Result<Pid> {
     debug!("Spawning process for service: {}", service.name);
-    let (path, arg_cstrings, env_cstrings) = exec_args(service)?;
-    let uid = service.user.get_uid()?;
+    let (path, arg_cstrings, mut env_cstrings) = exec_args(service, clock)?;
+    // Without the variables of the sockets, which only make sense for the process itself.
+    let environment: Vec<String> = env_cstrings
+        .iter()
+        .map(|kv| kv.to_string_lossy().into_owned())
+        .collect();
+    let mut listen_fds: Vec<RawFd> = if service.socket.listen.is_empty() {
+        vec![]
+    } else {
+        let sockets = sockets
+            .get(&service.name)
+            .context("Its sockets couldn't be bound")?;
+        sockets.iter().map(AsRawFd::as_raw_fd).collect()
+    };
+    if !listen_fds.is_empty() {
+        env_cstrings.retain(|kv| !is_listen_variable(kv));
+        env_cstrings.push(CString::new(format!("LISTEN_FDS={}", listen_fds.len()))?);
+    }
+    if service.service_type == ServiceType::Notify {
+        let notify_socket = service
+            .notify_socket
+            .as_ref()
+            .context("Its notify socket couldn't be bound")?;
+        env_cstrings.push(CString::new(format!(
+            "{}{}",
+            NOTIFY_SOCKET_PREFIX,
+            notify_socket.display()
+        ))?);
+    }
+    let uid = service.uid()?;
+    let gid = service.gid();
     let cwd = service.working_directory.clone();
+    let namespaces = Namespaces::new(service)?;
+    let cgroup_procs = cgroups::prepare(service)?;
     let arg_cptr: Vec<&CStr> = arg_cstrings.iter().map(|c| c.as_c_str()).collect();
-    let env_cptr: Vec<&CStr> = env_cstrings.iter().map(|c| c.as_c_str()).collect();
+    // With room for LISTEN_PID, which is only known by the child.
+    let mut env_cptr: Vec<&CStr> = Vec::with_capacity(env_cstrings.len() + 1);
+    env_cptr.extend(env_cstrings.iter().map(|c| c.as_c_str()));
     let mut service_copy = service.clone();
-    let (pipe_read, pipe_write) = if service.stdout_rotate_size > 0 {
-        let (pipe_read, pipe_write) = unistd::pipe()?;
-        (Some(pipe_read), Some(pipe_write))
+    // Otherwise, the process writes to its destination directly.
+    let capture = if outputs.captures(service) {
+        let (stdout_read, stdout_write) = cloexec_pipe()?;
+        let (stderr_read, stderr_write) = cloexec_pipe()?;
+        service_copy.stdout = LogOutput::Pipe(stdout_write.as_raw_fd());
+        service_copy.stderr = LogOutput::Pipe(stderr_write.as_raw_fd());
+        Some(((stdout_read, stdout_write), (stderr_read, stderr_write)))
     } else {
-        (None, None)
+        None
     };
-    match unsafe { fork() } {
-        Ok(ForkResult::Child) => {
-            if let Some(pipe_write) = &pipe_write {
-                drop(pipe_read.unwrap());
-                service_copy.stdout = LogOutput::Pipe(pipe_write.as_raw_fd());
+    let (error_read, error_write) = cloexec_pipe()?;
+    let error_fd = error_write.as_raw_fd();
+    let child_main = move || {
+        child_process_main(
+            &service_copy,
+            namespaces.as_ref(),
+            cgroup_procs.as_ref(),
+            &mut listen_fds,
+            (path, arg_cptr, env_cptr),
+            (cwd, uid, gid),
+            error_fd,
+        );
+        // The pipes are close-on-exec, but they have been duplicated into stdout and stderr.
+    };
+    match fork_child(service.sandbox.pid_namespace, child_main) {
+        Ok(child) => {
+            drop(error_write);
+            outputs.clear_tail(&service.name);
+            if let Some(((stdout_read, stdout_write), (stderr_read, stderr_write))) = capture {
+                drop((stdout_write, stderr_write));
+                outputs.pump(service, child, stdout_read, stderr_read);
             }
-            child_process_main(&service_copy, path, cwd, uid, arg_cptr, env_cptr);
-            unreachable!();
-            // Here the "pipe_write" would go out of scope and its descriptor would be closed.
-            // But because child_process_main() does an exec() and never returns, the raw
-            // descriptor inside the LogOutput::Pipe stays open.
-        }
-        Ok(ForkResult::Parent { child, .. }) => {
-            pipe_read.and_then(|pipe| {
-                drop(pipe_write.unwrap());
-                std::thread::spawn(move || {
-                    chunked_writer(pipe, service_copy).map_err(|e| error!("{e}"))
-                });
-                None::<()>
-            });
+            // The exit of a child which has failed is reaped, but not claimed by any service.
+            read_child_error(error_read)?;
+            SPAWNED_ENVIRONMENTS
+                .lock()
+                .unwrap()
+                .insert(service.name.clone(), environment);
             debug!("Spawned child with PID {}.", child);
             Ok(child)
         }
@@ -159,6 +356,64 @@ fn spawn_process(service: &Service) -> Result<Pid> {
     }
 }
 
+/// Runs `child_main` in a child process, which is the init (pid 1) of a new PID namespace if
+/// `pid_namespace` is set. `child_main` must not return, and only use async-signal-safe calls.
+fn fork_child(pid_namespace: bool, child_main: impl FnOnce()) -> nix::Result<Pid> {
+    if pid_namespace {
+        return clone_in_pid_namespace(child_main);
+    }
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            child_main();
+            unreachable!();
+        }
+        ForkResult::Parent { child } => Ok(child),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn clone_in_pid_namespace(child_main: impl FnOnce()) -> nix::Result<Pid> {
+    use nix::sched::{clone, CloneFlags};
+    use nix::sys::signal::Signal;
+
+    /// The memory of Horust is copied in the child, like in a fork, but it runs on this stack.
+    const STACK_SIZE: usize = 1024 * 1024;
+    let mut stack = vec![0u8; STACK_SIZE];
+    let mut child_main = Some(child_main);
+    let child_main = Box::new(move || {
+        if let Some(child_main) = child_main.take() {
+            child_main();
+        }
+        unreachable!();
+    });
+    // SIGCHLD is sent on exit, so that it's reaped like the other children.
+    unsafe {
+        clone(
+            child_main,
+            &mut stack,
+            CloneFlags::CLONE_NEWPID,
+            Some(Signal::SIGCHLD as i32),
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_in_pid_namespace(_child_main: impl FnOnce()) -> nix::Result<Pid> {
+    Err(Errno::ENOTSUP)
+}
+
+/// Creates a pipe, whose ends are not inherited by the processes spawned afterwards.
+fn cloexec_pipe() -> Result<(OwnedFd, OwnedFd)> {
+    let (read, write) = unistd::pipe()?;
+    for fd in [&read, &write] {
+        fcntl::fcntl(
+            fd.as_raw_fd(),
+            fcntl::FcntlArg::F_SETFD(fcntl::FdFlag::FD_CLOEXEC),
+        )?;
+    }
+    Ok((read, write))
+}
+
 /// Sets up the stdout / stderr descriptors.
 fn redirect_output(
     target_stream: &LogOutput,
@@ -204,42 +459,78 @@ fn redirect_output(
     Ok(())
 }
 
-fn open_next_chunk(base_path: &Path) -> io::Result<File> {
-    let mut count = 1;
-    let mut path = base_path;
-    let mut path_str;
+/// Where the notify services send their readiness, set by Horust.
+const NOTIFY_SOCKET_PREFIX: &str = "NOTIFY_SOCKET=";
 
-    while path.is_file() {
-        path_str = format!("{}.{count}", base_path.to_string_lossy());
-        path = Path::new(&path_str);
-        count += 1;
+/// The variables of the socket activation protocol, which are set by Horust.
+fn is_listen_variable(kv: &CStr) -> bool {
+    ["LISTEN_FDS=", "LISTEN_PID=", "LISTEN_FDNAMES="]
+        .iter()
+        .any(|prefix| kv.to_bytes().starts_with(prefix.as_bytes()))
+}
+
+/// Moves the sockets to the file descriptors 3, 4, ..., which are inherited by the service.
+/// `listen_fds` is overwritten.
+///
+/// # Safety
+///
+/// Use only async-signal-safe, otherwise it might lock.
+fn pass_sockets(listen_fds: &mut [RawFd]) -> std::result::Result<(), Errno> {
+    let end = LISTEN_FDS_START + listen_fds.len() as RawFd;
+    // Out of the way first, since a socket might already be in one of the targets.
+    for fd in listen_fds.iter_mut() {
+        *fd = fcntl::fcntl(*fd, fcntl::FcntlArg::F_DUPFD(end))?;
     }
-    debug!("Opening next log output: {}", path.display());
-    OpenOptions::new().create(true).append(true).open(path)
+    for (target, fd) in (LISTEN_FDS_START..).zip(listen_fds.iter()) {
+        // Unlike the sockets, their duplicates are not close-on-exec.
+        unistd::dup2(*fd, target)?;
+        unistd::close(*fd)?;
+    }
+    Ok(())
 }
 
-fn chunked_writer(fd: OwnedFd, service: Service) -> Result<()> {
-    let source = File::from(fd);
-    let path = match &service.stdout {
-        LogOutput::Path(path) => path,
-        _ => return Err(anyhow!("Log output path is not set")),
-    };
+/// Writes `LISTEN_PID=<pid of this process>` in `buf`, without allocating.
+fn listen_pid_variable(buf: &mut [u8; 32]) -> &CStr {
+    const NAME: &[u8] = b"LISTEN_PID=";
+    buf[..NAME.len()].copy_from_slice(NAME);
+    let mut pid = unistd::getpid().as_raw().unsigned_abs();
+    let mut digits = [0u8; 10];
+    let mut len = 0;
     loop {
-        let mut reader = BufReader::new(&source).take(service.stdout_rotate_size);
-        let mut output = open_next_chunk(path)?;
-        let copied = io::copy(&mut reader, &mut output)?;
-        if copied < service.stdout_rotate_size {
-            debug!("EOF reached");
+        digits[len] = b'0' + (pid % 10) as u8;
+        len += 1;
+        pid /= 10;
+        if pid == 0 {
             break;
         }
     }
-    Ok(())
+    for (i, digit) in digits[..len].iter().rev().enumerate() {
+        buf[NAME.len() + i] = *digit;
+    }
+    buf[NAME.len() + len] = 0;
+    CStr::from_bytes_until_nul(buf).unwrap_or_default()
 }
 
-/// Find program on PATH.
+/// Sets the soft RLIMIT_CORE (`None` is unlimited), capped by the hard limit.
+fn set_core_limit(limit: Option<u64>) -> std::result::Result<(), Errno> {
+    let (_soft, hard) = getrlimit(Resource::RLIMIT_CORE)?;
+    let soft = limit.unwrap_or(RLIM_INFINITY).min(hard);
+    setrlimit(Resource::RLIMIT_CORE, soft, hard)
+}
+
+/// Sets the soft RLIMIT_CPU to `cpu_time` (at least a second), and the hard one `grace` later,
+/// both capped by the current hard limit.
+fn set_cpu_limit(cpu_time: Duration, grace: Duration) -> std::result::Result<(), Errno> {
+    let (_soft, hard) = getrlimit(Resource::RLIMIT_CPU)?;
+    let soft = cpu_time.as_secs().max(1).min(hard);
+    let hard = soft.saturating_add(grace.as_secs().max(1)).min(hard);
+    setrlimit(Resource::RLIMIT_CPU, soft, hard)
+}
+
+/// Find program on `path_var`, or on Horust's PATH if it's not set.
 ///
-fn find_program(program_name: &String) -> Result<String> {
-    let path_var = match std::env::var_os("PATH") {
+fn find_program(program_name: &String, path_var: Option<OsString>) -> Result<String> {
+    let path_var = match path_var.or_else(|| std::env::var_os("PATH")) {
         Some(val) => val,
         None => return Err(anyhow!("PATH environment variable is not set")),
     };
@@ -251,7 +542,10 @@ fn find_program(program_name: &String) -> Result<String> {
 
         // Check if the program file exists at this path
         if program_path.is_file() {
-            return Ok(program_path.into_os_string().into_string().unwrap());
+            return program_path
+                .into_os_string()
+                .into_string()
+                .map_err(|path| anyhow!("Invalid path: {:?}", path));
         }
     }
 
@@ -261,7 +555,7 @@ fn find_program(program_name: &String) -> Result<String> {
     ))
 }
 
-/// Exec wrapper.
+/// Exec wrapper. On failure, returns which step has failed.
 ///
 /// # Safety
 ///
@@ -272,14 +566,22 @@ fn exec(
     arg_cptr: Vec<&CStr>,
     env_cptr: Vec<&CStr>,
     uid: Uid,
+    gid: Option<Gid>,
     cwd: PathBuf,
-) -> std::result::Result<(), Errno> {
+) -> std::result::Result<(), (&'static str, Errno)> {
+    let failed = |message| move |errno| (message, errno);
     // Changes the current working directory to the specified path.
-    unistd::chdir(&cwd)?;
+    unistd::chdir(&cwd).map_err(failed("Failed changing to the working directory"))?;
     // Create new session and set process group id
-    unistd::setsid()?;
+    unistd::setsid().map_err(failed("Failed creating a new session"))?;
+    // Set the group ID, dropping the supplementary groups
+    if let Some(gid) = gid {
+        unistd::setgroups(&[gid]).map_err(failed("Failed setting the groups"))?;
+        unistd::setgid(gid).map_err(failed("Failed setting the group"))?;
+    }
     // Set the user ID
-    unistd::setuid(uid)?;
-    unistd::execve(path.as_ref(), arg_cptr.as_ref(), env_cptr.as_ref())?;
+    unistd::setuid(uid).map_err(failed("Failed setting the user"))?;
+    unistd::execve(path.as_ref(), arg_cptr.as_ref(), env_cptr.as_ref())
+        .map_err(failed("Failed executing the command"))?;
     Ok(())
 }
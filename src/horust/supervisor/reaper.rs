@@ -3,56 +3,158 @@
 //! collect its exit status. This module will make sure to reap also zombie processes and not only
 //! child services.
 
-use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use std::fs;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::wait::{waitid, waitpid, Id, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 
+use crate::horust::formats::{ExitReason, ServiceName};
+use crate::horust::output::Outputs;
+use crate::horust::subprocess;
 use crate::horust::supervisor::repo::Repo;
+use crate::horust::supervisor::{core_dumps, crash_artifacts};
 use crate::horust::Event;
 
-/// Reaps up to `max_iterations` dead processes
-///
-/// # Safety
-///
-/// This function must run in isolation with respect to the fork processes to
-/// prevent pid reusage.
-pub(crate) fn run(repo: &Repo, max_iterations: u32) -> Vec<Event> {
-    (0..max_iterations)
-        .filter_map(
-            |_| match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
-                Ok(wait_status) => match wait_status {
-                    WaitStatus::Exited(pid, exit_code) => {
-                        debug!("Pid '{}' has exited with status: {}", pid, exit_code);
-                        repo.get_service_by_pid(pid)
-                            .map(|s_name| (s_name, exit_code))
-                    }
-                    WaitStatus::Signaled(pid, signal, core_dumped) => {
-                        debug!(
-                            "Pid '{:?}' has exited due to signal: {:?}. Core dumped: {}",
-                            pid, signal, core_dumped
-                        );
-                        repo.get_service_by_pid(pid).map(|s_name| (s_name, -137))
+/// How long the exit status of an unknown pid is kept. A service exiting right away might be
+/// reaped before the supervisor is told about its pid.
+const UNCLAIMED_RETENTION: Duration = Duration::from_secs(5);
+
+/// Collects the exit status of the dead processes.
+#[derive(Debug, Default)]
+pub(crate) struct Reaper {
+    /// Exit statuses of pids which don't belong to any service (yet), with when they were reaped
+    /// (and their /proc status, if they were killed by a signal).
+    unclaimed: Vec<(Instant, WaitStatus, Option<String>)>,
+}
+
+impl Reaper {
+    /// True if some exit statuses are still waiting for their service.
+    pub(crate) fn has_unclaimed(&self) -> bool {
+        !self.unclaimed.is_empty()
+    }
+
+    /// Reaps all the dead processes. Called once SIGCHLD has been received, which might stand
+    /// for more than one exited child, or while some exit statuses are unclaimed.
+    ///
+    /// # Safety
+    ///
+    /// This function must run in isolation with respect to the fork processes to
+    /// prevent pid reusage.
+    pub(crate) fn run(&mut self, repo: &Repo, now: Instant, outputs: &Outputs) -> Vec<Event> {
+        let unclaimed = std::mem::take(&mut self.unclaimed);
+        let reaped = reap_all()
+            .into_iter()
+            .map(|(wait_status, proc_status)| (now, wait_status, proc_status));
+        unclaimed
+            .into_iter()
+            .chain(reaped)
+            .filter_map(|(reaped_at, wait_status, proc_status)| {
+                let pid = wait_status.pid()?;
+                match repo.get_service_by_pid(pid) {
+                    Some(s_name) => {
+                        service_exited(repo, s_name, pid, wait_status, proc_status, outputs)
                     }
-                    _ => None,
-                },
-                Err(err) => {
-                    // waitpid() call can fail with:
-                    // EINVAL: Options argument is not valid - should not apply.
-                    // EINTR:  Possible & common.
-                    // ECHILD: The process specified by pid does not exist or is not a child of the
-                    // calling process, or the process group specified by pid does not exist or does
-                    // not have any member process that is a child of the calling process.
-                    // Since we're using -1 as parameter, we should never fall in this branch.
-                    if !err.to_string().contains("ECHILD") {
-                        // This is the only "actual" error.
-                        error!("Error waitpid(): {}", err);
+                    None => {
+                        let is_helper = subprocess::reaped(pid, wait_status);
+                        if !is_helper && now.duration_since(reaped_at) < UNCLAIMED_RETENTION {
+                            self.unclaimed.push((reaped_at, wait_status, proc_status));
+                        }
+                        None
                     }
-                    None
                 }
-            },
-        )
-        .map(|(sname, exit_code)| {
-            debug!("Service '{:?}' has exited.", sname);
-            Event::new_service_exited(sname.into(), exit_code)
-        })
-        .collect()
+            })
+            .collect()
+    }
+}
+
+/// Waits all the exited children, without blocking. The /proc status of the ones killed by a
+/// signal is read before reaping them, for their crash artifacts.
+fn reap_all() -> Vec<(WaitStatus, Option<String>)> {
+    let mut reaped = vec![];
+    loop {
+        let peek = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+        let (pid, proc_status) = match waitid(Id::All, peek) {
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(WaitStatus::Signaled(pid, ..)) => {
+                let proc_status = fs::read_to_string(format!("/proc/{}/status", pid)).ok();
+                (pid, proc_status)
+            }
+            Ok(wait_status) => (wait_status.pid().unwrap_or(Pid::from_raw(-1)), None),
+            Err(_) => (Pid::from_raw(-1), None),
+        };
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            // The other children are still running.
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(wait_status @ WaitStatus::Exited(pid, exit_code)) => {
+                debug!("Pid '{}' has exited with status: {}", pid, exit_code);
+                reaped.push((wait_status, None));
+            }
+            Ok(wait_status @ WaitStatus::Signaled(pid, signal, core_dumped)) => {
+                debug!(
+                    "Pid '{:?}' has exited due to signal: {:?}. Core dumped: {}",
+                    pid, signal, core_dumped
+                );
+                reaped.push((wait_status, proc_status));
+            }
+            Ok(_) | Err(Errno::EINTR) => continue,
+            Err(err) => {
+                // waitpid() call can fail with:
+                // EINVAL: Options argument is not valid - should not apply.
+                // EINTR:  Possible & common.
+                // ECHILD: The process specified by pid does not exist or is not a child of the
+                // calling process, or the process group specified by pid does not exist or does
+                // not have any member process that is a child of the calling process.
+                // Since we're using -1 as parameter (unless a child was peeked), it means that
+                // there are no children left.
+                if err != Errno::ECHILD {
+                    // This is the only "actual" error.
+                    error!("Error waitpid(): {}", err);
+                }
+                break;
+            }
+        }
+    }
+    reaped
+}
+
+/// The `ServiceExited` event of `s_name`, whose process `pid` has terminated, or its
+/// `ReplacedExited` one if `pid` was being replaced.
+fn service_exited(
+    repo: &Repo,
+    s_name: &ServiceName,
+    pid: Pid,
+    wait_status: WaitStatus,
+    proc_status: Option<String>,
+    outputs: &Outputs,
+) -> Option<Event> {
+    let is_replaced = repo.services[s_name]
+        .replaced
+        .is_some_and(|replaced| replaced.pid == pid);
+    let exit_reason = match wait_status {
+        WaitStatus::Exited(_pid, exit_code) => ExitReason::Code(exit_code),
+        WaitStatus::Signaled(_pid, signal, core_dumped) => {
+            let service = repo.services[s_name].service();
+            if core_dumped {
+                core_dumps::collect(service, pid);
+            }
+            let exit_reason = ExitReason::Signal {
+                signal,
+                core_dumped,
+            };
+            // Not a crash, if it's stopped by Horust.
+            if !is_replaced && !repo.services[s_name].is_in_killing() {
+                crash_artifacts::collect(service, pid, exit_reason, proc_status, outputs);
+            }
+            exit_reason
+        }
+        _ => return None,
+    };
+    if is_replaced {
+        debug!("The replaced process of '{:?}' has exited.", s_name);
+        return Some(Event::ReplacedExited(s_name.into(), exit_reason));
+    }
+    debug!("Service '{:?}' has exited.", s_name);
+    Some(Event::new_service_exited(s_name.into(), exit_reason))
 }
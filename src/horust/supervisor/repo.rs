@@ -1,33 +1,187 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use nix::unistd::Pid;
 
 use crate::horust::bus::BusConnector;
-use crate::horust::formats::{Service, ServiceName};
+use crate::horust::clock::Clock;
+use crate::horust::formats::{
+    Dependency, RunReport, Service, ServiceName, ServiceReport, ServiceStatus,
+};
 use crate::horust::supervisor::service_handler::ServiceHandler;
 use crate::horust::Event;
 
+/// The statuses in which a service can still make progress.
+const ACTIVE_STATUSES: [ServiceStatus; 7] = [
+    ServiceStatus::Initial,
+    ServiceStatus::Started,
+    ServiceStatus::Running,
+    ServiceStatus::Success,
+    ServiceStatus::Failed,
+    ServiceStatus::Broken,
+    ServiceStatus::InKilling,
+];
+
 #[derive(Debug)]
 pub(crate) struct Repo {
+    /// Read-only: the service handlers are modified via `get_mut_sh` and `insert_sh_by_name`, which
+    /// keep the indices up to date.
     pub services: HashMap<ServiceName, ServiceHandler>,
     pub(crate) bus: BusConnector<Event>,
     pub(crate) pid_map: HashMap<Pid, ServiceName>,
+    pub(crate) clock: Arc<dyn Clock>,
+    /// The services referred to by a name: their own, or one they `provide`.
+    providers: HashMap<ServiceName, Vec<ServiceName>>,
+    /// The services which start after a service.
+    dependents: HashMap<ServiceName, Vec<ServiceName>>,
+    /// The services which have to die if a service fails.
+    die_if_failed: HashMap<ServiceName, Vec<ServiceName>>,
+    /// The services in each status, as they were when they were last indexed.
+    by_status: HashMap<ServiceStatus, BTreeSet<ServiceName>>,
+    indexed_status: HashMap<ServiceName, ServiceStatus>,
+    /// The service which might have been modified since it was last indexed.
+    touched: Option<ServiceName>,
+    /// Received while waiting, not handled yet.
+    pending: Vec<Event>,
 }
 
 impl Repo {
-    pub(crate) fn new(bus: BusConnector<Event>, services: Vec<Service>) -> Self {
+    pub(crate) fn new(
+        bus: BusConnector<Event>,
+        services: Vec<Arc<Service>>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         let services = services
             .into_iter()
-            .map(|service| (service.name.clone(), service.into()))
+            .map(|service| {
+                let mut sh = ServiceHandler::from(service);
+                sh.initial_since = Some(clock.now());
+                (sh.name().clone(), sh)
+            })
             .collect();
-        Self {
+        let mut repo = Self {
             bus,
             services,
             pid_map: HashMap::new(),
+            clock,
+            providers: HashMap::new(),
+            dependents: HashMap::new(),
+            die_if_failed: HashMap::new(),
+            by_status: HashMap::new(),
+            indexed_status: HashMap::new(),
+            touched: None,
+            pending: vec![],
+        };
+        repo.index_definitions();
+        for (name, sh) in &repo.services {
+            repo.by_status
+                .entry(sh.status.clone())
+                .or_default()
+                .insert(name.clone());
+            repo.indexed_status.insert(name.clone(), sh.status.clone());
+        }
+        repo
+    }
+
+    /// Indexes the relations between the services, from their definitions.
+    fn index_definitions(&mut self) {
+        self.providers.clear();
+        self.dependents.clear();
+        self.die_if_failed.clear();
+        let mut names: Vec<&ServiceName> = self.services.keys().collect();
+        names.sort();
+        for name in &names {
+            let service = self.services[*name].service();
+            for provided in std::iter::once(&service.name).chain(&service.provides) {
+                let providers = self.providers.entry(provided.clone()).or_default();
+                if !providers.contains(name) {
+                    providers.push((*name).clone());
+                }
+            }
+            for failed in &service.termination.die_if_failed {
+                self.die_if_failed
+                    .entry(failed.clone())
+                    .or_default()
+                    .push((*name).clone());
+            }
+        }
+        for name in &names {
+            for dependency in self.services[*name].start_after() {
+                for provider in self.providers.get(dependency).into_iter().flatten() {
+                    let dependents = self.dependents.entry(provider.clone()).or_default();
+                    if !dependents.contains(name) {
+                        dependents.push((*name).clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the touched service to the index of its current status.
+    fn sync(&mut self) {
+        let Some(name) = self.touched.take() else {
+            return;
+        };
+        let status = self.services[&name].status.clone();
+        if let Some(indexed) = self.indexed_status.insert(name.clone(), status.clone()) {
+            if indexed == status {
+                return;
+            }
+            if let Some(names) = self.by_status.get_mut(&indexed) {
+                names.remove(&name);
+            }
         }
+        self.by_status.entry(status).or_default().insert(name);
+    }
+
+    /// The services which are in `status`.
+    fn with_status<'a>(
+        &'a self,
+        status: &'a ServiceStatus,
+    ) -> impl Iterator<Item = &'a ServiceHandler> + 'a {
+        let touched = self.touched.as_ref();
+        self.by_status
+            .get(status)
+            .into_iter()
+            .flatten()
+            .filter(move |name| Some(*name) != touched)
+            .chain(touched)
+            .map(|name| &self.services[name])
+            .filter(move |sh| sh.status == *status)
+    }
+
+    /// How many services are in `status`.
+    fn count(&self, status: &ServiceStatus) -> usize {
+        let indexed = self.by_status.get(status).map_or(0, BTreeSet::len);
+        let Some(touched) = &self.touched else {
+            return indexed;
+        };
+        let was = usize::from(self.indexed_status.get(touched) == Some(status));
+        let is = usize::from(self.services[touched].status == *status);
+        indexed - was + is
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// The wall-clock time of `clock`.
+    pub(crate) fn system_time(&self) -> SystemTime {
+        self.clock.system_time()
     }
+
     pub(crate) fn insert_sh_by_name(&mut self, name: ServiceName, sh: ServiceHandler) {
-        self.services.insert(name, sh);
+        self.sync();
+        let redefined = self
+            .services
+            .get(&name)
+            .is_none_or(|old| !Arc::ptr_eq(&old.shared_service(), &sh.shared_service()));
+        self.services.insert(name.clone(), sh);
+        if redefined {
+            self.index_definitions();
+        }
+        self.touched = Some(name);
     }
     pub(crate) fn get_service_by_pid(&self, pid: Pid) -> Option<&ServiceName> {
         self.pid_map.get(&pid)
@@ -36,26 +190,117 @@ impl Repo {
     pub(crate) fn add_pid(&mut self, pid: Pid, service: ServiceName) {
         self.pid_map.insert(pid, service);
     }
-    pub(crate) fn remove_pid(&mut self, pid: Pid) {
-        self.pid_map.remove(&pid);
+    /// Only if it's still the pid of `service`: it might have been reused by another one.
+    pub(crate) fn remove_pid(&mut self, pid: Pid, service: &str) {
+        if self.pid_map.get(&pid).is_some_and(|name| name == service) {
+            self.pid_map.remove(&pid);
+        }
     }
 
     /// Non blocking
     pub(crate) fn get_events(&mut self) -> Vec<Event> {
-        self.bus.try_get_events()
+        let mut events = std::mem::take(&mut self.pending);
+        events.extend(self.bus.try_get_events());
+        events
+    }
+
+    /// Waits for `timeout`, or until some children have exited. The events received in the
+    /// meanwhile are returned by the next `get_events`.
+    pub(crate) fn wait(&mut self, timeout: Duration) {
+        let deadline = self.clock.after(timeout);
+        let events = self
+            .bus
+            .get_events_until(&deadline, |ev| *ev == Event::ChildrenExited);
+        self.pending.extend(events);
     }
 
     pub fn all_have_finished(&self) -> bool {
-        //TODO: This can be improved. When a service is finished, it can be added in a list, or even
-        // a number. Then this check can be reduced to `return self.services.len() == self.finished_services`
+        let finished = [
+            ServiceStatus::Finished,
+            ServiceStatus::FinishedFailed,
+            ServiceStatus::Disabled,
+        ];
+        finished
+            .iter()
+            .map(|status| self.count(status))
+            .sum::<usize>()
+            == self.services.len()
+            && self.services.values().all(|sh| sh.replaced.is_none())
+    }
+
+    /// True if every service is either running or has successfully done its job. The services
+    /// which are waiting to be asked to start count as started, too.
+    pub fn all_have_started(&self) -> bool {
+        self.not_started().next().is_none()
+    }
 
+    /// The services which are neither running nor done with their job, nor waiting to be asked
+    /// to start.
+    pub(crate) fn not_started(&self) -> impl Iterator<Item = &ServiceHandler> {
+        const STARTED: [ServiceStatus; 4] = [
+            ServiceStatus::Running,
+            ServiceStatus::Finished,
+            ServiceStatus::Success,
+            ServiceStatus::Disabled,
+        ];
         self.services
+            .values()
+            .filter(|sh| !STARTED.contains(&sh.status) && !sh.is_waiting_for_start())
+    }
+
+    /// The services which can still make progress: the finished, disabled and starting ones are
+    /// left out.
+    pub(crate) fn active(&self) -> impl Iterator<Item = &ServiceHandler> {
+        ACTIVE_STATUSES
             .iter()
-            .all(|(_s_name, sh)| sh.is_finished() || sh.is_finished_failed())
+            .flat_map(|status| self.with_status(status))
+    }
+
+    /// The ready-queue: the services which are waiting to be started, and whose dependencies are
+    /// satisfied. Sorted by name.
+    pub(crate) fn ready(&self) -> Vec<ServiceName> {
+        let mut ready: Vec<ServiceName> = self
+            .with_status(&ServiceStatus::Initial)
+            .filter(|sh| self.is_service_runnable(sh))
+            .map(|sh| sh.name().clone())
+            .collect();
+        ready.sort();
+        ready
+    }
+
+    /// Asks the manual services (`auto-start = false`) to start, if a service which starts after
+    /// them is waiting to start. Lazy services are only started by a connection, and the ones with
+    /// a timer once it triggers.
+    pub(crate) fn request_dependencies(&mut self) {
+        loop {
+            let requested: BTreeSet<ServiceName> = self
+                .with_status(&ServiceStatus::Initial)
+                .filter(|sh| !sh.is_waiting_for_start())
+                .flat_map(|sh| sh.start_after())
+                .flat_map(|dependency| self.get_providers(dependency))
+                .filter(|sh| {
+                    sh.is_waiting_for_start()
+                        && !sh.service().socket.lazy
+                        && sh.service().timer.is_none()
+                })
+                .map(|sh| sh.name().clone())
+                .collect();
+            if requested.is_empty() {
+                return;
+            }
+            for name in requested {
+                debug!("{}: requested by a service starting after it", name);
+                self.get_mut_sh(&name).start_requested = true;
+            }
+        }
     }
 
     /// Get a mutable reference to the Service Handler
     pub fn get_mut_sh(&mut self, service_name: &str) -> &mut ServiceHandler {
+        if self.touched.as_deref() != Some(service_name) {
+            self.sync();
+            self.touched = Some(service_name.to_string());
+        }
         self.services.get_mut(service_name).unwrap()
     }
 
@@ -64,50 +309,193 @@ impl Repo {
         self.services.get(service_name).unwrap()
     }
 
-    /// Get all the services that have specified "start-after = [`service_name`]" in their config
+    /// Get all the services that have specified "start-after = [`service_name`]" in their config,
+    /// or one of the names provided by `service_name`.
     pub(crate) fn get_dependents(&self, service_name: &str) -> Vec<ServiceName> {
-        self.services
-            .iter()
-            .filter(|(_s_name, sh)| sh.service().start_after.contains(&service_name.to_string()))
-            .map(|(s_name, _sh)| s_name)
+        self.dependents
+            .get(service_name)
             .cloned()
-            .collect()
+            .unwrap_or_default()
+    }
+
+    /// The services which are referred to by `name`, either directly or via `provides`.
+    pub(crate) fn get_providers<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a ServiceHandler> {
+        self.providers
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|name| &self.services[name])
     }
 
     /// Get all the services that have specified "die-if-failed = [`service_name`]" in their config
     pub(crate) fn get_die_if_failed(&self, service_name: &str) -> Vec<&ServiceName> {
-        self.services
-            .iter()
-            .filter(|(_s_name, sh)| {
-                sh.service()
-                    .termination
-                    .die_if_failed
-                    .contains(&service_name.to_string())
-            })
-            .map(|(s_name, _sh)| s_name)
+        self.die_if_failed
+            .get(service_name)
+            .into_iter()
+            .flatten()
             .collect()
     }
 
+    #[track_caller]
     pub(crate) fn send_ev(&mut self, ev: Event) {
         self.bus.send_event(ev)
     }
 
     /// Checks if the service is runnable. So the current status is Initial, and
-    /// all the start-after have started or finished.
+    /// all the start-after have started or finished. Disabled dependencies are not waited for, nor
+    /// the optional ones if they have failed, or if their timeout has passed.
     pub(crate) fn is_service_runnable(&self, sh: &ServiceHandler) -> bool {
-        if !sh.is_initial() {
-            return false;
-        }
+        sh.is_initial() && !sh.is_waiting_for_start() && self.unmet_dependencies(sh).is_empty()
+    }
+
+    /// The services in `start_after` which the service is still waiting for.
+    pub(crate) fn unmet_dependencies<'a>(&self, sh: &'a ServiceHandler) -> Vec<&'a ServiceName> {
         let is_started = |service_name: &ServiceName| {
-            let sh = self.services.get(service_name).unwrap();
-            sh.is_running() || sh.is_finished()
+            self.get_providers(service_name)
+                .all(|sh| sh.is_running() || sh.is_finished() || sh.is_disabled())
         };
-        sh.start_after().iter().all(is_started)
+        let waited = sh
+            .initial_since
+            .map(|initial_since| self.now().saturating_duration_since(initial_since))
+            .unwrap_or_default();
+        sh.service()
+            .start_after
+            .iter()
+            .filter_map(|dependency| match dependency {
+                Dependency::Service(service_name) => {
+                    (!is_started(service_name)).then_some(service_name)
+                }
+                Dependency::Detailed {
+                    service,
+                    optional,
+                    timeout,
+                } => {
+                    let is_met = is_started(service)
+                        || (*optional
+                            && (self.get_providers(service).any(ServiceHandler::is_failed)
+                                || timeout.is_some_and(|timeout| waited >= timeout)));
+                    (!is_met).then_some(service)
+                }
+                // Checked when spawning the service.
+                Dependency::Tcp { .. } => None,
+            })
+            .collect()
     }
 
-    pub(crate) fn any_finished_failed(&self) -> bool {
-        self.services
-            .iter()
-            .any(|(_s_name, sh)| sh.is_finished_failed())
+    pub(crate) fn report(&self) -> RunReport {
+        let mut services: Vec<ServiceReport> =
+            self.services.values().map(ServiceHandler::report).collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        RunReport {
+            services,
+            boot_timed_out: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use nix::unistd::Pid;
+
+    use crate::horust::bus::Bus;
+    use crate::horust::clock::SystemClock;
+    use crate::horust::formats::{Dependency, Service, ServiceStatus};
+    use crate::horust::supervisor::repo::Repo;
+
+    fn optional(service: &str, timeout: Option<Duration>) -> Dependency {
+        Dependency::Detailed {
+            service: service.into(),
+            optional: true,
+            timeout,
+        }
+    }
+
+    #[test]
+    fn test_services_are_shared() {
+        let service = Arc::new(Service::from_name("a"));
+        let repo = Repo::new(
+            Bus::new().join_bus(),
+            vec![service.clone()],
+            Arc::new(SystemClock),
+        );
+        assert!(Arc::ptr_eq(&repo.services["a"].shared_service(), &service));
+    }
+
+    #[test]
+    fn test_indices() {
+        let mut db = Service::from_name("db");
+        db.provides = vec!["database".into()];
+        let api = Service::start_after("api", vec!["database", "db"]);
+        let mut worker = Service::start_after("worker", vec!["api"]);
+        worker.termination.die_if_failed = vec!["db".into()];
+        let services = vec![db, api, worker].into_iter().map(Arc::new).collect();
+        let mut repo = Repo::new(Bus::new().join_bus(), services, Arc::new(SystemClock));
+
+        assert_eq!(repo.get_dependents("db"), vec!["api".to_string()]);
+        assert_eq!(repo.get_dependents("api"), vec!["worker".to_string()]);
+        assert!(repo.get_dependents("worker").is_empty());
+        assert_eq!(repo.get_die_if_failed("db"), vec![&"worker".to_string()]);
+        assert!(repo.get_die_if_failed("api").is_empty());
+
+        assert_eq!(repo.ready(), vec!["db".to_string()]);
+        repo.get_mut_sh("db").status = ServiceStatus::Running;
+        // Not yet indexed, but already accounted for.
+        assert_eq!(repo.ready(), vec!["api".to_string()]);
+        assert_eq!(repo.active().count(), 3);
+        repo.get_mut_sh("api").status = ServiceStatus::Running;
+        repo.get_mut_sh("worker").status = ServiceStatus::Running;
+        assert!(repo.ready().is_empty());
+        assert!(repo.all_have_started());
+        assert!(!repo.all_have_finished());
+        for name in ["db", "api"] {
+            repo.get_mut_sh(name).status = ServiceStatus::Finished;
+        }
+        repo.get_mut_sh("worker").status = ServiceStatus::FinishedFailed;
+        assert!(repo.all_have_finished());
+        assert_eq!(repo.active().count(), 0);
+    }
+
+    #[test]
+    fn test_is_service_runnable_optional() {
+        let mut a = Service::from_name("a");
+        a.start_after = vec![
+            optional("b", None),
+            optional("c", Some(Duration::from_secs(30))),
+        ];
+        let services = vec![a, Service::from_name("b"), Service::from_name("c")];
+        let services = services.into_iter().map(Arc::new).collect();
+        let mut repo = Repo::new(Bus::new().join_bus(), services, Arc::new(SystemClock));
+        let runnable = |repo: &Repo| repo.is_service_runnable(&repo.services["a"]);
+        assert!(!runnable(&repo));
+
+        repo.get_mut_sh("b").status = ServiceStatus::FinishedFailed;
+        assert!(!runnable(&repo));
+        repo.get_mut_sh("a").initial_since = Instant::now().checked_sub(Duration::from_secs(31));
+        assert!(runnable(&repo));
+
+        // Non optional dependencies are always waited for.
+        repo.insert_sh_by_name("a".into(), Service::start_after("a", vec!["b"]).into());
+        assert!(!runnable(&repo));
+    }
+
+    #[test]
+    fn test_remove_pid() {
+        let services = vec![Service::from_name("a"), Service::from_name("b")];
+        let services = services.into_iter().map(Arc::new).collect();
+        let mut repo = Repo::new(Bus::new().join_bus(), services, Arc::new(SystemClock));
+        let pid = Pid::from_raw(1000);
+        repo.add_pid(pid, "a".into());
+        // Reused by the process of "b", before the exit of "a" was handled.
+        repo.add_pid(pid, "b".into());
+        repo.remove_pid(pid, "a");
+        assert_eq!(repo.get_service_by_pid(pid), Some(&"b".to_string()));
+        repo.remove_pid(pid, "b");
+        assert_eq!(repo.get_service_by_pid(pid), None);
     }
 }
@@ -0,0 +1,115 @@
+//! Rolling restarts (`horustctl restart`): the services are restarted a few at a time, and the
+//! next ones wait until the restarted ones are running again.
+//! Bulk stops (`horustctl stop`): the services are stopped in the reverse order of their start.
+
+use std::collections::VecDeque;
+
+use crate::horust::formats::{ServiceName, ServiceStatus};
+use crate::horust::supervisor::repo::Repo;
+
+#[derive(Debug)]
+pub(crate) struct Rollout {
+    /// Still to be restarted, in this order.
+    pending: VecDeque<ServiceName>,
+    /// Restarted, and not running again yet.
+    restarting: Vec<ServiceName>,
+    /// How many services can be restarting at the same time.
+    max_unavailable: usize,
+}
+
+impl Rollout {
+    pub(crate) fn new(services: Vec<ServiceName>, max_unavailable: usize) -> Self {
+        Self {
+            pending: services.into(),
+            restarting: vec![],
+            max_unavailable: max_unavailable.max(1),
+        }
+    }
+
+    /// The services to restart now. The services which are not up are skipped, since they'll use
+    /// their current definition once started anyway. If a restarted service has finished instead
+    /// of running again, the rest of the rollout is given up.
+    pub(crate) fn next(&mut self, repo: &Repo) -> Vec<ServiceName> {
+        let finished = self.restarting.iter().find(|name| {
+            let sh = &repo.services[*name];
+            sh.is_finished() || sh.status == ServiceStatus::FinishedFailed
+        });
+        if let Some(finished) = finished {
+            if !self.pending.is_empty() {
+                warn!(
+                    service = finished.as_str();
+                    "{}: it has finished, instead of running again. Giving up restarting: {:?}",
+                    finished,
+                    self.pending
+                );
+            }
+            self.pending.clear();
+            self.restarting.clear();
+            return vec![];
+        }
+        self.restarting
+            .retain(|name| !repo.services[name].has_restarted());
+        let mut next = vec![];
+        while self.restarting.len() < self.max_unavailable {
+            let Some(name) = self.pending.pop_front() else {
+                break;
+            };
+            if repo.services[&name].can_be_restarted() {
+                self.restarting.push(name.clone());
+                next.push(name);
+            } else {
+                debug!("{}: not up, it's not restarted", name);
+            }
+        }
+        next
+    }
+
+    /// All the services have been restarted, and they're running again.
+    pub(crate) fn is_done(&self) -> bool {
+        self.pending.is_empty() && self.restarting.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Stop {
+    /// All the services being stopped.
+    services: Vec<ServiceName>,
+    /// Still to be stopped.
+    pending: Vec<ServiceName>,
+}
+
+impl Stop {
+    pub(crate) fn new(services: Vec<ServiceName>) -> Self {
+        Self {
+            pending: services.clone(),
+            services,
+        }
+    }
+
+    /// The services to stop now: the ones which the other stopped services don't start after, or
+    /// which they start after but which are down already. The services which aren't stopped
+    /// aren't waited for.
+    pub(crate) fn next(&mut self, repo: &Repo) -> Vec<ServiceName> {
+        let is_up = |name: &ServiceName| {
+            matches!(
+                repo.services[name].status,
+                ServiceStatus::Starting
+                    | ServiceStatus::Started
+                    | ServiceStatus::Running
+                    | ServiceStatus::InKilling
+            )
+        };
+        let (next, pending) = self.pending.iter().cloned().partition(|name| {
+            !repo.get_dependents(name).iter().any(|dependent| {
+                dependent != name && self.services.contains(dependent) && is_up(dependent)
+            })
+        });
+        self.pending = pending;
+        next
+    }
+
+    /// All the services have been asked to stop.
+    pub(crate) fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
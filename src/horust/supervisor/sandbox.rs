@@ -0,0 +1,186 @@
+//! Sandboxing of the services, see `Service::sandbox`.
+//! The paths are prepared before forking, so that the child only uses async-signal-safe calls.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+
+use crate::horust::formats::Service;
+
+/// The namespaces of a service, and how to set them up.
+#[derive(Debug, Default)]
+pub(crate) struct Namespaces {
+    /// Paths to mount read-only in the mount namespace of the service.
+    read_only: Vec<CString>,
+    read_write: Vec<CString>,
+    /// Mount a new `/proc`, showing only the processes of the PID namespace of the service.
+    proc: bool,
+    /// Set in the UTS namespace of the service.
+    hostname: Option<String>,
+}
+
+impl Namespaces {
+    /// Paths which don't exist are skipped, since there's nothing to protect.
+    pub(crate) fn new(service: &Service) -> Result<Option<Self>> {
+        let sandbox = &service.sandbox;
+        let hostname = service.hostname();
+        if !sandbox.protect_system && !sandbox.pid_namespace && hostname.is_none() {
+            return Ok(None);
+        }
+        let to_cstrings = |paths: &[std::path::PathBuf]| {
+            paths
+                .iter()
+                .filter(|path| path.exists())
+                .map(|path| Ok(CString::new(path.as_os_str().as_bytes())?))
+                .collect::<Result<Vec<_>>>()
+        };
+        let mut namespaces = Namespaces {
+            proc: sandbox.pid_namespace,
+            hostname,
+            ..Default::default()
+        };
+        if sandbox.protect_system {
+            namespaces.read_only = to_cstrings(&sandbox.read_only_paths)?;
+            namespaces.read_write = to_cstrings(&sandbox.read_write_paths)?;
+        }
+        Ok(Some(namespaces))
+    }
+
+    fn has_mounts(&self) -> bool {
+        !self.read_only.is_empty() || !self.read_write.is_empty() || self.proc
+    }
+}
+
+/// Moves the calling process into new namespaces, and sets them up.
+/// To be called in the child, after the fork.
+#[cfg(target_os = "linux")]
+pub(crate) fn enter(namespaces: &Namespaces) -> Result<(), Errno> {
+    use nix::sched::{unshare, CloneFlags};
+
+    if let Some(hostname) = &namespaces.hostname {
+        unshare(CloneFlags::CLONE_NEWUTS)?;
+        nix::unistd::sethostname(hostname)?;
+    }
+    if namespaces.has_mounts() {
+        enter_mount_namespace(namespaces)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn enter_mount_namespace(mounts: &Namespaces) -> Result<(), Errno> {
+    use nix::mount::{mount, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+
+    const NONE: Option<&'static [u8]> = None;
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    // Otherwise the mounts would be propagated to Horust's namespace.
+    mount(
+        NONE,
+        c"/",
+        NONE,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        NONE,
+    )?;
+    // A bind mount of a path on itself can then be remounted with different flags.
+    let bind = |path: &CString, flags: MsFlags| -> Result<(), Errno> {
+        mount(
+            Some(path.as_c_str()),
+            path.as_c_str(),
+            NONE,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            NONE,
+        )?;
+        // The flags of the mount (e.g. nosuid) must be kept, or the remount might be denied.
+        let kept = kept_flags(path)?;
+        mount(
+            NONE,
+            path.as_c_str(),
+            NONE,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | flags | kept,
+            NONE,
+        )
+    };
+    for path in &mounts.read_only {
+        bind(path, MsFlags::MS_RDONLY)?;
+    }
+    // Bind mounts inherit the read-only flag of their source, so it's removed by the remount.
+    for path in &mounts.read_write {
+        bind(path, MsFlags::empty())?;
+    }
+    if mounts.proc {
+        mount(
+            Some(c"proc"),
+            c"/proc",
+            Some(c"proc"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            NONE,
+        )?;
+    }
+    Ok(())
+}
+
+/// The flags of the mount containing `path`, which aren't changed by the sandbox.
+#[cfg(target_os = "linux")]
+fn kept_flags(path: &CString) -> Result<nix::mount::MsFlags, Errno> {
+    use nix::mount::MsFlags;
+    use nix::sys::statvfs::{statvfs, FsFlags};
+
+    let flags = statvfs(path.as_c_str())?.flags();
+    let mapping = [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+    Ok(mapping
+        .into_iter()
+        .filter(|(fs_flag, _)| flags.contains(*fs_flag))
+        .fold(MsFlags::empty(), |kept, (_, ms_flag)| kept | ms_flag))
+}
+
+/// Whether `pid` has a handler for `signal`. The init of a PID namespace only receives the
+/// signals it handles (except SIGKILL and SIGSTOP): the others are discarded instead of
+/// triggering their default action. If it can't be told, it's assumed to be handled.
+pub(crate) fn catches_signal(pid: Pid, signal: Signal) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| caught_signal(&status, signal))
+        .unwrap_or(true)
+}
+
+/// Checks `signal` in the mask of the caught signals (`SigCgt`) of a `/proc/<pid>/status`.
+fn caught_signal(status: &str, signal: Signal) -> Option<bool> {
+    let mask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("SigCgt:"))?;
+    let mask = u64::from_str_radix(mask.trim(), 16).ok()?;
+    Some(mask & (1 << (signal as i32 - 1)) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enter(_namespaces: &Namespaces) -> Result<(), Errno> {
+    Err(Errno::ENOTSUP)
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::signal::Signal;
+
+    use crate::horust::supervisor::sandbox::caught_signal;
+
+    #[test]
+    fn test_caught_signal() {
+        let status = "Name:\tsleep\nSigIgn:\t0000000000000000\nSigCgt:\t0000000000004002\n";
+        assert_eq!(caught_signal(status, Signal::SIGTERM), Some(true));
+        assert_eq!(caught_signal(status, Signal::SIGHUP), Some(false));
+        assert_eq!(caught_signal(status, Signal::SIGINT), Some(true));
+        assert_eq!(caught_signal("Name:\tsleep\n", Signal::SIGTERM), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! The secrets of `Environment::from_provider`, fetched when the services are spawned. They're
+//! cached for a while, so that a restarting service doesn't hit the provider every time.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::horust::formats::{SecretProvider, ServiceName};
+
+/// A fetched secret, by service and variable.
+struct Cached {
+    command: String,
+    fetched_at: Instant,
+    value: String,
+}
+
+static CACHE: Mutex<BTreeMap<(ServiceName, String), Cached>> = Mutex::new(BTreeMap::new());
+
+/// The secret of `provider` for `service`: the cached one if it's recent enough, otherwise the one
+/// returned by `fetch`. Errors are not cached.
+pub(crate) fn get(
+    service: &ServiceName,
+    provider: &SecretProvider,
+    now: Instant,
+    fetch: impl FnOnce() -> Result<String>,
+) -> Result<String> {
+    let key = (service.clone(), provider.var.clone());
+    {
+        let cache = CACHE.lock().unwrap();
+        if let Some(cached) = cache.get(&key).filter(|cached| {
+            cached.command == provider.command
+                && now.saturating_duration_since(cached.fetched_at) < provider.cache
+        }) {
+            return Ok(cached.value.clone());
+        }
+    }
+    // Not holding the lock meanwhile, the command can take a while.
+    let value = fetch()?;
+    let cached = Cached {
+        command: provider.command.clone(),
+        fetched_at: now,
+        value: value.clone(),
+    };
+    CACHE.lock().unwrap().insert(key, cached);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use anyhow::anyhow;
+
+    use crate::horust::formats::SecretProvider;
+    use crate::horust::supervisor::secrets::get;
+
+    #[test]
+    fn test_get() {
+        let mut provider = SecretProvider {
+            command: "get-secret db".into(),
+            var: "DB_PASSWORD".into(),
+            timeout: Duration::from_secs(1),
+            cache: Duration::from_secs(60),
+            overrides: true,
+        };
+        let service = "secrets-test.toml".to_string();
+        let start = Instant::now();
+        let failed = get(&service, &provider, start, || Err(anyhow!("unreachable")));
+        assert!(failed.is_err());
+        let get_at = |provider: &SecretProvider, elapsed: u64, value: &str| {
+            let value = value.to_string();
+            get(
+                &service,
+                provider,
+                start + Duration::from_secs(elapsed),
+                || Ok(value),
+            )
+            .unwrap()
+        };
+        assert_eq!(get_at(&provider, 0, "first"), "first");
+        assert_eq!(get_at(&provider, 30, "second"), "first");
+        // Expired.
+        assert_eq!(get_at(&provider, 60, "third"), "third");
+        // The command has changed, e.g. once reloaded.
+        provider.command = "get-secret db2".into();
+        assert_eq!(get_at(&provider, 61, "fourth"), "fourth");
+        provider.cache = Duration::ZERO;
+        assert_eq!(get_at(&provider, 61, "fifth"), "fifth");
+    }
+}
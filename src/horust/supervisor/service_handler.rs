@@ -1,9 +1,12 @@
-use std::time::Instant;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use nix::unistd::Pid;
 
 use crate::horust::formats::{
-    FailureStrategy, HealthinessStatus, RestartStrategy, Service, ServiceName, ServiceStatus,
+    ExitReason, FailureStrategy, HealthinessStatus, PausedBy, Restart, RestartOrder,
+    RestartStrategy, Service, ServiceName, ServiceReport, ServiceStatus, ServiceType,
 };
 use crate::horust::supervisor::repo::Repo;
 use crate::horust::Event;
@@ -12,23 +15,118 @@ use super::{LifecycleStatus, ShuttingDown};
 
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 pub(crate) struct ServiceHandler {
-    service: Service,
+    /// The definition of the service, shared with the other components.
+    service: Arc<Service>,
     /// Status of this service.
     pub(super) status: ServiceStatus,
     /// Process ID of this service, if any
     pub(super) pid: Option<Pid>,
+    /// The process has been reaped, but its exit wasn't handled yet: it must not be signalled
+    /// anymore, its pid might have been reused already.
+    pub(super) exited: bool,
     /// How many times in a row we failed to start this service
     pub(super) restart_attempts: u32,
     /// Amount of healthiness checks failed, applies only if the service is running
     pub(super) healthiness_checks_failed: Option<i32>,
     /// Instant representing at which time we received a shutdown request. Will be used for comparing Service.termination.wait
     pub(super) shutting_down_start: Option<Instant>,
+    /// Progress of the pre-stop hook, if the service has one.
+    pub(super) pre_stop: PreStopStatus,
+    /// When the service entered the Initial status, used for the timeout of optional dependencies.
+    pub(super) initial_since: Option<Instant>,
+    /// How many times a process was spawned for this service.
+    pub(super) spawns: u32,
+    /// When the current process was spawned.
+    pub(super) spawned_at: Option<Instant>,
+    /// Sum of the time spent running by the exited processes.
+    pub(super) runtime: Duration,
+    pub(super) last_exit: Option<ExitReason>,
+    /// The last lines of output, recorded when the service failed.
+    pub(super) last_output: Vec<String>,
+    pub(super) failed_at: Option<Instant>,
+    /// The service is being restarted, because of its `max-runtime`, or because a path it watches
+    /// has changed. Or it's being stopped, since another variant was switched to.
+    pub(super) recycling: bool,
+    /// How many processes were killed by the OOM killer in the cgroup of the service, so far.
+    pub(super) oom_kills: u64,
+    /// The last process was killed by the OOM killer.
+    pub(super) oom_killed: bool,
+    /// How many times in a row the service was killed by the OOM killer.
+    pub(super) oom_restart_attempts: u32,
+    /// Who has paused the service: its processes are stopped (or its cgroup frozen) until all of
+    /// them have resumed it.
+    pub(super) paused_by: BTreeSet<PausedBy>,
+    /// The service was asked to start: by a connection on its sockets if it's lazy, via the
+    /// control socket, or by a service which starts after it.
+    pub(super) start_requested: bool,
+    /// The process of a notify service has sent `READY=1`.
+    pub(super) notified_ready: bool,
+    /// The process was killed because it wasn't ready within the `start-timeout`.
+    pub(super) start_timed_out: bool,
+    /// Killed for running longer than its `limits.wall-time`.
+    pub(super) wall_timed_out: bool,
+    /// The previous process, being replaced by a start-first restart.
+    pub(super) replaced: Option<Replaced>,
+    /// A variant which was switched away from, an instance which was scaled down, or a stopped
+    /// service: it's not started again until it's switched to (or scaled up, or asked to start).
+    pub(super) inactive: bool,
+    /// How many times in a row the service has failed, without running for longer than its
+    /// `restart.cooldown`.
+    pub(super) crashes: u32,
+    /// When the service was broken, see `restart.cooldown`.
+    pub(super) broken_at: Option<Instant>,
+    /// When the processes of the service have crashed, within the last `on-crash.window`.
+    pub(super) crashed_at: VecDeque<Instant>,
+    /// The condition which isn't met yet, while the service is waiting to be spawned.
+    pub(super) conditions_not_met: Option<String>,
+    /// When the backoff before spawning the service is over, while it's waiting to be spawned.
+    pub(super) backoff_until: Option<Instant>,
+}
+
+/// A process which keeps running while the one replacing it starts: it's stopped once the new one
+/// is running, or once the service has finished.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Replaced {
+    pub(super) pid: Pid,
+    pub(super) spawned_at: Option<Instant>,
+    /// When the termination signal was sent to it.
+    pub(super) stopping_since: Option<Instant>,
+    /// It was sent SIGKILL, after the termination `wait`.
+    pub(super) killed: bool,
+    /// It has been reaped, but its exit wasn't handled yet.
+    pub(super) exited: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
+pub(crate) enum PreStopStatus {
+    #[default]
+    NotRun,
+    Running,
+    Done,
 }
 
 impl From<Service> for ServiceHandler {
     fn from(service: Service) -> Self {
+        Arc::new(service).into()
+    }
+}
+
+impl From<Arc<Service>> for ServiceHandler {
+    fn from(service: Arc<Service>) -> Self {
+        let status = if service.enabled {
+            ServiceStatus::Initial
+        } else {
+            ServiceStatus::Disabled
+        };
+        // The instances beyond the ones started by the scale of their template.
+        let inactive = service
+            .scale
+            .zip(service.scaled_instance())
+            .is_some_and(|(scale, (_, index))| index > scale.instances);
         ServiceHandler {
             service,
+            status,
+            inactive,
             ..Default::default()
         }
     }
@@ -44,8 +142,44 @@ impl ServiceHandler {
         ALIVE_STATES.contains(&self.status)
     }
 
-    pub fn start_after(&self) -> &Vec<String> {
-        self.service.start_after.as_ref()
+    pub fn start_after(&self) -> Vec<&ServiceName> {
+        self.service.start_after_services()
+    }
+
+    /// The service is not started automatically (it's lazy, `auto-start` is false, it has a timer,
+    /// or it's an inactive variant or instance), and it wasn't asked to start yet.
+    pub(crate) fn is_waiting_for_start(&self) -> bool {
+        (self.service.socket.lazy
+            || !self.service.auto_start
+            || self.service.timer.is_some()
+            || self.inactive)
+            && !self.start_requested
+            && self.is_initial()
+    }
+
+    /// The service has a timer, and it's done with its last run: it's started again once the
+    /// timer triggers.
+    pub(crate) fn is_waiting_for_timer(&self) -> bool {
+        self.service.timer.is_some()
+            && matches!(
+                self.status,
+                ServiceStatus::Finished | ServiceStatus::FinishedFailed
+            )
+    }
+
+    /// The service is a job, ready once it has exited successfully.
+    pub fn is_oneshot(&self) -> bool {
+        self.service.service_type == ServiceType::Oneshot
+    }
+
+    /// The started process is ready, for the services which start after it. A oneshot is only
+    /// ready once it has exited.
+    pub fn is_ready(&self) -> bool {
+        match self.service.service_type {
+            ServiceType::Simple => !self.has_some_failed_healthchecks(),
+            ServiceType::Notify => self.notified_ready && !self.has_some_failed_healthchecks(),
+            ServiceType::Oneshot => false,
+        }
     }
 
     pub(crate) fn is_early_state(&self) -> bool {
@@ -60,6 +194,16 @@ impl ServiceHandler {
         &self.service
     }
 
+    /// Replaces the definition of the service, e.g. once it has been reloaded.
+    pub(crate) fn redefine(&mut self, service: Arc<Service>) {
+        self.service = service;
+    }
+
+    /// The definition of the service, for handing it to another component.
+    pub(crate) fn shared_service(&self) -> Arc<Service> {
+        self.service.clone()
+    }
+
     pub fn name(&self) -> &ServiceName {
         &self.service.name
     }
@@ -68,16 +212,85 @@ impl ServiceHandler {
         self.pid
     }
 
+    /// The processes of the service are stopped, until it's resumed.
+    pub fn is_paused(&self) -> bool {
+        !self.paused_by.is_empty()
+    }
+
     pub fn next(&self, repo: &Repo, status: LifecycleStatus) -> Vec<Event> {
         next(self, repo, status)
     }
-    pub fn change_status(&self, new_status: ServiceStatus) -> (ServiceHandler, ServiceStatus) {
-        handle_status_change(self, new_status)
+    pub fn change_status(
+        &self,
+        new_status: ServiceStatus,
+        now: Instant,
+    ) -> (ServiceHandler, ServiceStatus) {
+        handle_status_change(self, new_status, now)
     }
 
     /// Restart attempts are over if the attempts field is zero or we already retried enough times.
     pub fn restart_attempts_are_over(&self) -> bool {
-        self.service.restart.attempts == 0 || self.restart_attempts > self.service.restart.attempts
+        let (restart, attempts) = self.restart_policy();
+        restart.attempts == 0 || attempts > restart.attempts
+    }
+
+    /// The service has a `restart.cooldown`, and it has failed too many times in a row.
+    pub fn is_crash_looping(&self) -> bool {
+        let restart = &self.service.restart;
+        restart.cooldown.is_some() && self.crashes > restart.attempts
+    }
+
+    /// True if the service is broken, and its `restart.cooldown` is over.
+    pub fn has_cooled_down(&self, now: Instant) -> bool {
+        self.status == ServiceStatus::Broken
+            && self
+                .broken_at
+                .zip(self.service.restart.cooldown)
+                .is_some_and(|(broken_at, cooldown)| {
+                    now.saturating_duration_since(broken_at) >= cooldown
+                })
+    }
+
+    /// Records a crash of the service. True if it has crashed `on-crash.after` times within
+    /// `on-crash.window`, and its debug command is due: the count then starts over.
+    pub fn crashed(&mut self, now: Instant) -> bool {
+        let on_crash = &self.service.on_crash;
+        if on_crash.debug_command.is_none() {
+            return false;
+        }
+        while self
+            .crashed_at
+            .front()
+            .is_some_and(|crash| now.saturating_duration_since(*crash) > on_crash.window)
+        {
+            self.crashed_at.pop_front();
+        }
+        self.crashed_at.push_back(now);
+        if self.crashed_at.len() < on_crash.after {
+            return false;
+        }
+        self.crashed_at.clear();
+        true
+    }
+
+    /// The restart policy to apply (`restart.on-oom` after an OOM kill), and the attempts made.
+    pub fn restart_policy(&self) -> (Restart, u32) {
+        let restart = &self.service.restart;
+        match &restart.on_oom {
+            Some(on_oom) if self.oom_killed => {
+                let policy = Restart {
+                    strategy: on_oom.strategy.clone(),
+                    backoff: on_oom.backoff,
+                    attempts: on_oom.attempts,
+                    window: restart.window,
+                    on_oom: None,
+                    order: restart.order,
+                    cooldown: restart.cooldown,
+                };
+                (policy, self.oom_restart_attempts)
+            }
+            _ => (restart.clone(), self.restart_attempts),
+        }
     }
     pub fn add_healthcheck_event(&mut self, check: HealthinessStatus) {
         let previous_hc = self.healthiness_checks_failed.unwrap_or(0);
@@ -86,8 +299,12 @@ impl ServiceHandler {
         self.healthiness_checks_failed = Some(previous_hc + new_hc);
     }
 
-    pub fn is_finished_failed(&self) -> bool {
-        matches!(self.status, ServiceStatus::FinishedFailed)
+    /// True if the service failed, and might be restarted or not.
+    pub fn is_failed(&self) -> bool {
+        matches!(
+            self.status,
+            ServiceStatus::Failed | ServiceStatus::FinishedFailed
+        )
     }
 
     pub fn is_in_killing(&self) -> bool {
@@ -113,8 +330,154 @@ impl ServiceHandler {
         ServiceStatus::Finished == self.status
     }
 
-    pub fn shutting_down_started(&mut self) {
-        self.shutting_down_start = Some(Instant::now());
+    pub fn is_disabled(&self) -> bool {
+        ServiceStatus::Disabled == self.status
+    }
+
+    /// True if the pre-stop hook should be run before sending the termination signal.
+    pub fn needs_pre_stop(&self) -> bool {
+        self.pid.is_some()
+            && self.pre_stop == PreStopStatus::NotRun
+            && self.service.termination.pre_stop.is_some()
+    }
+
+    /// True if the process has been running for longer than `max-runtime`, and the restart window
+    /// is open at `time`.
+    pub fn should_recycle(&self, now: Instant, time: SystemTime) -> bool {
+        let max_runtime = self.service.max_runtime;
+        !self.recycling
+            && self
+                .spawned_at
+                .zip(max_runtime)
+                .is_some_and(|(spawned_at, max_runtime)| {
+                    now.saturating_duration_since(spawned_at) >= max_runtime
+                })
+            && self.is_restart_window_open(time)
+    }
+
+    /// True if the process is still not ready, `start-timeout` after it has been spawned.
+    pub fn has_start_timed_out(&self, now: Instant) -> bool {
+        let start_timeout = self.service.start_timeout;
+        self.status == ServiceStatus::Started
+            && !self.start_timed_out
+            && self
+                .spawned_at
+                .zip(start_timeout)
+                .is_some_and(|(spawned_at, start_timeout)| {
+                    now.saturating_duration_since(spawned_at) >= start_timeout
+                })
+    }
+
+    /// True if the process is still running, `limits.wall-time` after it has been spawned.
+    pub fn has_wall_timed_out(&self, now: Instant) -> bool {
+        let wall_time = self.service.limits.wall_time;
+        matches!(self.status, ServiceStatus::Started | ServiceStatus::Running)
+            && !self.wall_timed_out
+            && self
+                .spawned_at
+                .zip(wall_time)
+                .is_some_and(|(spawned_at, wall_time)| {
+                    now.saturating_duration_since(spawned_at) >= wall_time
+                })
+    }
+
+    /// True if the process is up, and it's not being restarted already.
+    pub fn can_be_restarted(&self) -> bool {
+        self.pid.is_some()
+            && !self.recycling
+            && matches!(self.status, ServiceStatus::Started | ServiceStatus::Running)
+    }
+
+    /// True if the service is a variant of `template`.
+    pub fn is_variant_of(&self, template: &str) -> bool {
+        self.service.variant
+            && self
+                .service
+                .instance()
+                .is_some_and(|(name, _)| name == template)
+    }
+
+    /// True if a new process can be started before stopping this one: the service restarts
+    /// start-first, and it's ready. A oneshot is never replaced.
+    pub fn can_be_replaced(&self) -> bool {
+        self.service.restart.order == RestartOrder::StartFirst
+            && !self.is_oneshot()
+            && self.is_running()
+            && self.pid.is_some()
+            && self.replaced.is_none()
+    }
+
+    /// Keeps the current process running as the replaced one, and goes back to Initial, so that a
+    /// new process is started.
+    pub(crate) fn replace(&mut self, now: Instant) {
+        self.replaced = self.pid.take().map(|pid| Replaced {
+            pid,
+            spawned_at: self.spawned_at.take(),
+            stopping_since: None,
+            killed: false,
+            exited: false,
+        });
+        self.status = ServiceStatus::Initial;
+        self.initial_since = Some(now);
+        self.healthiness_checks_failed = None;
+        self.pre_stop = PreStopStatus::NotRun;
+    }
+
+    /// True if the replaced process has to be sent its termination signal, or SIGKILL once the
+    /// termination `wait` is over.
+    pub fn should_stop_replaced(&self, lifecycle_status: LifecycleStatus, now: Instant) -> bool {
+        let Some(replaced) = &self.replaced else {
+            return false;
+        };
+        match replaced.stopping_since {
+            None => {
+                matches!(lifecycle_status, LifecycleStatus::ShuttingDown(_))
+                    || matches!(
+                        self.status,
+                        ServiceStatus::Running
+                            | ServiceStatus::Finished
+                            | ServiceStatus::FinishedFailed
+                    )
+            }
+            Some(stopping_since) => {
+                !replaced.killed
+                    && (lifecycle_status == LifecycleStatus::ShuttingDown(ShuttingDown::Forcefully)
+                        || now.saturating_duration_since(stopping_since)
+                            > self.service.termination.wait)
+            }
+        }
+    }
+
+    /// True if the service is running, and it's not being restarted, nor replaced.
+    pub fn has_restarted(&self) -> bool {
+        self.is_running() && !self.recycling && self.replaced.is_none()
+    }
+
+    /// True if restarts not caused by a failure are allowed at `time`.
+    pub fn is_restart_window_open(&self, time: SystemTime) -> bool {
+        self.service
+            .restart
+            .window
+            .is_none_or(|window| window.contains(time))
+    }
+
+    pub fn shutting_down_started(&mut self, now: Instant) {
+        self.shutting_down_start = Some(now);
+    }
+
+    pub fn report(&self) -> ServiceReport {
+        ServiceReport {
+            name: self.name().clone(),
+            description: self.service.description.clone(),
+            tags: self.service.tags.clone(),
+            status: self.status.clone(),
+            spawns: self.spawns,
+            last_exit: self.last_exit,
+            runtime: self.runtime,
+            last_output: self.last_output.clone(),
+            critical: self.service.failure.critical,
+            failed_at: self.failed_at,
+        }
     }
 }
 
@@ -127,7 +490,7 @@ pub(crate) fn next(
     match lifecycle_status {
         LifecycleStatus::Running => next_events(repo, service_handler),
         LifecycleStatus::ShuttingDown(shutting_down) => {
-            next_events_shutting_down(service_handler, shutting_down)
+            next_events_shutting_down(service_handler, shutting_down, repo.now())
         }
     }
 }
@@ -139,14 +502,21 @@ fn next_events(repo: &Repo, service_handler: &ServiceHandler) -> Vec<Event> {
         |status: ServiceStatus| Event::new_status_update(service_handler.name(), status);
     let vev_status = |status: ServiceStatus| vec![ev_status(status)];
 
+    // The services in Initial are started from the ready-queue of the repo.
     match service_handler.status {
-        ServiceStatus::Initial if repo.is_service_runnable(service_handler) => {
-            vec![Event::Run(service_handler.name().clone())]
-        }
-        // if enough time has passed, this will be considered running
-        ServiceStatus::Started if !service_handler.has_some_failed_healthchecks() => {
+        // if enough time has passed, this will be considered running. Unless it's being stopped
+        // (or restarted) meanwhile: it's going to be InKilling.
+        ServiceStatus::Started if service_handler.is_ready() && !service_handler.recycling => {
             vev_status(ServiceStatus::Running)
         }
+        ServiceStatus::Started if service_handler.has_start_timed_out(repo.now()) => {
+            vec![Event::StartTimeout(service_handler.name().clone())]
+        }
+        ServiceStatus::Running | ServiceStatus::Started
+            if service_handler.has_wall_timed_out(repo.now()) =>
+        {
+            vec![Event::WallTimeout(service_handler.name().clone())]
+        }
         // This will kill the service after 3 failed healthchecks in a row.
         // Maybe this should be parametrized
         ServiceStatus::Running
@@ -158,7 +528,32 @@ fn next_events(repo: &Repo, service_handler: &ServiceHandler) -> Vec<Event> {
                 Event::Kill(service_handler.name().clone()),
             ]
         }
-        ServiceStatus::Success => vec![handle_restart_strategy(service_handler, false)],
+        ServiceStatus::Running | ServiceStatus::Started
+            if service_handler.should_recycle(repo.now(), repo.system_time()) =>
+        {
+            vec![Event::Recycle(service_handler.name().clone())]
+        }
+        // Recycled services are restarted regardless of their restart strategy.
+        ServiceStatus::Success if service_handler.recycling => vev_status(ServiceStatus::Initial),
+        // A oneshot has done its job.
+        ServiceStatus::Success if service_handler.is_oneshot() => {
+            if service_handler.service.remain_after_exit {
+                vev_status(ServiceStatus::Running)
+            } else {
+                vev_status(ServiceStatus::Finished)
+            }
+        }
+        ServiceStatus::Success => {
+            let restart = handle_restart_strategy(service_handler, false);
+            let is_restarting =
+                restart == Event::new_status_update(service_handler.name(), ServiceStatus::Initial);
+            if is_restarting && !service_handler.is_restart_window_open(repo.system_time()) {
+                debug!("{}: restart deferred to the window", service_handler.name());
+                vec![]
+            } else {
+                vec![restart]
+            }
+        }
         ServiceStatus::Failed => {
             let mut failure_evs = handle_failed_service(
                 repo.get_dependents(service_handler.name()),
@@ -180,7 +575,10 @@ fn next_events(repo: &Repo, service_handler: &ServiceHandler) -> Vec<Event> {
             failure_evs.extend(other_services_termination);
             failure_evs
         }
-        ServiceStatus::InKilling if should_force_kill(service_handler, None) => vec![
+        ServiceStatus::Broken if service_handler.has_cooled_down(repo.now()) => {
+            vev_status(ServiceStatus::Initial)
+        }
+        ServiceStatus::InKilling if should_force_kill(service_handler, None, repo.now()) => vec![
             Event::new_force_kill(service_handler.name()),
             Event::new_status_changed(service_handler.name(), ServiceStatus::Failed),
         ],
@@ -194,6 +592,7 @@ fn next_events(repo: &Repo, service_handler: &ServiceHandler) -> Vec<Event> {
 fn next_events_shutting_down(
     service_handler: &ServiceHandler,
     shutting_down: ShuttingDown,
+    now: Instant,
 ) -> Vec<Event> {
     let ev_status =
         |status: ServiceStatus| Event::new_status_update(service_handler.name(), status);
@@ -201,13 +600,17 @@ fn next_events_shutting_down(
 
     // Handle the new state separately if we're shutting down.
     match &service_handler.status {
+        // A oneshot which remains after its exit has no process to stop.
+        ServiceStatus::Running if service_handler.pid.is_none() => {
+            vev_status(ServiceStatus::Finished)
+        }
         ServiceStatus::Running | ServiceStatus::Started => vec![
             ev_status(ServiceStatus::InKilling),
             Event::Kill(service_handler.name().clone()),
         ],
         ServiceStatus::Success | ServiceStatus::Initial => vev_status(ServiceStatus::Finished),
-        ServiceStatus::Failed => vev_status(ServiceStatus::FinishedFailed),
-        ServiceStatus::InKilling if should_force_kill(service_handler, shutting_down) => {
+        ServiceStatus::Failed | ServiceStatus::Broken => vev_status(ServiceStatus::FinishedFailed),
+        ServiceStatus::InKilling if should_force_kill(service_handler, shutting_down, now) => {
             vec![Event::new_force_kill(service_handler.name())]
         }
         _ => vec![],
@@ -219,6 +622,7 @@ fn next_events_shutting_down(
 fn handle_status_change(
     service_handler: &ServiceHandler,
     next_status: ServiceStatus,
+    now: Instant,
 ) -> (ServiceHandler, ServiceStatus) {
     let mut new_service_handler = service_handler.clone();
     if next_status == service_handler.status {
@@ -227,17 +631,20 @@ fn handle_status_change(
     //TODO: refactor + cleanup.
     // A -> [B,C] means that transition to A is allowed only if service is in state B or C.
     let allowed_transitions = hashmap! {
-        ServiceStatus::Initial        => vec![ServiceStatus::Success, ServiceStatus::Failed],
+        ServiceStatus::Initial        => vec![ServiceStatus::Success,
+                                              ServiceStatus::Failed,
+                                              ServiceStatus::Broken],
         ServiceStatus::Starting       => vec![ServiceStatus::Initial],
         ServiceStatus::Started        => vec![ServiceStatus::Starting],
         ServiceStatus::InKilling      => vec![ServiceStatus::Initial,
                                               ServiceStatus::Running,
                                               ServiceStatus::Starting,
                                               ServiceStatus::Started],
-        ServiceStatus::Running        => vec![ServiceStatus::Started],
+        ServiceStatus::Running        => vec![ServiceStatus::Started, ServiceStatus::Success],
         ServiceStatus::FinishedFailed => vec![ServiceStatus::Starting,
                                               ServiceStatus::Started,
                                               ServiceStatus::Failed,
+                                              ServiceStatus::Broken,
                                               ServiceStatus::InKilling],
         ServiceStatus::Success        => vec![ServiceStatus::Starting,
                                               ServiceStatus::Started,
@@ -248,7 +655,9 @@ fn handle_status_change(
                                               ServiceStatus::Running,
                                               ServiceStatus::InKilling],
         ServiceStatus::Finished       => vec![ServiceStatus::Success,
-                                             ServiceStatus::Initial],
+                                             ServiceStatus::Initial,
+                                             ServiceStatus::Running],
+        ServiceStatus::Broken         => vec![ServiceStatus::Failed],
     };
     let allowed = allowed_transitions
         .get(&next_status)
@@ -275,6 +684,34 @@ fn handle_status_change(
                     ServiceStatus::InKilling
                 };
             }
+            ServiceStatus::Initial if allowed.contains(&service_handler.status) => {
+                if service_handler.status == ServiceStatus::Broken {
+                    info!(
+                        service = service_handler.name().as_str();
+                        "{}: its cooldown is over, retrying it once.",
+                        service_handler.name()
+                    );
+                    // It's broken again if it fails once more.
+                    new_service_handler.crashes = service_handler.service.restart.attempts;
+                    new_service_handler.broken_at = None;
+                }
+                new_service_handler.status = ServiceStatus::Initial;
+                new_service_handler.initial_since = Some(now);
+                new_service_handler.recycling = false;
+            }
+            ServiceStatus::Broken => {
+                warn!(
+                    service = service_handler.name().as_str();
+                    "{}: it has failed {} times in a row, it's broken. It's retried once in {}.",
+                    service_handler.name(),
+                    service_handler.crashes,
+                    humantime::format_duration(
+                        service_handler.service.restart.cooldown.unwrap_or_default()
+                    )
+                );
+                new_service_handler.status = ServiceStatus::Broken;
+                new_service_handler.broken_at = Some(now);
+            }
             new_status => {
                 new_service_handler.status = new_status;
             }
@@ -293,7 +730,9 @@ fn handle_status_change(
 
 /// Produces events based on the Restart Strategy of the service.
 fn handle_restart_strategy(service_handler: &ServiceHandler, is_failed: bool) -> Event {
-    let new_status = match service_handler.service.restart.strategy {
+    let new_status = match service_handler.restart_policy().0.strategy {
+        // The circuit breaker applies whatever the strategy.
+        _ if is_failed && service_handler.is_crash_looping() => ServiceStatus::Broken,
         RestartStrategy::Never if is_failed => {
             debug!(
                 "restart attempts: {}, are over: {}, max: {}",
@@ -338,6 +777,7 @@ fn handle_failed_service(deps: Vec<ServiceName>, failed_sh: &Service) -> Vec<Eve
 fn should_force_kill(
     service_handler: &ServiceHandler,
     shutting_down: impl Into<Option<ShuttingDown>>,
+    now: Instant,
 ) -> bool {
     if service_handler.pid.is_none() {
         // Since it was in the started state, it doesn't have a pid yet.
@@ -349,7 +789,9 @@ fn should_force_kill(
         return true;
     }
     if let Some(shutting_down_elapsed_secs) = service_handler.shutting_down_start {
-        let shutting_down_elapsed_secs = shutting_down_elapsed_secs.elapsed().as_secs();
+        let shutting_down_elapsed_secs = now
+            .saturating_duration_since(shutting_down_elapsed_secs)
+            .as_secs();
         debug!(
             "{}, should not force kill. Elapsed: {}, termination wait: {}",
             service_handler.name(),
@@ -370,7 +812,7 @@ fn should_force_kill(
 mod test {
     use std::ops::Sub;
     use std::str::FromStr;
-    use std::time::Duration;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
     use nix::unistd::Pid;
 
@@ -409,6 +851,43 @@ strategy = "{}"
             });
     }
 
+    #[test]
+    fn test_restart_on_oom() {
+        let service = r#"name="servicename"
+command = "Not relevant"
+[restart]
+strategy = "never"
+backoff = "1s"
+[restart.on-oom]
+strategy = "never"
+backoff = "10s"
+attempts = 1
+"#;
+        let service: Service = toml::from_str(service).unwrap();
+        let mut sh: ServiceHandler = service.into();
+        let new_status = |status| Event::new_status_update("servicename", status);
+        assert_eq!(sh.restart_policy().0.backoff, Duration::from_secs(1));
+        assert_eq!(
+            handle_restart_strategy(&sh, true),
+            new_status(ServiceStatus::FinishedFailed)
+        );
+
+        // The OOM kills have their own attempts.
+        sh.oom_killed = true;
+        sh.oom_restart_attempts = 1;
+        let (restart, attempts) = sh.restart_policy();
+        assert_eq!((restart.backoff, attempts), (Duration::from_secs(10), 1));
+        assert_eq!(
+            handle_restart_strategy(&sh, true),
+            new_status(ServiceStatus::Initial)
+        );
+        sh.oom_restart_attempts = 2;
+        assert_eq!(
+            handle_restart_strategy(&sh, true),
+            new_status(ServiceStatus::FinishedFailed)
+        );
+    }
+
     #[test]
     fn test_should_force_kill() {
         let service = r#"command="notrelevant"
@@ -417,19 +896,43 @@ wait = "10s"
 "#;
         let service: Service = toml::from_str(service).unwrap();
         let mut sh: ServiceHandler = service.into();
-        assert!(!should_force_kill(&sh, None));
-        sh.shutting_down_started();
+        assert!(!should_force_kill(&sh, None, Instant::now()));
+        sh.shutting_down_started(Instant::now());
         sh.status = ServiceStatus::InKilling;
-        assert!(!should_force_kill(&sh, None));
+        assert!(!should_force_kill(&sh, None, Instant::now()));
         let old_start = sh.shutting_down_start;
         let past_wait = Some(sh.shutting_down_start.unwrap().sub(Duration::from_secs(20)));
         sh.shutting_down_start = past_wait;
-        assert!(!should_force_kill(&sh, None));
+        assert!(!should_force_kill(&sh, None, Instant::now()));
         sh.pid = Some(Pid::this());
         sh.shutting_down_start = old_start;
-        assert!(!should_force_kill(&sh, None));
+        assert!(!should_force_kill(&sh, None, Instant::now()));
         sh.shutting_down_start = past_wait;
-        assert!(should_force_kill(&sh, None));
+        assert!(should_force_kill(&sh, None, Instant::now()));
+    }
+
+    #[test]
+    fn test_should_recycle() {
+        let mut service = Service::from_name("a");
+        service.max_runtime = Some(Duration::from_secs(60));
+        let mut sh: ServiceHandler = service.into();
+        assert!(!sh.should_recycle(Instant::now(), SystemTime::now()));
+        sh.spawned_at = Some(Instant::now());
+        assert!(!sh.should_recycle(Instant::now(), SystemTime::now()));
+        sh.spawned_at = Some(Instant::now().sub(Duration::from_secs(61)));
+        assert!(sh.should_recycle(Instant::now(), SystemTime::now()));
+        sh.recycling = true;
+        assert!(!sh.should_recycle(Instant::now(), SystemTime::now()));
+
+        // Only within the restart window:
+        let mut service = Service::from_name("a");
+        service.max_runtime = Some(Duration::from_secs(60));
+        service.restart.window = Some("02:00-03:00 UTC".parse().unwrap());
+        let mut sh: ServiceHandler = service.into();
+        sh.spawned_at = Some(Instant::now().sub(Duration::from_secs(61)));
+        let at = |hours: u64| UNIX_EPOCH + Duration::from_secs((100 * 24 + hours) * 60 * 60);
+        assert!(!sh.should_recycle(Instant::now(), at(1)));
+        assert!(sh.should_recycle(Instant::now(), at(2)));
     }
 
     #[test]
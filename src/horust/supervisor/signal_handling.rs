@@ -1,9 +1,31 @@
-use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGINT, SIGTERM};
+use std::os::fd::{AsRawFd, BorrowedFd, IntoRawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
 
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::signal::{
+    sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGCHLD, SIGHUP, SIGINT, SIGTERM,
+};
+use nix::unistd;
+
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::Event;
 use crate::horust::signal_safe::panic_ssafe;
 
 static mut SIGTERM_RECEIVED: bool = false;
 
+/// Set on SIGHUP, until the services are reloaded.
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// Set on SIGCHLD, until the children are reaped.
+static SIGCHLD_RECEIVED: AtomicBool = AtomicBool::new(false);
+/// The self-pipe written on SIGCHLD, for waking up the watcher. -1 until `init`.
+static SIGCHLD_PIPE_READ: AtomicI32 = AtomicI32::new(-1);
+static SIGCHLD_PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+/// Tells the watcher to exit, once it's woken up.
+static SIGCHLD_WATCHER_STOP: AtomicBool = AtomicBool::new(false);
+
 pub(crate) fn is_sigterm_received() -> bool {
     unsafe { SIGTERM_RECEIVED }
 }
@@ -14,6 +36,89 @@ pub(crate) fn clear_sigtem() {
     }
 }
 
+/// True if SIGHUP was received since the last call, so the services have to be reloaded.
+pub(crate) fn take_sighup() -> bool {
+    SIGHUP_RECEIVED.swap(false, Ordering::SeqCst)
+}
+
+/// True if some children have exited since the last call, so they have to be reaped.
+pub(crate) fn take_sigchld() -> bool {
+    SIGCHLD_RECEIVED.swap(false, Ordering::SeqCst)
+}
+
+/// Publishes an `Event::ChildrenExited` on the bus every time SIGCHLD is received, until
+/// `stop_sigchld_watcher` is called.
+pub(crate) fn spawn_sigchld_watcher(bus: BusConnector<Event>) {
+    let read_fd = SIGCHLD_PIPE_READ.load(Ordering::SeqCst);
+    if read_fd < 0 {
+        warn!("The signal handlers aren't set up, the exited processes won't be noticed.");
+        return;
+    }
+    SIGCHLD_WATCHER_STOP.store(false, Ordering::SeqCst);
+    thread::spawn(move || {
+        // The pipe is never closed.
+        let read_fd = unsafe { BorrowedFd::borrow_raw(read_fd) };
+        let mut buf = [0u8; 64];
+        loop {
+            match unistd::read(read_fd.as_raw_fd(), &mut buf) {
+                Ok(_) | Err(Errno::EINTR) => (),
+                Err(error) => {
+                    error!("Failed reading the SIGCHLD pipe: {}", error);
+                    break;
+                }
+            }
+            if SIGCHLD_WATCHER_STOP.load(Ordering::SeqCst) {
+                break;
+            }
+            bus.send_event(Event::ChildrenExited);
+        }
+    });
+}
+
+/// Makes the watcher spawned by `spawn_sigchld_watcher` exit, leaving the bus.
+pub(crate) fn stop_sigchld_watcher() {
+    SIGCHLD_WATCHER_STOP.store(true, Ordering::SeqCst);
+
+    wake_sigchld_watcher();
+}
+
+/// Async-signal-safe: a full pipe already means that the watcher will wake up.
+fn wake_sigchld_watcher() {
+    let write_fd = SIGCHLD_PIPE_WRITE.load(Ordering::SeqCst);
+    if write_fd >= 0 {
+        let _ = unistd::write(unsafe { BorrowedFd::borrow_raw(write_fd) }, &[0]);
+    }
+}
+
+/// The self-pipe of SIGCHLD: the signal handler only writes to it, without blocking.
+fn create_sigchld_pipe() {
+    if SIGCHLD_PIPE_WRITE.load(Ordering::SeqCst) >= 0 {
+        return;
+    }
+    let (read, write) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            panic_ssafe(
+                "signal_handling: pipe() for SIGCHLD failed.",
+                None,
+                err,
+                108,
+            );
+            return;
+        }
+    };
+    if let Err(err) = fcntl(write.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
+        panic_ssafe(
+            "signal_handling: fcntl() for SIGCHLD failed.",
+            None,
+            err,
+            109,
+        );
+    }
+    SIGCHLD_PIPE_READ.store(read.into_raw_fd(), Ordering::SeqCst);
+    SIGCHLD_PIPE_WRITE.store(write.into_raw_fd(), Ordering::SeqCst);
+}
+
 /// Setup the signal handlers
 #[inline]
 pub(crate) fn init() {
@@ -39,6 +144,36 @@ pub(crate) fn init() {
             104,
         );
     };
+
+    let sig_action = SigAction::new(SigHandler::Handler(handle_sighup), flags, SigSet::empty());
+    if let Err(err) = unsafe { sigaction(SIGHUP, &sig_action) } {
+        panic_ssafe(
+            "signal_handling: sigaction() SIGHUP failed.",
+            None,
+            err,
+            112,
+        );
+    };
+
+    init_sigchld();
+}
+
+/// Sets up the handler of SIGCHLD, and its self-pipe.
+fn init_sigchld() {
+    create_sigchld_pipe();
+    let sig_action = SigAction::new(
+        SigHandler::Handler(handle_sigchld),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    if let Err(err) = unsafe { sigaction(SIGCHLD, &sig_action) } {
+        panic_ssafe(
+            "signal_handling: sigaction() SIGCHLD failed.",
+            None,
+            err,
+            110,
+        );
+    };
 }
 
 extern "C" fn handle_sigterm(_signal: libc::c_int) {
@@ -46,3 +181,51 @@ extern "C" fn handle_sigterm(_signal: libc::c_int) {
         SIGTERM_RECEIVED = true;
     }
 }
+
+extern "C" fn handle_sighup(_signal: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_sigchld(_signal: libc::c_int) {
+    // The write to the pipe might change the errno seen by the interrupted code.
+    let errno = Errno::last_raw();
+    SIGCHLD_RECEIVED.store(true, Ordering::SeqCst);
+    wake_sigchld_watcher();
+    Errno::set_raw(errno);
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use crossbeam::channel::after;
+
+    use crate::horust::bus::Bus;
+    use crate::horust::formats::Event;
+    use crate::horust::supervisor::signal_handling::{
+        init_sigchld, spawn_sigchld_watcher, stop_sigchld_watcher, take_sigchld,
+    };
+
+    #[test]
+    fn test_sigchld_watcher() {
+        // Only SIGCHLD: the handlers of SIGTERM and SIGINT would outlive the test, in the
+        // process running the other ones.
+        init_sigchld();
+        let bus = Bus::new();
+        let observer = bus.join_bus();
+        spawn_sigchld_watcher(bus.join_bus());
+        let dispatcher = std::thread::spawn(move || bus.run());
+
+        Command::new("true").status().unwrap();
+        let deadline = after(Duration::from_secs(5));
+        let events = observer.get_events_until(&deadline, |ev| *ev == Event::ChildrenExited);
+        assert_eq!(events.last(), Some(&Event::ChildrenExited));
+        assert!(take_sigchld() || !events.is_empty());
+
+        // The watcher leaves the bus, so that it can stop.
+        stop_sigchld_watcher();
+        drop(observer);
+        dispatcher.join().unwrap();
+    }
+}
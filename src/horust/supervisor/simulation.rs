@@ -0,0 +1,1559 @@
+//! A deterministic simulation of the supervisor: processes are never forked, and the time only
+//! moves forward with the simulation. The services run as described by their `Behavior`, so that
+//! the dependencies, restarts and shutdown of a set of services can be tested without waiting
+//! for them, nor relying on the scheduler.
+//!
+//! Only available with the `simulation` feature.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use horust::horust::simulation::{Behavior, Simulation};
+//! use horust::horust::ServiceStatus;
+//! use horust::Horust;
+//!
+//! let horust = Horust::from_services_dirs(&["/etc/horust/services".into()]).unwrap();
+//! let mut simulation =
+//!     Simulation::new(&horust).with_behavior("database", Behavior::default().ignoring_termination());
+//! simulation.run_for(Duration::from_secs(10));
+//! assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+//! simulation.shutdown();
+//! assert!(simulation.run_until_finished(Duration::from_secs(60)).is_some());
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use crate::horust::bus::{Bus, BusConnector};
+use crate::horust::clock::{Clock, SimulatedClock};
+use crate::horust::formats::{
+    BootTimeoutAction, Event, ExitReason, HealthinessStatus, PausedBy, RunReport, Service,
+    ServiceName, ServiceStatus, ShuttingDown, StartDelay,
+};
+use crate::horust::output::Outputs;
+use crate::horust::supervisor::backend::Backend;
+use crate::horust::supervisor::repo::Repo;
+use crate::horust::supervisor::service_handler::ServiceHandler;
+use crate::horust::supervisor::{Supervisor, LOOP_INTERVAL};
+use crate::horust::Horust;
+
+/// Pids of the simulated processes start from here.
+const FIRST_PID: i32 = 1000;
+
+/// How often the simulated healthchecks run, like the real ones.
+const HEALTHCHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the process of a service behaves. By default it runs until it's terminated, it's
+/// healthy, and it's killed by the termination signal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Behavior {
+    spawns: bool,
+    exits: Option<(Duration, ExitReason)>,
+    termination: Termination,
+    healthy: bool,
+}
+
+/// What the process does once it receives the termination signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Termination {
+    Killed,
+    Exits(i32),
+    Ignored,
+}
+
+impl Default for Behavior {
+    fn default() -> Self {
+        Self {
+            spawns: true,
+            exits: None,
+            termination: Termination::Killed,
+            healthy: true,
+        }
+    }
+}
+
+impl Behavior {
+    /// The process exits with `code`, `after` it has been spawned.
+    pub fn exits_after(mut self, after: Duration, code: i32) -> Self {
+        self.exits = Some((after, ExitReason::Code(code)));
+        self
+    }
+
+    /// The process can't be spawned.
+    pub fn fails_to_spawn(mut self) -> Self {
+        self.spawns = false;
+        self
+    }
+
+    /// The process handles the termination signal, by exiting with `code`.
+    pub fn exits_on_termination(mut self, code: i32) -> Self {
+        self.termination = Termination::Exits(code);
+        self
+    }
+
+    /// The process ignores the termination signal, so it's only stopped by SIGKILL.
+    pub fn ignoring_termination(mut self) -> Self {
+        self.termination = Termination::Ignored;
+        self
+    }
+
+    /// The healthchecks of the service fail. Services without healthchecks are always healthy.
+    pub fn unhealthy(mut self) -> Self {
+        self.healthy = false;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Process {
+    service: ServiceName,
+    /// When it's going to exit, and how.
+    exit: Option<(Instant, ExitReason)>,
+}
+
+/// The simulated system: the processes, and the spawns and hooks in progress.
+#[derive(Debug)]
+struct World {
+    clock: Arc<SimulatedClock>,
+    behaviors: HashMap<ServiceName, Behavior>,
+    next_pid: i32,
+    /// When the spawn is due, and the service to spawn.
+    spawns: Vec<(Instant, ServiceName)>,
+    pre_stops: Vec<ServiceName>,
+    /// The debug commands which were run, and the crashed processes they were run against.
+    debug_commands: Vec<(ServiceName, Pid)>,
+    processes: HashMap<Pid, Process>,
+    /// The signals sent to processes which had already exited: on a real system, their pids
+    /// could have been reused by then.
+    stray_signals: Vec<(Pid, Signal)>,
+    /// When the next healthcheck of the started services is due.
+    healthchecks: HashMap<ServiceName, Instant>,
+    shutting_down: bool,
+}
+
+impl World {
+    fn behavior(&self, service: &str) -> Behavior {
+        self.behaviors.get(service).cloned().unwrap_or_default()
+    }
+
+    /// Makes progress on the spawns, hooks and healthchecks which are due.
+    fn tick(&mut self) -> Vec<Event> {
+        let now = self.clock.now();
+        let mut events = vec![];
+        let (due, pending) = std::mem::take(&mut self.spawns)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(at, _)| self.shutting_down || *at <= now);
+        self.spawns = pending;
+        for (_, service) in due {
+            let behavior = self.behavior(&service);
+            if self.shutting_down || !behavior.spawns {
+                let reason = if self.shutting_down {
+                    "Horust is shutting down"
+                } else {
+                    "It fails to spawn"
+                };
+                events.push(Event::SpawnFailed(service, reason.into()));
+                continue;
+            }
+            let pid = Pid::from_raw(self.next_pid);
+            self.next_pid += 1;
+            let exit = behavior
+                .exits
+                .map(|(after, exit_reason)| (now + after, exit_reason));
+            self.processes.insert(
+                pid,
+                Process {
+                    service: service.clone(),
+                    exit,
+                },
+            );
+            events.push(Event::new_pid_changed(service, pid));
+        }
+        events.extend(self.pre_stops.drain(..).map(Event::PreStopDone));
+        let mut checks: Vec<_> = self
+            .healthchecks
+            .iter_mut()
+            .filter(|(_, at)| **at <= now)
+            .map(|(service, at)| {
+                *at = now + HEALTHCHECK_INTERVAL;
+                service.clone()
+            })
+            .collect();
+        checks.sort();
+        for service in checks {
+            let status = if self.behavior(&service).healthy {
+                HealthinessStatus::Healthy
+            } else {
+                HealthinessStatus::Unhealthy
+            };
+            events.push(Event::HealthCheck(service, status));
+        }
+        events
+    }
+
+    /// The process of `service` exits now.
+    fn exit(&mut self, service: &str, exit_reason: ExitReason) {
+        let now = self.clock.now();
+        self.processes
+            .values_mut()
+            .filter(|process| process.service == service)
+            .for_each(|process| process.exit = Some((now, exit_reason)));
+    }
+}
+
+/// Acts on the simulated processes of the `World`.
+#[derive(Debug)]
+struct Simulated {
+    world: Arc<Mutex<World>>,
+}
+
+impl Backend for Simulated {
+    fn spawn(&mut self, service: Arc<Service>, backoff: Duration, _bus: BusConnector<Event>) {
+        let start_delay = match service.start_delay {
+            StartDelay::Fixed(delay) => delay,
+            StartDelay::Range { min, .. } => min,
+        };
+        let mut world = self.world.lock().unwrap();
+        let at = world.clock.now() + backoff + start_delay;
+        world.spawns.push((at, service.name.clone()));
+    }
+
+    fn spawn_pre_stop(&mut self, service: Arc<Service>, _bus: BusConnector<Event>) {
+        self.world
+            .lock()
+            .unwrap()
+            .pre_stops
+            .push(service.name.clone());
+    }
+
+    fn spawn_debug_command(&mut self, service: Arc<Service>, pid: Pid, _exit_reason: ExitReason) {
+        self.world
+            .lock()
+            .unwrap()
+            .debug_commands
+            .push((service.name.clone(), pid));
+    }
+
+    fn send_signal(&mut self, sh: &ServiceHandler, signal: Signal) {
+        let Some(pid) = sh.pid() else {
+            return;
+        };
+        let termination_signal: Signal = sh.service().termination.signal.into();
+        let mut world = self.world.lock().unwrap();
+        let termination = world.behavior(sh.name()).termination;
+        let now = world.clock.now();
+        let Some(process) = world.processes.get_mut(&pid) else {
+            world.stray_signals.push((pid, signal));
+            return;
+        };
+        let killed = ExitReason::Signal {
+            signal,
+            core_dumped: false,
+        };
+        let exit_reason = match termination {
+            _ if signal == signal::SIGKILL => killed,
+            _ if signal != termination_signal => return,
+            Termination::Killed => killed,
+            Termination::Exits(code) => ExitReason::Code(code),
+            Termination::Ignored => return,
+        };
+        process.exit = Some((now, exit_reason));
+    }
+
+    fn catches_signal(&self, _pid: Pid, _signal: Signal) -> bool {
+        true
+    }
+
+    fn reap(&mut self, repo: &Repo) -> Vec<Event> {
+        let mut world = self.world.lock().unwrap();
+        let now = world.clock.now();
+        let mut exited: Vec<Pid> = world
+            .processes
+            .iter()
+            .filter(|(_, process)| process.exit.as_ref().is_some_and(|(at, _)| *at <= now))
+            .map(|(pid, _)| *pid)
+            .collect();
+        exited.sort();
+        exited
+            .into_iter()
+            .filter_map(|pid| Some((pid, world.processes.remove(&pid)?)))
+            .filter_map(|(pid, process)| {
+                let (_, exit_reason) = process.exit?;
+                let is_replaced = repo
+                    .services
+                    .get(&process.service)
+                    .and_then(|sh| sh.replaced)
+                    .is_some_and(|replaced| replaced.pid == pid);
+                if is_replaced {
+                    Some(Event::ReplacedExited(process.service, exit_reason))
+                } else {
+                    Some(Event::new_service_exited(process.service, exit_reason))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Runs the supervisor on a simulated system, see the module documentation.
+pub struct Simulation {
+    supervisor: Supervisor,
+    bus: Bus<Event>,
+    /// Sends the events of the simulated system, and observes all of them.
+    connector: BusConnector<Event>,
+    world: Arc<Mutex<World>>,
+    clock: Arc<SimulatedClock>,
+    services: HashMap<ServiceName, Arc<Service>>,
+    events: Vec<(Duration, Event)>,
+    report: Option<RunReport>,
+}
+
+impl Simulation {
+    /// Simulates the services of `horust`. They all behave as `Behavior::default()`, until told
+    /// otherwise by `with_behavior`.
+    pub fn new(horust: &Horust) -> Self {
+        let services: Vec<Arc<Service>> = horust
+            .get_services()
+            .iter()
+            .cloned()
+            .map(Arc::new)
+            .collect();
+        let clock = Arc::new(SimulatedClock::new());
+        let world = Arc::new(Mutex::new(World {
+            clock: clock.clone(),
+            behaviors: HashMap::new(),
+            next_pid: FIRST_PID,
+            spawns: vec![],
+            pre_stops: vec![],
+            debug_commands: vec![],
+            processes: HashMap::new(),
+            stray_signals: vec![],
+            healthchecks: HashMap::new(),
+            shutting_down: false,
+        }));
+        let bus = Bus::new();
+        let supervisor = Supervisor::new(
+            bus.join_bus(),
+            services.clone(),
+            Outputs::new(None, clock.clone()),
+            None,
+            Box::new(Simulated {
+                world: world.clone(),
+            }),
+            clock.clone(),
+        );
+        Self {
+            supervisor,
+            connector: bus.join_bus(),
+            bus,
+            world,
+            clock,
+            services: services
+                .into_iter()
+                .map(|service| (service.name.clone(), service))
+                .collect(),
+            events: vec![],
+            report: None,
+        }
+    }
+
+    /// The processes of `service` behave as `behavior`.
+    pub fn with_behavior(self, service: &str, behavior: Behavior) -> Self {
+        self.world
+            .lock()
+            .unwrap()
+            .behaviors
+            .insert(service.to_string(), behavior);
+        self
+    }
+
+    /// A graceful shutdown lasts at most `timeout`, like with Horust's `shutdown-timeout`.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.supervisor.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// The services must be all up within `timeout`, otherwise `action` is taken, like with
+    /// Horust's `boot-timeout` and `boot-timeout-action`.
+    pub fn with_boot_timeout(mut self, timeout: Duration, action: BootTimeoutAction) -> Self {
+        self.supervisor.boot_timeout = Some(timeout);
+        self.supervisor.boot_timeout_action = action;
+        self
+    }
+
+    /// Runs an iteration of the supervisor, and moves the time forward. Returns false once all
+    /// the services have finished.
+    pub fn step(&mut self) -> bool {
+        if self.report.is_some() {
+            return false;
+        }
+        let events = self.world.lock().unwrap().tick();
+        events
+            .into_iter()
+            .for_each(|ev| self.connector.send_event(ev));
+        self.deliver();
+        self.supervisor.step();
+        self.deliver();
+        if self.supervisor.has_finished() {
+            self.report = Some(self.supervisor.finish());
+            self.deliver();
+            return false;
+        }
+        self.clock.advance(LOOP_INTERVAL);
+        true
+    }
+
+    /// Runs the simulation for `duration`, or until all the services have finished.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.elapsed() + duration;
+        while self.elapsed() < until && self.step() {}
+    }
+
+    /// Runs the simulation until all the services have finished, returning the report of the
+    /// run. None if they're still running after `timeout`.
+    pub fn run_until_finished(&mut self, timeout: Duration) -> Option<&RunReport> {
+        self.run_for(timeout);
+        self.report.as_ref()
+    }
+
+    /// Starts a graceful shutdown, as if Horust received SIGTERM.
+    pub fn shutdown(&mut self) {
+        self.connector
+            .send_event(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
+    }
+
+    /// A connection comes in on the sockets of the lazy `service`.
+    pub fn connect(&mut self, service: &str) {
+        self.connector
+            .send_event(Event::SocketActivated(service.into()));
+    }
+
+    /// `service` is asked to start, like `horustctl start` does.
+    pub fn start(&mut self, service: &str) {
+        self.connector.send_event(Event::Start(service.into()));
+    }
+
+    /// The process of the notify `service` sends `READY=1` on its notify socket.
+    pub fn notify_ready(&mut self, service: &str) {
+        self.connector
+            .send_event(Event::NotifiedReady(service.into()));
+    }
+
+    /// A path watched by `service` changes.
+    pub fn change_path(&mut self, service: &str) {
+        self.connector
+            .send_event(Event::PathChanged(service.into()));
+    }
+
+    /// The definition of `service` has changed, like once the services are reloaded.
+    pub fn redefine(&mut self, service: Service) {
+        self.connector
+            .send_event(Event::ServiceChanged(Arc::new(service)));
+    }
+
+    /// The transient `service` is added, like `horustctl run` does.
+    pub fn add(&mut self, service: Service) {
+        self.connector
+            .send_event(Event::ServiceAdded(Arc::new(service)));
+    }
+
+    /// The variant `service` is switched to, like `horustctl switch` does.
+    pub fn switch(&mut self, service: &str) {
+        self.connector.send_event(Event::Switch(service.into()));
+    }
+
+    /// `services` are restarted, at most `max_unavailable` at a time, like `horustctl restart`.
+    pub fn restart(&mut self, services: &[&str], max_unavailable: usize) {
+        let services = services.iter().map(|service| service.to_string()).collect();
+        self.connector
+            .send_event(Event::Restart(services, max_unavailable));
+    }
+
+    /// `services` are stopped, each one after the ones which start after it, like `horustctl stop`.
+    pub fn stop(&mut self, services: &[&str]) {
+        let services = services.iter().map(|service| service.to_string()).collect();
+        self.connector.send_event(Event::Stop(services));
+    }
+
+    /// The scaled `template` is scaled to `instances`, like `horustctl scale` does.
+    pub fn scale(&mut self, template: &str, instances: usize) {
+        self.connector
+            .send_event(Event::Scale(template.into(), instances));
+    }
+
+    /// The broken `service` is reset and started again, like `horustctl clear`.
+    pub fn clear(&mut self, service: &str) {
+        self.connector.send_event(Event::Clear(service.into()));
+    }
+
+    /// `service` is paused by an operator, like `horustctl pause` does.
+    pub fn pause(&mut self, service: &str) {
+        self.connector
+            .send_event(Event::Pause(service.into(), PausedBy::Operator));
+    }
+
+    /// The paused `service` is resumed by an operator, like `horustctl resume` does.
+    pub fn resume(&mut self, service: &str) {
+        self.connector
+            .send_event(Event::Resume(service.into(), PausedBy::Operator));
+    }
+
+    /// The process of `service` exits now, regardless of its behavior.
+    pub fn exit(&mut self, service: &str, exit_reason: ExitReason) {
+        self.world.lock().unwrap().exit(service, exit_reason);
+    }
+
+    /// The current status of `service`, if it exists.
+    pub fn status(&self, service: &str) -> Option<ServiceStatus> {
+        self.supervisor
+            .repo
+            .services
+            .get(service)
+            .map(|sh| sh.status.clone())
+    }
+
+    /// The simulated time passed since the start.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// The services whose `on-crash.debug-command` was run, and the pids of their crashed
+    /// processes.
+    pub fn debug_commands(&self) -> Vec<(ServiceName, Pid)> {
+        self.world.lock().unwrap().debug_commands.clone()
+    }
+
+    /// The signals sent to processes which had already exited, and their pids.
+    pub fn stray_signals(&self) -> Vec<(Pid, Signal)> {
+        self.world.lock().unwrap().stray_signals.clone()
+    }
+
+    /// All the events sent so far, with the time they were observed at.
+    pub fn events(&self) -> &[(Duration, Event)] {
+        &self.events
+    }
+
+    /// Dispatches the pending events, and reacts to them as the rest of Horust would.
+    fn deliver(&mut self) {
+        self.bus.dispatch_pending();
+        let now = self.clock.now();
+        let elapsed = self.elapsed();
+        let mut world = self.world.lock().unwrap();
+        for ev in self.connector.try_get_events() {
+            match &ev {
+                Event::StatusChanged(service_name, ServiceStatus::Started) => {
+                    let has_checks = self
+                        .services
+                        .get(service_name)
+                        .is_some_and(|service| service.healthiness.has_any_check_defined());
+                    if has_checks {
+                        world.healthchecks.insert(service_name.clone(), now);
+                    } else {
+                        self.connector.send_event(Event::HealthCheck(
+                            service_name.clone(),
+                            HealthinessStatus::Healthy,
+                        ));
+                    }
+                }
+                Event::ServiceExited(service_name, _) => {
+                    world.healthchecks.remove(service_name);
+                }
+                Event::ShuttingDownInitiated(_) => {
+                    world.shutting_down = true;
+                    world.healthchecks.clear();
+                }
+                _ => {}
+            }
+            self.events.push((elapsed, ev));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use nix::sys::signal;
+
+    use crate::horust::formats::{
+        BootTimeoutAction, Dependency, Event, ExitReason, HealthinessStatus, PausedBy,
+        RestartOrder, RestartStrategy, Scale, Service, ServiceStatus, ServiceType, StartDelay,
+        TerminationSignal, Timer,
+    };
+    use crate::horust::supervisor::simulation::{Behavior, Simulation};
+    use crate::horust::templates;
+    use crate::horust::Horust;
+
+    /// When `service` has first reached `status`.
+    fn reached(simulation: &Simulation, service: &str, status: ServiceStatus) -> Option<Duration> {
+        simulation
+            .events()
+            .iter()
+            .find(|(_, ev)| *ev == Event::StatusChanged(service.into(), status.clone()))
+            .map(|(elapsed, _)| *elapsed)
+    }
+
+    #[test]
+    fn test_simulation_dependencies() {
+        let database = Service::from_name("database");
+        let mut api = Service::from_name("api");
+        api.start_after = vec![Dependency::Service("database".into())];
+        api.start_delay = Duration::from_secs(5).into();
+        let horust = Horust::new(vec![database, api]);
+        let mut simulation = Simulation::new(&horust)
+            .with_behavior("database", Behavior::default().exits_on_termination(0))
+            .with_behavior("api", Behavior::default().exits_on_termination(0));
+
+        simulation.run_for(Duration::from_secs(60));
+        assert_eq!(simulation.status("database"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+        let database_running = reached(&simulation, "database", ServiceStatus::Running).unwrap();
+        let api_started = reached(&simulation, "api", ServiceStatus::Started).unwrap();
+        assert!(api_started >= database_running + Duration::from_secs(5));
+
+        simulation.shutdown();
+        let report = simulation
+            .run_until_finished(Duration::from_secs(60))
+            .unwrap();
+        assert!(report
+            .services
+            .iter()
+            .all(|service| service.status == ServiceStatus::Finished));
+    }
+
+    #[test]
+    fn test_simulation_restart() {
+        let mut service = Service::from_name("flaky");
+        service.restart.strategy = RestartStrategy::Always;
+        let horust = Horust::new(vec![service]);
+        let mut simulation = Simulation::new(&horust).with_behavior(
+            "flaky",
+            Behavior::default().exits_after(Duration::from_secs(2), 1),
+        );
+
+        simulation.run_for(Duration::from_secs(30));
+        let spawns = simulation
+            .events()
+            .iter()
+            .filter(|(_, ev)| matches!(ev, Event::PidChanged(..)))
+            .count();
+        assert!(spawns > 1, "spawns: {}", spawns);
+
+        simulation.exit("flaky", ExitReason::Code(0));
+        simulation.shutdown();
+        assert!(simulation
+            .run_until_finished(Duration::from_secs(60))
+            .is_some());
+    }
+
+    #[test]
+    fn test_simulation_storm() {
+        let mut service = Service::from_name("storm");
+        service.restart.strategy = RestartStrategy::Always;
+        let horust = Horust::new(vec![service]);
+        let mut simulation = Simulation::new(&horust).with_behavior(
+            "storm",
+            Behavior::default().exits_after(Duration::from_millis(100), 0),
+        );
+
+        simulation.run_for(Duration::from_secs(60));
+        let spawns = simulation
+            .events()
+            .iter()
+            .filter(|(_, ev)| matches!(ev, Event::PidChanged(..)))
+            .count();
+        // Restarted at most every 5 seconds, after the first 10 exits.
+        assert!((10..=22).contains(&spawns), "spawns: {}", spawns);
+    }
+
+    #[test]
+    fn test_simulation_debug_command() {
+        let mut service = Service::from_name("crashing");
+        service.restart.strategy = RestartStrategy::Always;
+        service.on_crash.debug_command = Some("coredumpctl info {pid}".into());
+        service.on_crash.after = 2;
+        let horust = Horust::new(vec![service]);
+        let mut simulation = Simulation::new(&horust);
+        let segfault = ExitReason::Signal {
+            signal: signal::SIGSEGV,
+            core_dumped: true,
+        };
+        let last_pid = |simulation: &Simulation| {
+            simulation
+                .events()
+                .iter()
+                .rev()
+                .find_map(|(_, ev)| match ev {
+                    Event::PidChanged(_, pid) => Some(*pid),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        simulation.run_for(Duration::from_secs(5));
+        simulation.exit("crashing", segfault);
+        simulation.run_for(Duration::from_secs(5));
+        assert!(simulation.debug_commands().is_empty());
+        let pid = last_pid(&simulation);
+        simulation.exit("crashing", segfault);
+        simulation.run_for(Duration::from_secs(5));
+        assert_eq!(simulation.debug_commands(), vec![("crashing".into(), pid)]);
+
+        // Being terminated isn't a crash.
+        simulation.shutdown();
+        simulation
+            .run_until_finished(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(simulation.debug_commands().len(), 1);
+    }
+
+    #[test]
+    fn test_simulation_broken() {
+        let mut service = Service::from_name("crashing");
+        service.restart.strategy = RestartStrategy::Always;
+        service.restart.attempts = 2;
+        service.restart.cooldown = Some(Duration::from_secs(60));
+        let horust = Horust::new(vec![service]);
+        let mut simulation = Simulation::new(&horust).with_behavior(
+            "crashing",
+            Behavior::default().exits_after(Duration::from_secs(1), 1),
+        );
+        let spawns = |simulation: &Simulation| {
+            simulation
+                .events()
+                .iter()
+                .filter(|(_, ev)| matches!(ev, Event::PidChanged(..)))
+                .count()
+        };
+
+        simulation.run_for(Duration::from_secs(30));
+        assert_eq!(simulation.status("crashing"), Some(ServiceStatus::Broken));
+        assert_eq!(spawns(&simulation), 3);
+        let broken = reached(&simulation, "crashing", ServiceStatus::Broken).unwrap();
+
+        // Retried once after the cooldown.
+        simulation.run_for(broken + Duration::from_secs(70) - simulation.elapsed());
+        assert_eq!(simulation.status("crashing"), Some(ServiceStatus::Broken));
+        assert_eq!(spawns(&simulation), 4);
+
+        simulation.clear("crashing");
+        simulation.run_for(Duration::from_secs(30));
+        assert_eq!(simulation.status("crashing"), Some(ServiceStatus::Broken));
+        assert_eq!(spawns(&simulation), 7);
+
+        simulation.shutdown();
+        let report = simulation
+            .run_until_finished(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(report.services[0].status, ServiceStatus::FinishedFailed);
+    }
+
+    #[test]
+    fn test_simulation_failures() {
+        let broken = Service::from_name("broken");
+        let mut sick = Service::from_name("sick");
+        sick.healthiness.dns_name = Some("localhost".into());
+        let horust = Horust::new(vec![broken, sick]);
+        let mut simulation = Simulation::new(&horust)
+            .with_behavior("broken", Behavior::default().fails_to_spawn())
+            .with_behavior("sick", Behavior::default().unhealthy());
+
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(
+            simulation.status("broken"),
+            Some(ServiceStatus::FinishedFailed)
+        );
+        // Never healthy, so never running.
+        assert_eq!(simulation.status("sick"), Some(ServiceStatus::Started));
+        assert!(simulation
+            .events()
+            .iter()
+            .any(|(_, ev)| *ev == Event::HealthCheck("sick".into(), HealthinessStatus::Unhealthy)));
+    }
+
+    #[test]
+    fn test_simulation_shutdown() {
+        let mut stubborn = Service::from_name("stubborn");
+        stubborn.termination.wait = Duration::from_secs(10);
+        let horust = Horust::new(vec![stubborn, Service::from_name("polite")]);
+        let mut simulation = Simulation::new(&horust)
+            .with_behavior("stubborn", Behavior::default().ignoring_termination());
+
+        simulation.run_for(Duration::from_secs(5));
+        simulation.shutdown();
+        let start = simulation.elapsed();
+        let report = simulation
+            .run_until_finished(Duration::from_secs(60))
+            .unwrap()
+            .clone();
+        let exit = |name: &str| {
+            report
+                .services
+                .iter()
+                .find(|service| service.name == name)
+                .and_then(|service| service.last_exit)
+        };
+        let killed_by = |signal| {
+            Some(ExitReason::Signal {
+                signal,
+                core_dumped: false,
+            })
+        };
+        assert_eq!(exit("polite"), killed_by(signal::SIGTERM));
+        assert_eq!(exit("stubborn"), killed_by(signal::SIGKILL));
+        assert!(simulation.elapsed() - start >= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_simulation_shutdown_timeout() {
+        let mut database = Service::from_name("database");
+        database.termination.wait = Duration::from_secs(60);
+        let mut sidecar = Service::from_name("sidecar");
+        sidecar.termination.wait = Duration::from_secs(2);
+        let horust = Horust::new(vec![database, sidecar]);
+        let mut simulation = Simulation::new(&horust)
+            .with_behavior("database", Behavior::default().ignoring_termination())
+            .with_behavior("sidecar", Behavior::default().ignoring_termination())
+            .with_shutdown_timeout(Duration::from_secs(20));
+
+        simulation.run_for(Duration::from_secs(5));
+        simulation.shutdown();
+        let start = simulation.elapsed();
+        simulation.run_for(Duration::from_secs(10));
+        // Killed after its own wait, while the database is still given time.
+        assert_eq!(
+            simulation.status("sidecar"),
+            Some(ServiceStatus::FinishedFailed)
+        );
+        assert_eq!(
+            simulation.status("database"),
+            Some(ServiceStatus::InKilling)
+        );
+
+        // Killed once the whole shutdown has lasted too long, before its own wait is over.
+        assert!(simulation
+            .run_until_finished(Duration::from_secs(30))
+            .is_some());
+        let elapsed = simulation.elapsed() - start;
+        assert!(
+            (Duration::from_secs(20)..Duration::from_secs(25)).contains(&elapsed),
+            "elapsed: {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn test_simulation_exits_while_killed() {
+        // The process exits on its own around when the termination wait is over: it must not be
+        // signalled once it has been reaped.
+        for offset in 0..20 {
+            let mut stubborn = Service::from_name("stubborn");
+            stubborn.termination.wait = Duration::from_secs(2);
+            let horust = Horust::new(vec![stubborn]);
+            let exits_after = Duration::from_secs(6) + Duration::from_millis(100) * offset;
+            let mut simulation = Simulation::new(&horust).with_behavior(
+                "stubborn",
+                Behavior::default()
+                    .ignoring_termination()
+                    .exits_after(exits_after, 0),
+            );
+
+            simulation.run_for(Duration::from_secs(5));
+            simulation.shutdown();
+            assert!(simulation
+                .run_until_finished(Duration::from_secs(60))
+                .is_some());
+            assert_eq!(
+                simulation.stray_signals(),
+                vec![],
+                "exits after: {:?}",
+                exits_after
+            );
+        }
+    }
+
+    #[test]
+    fn test_simulation_system_ready() {
+        let mut web = Service::from_name("web");
+        web.restart.strategy = RestartStrategy::Always;
+        let mut sick = Service::from_name("sick");
+        sick.healthiness.dns_name = Some("localhost".into());
+        let system_ready = |simulation: &Simulation| {
+            simulation
+                .events()
+                .iter()
+                .filter(|(_, ev)| *ev == Event::SystemReady)
+                .count()
+        };
+
+        let horust = Horust::new(vec![web.clone(), Service::from_name("batch")]);
+        let mut simulation = Simulation::new(&horust).with_behavior(
+            "web",
+            Behavior::default().exits_after(Duration::from_secs(5), 1),
+        );
+        simulation.run_for(Duration::from_secs(30));
+        // Only once, even if the service has been restarted in the meanwhile.
+        let spawns = simulation
+            .events()
+            .iter()
+            .filter(|(_, ev)| matches!(ev, Event::PidChanged(service, _) if service == "web"))
+            .count();
+        assert!(spawns > 1);
+        assert_eq!(system_ready(&simulation), 1);
+
+        let horust = Horust::new(vec![web, sick]);
+        let mut simulation =
+            Simulation::new(&horust).with_behavior("sick", Behavior::default().unhealthy());
+        simulation.run_for(Duration::from_secs(30));
+        assert_eq!(system_ready(&simulation), 0);
+    }
+
+    #[test]
+    fn test_simulation_boot_timeout() {
+        let mut sick = Service::from_name("sick");
+        sick.healthiness.dns_name = Some("localhost".into());
+        let services = vec![Service::from_name("web"), sick];
+        let boot = |action| {
+            let horust = Horust::new(services.clone());
+            let mut simulation = Simulation::new(&horust)
+                .with_behavior("sick", Behavior::default().unhealthy())
+                .with_boot_timeout(Duration::from_secs(30), action);
+            simulation.run_for(Duration::from_secs(25));
+            assert!(!simulation
+                .events()
+                .iter()
+                .any(|(_, ev)| matches!(ev, Event::ShuttingDownInitiated(_))));
+            simulation
+                .run_until_finished(Duration::from_secs(20))
+                .cloned()
+        };
+
+        // Never healthy, so the services are shut down once the boot timeout is over.
+        let report = boot(BootTimeoutAction::Shutdown).unwrap();
+        assert!(report.boot_timed_out);
+        let report = boot(BootTimeoutAction::Kill).unwrap();
+        assert!(report.boot_timed_out);
+        // Only logged, the services keep running.
+        assert_eq!(boot(BootTimeoutAction::Warn), None);
+    }
+
+    #[test]
+    fn test_simulation_fan_out() {
+        let jobs: Vec<Service> = (0..1_000)
+            .map(|i| Service::from_name(&format!("job-{i}")))
+            .collect();
+        let mut report = Service::from_name("report");
+        report.start_after = jobs
+            .iter()
+            .map(|job| Dependency::Service(job.name.clone()))
+            .collect();
+        let names: Vec<String> = jobs.iter().map(|job| job.name.clone()).collect();
+        let horust = Horust::new(jobs.into_iter().chain([report]).collect());
+        let oneshot = Behavior::default().exits_after(Duration::from_secs(1), 0);
+        let mut simulation = names
+            .iter()
+            .map(String::as_str)
+            .chain(["report"])
+            .fold(Simulation::new(&horust), |simulation, name| {
+                simulation.with_behavior(name, oneshot.clone())
+            });
+
+        let report = simulation
+            .run_until_finished(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(report.services.len(), 1_001);
+        assert!(report
+            .services
+            .iter()
+            .all(|service| service.status == ServiceStatus::Finished));
+        let last_job = names
+            .iter()
+            .filter_map(|name| reached(&simulation, name, ServiceStatus::Running))
+            .max()
+            .unwrap();
+        let report_started = reached(&simulation, "report", ServiceStatus::Started).unwrap();
+        assert!(report_started >= last_job);
+    }
+
+    #[test]
+    fn test_simulation_lazy() {
+        let mut admin = Service::from_name("admin");
+        admin.socket.listen = vec!["127.0.0.1:8081".parse().unwrap()];
+        admin.socket.lazy = true;
+        let mut dashboard = Service::from_name("dashboard");
+        dashboard.start_after = vec![Dependency::Service("admin".into())];
+        let horust = Horust::new(vec![admin, Service::from_name("api"), dashboard]);
+        let mut simulation = Simulation::new(&horust);
+
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("admin"), Some(ServiceStatus::Initial));
+        assert_eq!(simulation.status("dashboard"), Some(ServiceStatus::Initial));
+
+        simulation.connect("admin");
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(simulation.status("admin"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("dashboard"), Some(ServiceStatus::Running));
+    }
+
+    #[test]
+    fn test_simulation_manual_start() {
+        let manual = |mut service: Service| {
+            service.auto_start = false;
+            service
+        };
+        let horust = Horust::new(vec![
+            manual(Service::from_name("schema")),
+            manual(Service::start_after("migrate", vec!["schema"])),
+            manual(Service::from_name("backup")),
+            Service::start_after("api", vec!["migrate"]),
+        ]);
+        let mut simulation = Simulation::new(&horust);
+
+        // Pulled in by api, through migrate.
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(simulation.status("schema"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("migrate"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("backup"), Some(ServiceStatus::Initial));
+
+        simulation.start("backup");
+        simulation.run_for(Duration::from_secs(5));
+        assert_eq!(simulation.status("backup"), Some(ServiceStatus::Running));
+    }
+
+    #[test]
+    fn test_simulation_timer() {
+        let mut backup = Service::from_name("backup");
+        backup.service_type = ServiceType::Oneshot;
+        backup.timer = Some(Timer {
+            on_calendar: "daily".parse().unwrap(),
+            persistent: false,
+            accuracy: Duration::ZERO,
+        });
+        let horust = Horust::new(vec![backup]);
+        let job = Behavior::default().exits_after(Duration::from_secs(5), 0);
+        let mut simulation = Simulation::new(&horust).with_behavior("backup", job);
+
+        // Not started at boot, and it keeps waiting for its timer once it has finished.
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(simulation.status("backup"), Some(ServiceStatus::Initial));
+        for _ in 0..2 {
+            simulation.start("backup");
+            assert!(simulation
+                .run_until_finished(Duration::from_secs(10))
+                .is_none());
+            assert_eq!(simulation.status("backup"), Some(ServiceStatus::Finished));
+        }
+
+        simulation.shutdown();
+        let report = simulation
+            .run_until_finished(Duration::from_secs(5))
+            .unwrap();
+        let backup = report.service("backup").unwrap();
+        assert_eq!(backup.status, ServiceStatus::Finished);
+        assert_eq!(backup.spawns, 2);
+    }
+
+    #[test]
+    fn test_simulation_oneshot() {
+        let oneshot = |name: &str, remain_after_exit| {
+            let mut service = Service::from_name(name);
+            service.service_type = ServiceType::Oneshot;
+            service.remain_after_exit = remain_after_exit;
+            service
+        };
+        let horust = Horust::new(vec![
+            oneshot("migrate", false),
+            oneshot("sysctl", true),
+            Service::start_after("api", vec!["migrate", "sysctl"]),
+        ]);
+        let job = Behavior::default().exits_after(Duration::from_secs(5), 0);
+        let mut simulation = Simulation::new(&horust)
+            .with_behavior("migrate", job.clone())
+            .with_behavior("sysctl", job);
+
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("migrate"), Some(ServiceStatus::Started));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Initial));
+
+        simulation.run_for(Duration::from_secs(5));
+        assert_eq!(simulation.status("migrate"), Some(ServiceStatus::Finished));
+        assert_eq!(simulation.status("sysctl"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+        let api_started = reached(&simulation, "api", ServiceStatus::Started).unwrap();
+        assert!(api_started >= Duration::from_secs(5));
+
+        simulation.shutdown();
+        let report = simulation
+            .run_until_finished(Duration::from_secs(60))
+            .unwrap();
+        // Without a process to stop.
+        let sysctl = report.services.iter().find(|s| s.name == "sysctl").unwrap();
+        assert_eq!(sysctl.status, ServiceStatus::Finished);
+    }
+
+    #[test]
+    fn test_simulation_notify() {
+        let notify = |name: &str| {
+            let mut service = Service::from_name(name);
+            service.service_type = ServiceType::Notify;
+            service.start_timeout = Some(Duration::from_secs(10));
+            service
+        };
+        let horust = Horust::new(vec![
+            notify("database"),
+            notify("stuck"),
+            Service::start_after("api", vec!["database"]),
+        ]);
+        let mut simulation = Simulation::new(&horust);
+
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("database"), Some(ServiceStatus::Started));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Initial));
+
+        simulation.notify_ready("database");
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("database"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("stuck"), Some(ServiceStatus::Started));
+
+        // Never notified, it's killed once its start-timeout is over.
+        simulation.run_for(Duration::from_secs(10));
+        assert!(simulation
+            .events()
+            .iter()
+            .any(|(_, ev)| *ev == Event::StartTimeout("stuck".into())));
+        assert_eq!(
+            simulation.status("stuck"),
+            Some(ServiceStatus::FinishedFailed)
+        );
+    }
+
+    #[test]
+    fn test_simulation_pause() {
+        let horust = Horust::new(vec![Service::from_name("noisy")]);
+        let mut simulation = Simulation::new(&horust);
+        let paused = |simulation: &Simulation| {
+            simulation
+                .events()
+                .iter()
+                .filter_map(|(_, ev)| match ev {
+                    Event::Paused(_, paused) => Some(*paused),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        simulation.run_for(Duration::from_secs(5));
+        simulation.pause("noisy");
+        simulation.run_for(Duration::from_secs(1));
+        assert_eq!(paused(&simulation), vec![true]);
+
+        // Paused by the pressure monitoring too: it stays paused until both have resumed it.
+        simulation
+            .connector
+            .send_event(Event::Pause("noisy".into(), PausedBy::Pressure));
+        simulation.run_for(Duration::from_secs(1));
+        simulation
+            .connector
+            .send_event(Event::Resume("noisy".into(), PausedBy::Pressure));
+        simulation.run_for(Duration::from_secs(1));
+        assert_eq!(paused(&simulation), vec![true]);
+        simulation.resume("noisy");
+        simulation.run_for(Duration::from_secs(1));
+        assert_eq!(paused(&simulation), vec![true, false]);
+        assert_eq!(simulation.status("noisy"), Some(ServiceStatus::Running));
+    }
+
+    #[test]
+    fn test_simulation_wall_time() {
+        let mut batch = Service::from_name("batch");
+        batch.limits.wall_time = Some(Duration::from_secs(10));
+        let horust = Horust::new(vec![batch]);
+        let mut simulation = Simulation::new(&horust)
+            .with_behavior("batch", Behavior::default().exits_on_termination(0));
+
+        simulation.run_for(Duration::from_secs(5));
+        assert_eq!(simulation.status("batch"), Some(ServiceStatus::Running));
+
+        // Killed once its wall-time is over: it has failed, even though it exited successfully.
+        simulation.run_for(Duration::from_secs(10));
+        assert!(simulation
+            .events()
+            .iter()
+            .any(|(_, ev)| *ev == Event::WallTimeout("batch".into())));
+        assert_eq!(
+            simulation.status("batch"),
+            Some(ServiceStatus::FinishedFailed)
+        );
+    }
+
+    #[test]
+    fn test_simulation_watch() {
+        let mut web = Service::from_name("web");
+        web.watch = vec!["/etc/web.yaml".into()];
+        let mut proxy = Service::from_name("proxy");
+        proxy.watch = vec!["/etc/proxy.yaml".into()];
+        proxy.reload_signal = Some(TerminationSignal::HUP);
+        let horust = Horust::new(vec![web, proxy]);
+        let mut simulation = Simulation::new(&horust);
+        let spawns = |simulation: &Simulation, service: &str| {
+            simulation
+                .events()
+                .iter()
+                .filter(|(_, ev)| matches!(ev, Event::PidChanged(name, _) if name == service))
+                .count()
+        };
+
+        simulation.run_for(Duration::from_secs(3));
+        simulation.change_path("web");
+        simulation.change_path("proxy");
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("web"), Some(ServiceStatus::Running));
+        assert_eq!(spawns(&simulation, "web"), 2);
+        // Reloaded on its own, the simulated process ignores SIGHUP.
+        assert_eq!(simulation.status("proxy"), Some(ServiceStatus::Running));
+        assert_eq!(spawns(&simulation, "proxy"), 1);
+    }
+
+    #[test]
+    fn test_simulation_start_first() {
+        let mut web = Service::from_name("web");
+        web.restart.order = RestartOrder::StartFirst;
+        web.healthiness.file_path = Some("/var/web/up".into());
+        let mut slow = Service::from_name("slow");
+        slow.restart.order = RestartOrder::StartFirst;
+        slow.start_delay = StartDelay::Fixed(Duration::from_secs(5));
+        let horust = Horust::new(vec![web, slow]);
+        let mut simulation = Simulation::new(&horust);
+        let first = |simulation: &Simulation, expected: &Event| {
+            simulation
+                .events()
+                .iter()
+                .position(|(_, ev)| ev == expected)
+        };
+
+        simulation.run_for(Duration::from_secs(8));
+        simulation.change_path("web");
+        simulation.change_path("slow");
+        simulation.run_for(Duration::from_secs(4));
+        assert_eq!(simulation.status("web"), Some(ServiceStatus::Running));
+        let pids: Vec<_> = simulation
+            .events()
+            .iter()
+            .filter_map(|(_, ev)| match ev {
+                Event::PidChanged(name, pid) if name == "web" => Some(*pid),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pids.len(), 2);
+        // The old process is only stopped once the new one is running, and the service is never
+        // stopped in between.
+        let exited = first(
+            &simulation,
+            &Event::ReplacedExited(
+                "web".into(),
+                ExitReason::Signal {
+                    signal: signal::SIGTERM,
+                    core_dumped: false,
+                },
+            ),
+        )
+        .unwrap();
+        let running = simulation
+            .events()
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, ev))| {
+                *ev == Event::StatusChanged("web".into(), ServiceStatus::Running)
+            })
+            .map(|(index, _)| index)
+            .nth(1)
+            .unwrap();
+        assert!(running < exited);
+        assert!(first(
+            &simulation,
+            &Event::StatusChanged("web".into(), ServiceStatus::InKilling)
+        )
+        .is_none());
+
+        // Still starting: the replaced process keeps running, until Horust shuts down.
+        assert_eq!(simulation.status("slow"), Some(ServiceStatus::Starting));
+        assert!(!simulation
+            .events()
+            .iter()
+            .any(|(_, ev)| matches!(ev, Event::ReplacedExited(name, _) if name == "slow")));
+        simulation.shutdown();
+        assert!(simulation
+            .run_until_finished(Duration::from_secs(60))
+            .is_some());
+        assert!(simulation
+            .events()
+            .iter()
+            .any(|(_, ev)| matches!(ev, Event::ReplacedExited(name, _) if name == "slow")));
+    }
+
+    #[test]
+    fn test_simulation_switch() {
+        let mut blue = Service::from_name("app@blue");
+        blue.variant = true;
+        let mut green = Service::from_name("app@green");
+        green.variant = true;
+        green.auto_start = false;
+        green.start_delay = StartDelay::Fixed(Duration::from_secs(2));
+        let horust = Horust::new(vec![blue, green]);
+        let mut simulation = Simulation::new(&horust);
+
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("app@blue"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("app@green"), Some(ServiceStatus::Initial));
+
+        simulation.switch("app@green");
+        simulation.run_for(Duration::from_secs(1));
+        // Blue is only stopped once green is running.
+        assert_eq!(simulation.status("app@blue"), Some(ServiceStatus::Running));
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("app@green"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("app@blue"), Some(ServiceStatus::Initial));
+        let green_running = reached(&simulation, "app@green", ServiceStatus::Running).unwrap();
+        let blue_stopped = reached(&simulation, "app@blue", ServiceStatus::InKilling).unwrap();
+        assert!(green_running <= blue_stopped);
+
+        // And back.
+        simulation.switch("app@blue");
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("app@blue"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("app@green"), Some(ServiceStatus::Initial));
+        simulation.shutdown();
+        assert!(simulation
+            .run_until_finished(Duration::from_secs(60))
+            .is_some());
+    }
+
+    #[test]
+    fn test_simulation_rolling_restart() {
+        let workers = ["worker@1", "worker@2", "worker@3"];
+        let services = workers
+            .iter()
+            .map(|name| {
+                let mut worker = Service::from_name(name);
+                worker.healthiness.file_path = Some("/var/worker/up".into());
+                worker
+            })
+            .collect();
+        let horust = Horust::new(services);
+        let mut simulation = Simulation::new(&horust);
+
+        simulation.run_for(Duration::from_secs(3));
+        let start = simulation.events().len();
+        simulation.restart(&workers, 1);
+        simulation.run_for(Duration::from_secs(10));
+        // How many workers are not running, after every status change.
+        let mut unavailable = 0;
+        let mut max_unavailable = 0;
+        for (_, ev) in &simulation.events()[start..] {
+            match ev {
+                Event::StatusChanged(_, ServiceStatus::InKilling) => unavailable += 1,
+                Event::StatusChanged(_, ServiceStatus::Running) => unavailable -= 1,
+                _ => continue,
+            }
+            max_unavailable = max_unavailable.max(unavailable);
+        }
+        assert_eq!(max_unavailable, 1);
+        for worker in workers {
+            assert_eq!(simulation.status(worker), Some(ServiceStatus::Running));
+            let spawns = simulation
+                .events()
+                .iter()
+                .filter(|(_, ev)| matches!(ev, Event::PidChanged(name, _) if name == worker))
+                .count();
+            assert_eq!(spawns, 2, "{}", worker);
+        }
+
+        // Two at a time.
+        let start = simulation.events().len();
+        simulation.restart(&workers, 2);
+        simulation.run_for(Duration::from_secs(10));
+        let restarted: Vec<&str> = simulation.events()[start..]
+            .iter()
+            .filter_map(|(_, ev)| match ev {
+                Event::StatusChanged(name, ServiceStatus::InKilling) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(restarted, workers);
+        let first_running = simulation.events()[start..]
+            .iter()
+            .position(|(_, ev)| matches!(ev, Event::StatusChanged(_, ServiceStatus::Running)))
+            .unwrap();
+        let second_killed = simulation.events()[start..]
+            .iter()
+            .position(|(_, ev)| {
+                *ev == Event::StatusChanged("worker@2".into(), ServiceStatus::InKilling)
+            })
+            .unwrap();
+        assert!(second_killed < first_running);
+    }
+
+    #[test]
+    fn test_simulation_scale() {
+        let mut template = Service::from_name("worker@");
+        template.scale = Some(Scale {
+            instances: 2,
+            max: 4,
+        });
+        let horust = Horust::new(templates::expand(vec![template]));
+        let mut simulation = Simulation::new(&horust);
+        let running = |simulation: &Simulation| {
+            (1..=4)
+                .map(|index| format!("worker@{}", index))
+                .filter(|name| simulation.status(name) == Some(ServiceStatus::Running))
+                .count()
+        };
+
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(running(&simulation), 2);
+        assert_eq!(simulation.status("worker@3"), Some(ServiceStatus::Initial));
+
+        simulation.scale("worker", 4);
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(running(&simulation), 4);
+
+        // The instances beyond are gracefully stopped, and wait to be scaled up again.
+        simulation.scale("worker", 1);
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(running(&simulation), 1);
+        assert_eq!(simulation.status("worker@1"), Some(ServiceStatus::Running));
+        assert_eq!(simulation.status("worker@4"), Some(ServiceStatus::Initial));
+        assert!(reached(&simulation, "worker@4", ServiceStatus::InKilling).is_some());
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(running(&simulation), 1);
+
+        simulation.scale("worker", 2);
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("worker@2"), Some(ServiceStatus::Running));
+        simulation.shutdown();
+        assert!(simulation
+            .run_until_finished(Duration::from_secs(60))
+            .is_some());
+    }
+
+    #[test]
+    fn test_simulation_stop() {
+        let mut api = Service::start_after("api", vec!["database"]);
+        api.provides = vec!["backend".into()];
+        let horust = Horust::new(vec![
+            Service::from_name("database"),
+            api,
+            Service::start_after("worker", vec!["backend"]),
+            Service::from_name("metrics"),
+        ]);
+        let mut simulation = Simulation::new(&horust);
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(simulation.status("worker"), Some(ServiceStatus::Running));
+
+        // Each one is stopped once the ones starting after it have exited.
+        let start = simulation.events().len();
+        simulation.stop(&["database", "api", "worker"]);
+        simulation.run_for(Duration::from_secs(10));
+        let position = |expected: Event| {
+            simulation.events()[start..]
+                .iter()
+                .position(|(_, ev)| *ev == expected)
+                .unwrap()
+        };
+        let killed =
+            |name: &str| position(Event::StatusChanged(name.into(), ServiceStatus::InKilling));
+        let exited = |name: &str| {
+            simulation.events()[start..]
+                .iter()
+                .position(
+                    |(_, ev)| matches!(ev, Event::ServiceExited(service, _) if service == name),
+                )
+                .unwrap()
+        };
+        assert!(exited("worker") < killed("api"));
+        assert!(exited("api") < killed("database"));
+        for service in ["database", "api", "worker"] {
+            assert_eq!(simulation.status(service), Some(ServiceStatus::Initial));
+        }
+        assert_eq!(simulation.status("metrics"), Some(ServiceStatus::Running));
+
+        // They wait there, until they're started again.
+        simulation.run_for(Duration::from_secs(10));
+        assert_eq!(simulation.status("worker"), Some(ServiceStatus::Initial));
+        for service in ["database", "api", "worker"] {
+            simulation.start(service);
+        }
+        simulation.run_for(Duration::from_secs(10));
+        for service in ["database", "api", "worker"] {
+            assert_eq!(simulation.status(service), Some(ServiceStatus::Running));
+        }
+        simulation.shutdown();
+        assert!(simulation
+            .run_until_finished(Duration::from_secs(60))
+            .is_some());
+    }
+
+    #[test]
+    fn test_simulation_reload() {
+        let horust = Horust::new(vec![
+            Service::from_name("api"),
+            Service::from_name("worker"),
+        ]);
+        let mut simulation = Simulation::new(&horust);
+        let spawns = |simulation: &Simulation, service: &str| {
+            simulation
+                .events()
+                .iter()
+                .filter(|(_, ev)| matches!(ev, Event::PidChanged(name, _) if name == service))
+                .count()
+        };
+
+        simulation.run_for(Duration::from_secs(3));
+        let mut api = Service::from_name("api");
+        api.command = "api --verbose".into();
+        simulation.redefine(api);
+        let mut worker = Service::from_name("worker");
+        worker.command = "worker --verbose".into();
+        worker.reload_on_change = false;
+        simulation.redefine(worker);
+        simulation.run_for(Duration::from_secs(3));
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+        assert_eq!(spawns(&simulation, "api"), 2);
+        assert_eq!(simulation.status("worker"), Some(ServiceStatus::Running));
+        assert_eq!(spawns(&simulation, "worker"), 1);
+    }
+
+    #[test]
+    fn test_simulation_transient() {
+        let horust = Horust::new(vec![Service::from_name("api")]);
+        let mut simulation = Simulation::new(&horust).with_behavior(
+            "job",
+            Behavior::default().exits_after(Duration::from_secs(2), 1),
+        );
+        simulation.run_for(Duration::from_secs(3));
+
+        let job = Service::from_name("job");
+        simulation.add(job.clone());
+        simulation.run_for(Duration::from_secs(2));
+        assert_eq!(simulation.status("job"), Some(ServiceStatus::Running));
+        // Already there.
+        simulation.add(job);
+        simulation.run_for(Duration::from_secs(5));
+        assert_eq!(
+            simulation.status("job"),
+            Some(ServiceStatus::FinishedFailed)
+        );
+        let spawns = simulation
+            .events()
+            .iter()
+            .filter(|(_, ev)| matches!(ev, Event::PidChanged(name, _) if name == "job"))
+            .count();
+        assert_eq!(spawns, 1);
+        assert_eq!(simulation.status("api"), Some(ServiceStatus::Running));
+    }
+}
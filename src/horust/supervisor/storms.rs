@@ -0,0 +1,150 @@
+//! Protection against the services which exit over and over: once a service has exited
+//! `STORM_EXITS` times within `STORM_WINDOW`, it's in an event storm. Until it calms down, it's
+//! restarted at most once every `STORM_BACKOFF`, and its exits are summarized once per
+//! `STORM_WINDOW` instead of being logged one by one.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::horust::formats::ServiceName;
+
+/// How far back the exits are counted.
+const STORM_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many exits within `STORM_WINDOW` make an event storm.
+const STORM_EXITS: usize = 10;
+
+/// The minimum backoff of the restarts during an event storm.
+pub(super) const STORM_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub(crate) struct Storms {
+    services: HashMap<ServiceName, Storm>,
+}
+
+#[derive(Debug)]
+struct Storm {
+    /// When the service has exited, within the last `STORM_WINDOW`.
+    exits: VecDeque<Instant>,
+    /// When the storm was last summarized, if it's raging.
+    summarized_at: Option<Instant>,
+    /// The exits which weren't logged since then.
+    unlogged: usize,
+}
+
+impl Storm {
+    fn forget_before(&mut self, now: Instant) {
+        while self
+            .exits
+            .front()
+            .is_some_and(|exit| now.saturating_duration_since(*exit) > STORM_WINDOW)
+        {
+            self.exits.pop_front();
+        }
+    }
+}
+
+impl Storms {
+    /// Records an exit of the service. Returns false if it's in an event storm, and the exit
+    /// shouldn't be logged.
+    pub(crate) fn exited(&mut self, service_name: &ServiceName, now: Instant) -> bool {
+        let storm = self
+            .services
+            .entry(service_name.clone())
+            .or_insert_with(|| Storm {
+                exits: VecDeque::new(),
+                summarized_at: None,
+                unlogged: 0,
+            });
+        storm.forget_before(now);
+        storm.exits.push_back(now);
+        if storm.summarized_at.is_some() {
+            storm.unlogged += 1;
+            return false;
+        }
+        if storm.exits.len() < STORM_EXITS {
+            return true;
+        }
+        warn!(
+            service = service_name.as_str();
+            "{}: event storm, it has exited {} times in the last {}. It's restarted at most once every {}, and its exits are only summarized until it calms down.",
+            service_name,
+            storm.exits.len(),
+            humantime::format_duration(STORM_WINDOW),
+            humantime::format_duration(STORM_BACKOFF)
+        );
+        storm.summarized_at = Some(now);
+        false
+    }
+
+    /// True if the service is in an event storm.
+    pub(crate) fn is_raging(&self, service_name: &ServiceName) -> bool {
+        self.services
+            .get(service_name)
+            .is_some_and(|storm| storm.summarized_at.is_some())
+    }
+
+    /// Summarizes the storms which are still raging, once per `STORM_WINDOW`, and ends the ones
+    /// which have calmed down.
+    pub(crate) fn summarize(&mut self, now: Instant) {
+        for (service_name, storm) in &mut self.services {
+            storm.forget_before(now);
+            let Some(summarized_at) = storm.summarized_at else {
+                continue;
+            };
+            if storm.exits.len() < STORM_EXITS {
+                info!(
+                    service = service_name.as_str();
+                    "{}: the event storm is over, {} exits weren't logged.",
+                    service_name,
+                    storm.unlogged
+                );
+                storm.summarized_at = None;
+                storm.unlogged = 0;
+            } else if now.saturating_duration_since(summarized_at) >= STORM_WINDOW {
+                warn!(
+                    service = service_name.as_str();
+                    "{}: event storm, {} exits weren't logged in the last {}.",
+                    service_name,
+                    storm.unlogged,
+                    humantime::format_duration(STORM_WINDOW)
+                );
+                storm.summarized_at = Some(now);
+                storm.unlogged = 0;
+            }
+        }
+        self.services.retain(|_, storm| !storm.exits.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::horust::clock::{Clock, SimulatedClock};
+    use crate::horust::formats::ServiceName;
+    use crate::horust::supervisor::storms::{Storms, STORM_EXITS, STORM_WINDOW};
+
+    #[test]
+    fn test_storms() {
+        let flaky: ServiceName = "flaky".into();
+        let mut storms = Storms::default();
+        let clock = SimulatedClock::new();
+        for _ in 1..STORM_EXITS {
+            assert!(storms.exited(&flaky, clock.now()));
+            clock.advance(Duration::from_secs(1));
+        }
+        let now = clock.now();
+        assert!(!storms.is_raging(&flaky));
+        assert!(!storms.exited(&flaky, now));
+        assert!(storms.is_raging(&flaky));
+        assert!(!storms.exited(&flaky, now));
+
+        storms.summarize(now + Duration::from_secs(1));
+        assert!(storms.is_raging(&flaky));
+        // No more exits.
+        storms.summarize(now + STORM_WINDOW * 2);
+        assert!(!storms.is_raging(&flaky));
+        assert!(storms.exited(&flaky, now + STORM_WINDOW * 2));
+    }
+}
@@ -0,0 +1,64 @@
+//! The `on-system-ready` command: run once all the services are up for the first time, e.g. to
+//! register the host in a load balancer, or to write the file checked by a startup probe.
+
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::Event;
+use crate::horust::subprocess;
+
+/// How long the command can run, before it's killed.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs `command` on the `SystemReady` event, unless Horust is shutting down before it.
+pub(crate) fn spawn(bus: BusConnector<Event>, command: String, clock: Arc<dyn Clock>) {
+    thread::spawn(move || {
+        for ev in bus.iter() {
+            match ev {
+                Event::SystemReady => return run(&command, clock.as_ref()),
+                Event::ShuttingDownInitiated(_) => return,
+                _ => (),
+            }
+        }
+    });
+}
+
+fn run(command: &str, clock: &dyn Clock) {
+    info!("Running the on-system-ready command: {}", command);
+    let result = to_command(command).and_then(|mut cmd| {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        Ok(subprocess::run(&mut cmd, TIMEOUT, clock)?)
+    });
+    match result {
+        Ok(output) if output.status.success() => (),
+        Ok(output) => {
+            let printed = [output.stdout, output.stderr].concat();
+            let lines: Vec<String> = String::from_utf8_lossy(&printed)
+                .lines()
+                .map(|line| format!("  | {}", line))
+                .collect();
+            warn!(
+                "The on-system-ready command has failed: {}\n{}",
+                output.status,
+                lines.join("\n")
+            );
+        }
+        Err(error) => error!("The on-system-ready command error: {:?}", error),
+    }
+}
+
+fn to_command(command: &str) -> Result<Command> {
+    let chunks = shlex::split(command).with_context(|| format!("Invalid command: {}", command))?;
+    let (program, args) = chunks
+        .split_first()
+        .context("The on-system-ready command is empty")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    Ok(cmd)
+}
@@ -0,0 +1,180 @@
+//! Templates, named like `worker@.toml`: they're instantiated as the instances they list in
+//! `instances` (like `worker@a.toml`), or as `worker@1.toml` up to `worker@<max>.toml` if they're
+//! scaled. Only the first `scale.instances` of these are started: `horustctl scale` starts or
+//! stops instances at runtime, and the number of instances can be kept in a state file, so that it
+//! survives restarts of Horust.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::horust::formats::Service;
+
+const STATE_FILE: &str = "instances.json";
+
+/// The number of instances, by template.
+type Instances = BTreeMap<String, usize>;
+
+/// Replaces the templates with their instances.
+pub(crate) fn expand(services: Vec<Service>) -> Vec<Service> {
+    services
+        .into_iter()
+        .flat_map(|service| {
+            if !service.is_template() {
+                return vec![service];
+            }
+            let instances: Vec<String> = match service.scale {
+                Some(scale) => (1..=scale.max).map(|index| index.to_string()).collect(),
+                None => service.instances.keys().cloned().collect(),
+            };
+            for instance in service.instances.keys() {
+                if !instances.contains(instance) {
+                    warn!(
+                        "{}: no instance {}, its overrides are ignored",
+                        service.name, instance
+                    );
+                }
+            }
+            instances
+                .iter()
+                .map(|instance| service.instantiate(instance))
+                .collect()
+        })
+        .collect()
+}
+
+/// Sets the number of instances of the templates, as persisted by `persist`.
+pub(crate) fn restore(services: &mut [Service], state_dir: &Path) {
+    let state_file = state_dir.join(STATE_FILE);
+    let Ok(content) = fs::read_to_string(&state_file) else {
+        return;
+    };
+    let persisted: Instances = match serde_json::from_str(&content) {
+        Ok(persisted) => persisted,
+        Err(error) => {
+            error!("Failed reading {}: {}", state_file.display(), error);
+            return;
+        }
+    };
+    for service in services.iter_mut() {
+        let Some((template, _)) = service.scaled_instance() else {
+            continue;
+        };
+        let Some(&instances) = persisted.get(template) else {
+            continue;
+        };
+        if let Some(scale) = &mut service.scale {
+            scale.instances = instances.min(scale.max);
+        }
+    }
+}
+
+/// Keeps the number of instances of `template`, for the next time Horust starts.
+pub(crate) fn persist(state_dir: &Path, template: &str, instances: usize) -> Result<()> {
+    let state_file = state_dir.join(STATE_FILE);
+    let mut persisted: Instances = fs::read_to_string(&state_file)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    persisted.insert(template.to_string(), instances);
+    fs::create_dir_all(state_dir)
+        .and_then(|_| fs::write(&state_file, serde_json::to_string(&persisted)?))
+        .with_context(|| format!("Failed saving {}", state_file.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use tempdir::TempDir;
+
+    use crate::horust::formats::{Instance, Scale, Service};
+    use crate::horust::templates::{expand, persist, restore};
+
+    #[test]
+    fn test_templates() {
+        let mut template = Service::from_name("worker@.toml");
+        template.scale = Some(Scale {
+            instances: 1,
+            max: 3,
+        });
+        let mut services = expand(vec![template, Service::from_name("api.toml")]);
+        let names: Vec<&str> = services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "worker@1.toml",
+                "worker@2.toml",
+                "worker@3.toml",
+                "api.toml"
+            ]
+        );
+        assert_eq!(services[1].scaled_instance(), Some(("worker", 2)));
+        assert_eq!(services[3].scaled_instance(), None);
+
+        let state_dir = TempDir::new("templates").unwrap();
+        // Nothing persisted.
+        restore(&mut services, state_dir.path());
+        assert_eq!(services[0].scale.unwrap().instances, 1);
+
+        persist(state_dir.path(), "worker", 2).unwrap();
+        persist(state_dir.path(), "other", 4).unwrap();
+        restore(&mut services, state_dir.path());
+        assert!(services[..3]
+            .iter()
+            .all(|service| service.scale.unwrap().instances == 2));
+
+        // At most `max`.
+        persist(state_dir.path(), "worker", 5).unwrap();
+        restore(&mut services, state_dir.path());
+        assert_eq!(services[0].scale.unwrap().instances, 3);
+    }
+
+    #[test]
+    fn test_instances() {
+        let mut template = Service::from_name("app@.toml");
+        template
+            .environment
+            .additional
+            .insert("PORT".into(), "8080".into());
+        template.instances.insert("a".into(), Default::default());
+        template.instances.insert(
+            "b".into(),
+            Instance {
+                command: Some("app --verbose".into()),
+                working_directory: Some("/var/lib/app-b".into()),
+                environment: [("PORT".to_string(), "8081".to_string())].into(),
+            },
+        );
+        let services = expand(vec![template.clone()]);
+        assert_eq!(services.len(), 2);
+        let (a, b) = (&services[0], &services[1]);
+        assert_eq!(a.name, "app@a.toml");
+        assert_eq!(a.command, template.command);
+        assert_eq!(a.environment.additional["PORT"], "8080");
+        assert!(a.instances.is_empty());
+        assert_eq!(b.name, "app@b.toml");
+        assert_eq!(b.command, "app --verbose");
+        assert_eq!(b.working_directory, PathBuf::from("/var/lib/app-b"));
+        assert_eq!(b.environment.additional["PORT"], "8081");
+
+        // The overrides of a scaled template are by index.
+        template.scale = Some(Scale {
+            instances: 1,
+            max: 2,
+        });
+        template.instances = [(
+            "2".to_string(),
+            Instance {
+                command: Some("app --verbose".into()),
+                ..Default::default()
+            },
+        )]
+        .into();
+        let services = expand(vec![template.clone()]);
+        assert_eq!(services[0].command, template.command);
+        assert_eq!(services[1].command, "app --verbose");
+    }
+}
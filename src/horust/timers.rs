@@ -0,0 +1,280 @@
+//! The timers of the services (see `Service::timer`): a service with a timer isn't started with
+//! the others, but every time its calendar expression triggers. The supervisor ignores the
+//! triggers of a service which is still running. The last trigger of the persistent timers is kept
+//! in a state file, so that the trigger missed while Horust was down happens once it starts.
+//! A timer can be late by up to its `accuracy`: the timers due by then all trigger at once, which
+//! saves the wakeups.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Event, Service, ServiceName, Timer};
+
+/// The wall-clock time can jump (e.g. after a suspend, or when it's synchronized), so the timers
+/// are checked again at least this often.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+const STATE_FILE: &str = "timers.json";
+
+/// When the persistent timers have last triggered (in seconds since the Unix epoch), by service.
+type LastTriggers = BTreeMap<ServiceName, u64>;
+
+/// Sends `Event::Start` for each of the services with a timer, whenever it triggers.
+pub(crate) fn spawn(
+    bus: BusConnector<Event>,
+    services: &[Arc<Service>],
+    clock: Arc<dyn Clock>,
+    state_dir: PathBuf,
+) {
+    let mut timers = Timers::new(services, clock.system_time(), &restore(&state_dir));
+    thread::spawn(move || loop {
+        let now = clock.system_time();
+        for service_name in timers.due(now) {
+            info!(service = service_name.as_str(); "{}: its timer has triggered.", service_name);
+            if timers.is_persistent(&service_name) {
+                if let Err(error) = persist(&state_dir, &service_name, now) {
+                    error!("{:?}", error);
+                }
+            }
+            bus.send_event(Event::Start(service_name));
+        }
+        let wait = timers.next_wakeup().map_or(MAX_WAIT, |next| {
+            next.duration_since(now).unwrap_or_default().min(MAX_WAIT)
+        });
+        let deadline = clock.after(wait);
+        let events = bus.get_events_until(&deadline, |ev| {
+            matches!(
+                ev,
+                Event::ShuttingDownInitiated(_) | Event::ServiceChanged(_) | Event::ServiceAdded(_)
+            )
+        });
+        for ev in events {
+            match ev {
+                Event::ShuttingDownInitiated(_) => return,
+                Event::ServiceChanged(service) | Event::ServiceAdded(service) => {
+                    timers.update(&service, clock.system_time())
+                }
+                _ => (),
+            }
+        }
+    });
+}
+
+/// The next trigger of the timer of each service.
+#[derive(Debug, Default)]
+struct Timers {
+    timers: BTreeMap<ServiceName, (Timer, Option<SystemTime>)>,
+}
+
+impl Timers {
+    /// A persistent timer is scheduled from its last trigger, if any: it's due right away if it
+    /// should have triggered since.
+    fn new(services: &[Arc<Service>], now: SystemTime, last_triggers: &LastTriggers) -> Self {
+        let mut timers = Self::default();
+        for service in services.iter().filter(|service| service.enabled) {
+            let last_trigger = service
+                .timer
+                .as_ref()
+                .filter(|timer| timer.persistent)
+                .and_then(|_| last_triggers.get(&service.name))
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs));
+            timers.update(service, last_trigger.unwrap_or(now));
+        }
+        timers
+    }
+
+    /// Schedules the timer of `service` after `now`, if its definition has changed.
+    fn update(&mut self, service: &Service, now: SystemTime) {
+        let Some(timer) = &service.timer else {
+            self.timers.remove(&service.name);
+            return;
+        };
+        if self
+            .timers
+            .get(&service.name)
+            .is_some_and(|(scheduled, _)| scheduled == timer)
+        {
+            return;
+        }
+        let next = timer.on_calendar.next_trigger(now);
+        if next.is_none() {
+            warn!(
+                service = service.name.as_str();
+                "{}: its timer ({}) never triggers.",
+                service.name,
+                timer.on_calendar
+            );
+        }
+        self.timers
+            .insert(service.name.clone(), (timer.clone(), next));
+    }
+
+    /// The services whose timers have triggered by `now`. Their next triggers are after `now`.
+    fn due(&mut self, now: SystemTime) -> Vec<ServiceName> {
+        let mut due = vec![];
+        for (service_name, (timer, next)) in &mut self.timers {
+            if next.is_some_and(|next| next <= now) {
+                *next = timer.on_calendar.next_trigger(now);
+                due.push(service_name.clone());
+            }
+        }
+        due
+    }
+
+    /// When the timers are checked next, if any timer triggers again: once the first one can't be
+    /// late anymore. The others which are due by then trigger with it.
+    fn next_wakeup(&self) -> Option<SystemTime> {
+        self.timers
+            .values()
+            .filter_map(|(timer, next)| next.map(|next| next + timer.accuracy))
+            .min()
+    }
+
+    fn is_persistent(&self, service_name: &str) -> bool {
+        self.timers
+            .get(service_name)
+            .is_some_and(|(timer, _)| timer.persistent)
+    }
+}
+
+/// The last triggers of the persistent timers, as kept by `persist`.
+fn restore(state_dir: &Path) -> LastTriggers {
+    let state_file = state_dir.join(STATE_FILE);
+    let Ok(content) = fs::read_to_string(&state_file) else {
+        return LastTriggers::default();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|error| {
+        error!("Failed reading {}: {}", state_file.display(), error);
+        LastTriggers::default()
+    })
+}
+
+/// Keeps when the timer of `service_name` has triggered, for the next time Horust starts.
+fn persist(state_dir: &Path, service_name: &str, triggered: SystemTime) -> Result<()> {
+    let state_file = state_dir.join(STATE_FILE);
+    let mut last_triggers = restore(state_dir);
+    let secs = triggered
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    last_triggers.insert(service_name.to_string(), secs);
+    fs::create_dir_all(state_dir)
+        .and_then(|_| fs::write(&state_file, serde_json::to_string(&last_triggers)?))
+        .with_context(|| format!("Failed saving {}", state_file.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use tempdir::TempDir;
+
+    use crate::horust::formats::{Service, Timer};
+    use crate::horust::timers::{persist, restore, LastTriggers, Timers};
+
+    fn with_timer(name: &str, on_calendar: &str) -> Service {
+        let mut service = Service::from_name(name);
+        service.timer = Some(Timer {
+            on_calendar: on_calendar.parse().unwrap(),
+            persistent: false,
+            accuracy: Duration::ZERO,
+        });
+        service
+    }
+
+    fn persistent(name: &str, on_calendar: &str) -> Service {
+        let mut service = with_timer(name, on_calendar);
+        service.timer.as_mut().unwrap().persistent = true;
+        service
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_timers() {
+        // 1970-01-01 00:00:10 UTC.
+        let now = at(10);
+        let services: Vec<Arc<Service>> = vec![
+            Arc::new(with_timer("minutely", "*:* UTC")),
+            Arc::new(with_timer("hourly", "*:00 UTC")),
+            Arc::new(with_timer("past", "1970-01-01 00:00 UTC")),
+            Arc::new(Service::from_name("api")),
+        ];
+        let mut timers = Timers::new(&services, now, &LastTriggers::default());
+        assert_eq!(timers.next_wakeup(), Some(at(60)));
+        assert!(timers.due(now).is_empty());
+
+        assert_eq!(timers.due(at(60)), vec!["minutely"]);
+        assert_eq!(timers.next_wakeup(), Some(at(120)));
+        // Only once, even if some triggers were missed.
+        assert_eq!(timers.due(at(3630)), vec!["hourly", "minutely"]);
+        assert_eq!(timers.next_wakeup(), Some(at(3660)));
+
+        // Unchanged: still scheduled at its next trigger.
+        timers.update(&services[0], at(3650));
+        assert_eq!(timers.next_wakeup(), Some(at(3660)));
+        timers.update(&with_timer("minutely", "*:0/5 UTC"), at(3650));
+        assert_eq!(timers.next_wakeup(), Some(at(3900)));
+        timers.update(&Service::from_name("minutely"), at(3650));
+        assert_eq!(timers.next_wakeup(), Some(at(7200)));
+    }
+
+    #[test]
+    fn test_timers_persistent() {
+        let state_dir = TempDir::new("timers").unwrap();
+        assert!(restore(state_dir.path()).is_empty());
+        // Triggered at 00:30 and 00:50 of the first day, and then Horust went down.
+        persist(state_dir.path(), "backup", at(1800)).unwrap();
+        persist(state_dir.path(), "report", at(3000)).unwrap();
+        persist(state_dir.path(), "cleanup", at(1800)).unwrap();
+        let last_triggers = restore(state_dir.path());
+        assert_eq!(last_triggers["backup"], 1800);
+
+        // Started at 02:10.
+        let now = at(7800);
+        let services: Vec<Arc<Service>> = vec![
+            // Missed its 01:00 and 02:00 triggers: triggered once.
+            Arc::new(persistent("backup", "hourly UTC")),
+            // Its next trigger is at 12:00.
+            Arc::new(persistent("report", "12:00 UTC")),
+            // Not persistent: its missed triggers are skipped.
+            Arc::new(with_timer("cleanup", "hourly UTC")),
+            // Never triggered yet.
+            Arc::new(persistent("rotate", "hourly UTC")),
+        ];
+        let mut timers = Timers::new(&services, now, &last_triggers);
+        assert_eq!(timers.due(now), vec!["backup"]);
+        assert_eq!(timers.next_wakeup(), Some(at(10800)));
+        assert_eq!(timers.due(at(10800)), vec!["backup", "cleanup", "rotate"]);
+        assert!(timers.is_persistent("backup"));
+        assert!(!timers.is_persistent("cleanup"));
+    }
+
+    #[test]
+    fn test_timers_accuracy() {
+        let mut minutely = with_timer("minutely", "*:* UTC");
+        minutely.timer.as_mut().unwrap().accuracy = Duration::from_secs(60);
+        let services: Vec<Arc<Service>> = vec![
+            Arc::new(minutely),
+            Arc::new(with_timer("half", "*:*:30 UTC")),
+        ];
+        let mut timers = Timers::new(&services, at(10), &LastTriggers::default());
+        assert_eq!(timers.next_wakeup(), Some(at(30)));
+        assert_eq!(timers.due(at(30)), vec!["half"]);
+        // minutely can wait until the next trigger of half, at 00:01:30.
+        assert_eq!(timers.next_wakeup(), Some(at(90)));
+        assert_eq!(timers.due(at(90)), vec!["half", "minutely"]);
+        assert_eq!(timers.next_wakeup(), Some(at(150)));
+    }
+}
@@ -0,0 +1,213 @@
+//! Per-user services: each of the configured users can define services in
+//! `~/.config/horust/services`, which are run as that user.
+
+use std::path::{Component, Path};
+
+use anyhow::{bail, Result};
+
+use crate::horust::fetch_services;
+use crate::horust::formats::{CoreDumps, Dependency, LogOutput, Service, ServiceName, User};
+
+/// Where the services of a user are loaded from, relative to their home directory.
+const USER_SERVICES_DIR: &str = ".config/horust/services";
+
+/// Loads the services of `user`. Their names (and what they provide) are prefixed with
+/// `<username>:`, so they can't clash with the services of Horust or of other users.
+/// Invalid services are skipped, like in the services directories.
+pub(crate) fn fetch_user_services(user: &User) -> Result<Vec<Service>> {
+    let user_name = user.get_name()?;
+    let home = user.get_home()?;
+    let dir = home.join(USER_SERVICES_DIR);
+    if !dir.is_dir() {
+        debug!("{}: no user services in {}", user_name, dir.display());
+        return Ok(vec![]);
+    }
+    let services = fetch_services(&dir, &[])?;
+    let own_names: Vec<ServiceName> = services
+        .iter()
+        .flat_map(|service| std::iter::once(&service.name).chain(&service.provides))
+        .cloned()
+        .collect();
+    let uid = user.get_uid()?.as_raw();
+    Ok(services
+        .into_iter()
+        .filter_map(|service| {
+            let name = service.name.clone();
+            into_user_service(service, uid, &user_name, &home, &own_names)
+                .map_err(|error| error!("{}: skipping service {}: {}", user_name, name, error))
+                .ok()
+        })
+        .collect())
+}
+
+/// Makes `service` run as `uid`, and moves its names in the namespace of `user_name`.
+fn into_user_service(
+    mut service: Service,
+    uid: u32,
+    user_name: &str,
+    home: &Path,
+    own_names: &[ServiceName],
+) -> Result<Service> {
+    if service.user != User::default() && service.user.get_uid()?.as_raw() != uid {
+        bail!("it can't run as another user ({:?})", service.user);
+    }
+    service.user = User::Uid(uid);
+    if service.working_directory == Service::default_working_directory() {
+        service.working_directory = home.to_path_buf();
+    }
+    // Horust would open these paths as root, following the symlinks of the user.
+    let mut handled_as_root = vec![];
+    for (field, output) in [("stdout", &service.stdout), ("stderr", &service.stderr)] {
+        if let LogOutput::Path(_) | LogOutput::Tee(_) = output {
+            handled_as_root.push(field);
+        }
+    }
+    if let Some(CoreDumps::Enabled { dir: Some(_), .. }) = &service.core_dumps {
+        handled_as_root.push("core-dumps.dir");
+    }
+    if service.crash_artifacts.is_some() {
+        handled_as_root.push("crash-artifacts");
+    }
+    let environment = &service.environment;
+    if !environment.source.is_empty() {
+        handled_as_root.push("environment.source");
+    }
+    if !environment.files.is_empty() {
+        handled_as_root.push("environment.files");
+    }
+    // Horust's own environment isn't for the users.
+    if environment.keep_env {
+        handled_as_root.push("environment.keep-env");
+    }
+    if !environment.keep.is_empty() {
+        handled_as_root.push("environment.keep");
+    }
+    if !environment.re_export.is_empty() {
+        handled_as_root.push("environment.re-export");
+    }
+    if !handled_as_root.is_empty() {
+        bail!(
+            "{} can't be used by user services, Horust would handle them as root",
+            handled_as_root.join(", ")
+        );
+    }
+    // Only checked for existence.
+    if let Some(path) = &service.healthiness.file_path {
+        if !is_within(path, home) {
+            bail!("{} is not in the home directory", path.display());
+        }
+    }
+
+    let prefixed = |name: &ServiceName| format!("{}:{}", user_name, name);
+    let namespaced = |name: &ServiceName| {
+        if own_names.contains(name) {
+            prefixed(name)
+        } else {
+            // e.g. a dependency on a service of Horust.
+            name.clone()
+        }
+    };
+    service.name = prefixed(&service.name);
+    service.provides = service.provides.iter().map(prefixed).collect();
+    for dependency in &mut service.start_after {
+        match dependency {
+            Dependency::Service(name) | Dependency::Detailed { service: name, .. } => {
+                *name = namespaced(name)
+            }
+            Dependency::Tcp { .. } => (),
+        }
+    }
+    let die_if_failed = &mut service.termination.die_if_failed;
+    *die_if_failed = die_if_failed.iter().map(namespaced).collect();
+    Ok(service)
+}
+
+fn is_within(path: &Path, dir: &Path) -> bool {
+    path.is_absolute()
+        && path.starts_with(dir)
+        && !path
+            .components()
+            .any(|component| component == Component::ParentDir)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use crate::horust::formats::{Dependency, EnvironmentFile, LogOutput, Service, User};
+    use crate::horust::user_services::into_user_service;
+
+    #[test]
+    fn test_into_user_service() {
+        let home = Path::new("/home/alice");
+        let own_names = vec!["web".to_string(), "db".to_string(), "cache".to_string()];
+        let mut web = Service::start_after("web", vec!["db", "system-db"]);
+        web.start_after.push(Dependency::Detailed {
+            service: "cache".into(),
+            optional: true,
+            timeout: None,
+        });
+        web.provides = vec!["http".into()];
+        web.termination.die_if_failed = vec!["db".into()];
+        web.healthiness.file_path = Some("/home/alice/web.ready".into());
+
+        let web = into_user_service(web, 1000, "alice", home, &own_names).unwrap();
+        assert_eq!(web.name, "alice:web");
+        assert_eq!(web.user, User::Uid(1000));
+        assert_eq!(web.working_directory, home);
+        assert_eq!(web.provides, vec!["alice:http"]);
+        assert_eq!(
+            web.start_after_services(),
+            vec!["alice:db", "system-db", "alice:cache"]
+        );
+        assert_eq!(web.termination.die_if_failed, vec!["alice:db"]);
+
+        let user_service = |service: Service| {
+            into_user_service(service, 1000, "alice", home, &own_names).map(|s| s.name)
+        };
+        let mut escaping = Service::from_name("web");
+        escaping.healthiness.file_path = Some("/home/alice/../bob/.bashrc".into());
+        user_service(escaping).unwrap_err();
+        let mut escaping = Service::from_name("web");
+        escaping.healthiness.file_path = Some("/etc/shadow".into());
+        user_service(escaping).unwrap_err();
+        let mut inherited = Service::from_name("web");
+        inherited.environment.keep_env = true;
+        user_service(inherited).unwrap_err();
+        let mut other_user = Service::from_name("web");
+        other_user.user = User::Uid(1001);
+        user_service(other_user).unwrap_err();
+    }
+
+    #[test]
+    fn test_into_user_service_symlinks() {
+        // A symlink in the home directory, to a file of root.
+        let home = TempDir::new("home").unwrap();
+        let link = home.path().join("web.log");
+        std::os::unix::fs::symlink("/etc/passwd", &link).unwrap();
+        let user_service = |service: Service| {
+            into_user_service(service, 1000, "alice", home.path(), &[]).map(|s| s.name)
+        };
+
+        let mut web = Service::from_name("web");
+        web.stdout = LogOutput::Path(link.clone());
+        let error = user_service(web).unwrap_err().to_string();
+        assert!(
+            error.contains("stdout can't be used by user services"),
+            "{}",
+            error
+        );
+        let mut web = Service::from_name("web");
+        web.stderr = LogOutput::Tee(link.clone());
+        user_service(web).unwrap_err();
+        let mut web = Service::from_name("web");
+        web.environment.files = vec![EnvironmentFile::Path(link.clone())];
+        user_service(web).unwrap_err();
+        let mut web = Service::from_name("web");
+        web.environment.source = vec![link];
+        let error = user_service(web).unwrap_err().to_string();
+        assert!(error.contains("environment.source"), "{}", error);
+    }
+}
@@ -0,0 +1,213 @@
+//! Watches the paths listed in the `watch` of the services, and sends `Event::PathChanged` when
+//! they change, so that the service is restarted (or sent its `reload-signal`).
+//!
+//! The parent directory of a watched file is watched instead of the file itself: configuration
+//! files are often replaced (e.g. by an editor, or by Kubernetes swapping a symlink), rather than
+//! written in place.
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+
+use crate::horust::bus::BusConnector;
+use crate::horust::clock::Clock;
+use crate::horust::formats::{Event, Service, ServiceName};
+
+/// How often the watcher checks whether Horust is shutting down.
+const WATCHER_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Changes are coalesced for this long, since saving a file usually takes several operations.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Sends `Event::PathChanged` for a service, whenever any of the paths it watches changes.
+pub(crate) fn spawn(bus: BusConnector<Event>, services: &[Arc<Service>], clock: Arc<dyn Clock>) {
+    let watched: Vec<(ServiceName, PathBuf)> = services
+        .iter()
+        .flat_map(|service| {
+            service
+                .watch
+                .iter()
+                .map(|path| (service.name.clone(), path.clone()))
+        })
+        .collect();
+    if watched.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        if let Err(error) = run(&bus, watched, clock.as_ref()) {
+            error!("Watching the paths disabled: {}", error);
+        }
+    });
+}
+
+/// What identifies the content of a file: it changes if it's written, or replaced. None if it
+/// doesn't exist.
+type Fingerprint = Option<(SystemTime, u64, u64)>;
+
+fn fingerprint(path: &Path) -> Fingerprint {
+    // Following the symlinks, since it's their target which is read by the service.
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.ino(), metadata.len()))
+}
+
+/// A path watched by a service.
+struct Watched {
+    service: ServiceName,
+    path: PathBuf,
+    /// None for directories: any change in them counts.
+    fingerprint: Option<Fingerprint>,
+}
+
+fn run(
+    bus: &BusConnector<Event>,
+    watched: Vec<(ServiceName, PathBuf)>,
+    clock: &dyn Clock,
+) -> nix::Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_ATTRIB;
+    // The same directory is watched once, for all of its watched files.
+    let mut watches: HashMap<WatchDescriptor, Vec<Watched>> = HashMap::new();
+    for (service, path) in watched {
+        let is_dir = path.is_dir();
+        let dir = if is_dir {
+            path.as_path()
+        } else {
+            path.parent().unwrap_or_else(|| Path::new("/"))
+        };
+        match inotify.add_watch(dir, flags) {
+            Ok(wd) => {
+                let fingerprint = (!is_dir).then(|| fingerprint(&path));
+                watches.entry(wd).or_default().push(Watched {
+                    service,
+                    path,
+                    fingerprint,
+                });
+            }
+            Err(error) => warn!(
+                service = service.as_str();
+                "{}: cannot watch {}: {}",
+                service,
+                path.display(),
+                error
+            ),
+        }
+    }
+    if watches.is_empty() {
+        return Ok(());
+    }
+
+    loop {
+        let shutting_down = bus
+            .try_get_events()
+            .into_iter()
+            .any(|ev| matches!(ev, Event::ShuttingDownInitiated(_)));
+        if shutting_down {
+            return Ok(());
+        }
+        let mut fds = [PollFd::new(inotify.as_fd(), PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(WATCHER_INTERVAL).unwrap_or(PollTimeout::MAX);
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => (),
+            Err(error) => return Err(error),
+        }
+        clock.sleep(SETTLE_TIME);
+        let mut changed_dirs = vec![];
+        loop {
+            match inotify.read_events() {
+                Ok(events) => changed_dirs.extend(events.into_iter().map(|event| event.wd)),
+                Err(Errno::EAGAIN) => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        changed_dirs.sort();
+        changed_dirs.dedup();
+        let mut changed: Vec<ServiceName> = vec![];
+        for wd in changed_dirs {
+            for watched in watches.get_mut(&wd).into_iter().flatten() {
+                let has_changed = match &mut watched.fingerprint {
+                    None => true,
+                    Some(previous) => {
+                        let current = fingerprint(&watched.path);
+                        std::mem::replace(previous, current) != current
+                    }
+                };
+                if has_changed && !changed.contains(&watched.service) {
+                    info!(
+                        service = watched.service.as_str();
+                        "{}: {} has changed",
+                        watched.service,
+                        watched.path.display()
+                    );
+                    changed.push(watched.service.clone());
+                }
+            }
+        }
+        for service in changed {
+            bus.send_event(Event::PathChanged(service));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::horust::bus::Bus;
+    use crate::horust::clock::SystemClock;
+    use crate::horust::formats::{Event, Service, ShuttingDown};
+    use crate::horust::watcher::spawn;
+
+    #[test]
+    fn test_watcher() {
+        let temp_dir = TempDir::new("watcher").unwrap();
+        let config = temp_dir.path().join("config.yaml");
+        let other = temp_dir.path().join("other.yaml");
+        fs::write(&config, "a: 1").unwrap();
+        let mut service = Service::from_name("app");
+        service.watch = vec![config.clone()];
+
+        let bus = Bus::new();
+        let receiver = bus.join_bus();
+        spawn(bus.join_bus(), &[Arc::new(service)], Arc::new(SystemClock));
+        std::thread::spawn(move || bus.run());
+        std::thread::sleep(Duration::from_millis(300));
+
+        // Not watched.
+        fs::write(&other, "b: 1").unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!receiver
+            .try_get_events()
+            .contains(&Event::PathChanged("app".into())));
+
+        // Replaced, like editors do.
+        let new_config = temp_dir.path().join("config.yaml.new");
+        fs::write(&new_config, "a: 2").unwrap();
+        fs::rename(&new_config, &config).unwrap();
+        let ev = receiver
+            .iter()
+            .find(|ev| matches!(ev, Event::PathChanged(_)))
+            .unwrap();
+        assert_eq!(ev, Event::PathChanged("app".into()));
+        receiver.send_event(Event::ShuttingDownInitiated(ShuttingDown::Gracefully));
+    }
+}
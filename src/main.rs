@@ -1,19 +1,26 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, Parser};
+use env_logger::WriteStyle;
 use horust::horust::ExitStatus;
-use horust::horust::HorustConfig;
+use horust::horust::{HorustConfig, ServiceOverride};
 use horust::Horust;
-use log::{error, info};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{error, info, LevelFilter};
 
 #[derive(clap::Parser, Debug)]
 #[clap(author, about)]
 /// Horust is a complete supervisor and init system, designed for running in containers.
 struct Opts {
-    #[clap(long, default_value = "/etc/horust/horust.toml")]
-    /// Horust's path to config.
-    config_path: PathBuf,
+    #[clap(long, global = true)]
+    /// Horust's path to config. By default, `/etc/horust/horust.toml` and then
+    /// `$XDG_CONFIG_HOME/horust/horust.toml` are loaded, the latter overriding the former.
+    config_path: Option<PathBuf>,
 
     #[clap(flatten)]
     horust_config: HorustConfig,
@@ -22,60 +29,343 @@ struct Opts {
     /// Print a sample service file with all the possible options
     sample_service: bool,
 
-    #[clap(long = "services-path", default_value = "/etc/horust/services")]
+    #[clap(long)]
+    /// Print the order in which the services are going to be started, and exit.
+    dry_run: bool,
+
+    #[clap(short, long, action = ArgAction::Count, global = true)]
+    /// Increase the verbosity of Horust's logs (can be repeated, e.g. -vv).
+    verbose: u8,
+
+    #[clap(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    /// Decrease the verbosity of Horust's logs (can be repeated).
+    quiet: u8,
+
+    #[clap(long, requires = "command")]
+    /// Only print Horust's logs if something has failed, when wrapping a single command whose
+    /// output is parsed.
+    hold_back_logs: bool,
+
+    #[clap(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    /// Format of Horust's logs.
+    log_format: LogFormat,
+
+    #[clap(long, value_enum, global = true)]
+    /// Whether Horust's logs are colored. By default they are only if stderr is a terminal,
+    /// and neither `NO_COLOR` nor `HORUST_LOG_STYLE=never` are set.
+    color: Option<Color>,
+
+    #[clap(long, global = true)]
+    /// Log filter directives, e.g. `horust::horust::healthcheck=off,horust::horust::supervisor=debug`.
+    /// They have precedence over -v/-q and the HORUST_LOG environment variable.
+    log_filter: Option<String>,
+
+    #[clap(long = "services-path", global = true)]
     /// Path to service file or a directory containing services to run. You can provide more than one argument to load multiple directories / services.
+    /// By default, `/etc/horust/services` and then `$XDG_CONFIG_HOME/horust/services` (if it exists).
     services_paths: Vec<PathBuf>,
 
+    #[clap(long = "set", value_name = "SERVICE.KEY=VALUE")]
+    /// Override a field of a service, e.g. `--set web.restart.strategy=never` or
+    /// `--set web.environment.PORT=9090`. Can be repeated.
+    set: Vec<ServiceOverride>,
+
     #[clap(required = false, last = true)]
     /// Specify a command to run instead of load services path. Useful if you just want to use the reaping capability. Prefix your command with --
     command: Vec<String>,
+
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
 }
 
-fn main() -> Result<()> {
-    // Set up logging.
-    let env = env_logger::Env::new()
-        .filter("HORUST_LOG")
-        .write_style("HORUST_LOG_STYLE");
-    env_logger::init_from_env(env);
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// A JSON object per line, with the timestamp, level, target, service (if any) and message.
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Generate a systemd .service unit for each of the services, and exit.
+    ExportSystemd {
+        #[clap(long)]
+        /// Directory where the unit files will be written.
+        out: PathBuf,
+    },
+    /// Print the shell completion script for horust, and exit.
+    Completions {
+        #[clap(value_enum)]
+        /// The shell to generate the script for.
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page of horust, and exit.
+    Man,
+    /// Inspect Horust's configuration.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
 
+#[derive(clap::Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective configuration, and where each value comes from.
+    Show,
+}
+
+fn main() -> Result<()> {
     let opts = Opts::parse();
+    let held_back_logs = init_logging(&opts);
+    let result = run(opts);
+    // Something went wrong, so the logs are needed.
+    if !matches!(result, Ok(0)) {
+        if let Some(logs) = held_back_logs {
+            logs.release();
+        }
+    }
+    match result? {
+        0 => Ok(()),
+        exit_code => std::process::exit(exit_code),
+    }
+}
 
+/// Runs the subcommand or the services, and returns the exit code.
+fn run(opts: Opts) -> Result<i32> {
     if opts.sample_service {
         println!("{}", horust::get_sample_service());
-        return Ok(());
+        return Ok(0);
+    }
+
+    let config_paths = match &opts.config_path {
+        Some(config_path) => vec![config_path.clone()],
+        None => HorustConfig::config_search_paths(),
+    };
+    let services_paths = if opts.services_paths.is_empty() {
+        HorustConfig::services_search_paths()
+    } else {
+        opts.services_paths.clone()
+    };
+
+    match &opts.subcommand {
+        Some(Subcommand::ExportSystemd { out }) => {
+            let horust = Horust::from_services_dirs(&services_paths).with_context(|| {
+                format!(
+                    "Failed loading services from {}",
+                    display_directories(&services_paths)
+                )
+            })?;
+            horust.check_commands()?;
+            for unit in horust.export_systemd(out)? {
+                println!("{}", unit.display());
+            }
+            return Ok(0);
+        }
+        Some(Subcommand::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Opts::command(), "horust", &mut io::stdout());
+            return Ok(0);
+        }
+        Some(Subcommand::Man) => {
+            clap_mangen::Man::new(Opts::command()).render(&mut io::stdout())?;
+            return Ok(0);
+        }
+        Some(Subcommand::Config(ConfigCommand::Show)) | None => (),
     }
 
-    let config = HorustConfig::load_and_merge(&opts.horust_config, &opts.config_path)
+    let (config, origins) = HorustConfig::load_layers(&opts.horust_config, &config_paths)
         .with_context(|| {
             format!(
                 "Failed loading configuration: {}",
-                &opts.config_path.display()
+                display_directories(&config_paths)
             )
         })?;
 
+    if let Some(Subcommand::Config(ConfigCommand::Show)) = &opts.subcommand {
+        print!("{}", config.show(&origins)?);
+        return Ok(0);
+    }
+
+    if let Some(run_as) = &config.run_as {
+        run_as
+            .check()
+            .with_context(|| format!("Can't run as {}", run_as))?;
+    }
+
     let mut horust = if !opts.command.is_empty() {
         info!("Running command: {:?}", opts.command);
         Horust::from_command(opts.command.join(" "))
     } else {
         info!(
             "Loading services from {}",
-            display_directories(&opts.services_paths)
+            display_directories(&services_paths)
         );
-        Horust::from_services_dirs(&opts.services_paths).with_context(|| {
+        let mut horust = Horust::from_services_dirs_with_overrides(&services_paths, &opts.set)
+            .with_context(|| {
+                format!(
+                    "Failed loading services from {}",
+                    display_directories(&services_paths)
+                )
+            })?;
+        if !config.user_services.is_empty() {
+            horust
+                .add_user_services(&config.user_services)
+                .context("Failed loading the user services")?;
+        }
+        horust.mask(&config.mask);
+        horust.check_commands().with_context(|| {
             format!(
                 "Failed loading services from {}",
-                display_directories(&opts.services_paths)
+                display_directories(&services_paths)
             )
-        })?
+        })?;
+        horust
     };
 
-    if let ExitStatus::SomeServiceFailed = horust.run() {
-        if config.unsuccessful_exit_finished_failed {
+    horust
+        .set_config(config.clone())
+        .context("Failed loading the configuration")?;
+
+    if opts.dry_run {
+        print!("{}", horust.plan());
+        return Ok(0);
+    }
+
+    let report = horust.run();
+    let exit_code = config.exit_code(&report);
+    if let ExitStatus::SomeServiceFailed = report.exit_status() {
+        if exit_code != 0 {
             error!("Some processes have failed.");
-            std::process::exit(101);
+            for service in report.failed() {
+                error!("  {}", service);
+            }
         }
     }
-    Ok(())
+    Ok(exit_code)
+}
+
+/// Sets up logging: the HORUST_LOG environment variable is overridden by -v/-q, and then by
+/// `--log-filter`. Likewise, `--color` overrides HORUST_LOG_STYLE and NO_COLOR. With
+/// `--hold-back-logs` the logs are held back, and they're returned.
+fn init_logging(opts: &Opts) -> Option<HeldBackLogs> {
+    let env = env_logger::Env::new()
+        .filter("HORUST_LOG")
+        .write_style("HORUST_LOG_STYLE");
+    let mut builder = env_logger::Builder::from_env(env);
+    if opts.verbose > 0 || opts.quiet > 0 {
+        let levels = [
+            LevelFilter::Off,
+            LevelFilter::Error,
+            LevelFilter::Warn,
+            LevelFilter::Info,
+            LevelFilter::Debug,
+            LevelFilter::Trace,
+        ];
+        // Error is the default level.
+        let level = (1 + opts.verbose as usize)
+            .saturating_sub(opts.quiet as usize)
+            .min(levels.len() - 1);
+        builder.filter_level(levels[level]);
+    }
+    if let Some(log_filter) = &opts.log_filter {
+        builder.parse_filters(log_filter);
+    }
+    if let Some(color) = opts.color {
+        builder.write_style(match color {
+            Color::Auto => WriteStyle::Auto,
+            Color::Always => WriteStyle::Always,
+            Color::Never => WriteStyle::Never,
+        });
+    }
+    if opts.log_format == LogFormat::Json {
+        builder.format(format_json);
+    }
+    let held_back_logs = opts.hold_back_logs.then(HeldBackLogs::default);
+    if let Some(logs) = &held_back_logs {
+        builder.target(env_logger::Target::Pipe(Box::new(logs.clone())));
+    }
+    builder.init();
+    held_back_logs
+}
+
+/// Only the latest held back logs are kept, up to this size.
+const MAX_HELD_BACK_LOGS: usize = 1024 * 1024;
+
+/// Logs kept in memory, and written to stderr only if they're released.
+#[derive(Clone, Default)]
+struct HeldBackLogs(Arc<Mutex<LogBuffer>>);
+
+/// The latest logs, up to `MAX_HELD_BACK_LOGS`.
+#[derive(Default)]
+struct LogBuffer {
+    logs: VecDeque<u8>,
+    /// Whether the earlier logs have been dropped.
+    dropped: bool,
+}
+
+impl HeldBackLogs {
+    fn release(&self) {
+        let mut buffer = self.0.lock().unwrap();
+        let mut stderr = io::stderr().lock();
+        if buffer.dropped {
+            let _ = writeln!(stderr, "(the earlier logs have been dropped)");
+            // Starting from the first complete line.
+            let partial_line = buffer.logs.iter().position(|byte| *byte == b'\n');
+            buffer.logs.drain(..partial_line.map_or(0, |end| end + 1));
+        }
+        let (front, back) = buffer.logs.as_slices();
+        let _ = stderr.write_all(front).and_then(|_| stderr.write_all(back));
+    }
+}
+
+impl Write for HeldBackLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.0.lock().unwrap();
+        buffer.logs.extend(buf);
+        let excess = buffer.logs.len().saturating_sub(MAX_HELD_BACK_LOGS);
+        if excess > 0 {
+            buffer.logs.drain(..excess);
+            buffer.dropped = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats `record` as a JSON object. Its key-values (like `service`) become fields of the object.
+fn format_json(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            self.0.insert(key.to_string(), value.to_string().into());
+            Ok(())
+        }
+    }
+
+    let mut fields = serde_json::Map::new();
+    fields.insert(
+        "timestamp".into(),
+        humantime::format_rfc3339_millis(SystemTime::now())
+            .to_string()
+            .into(),
+    );
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    record
+        .key_values()
+        .visit(&mut Fields(&mut fields))
+        .map_err(io::Error::other)?;
+    fields.insert("message".into(), record.args().to_string().into());
+    writeln!(buf, "{}", serde_json::Value::Object(fields))
 }
 
 fn display_directories(dirs: &[PathBuf]) -> String {
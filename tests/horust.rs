@@ -1,7 +1,13 @@
 use assert_cmd::prelude::*;
-use predicates::str::contains;
+use predicates::prelude::*;
+use predicates::str::{contains, is_empty};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::mpsc;
+use std::thread;
 use std::time::Duration;
 
 #[allow(dead_code)]
@@ -14,6 +20,507 @@ fn test_cli_help() {
     cmd.args(vec!["--help"]).assert().success();
 }
 
+#[test]
+fn test_completions_and_man() {
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(contains("--services-path"));
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["man"])
+        .assert()
+        .success()
+        .stdout(contains(".TH horust"));
+}
+
+#[test]
+fn test_log_verbosity() {
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["-vv", "--", "/bin/true"])
+        .assert()
+        .success()
+        .stderr(contains("Running command"));
+
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "-q",
+        "--unsuccessful-exit-finished-failed",
+        "--",
+        "\"",
+    ])
+    .assert()
+    .failure()
+    .stderr(is_empty());
+
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["-q", "--log-filter", "horust=info", "--", "/bin/true"])
+        .assert()
+        .success()
+        .stderr(contains("Running command"));
+}
+
+#[test]
+fn test_quiet() {
+    // The output is the same as running the command directly.
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["--hold-back-logs", "--", "printf 'a\\nb'"])
+        .assert()
+        .success()
+        .stdout("a\nb")
+        .stderr(is_empty());
+
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["--hold-back-logs", "--", "\""])
+        .assert()
+        .success()
+        .stderr(is_empty());
+
+    // Logs are shown if something has failed.
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "--hold-back-logs",
+        "--unsuccessful-exit-finished-failed",
+        "--",
+        "\"",
+    ])
+    .assert()
+    .failure()
+    .stderr(contains("Failed spawning the process"));
+
+    // Only when wrapping a single command.
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["--hold-back-logs"])
+        .assert()
+        .failure()
+        .stderr(contains("required arguments were not provided"));
+}
+
+#[test]
+fn test_color() {
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["--color", "always", "--", "\""])
+        .assert()
+        .success()
+        .stderr(contains("\u{1b}["));
+
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.env("HORUST_LOG_STYLE", "always")
+        .args(vec!["--color", "never", "--", "\""])
+        .assert()
+        .success()
+        .stderr(contains("Failed spawning the process"))
+        .stderr(contains("\u{1b}[").not());
+}
+
+#[test]
+fn test_log_format_json() {
+    let (mut cmd, _temp_dir) = get_cli();
+    let output = cmd
+        .args(vec!["--log-format", "json", "--", "\""])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr
+        .lines()
+        .find(|line| line.contains("Failed spawning the process"))
+        .unwrap();
+    let line: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(line["level"], "ERROR");
+    assert_eq!(line["service"], "\"");
+    assert!(line["timestamp"].is_string());
+    assert!(line["target"].as_str().unwrap().starts_with("horust::"));
+}
+
+#[test]
+fn test_export_systemd() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), r#"command = "/bin/true""#, Some("a.toml"));
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/sleep 1"
+start-after = ["a.toml"]"#,
+        Some("b.toml"),
+    );
+    let out = temp_dir.path().join("units");
+    cmd.args(vec!["export-systemd", "--out", out.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("b.service"));
+    let unit = std::fs::read_to_string(out.join("b.service")).unwrap();
+    assert!(unit.contains("ExecStart=/bin/sleep 1"));
+    assert!(unit.contains("After=a.service"));
+    assert!(out.join("a.service").is_file());
+}
+
+#[test]
+fn test_dry_run() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), r#"command = "/bin/true""#, Some("a.toml"));
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/false"
+start-delay = "1s"
+start-after = ["a.toml"]"#,
+        Some("b.toml"),
+    );
+    cmd.args(vec!["--dry-run"])
+        .assert()
+        .success()
+        .stdout("Wave 1:\n  a.toml\nWave 2:\n  b.toml (start-delay 1s)\n    after a.toml\n");
+}
+
+#[test]
+fn test_disabled_and_masked() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/false\"\nenabled = false",
+        Some("a.toml"),
+    );
+    // The commands of the masked services aren't checked.
+    store_service(temp_dir.path(), r#"command = "/missing/b""#, Some("b.toml"));
+    store_service(temp_dir.path(), r#"command = "/missing/c""#, Some("c.toml"));
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/true"
+start-after = ["a.toml", "b.toml", "c.toml"]"#,
+        Some("d.toml"),
+    );
+    std::fs::write(temp_dir.path().join("b.mask"), "").unwrap();
+    cmd.args(vec!["--mask", "c.toml"]);
+    cmd.args(vec!["--dry-run"])
+        .assert()
+        .success()
+        .stdout("Wave 1:\n  d.toml\nDisabled:\n  a.toml\n  b.toml\n  c.toml\n");
+
+    // The disabled services are not started, and d.toml doesn't wait for them.
+    let (mut cmd, _other_dir) = get_cli();
+    cmd.args(vec![
+        "--services-path",
+        temp_dir.path().to_str().unwrap(),
+        "--mask",
+        "c.toml",
+        "--unsuccessful-exit-finished-failed",
+    ])
+    .assert()
+    .success();
+}
+
+#[test]
+fn test_set_overrides() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho \"port: $PORT\"\nexit 1",
+        Some("[restart]\nstrategy = \"always\""),
+        Some("web"),
+    );
+    cmd.args(vec![
+        "--set",
+        "web.environment.PORT=9090",
+        "--set",
+        "web.toml.restart.strategy=never",
+    ])
+    .assert()
+    .success()
+    .stdout("port: 9090\n");
+
+    let (mut cmd, _other_dir) = get_cli();
+    cmd.args(vec![
+        "--services-path",
+        temp_dir.path().to_str().unwrap(),
+        "--set",
+        "api.enabled=false",
+    ])
+    .assert()
+    .failure()
+    .stderr(contains("api.enabled=false"));
+}
+
+#[test]
+fn test_config_show() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = temp_dir.path().join("config");
+    std::fs::create_dir_all(config_dir.join("horust")).unwrap();
+    std::fs::write(
+        config_dir.join("horust").join("horust.toml"),
+        "[exit-codes]\nkilled = 9",
+    )
+    .unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .args(vec![
+            "--unsuccessful-exit-finished-failed",
+            "config",
+            "show",
+        ])
+        .assert()
+        .success()
+        .stdout(contains(
+            "unsuccessful-exit-finished-failed = true # command line\n",
+        ))
+        .stdout(contains(format!(
+            "killed = 9 # {}\n",
+            config_dir.join("horust").join("horust.toml").display()
+        )))
+        .stdout(contains("exited = 1 # default\n"));
+}
+
+#[test]
+fn test_services_layers() {
+    // A service replaces the one with the same name from a previous services path.
+    let (mut cmd, temp_dir, temp_dir_2) = get_cli_multiple();
+    store_service(temp_dir.path(), r#"command = "/bin/false""#, Some("a.toml"));
+    store_service(
+        temp_dir_2.path(),
+        r#"command = "/bin/true""#,
+        Some("a.toml"),
+    );
+    cmd.args(vec!["--unsuccessful-exit-finished-failed"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_trace_events() {
+    let (mut cmd, temp_dir) = get_cli();
+    let trace = temp_dir.path().join("events.trace");
+    cmd.args(vec![
+        "--trace-events",
+        trace.to_str().unwrap(),
+        "--",
+        "/bin/true",
+    ])
+    .assert()
+    .success();
+    let trace = std::fs::read_to_string(trace).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert!(lines[0].starts_with("seq=0 time="), "{}", trace);
+    assert!(trace.contains(r#"source=supervisor/process_spawner event=PidChanged("/bin/true""#));
+    assert!(trace.contains(r#"source=supervisor event=ServiceExited("/bin/true", Code(0))"#));
+    assert!(lines
+        .last()
+        .unwrap()
+        .ends_with("event=ShuttingDownInitiated(Gracefully)"));
+}
+
+#[test]
+fn test_otlp_endpoint() {
+    // A fake OTLP collector, which forwards the bodies of the export requests.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut content_length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = reader.into_inner();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let _ = sender.send(String::from_utf8(body).unwrap());
+        }
+    });
+
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), r#"command = "/bin/true""#, Some("a.toml"));
+    cmd.env("NO_PROXY", "127.0.0.1")
+        .args(vec!["--otlp-endpoint", endpoint.as_str()])
+        .assert()
+        .success();
+    let exported: String = receiver.try_iter().collect();
+    assert!(exported.contains(r#""name":"run a.toml""#), "{}", exported);
+    assert!(exported.contains(r#""name":"boot""#), "{}", exported);
+}
+
+#[test]
+fn test_run_as() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    // The command runs as the new user of horust.
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "--run-as",
+        "nobody",
+        "--",
+        "/bin/sh -c 'id -u; id -g'",
+    ])
+    .assert()
+    .success()
+    .stdout("65534\n65534\n");
+
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec!["--run-as", "no-such-user", "--", "/bin/true"])
+        .assert()
+        .failure()
+        .stderr(contains("Can't run as no-such-user"));
+}
+
+#[test]
+fn test_dynamic_user() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"command = "/bin/sh -c 'id -u; id -g; pwd; printenv USER'"
+user = "dynamic"
+"#;
+    store_service(temp_dir.path(), service, Some("helper.toml"));
+    let state_dir = temp_dir.path().join("state");
+    let runtime_dir = temp_dir.path().join("run");
+    cmd.args(vec![
+        "--state-dir",
+        state_dir.to_str().unwrap(),
+        "--runtime-dir",
+        runtime_dir.to_str().unwrap(),
+    ]);
+    let output = cmd.output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success());
+    let lines: Vec<&str> = stdout.lines().collect();
+    let uid: u32 = lines[0].parse().unwrap();
+    assert!((61184..=65519).contains(&uid), "{}", uid);
+    assert_eq!(lines[1], lines[0]);
+    assert_eq!(
+        lines[2],
+        runtime_dir.join("horust-helper").display().to_string()
+    );
+    assert_eq!(lines[3], "horust-helper");
+    // The home is removed on exit, while the uid is kept for the next run.
+    assert!(!runtime_dir.join("horust-helper").exists());
+    let state = std::fs::read_to_string(state_dir.join("dynamic-users.json")).unwrap();
+    assert!(
+        state.contains(&format!("\"helper.toml\":{}", uid)),
+        "{}",
+        state
+    );
+}
+
+#[test]
+fn test_pressure_pause() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    // Any pressure is over the threshold.
+    std::fs::write(&config_path, "[pressure]\ncpu = 0.0\npause = [\"a.toml\"]").unwrap();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nsleep 2\necho done",
+        None,
+        Some("a"),
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nsleep 3\nkill -TERM $PPID",
+        None,
+        Some("b"),
+    );
+    cmd.args(vec!["-vv", "--config-path", config_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("done").not())
+        .stderr(contains("a.toml: pausing"));
+}
+
+#[test]
+fn test_plugins() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let plugins_dir = config_dir.path().join("plugins");
+    std::fs::create_dir(&plugins_dir).unwrap();
+    let events = config_dir.path().join("events");
+    let plugin = plugins_dir.join("record.sh");
+    std::fs::write(
+        &plugin,
+        format!(
+            "#!/usr/bin/env bash
+cat >> {}",
+            events.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "[plugins]\ndir = \"{}\"\nevents = [\"service-started\"]",
+            plugins_dir.display()
+        ),
+    )
+    .unwrap();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nsleep 2",
+        None,
+        Some("a"),
+    );
+    cmd.args(vec!["--config-path", config_path.to_str().unwrap()])
+        .assert()
+        .success();
+    let events = std::fs::read_to_string(events).unwrap();
+    assert!(
+        events.contains(r#""event":"service-started","service":"a.toml""#),
+        "{}",
+        events
+    );
+}
+
+#[test]
+fn test_policies() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let started = temp_dir.path().join("started");
+    // Fails three times, then succeeds.
+    let runs = temp_dir.path().join("runs");
+    store_service_script(
+        temp_dir.path(),
+        &format!(
+            "#!/usr/bin/env bash\necho >> {0}\n[ $(wc -l < {0}) -gt 3 ]",
+            runs.display()
+        ),
+        Some("[restart]\nstrategy = \"on-failure\"\nbackoff = \"0s\""),
+        Some("flaky"),
+    );
+    store_service_script(
+        temp_dir.path(),
+        &format!("#!/usr/bin/env bash\ntouch {}", started.display()),
+        Some("auto-start = false"),
+        Some("fallback"),
+    );
+    let policies = config_dir.path().join("policies");
+    std::fs::write(
+        &policies,
+        "# If flaky keeps failing, start the fallback.\non flaky failed 3 times within 1m: start fallback",
+    )
+    .unwrap();
+    cmd.args(vec!["--policies", policies.to_str().unwrap()])
+        .assert()
+        .success();
+    assert!(started.exists());
+
+    // Reported when loading, before anything is set up.
+    std::fs::write(&policies, "on flaky crashed: start fallback").unwrap();
+    cmd.assert()
+        .failure()
+        .stderr(contains("Unknown event: crashed"))
+        .stderr(contains("panicked").not());
+    cmd.arg("--dry-run")
+        .assert()
+        .failure()
+        .stderr(contains("Unknown event: crashed"));
+}
+
 #[test]
 fn test_config_unsuccessful_exit_finished_failed() {
     let (mut cmd, temp_dir) = get_cli();
@@ -28,10 +535,101 @@ exit 1
     recv.recv_or_kill(Duration::from_secs(15));
 }
 
+#[test]
+fn test_exit_code_strategy() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nexit 7",
+        None,
+        Some("a"),
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nsleep 1\nexit 9",
+        Some("[failure]\nstrategy = \"ignore\"\ncritical = true"),
+        Some("b"),
+    );
+    cmd.args(vec!["--exit-code-strategy", "first-failed"])
+        .assert()
+        .code(7);
+
+    let (mut cmd, _other_dir) = get_cli();
+    cmd.args(vec![
+        "--services-path",
+        temp_dir.path().to_str().unwrap(),
+        "--exit-code-strategy",
+        "critical",
+    ])
+    .assert()
+    .code(9);
+}
+
+#[test]
+fn test_history() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let history = config_dir.path().join("history.jsonl");
+    let config_path = config_dir.path().join("horust.toml");
+    std::fs::write(
+        &config_path,
+        format!("[history]\npath = \"{}\"", history.display()),
+    )
+    .unwrap();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nexit 3",
+        None,
+        Some("a"),
+    );
+    cmd.args(vec!["--config-path", config_path.to_str().unwrap()]);
+    // Kept across the runs of Horust.
+    cmd.assert().success();
+    cmd.assert().success();
+    let history = std::fs::read_to_string(history).unwrap();
+    let runs: Vec<&str> = history.lines().collect();
+    assert_eq!(runs.len(), 2, "{}", history);
+    for run in runs {
+        assert!(run.contains(r#""service":"a.toml","generation":1,"#));
+        assert!(run.contains(r#""exit-code":3,"failed":true"#));
+    }
+}
+
+#[test]
+fn test_boot_timeout() {
+    let (mut cmd, temp_dir) = get_cli();
+    let never_ready = temp_dir.path().join("never-ready");
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nsleep 30",
+        Some(&format!(
+            "[healthiness]\nfile-path = \"{}\"",
+            never_ready.display()
+        )),
+        None,
+    );
+    cmd.args(vec!["--boot-timeout", "2s"])
+        .assert()
+        .code(1)
+        .stderr(contains("Boot timeout (2s) is over"));
+}
+
+#[test]
+fn test_on_system_ready() {
+    let (mut cmd, temp_dir) = get_cli();
+    let ready = temp_dir.path().join("ready");
+    store_service_script(temp_dir.path(), "#!/usr/bin/env bash\nsleep 2", None, None);
+    cmd.args(vec![
+        "--on-system-ready",
+        &format!("touch {}", ready.display()),
+    ])
+    .assert()
+    .success();
+    assert!(ready.exists());
+}
+
 #[test]
 /// This tests prints a couple of log lines:
-/// Error spawning process: NixError: ENOENT: No such file or directory
-/// it's fine
 fn test_command_not_found() {
     let (mut cmd, temp_dir) = get_cli();
     let dir = temp_dir.path();
@@ -42,14 +640,41 @@ fn test_command_not_found() {
         .collect::<String>();
     let service_name = format!("{}.toml", rnd_name.as_str());
     let service = format!(r#"command = ",sorry_not_found{}""#, rnd_name);
+    std::fs::write(dir.join(&service_name), &service).unwrap();
+    // Reported when the services are loaded.
+    cmd.assert().failure().stderr(contains(format!(
+        "Service '{}' has a command which can't be executed: ,sorry_not_found{} not found",
+        service_name, rnd_name
+    )));
+
+    // Only a warning, then it fails to spawn.
+    let service = format!("{}\ncommand-check = \"warn\"", service);
     std::fs::write(dir.join(&service_name), service).unwrap();
-    let recv = run_async(&mut cmd, true);
-    // Error spawning process: NixError: ENOENT: No such file or directory
-    // It's fine
-    recv.recv_or_kill(Duration::from_secs(15));
-    let cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
-    let recv = run_async(cmd, false);
-    recv.recv_or_kill(Duration::from_secs(15));
+    cmd.arg("-v")
+        .assert()
+        .stderr(contains("the command can't be executed (yet)"))
+        .stderr(contains("Failed spawning the process"));
+}
+
+#[test]
+fn test_working_directory_not_found() {
+    let (mut cmd, temp_dir) = get_cli();
+    let dir = temp_dir.path();
+    let service = format!(
+        r#"command = "/usr/bin/env true"
+working-directory = "{}""#,
+        dir.join("missing").display()
+    );
+    std::fs::write(dir.join("missing-cwd.toml"), service).unwrap();
+    let trace = dir.join("events.trace");
+    cmd.args(vec!["--trace-events", trace.to_str().unwrap()]);
+    // Reported by the child, instead of exiting right away.
+    let reason = "Failed changing to the working directory: ENOENT: No such file or directory";
+    cmd.assert()
+        .stderr(contains(format!("Failed spawning the process: {}", reason)));
+    let trace = std::fs::read_to_string(trace).unwrap();
+    let event = format!(r#"event=SpawnFailed("missing-cwd.toml", "{}")"#, reason);
+    assert!(trace.contains(&event), "{}", trace);
 }
 
 #[test]
@@ -115,3 +740,55 @@ fn test_stress_test_chained_services() {
     let recv = run_async(&mut cmd, true);
     recv.recv_or_kill(Duration::from_secs(max * 2));
 }
+
+#[test]
+fn test_reload() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo "$NAME $VERSION" >> {}
+while true ; do
+    sleep 1
+done
+"#,
+        runs.display()
+    );
+    let service = |name: &str, version: u32| {
+        format!(
+            r#"[environment]
+additional = {{ NAME = "{}", VERSION = "{}" }}
+"#,
+            name, version
+        )
+    };
+    store_service_script(
+        temp_dir.path(),
+        &script,
+        Some(&service("api", 1)),
+        Some("api"),
+    );
+    store_service_script(
+        temp_dir.path(),
+        &script,
+        Some(&service("worker", 1)),
+        Some("worker"),
+    );
+    let recv = run_async(&mut cmd, true);
+    thread::sleep(Duration::from_secs(1));
+    // The worker is unchanged.
+    store_service_script(
+        temp_dir.path(),
+        &script,
+        Some(&service("api", 2)),
+        Some("api"),
+    );
+    nix::sys::signal::kill(recv.pid, nix::sys::signal::Signal::SIGHUP).expect("kill");
+    thread::sleep(Duration::from_secs(2));
+    nix::sys::signal::kill(recv.pid, nix::sys::signal::Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    let runs = std::fs::read_to_string(&runs).unwrap();
+    let mut runs: Vec<&str> = runs.lines().collect();
+    runs.sort();
+    assert_eq!(runs, vec!["api 1", "api 2", "worker 1"]);
+}
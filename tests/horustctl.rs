@@ -0,0 +1,934 @@
+use assert_cmd::prelude::*;
+use nix::sys::signal::{kill, Signal};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[allow(dead_code)]
+mod utils;
+use utils::*;
+
+/// Waits for horust to create its control socket.
+fn wait_for_socket(socket: &Path) {
+    for _ in 0..50 {
+        if socket.exists() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("Control socket {} was not created", socket.display());
+}
+
+fn horustctl(socket: &Path) -> Command {
+    let mut cmd = Command::cargo_bin("horustctl").unwrap();
+    cmd.args(vec!["--socket", socket.to_str().unwrap()]);
+    cmd
+}
+
+/// The status (as JSON) and the pid of each service, sorted by name.
+type States = Vec<(String, Option<i64>)>;
+
+/// Polls the states of the services until `expected` holds for them, for up to 10 seconds.
+fn wait_for_states(socket: &Path, expected: impl Fn(&States) -> bool) -> States {
+    let mut states = vec![];
+    for _ in 0..100 {
+        let output = horustctl(socket)
+            .args(vec!["status", "--json"])
+            .output()
+            .unwrap();
+        let services: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+        states = services
+            .iter()
+            .map(|service| (service["status"].to_string(), service["pid"].as_i64()))
+            .collect();
+        if expected(&states) {
+            return states;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!(
+        "The services didn't reach the expected states: {:?}",
+        states
+    );
+}
+
+#[test]
+fn test_exec() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let workdir = temp_dir.path().join("workdir");
+    std::fs::create_dir(&workdir).unwrap();
+    let spawned_env = temp_dir.path().join("spawned-env");
+    let service = format!(
+        r#"command = "/bin/sh -c 'env > {}; sleep 30'"
+working-directory = "{}"
+[environment]
+additional = {{ GREETING = "hello from the service" }}
+from-command = {{ SPAWN_ID = "cat /proc/sys/kernel/random/uuid" }}
+"#,
+        spawned_env.display(),
+        workdir.display()
+    );
+    store_service(temp_dir.path(), &service, Some("app.toml"));
+    let audit_log = temp_dir.path().join("audit.log");
+    cmd.args(vec![
+        "--control-socket",
+        socket.to_str().unwrap(),
+        "--audit-log",
+        audit_log.to_str().unwrap(),
+    ]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+
+    horustctl(&socket)
+        .args(vec![
+            "exec",
+            "app",
+            "--",
+            "/bin/sh",
+            "-c",
+            "echo $GREETING; pwd",
+        ])
+        .assert()
+        .success()
+        .stdout(contains("hello from the service"))
+        .stdout(contains(workdir.to_str().unwrap()));
+    // The variables computed when the service was spawned aren't computed again.
+    thread::sleep(Duration::from_millis(500));
+    let spawned_id = std::fs::read_to_string(&spawned_env).unwrap();
+    let spawned_id = spawned_id
+        .lines()
+        .find_map(|line| line.strip_prefix("SPAWN_ID="))
+        .unwrap();
+    horustctl(&socket)
+        .args(vec!["exec", "app", "--", "/bin/sh", "-c", "echo $SPAWN_ID"])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", spawned_id));
+    horustctl(&socket)
+        .args(vec!["exec", "app.toml", "--", "/bin/sh", "-c", "exit 3"])
+        .assert()
+        .code(3);
+    horustctl(&socket)
+        .args(vec!["exec", "missing", "--", "/bin/true"])
+        .assert()
+        .failure()
+        .stderr(contains("Service not found: missing"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+    assert!(!socket.exists());
+    let audit_log = std::fs::read_to_string(audit_log).unwrap();
+    let lines: Vec<&str> = audit_log.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", audit_log);
+    let uid = nix::unistd::getuid();
+    assert!(lines[0].contains(&format!(r#""uid":{},"#, uid)));
+    assert!(lines[0].contains(r#""request":"exec","service":"app","result":"ok""#));
+    assert!(lines[3].contains(r#""result":"error","error":"Service not found: missing""#));
+}
+
+#[test]
+fn test_socket_ownership() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(temp_dir.path(), r#"command = "/bin/sleep 30""#, None);
+    let uid = nix::unistd::getuid().to_string();
+    let gid = nix::unistd::getgid().to_string();
+    cmd.args(vec![
+        "--control-socket",
+        socket.to_str().unwrap(),
+        "--control-socket-owner",
+        uid.as_str(),
+        "--control-socket-group",
+        gid.as_str(),
+        "--control-socket-mode",
+        "640",
+    ]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    // The ownership is applied before the socket is moved to its path.
+    let metadata = std::fs::metadata(&socket).unwrap();
+    assert_eq!(metadata.uid().to_string(), uid);
+    assert_eq!(metadata.gid().to_string(), gid);
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    // The private directory it was bound in is gone.
+    let entries: Vec<_> = std::fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().starts_with(".horust-"))
+        .collect();
+    assert!(entries.is_empty(), "{:?}", entries);
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+
+    // Horust doesn't start if the ownership can't be applied.
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(temp_dir.path(), r#"command = "/bin/sleep 30""#, None);
+    cmd.args(vec![
+        "--control-socket",
+        socket.to_str().unwrap(),
+        "--control-socket-group",
+        "no-such-group",
+    ])
+    .assert()
+    .failure()
+    .stderr(contains(
+        "Failed applying the ownership of the control socket",
+    ));
+    assert!(!socket.exists());
+}
+
+#[test]
+fn test_attach() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let script = r#"#!/usr/bin/env bash
+while true ; do
+    echo "tick"
+    echo "tock" 1>&2
+    sleep 0.2
+done
+"#;
+    let log = temp_dir.path().join("app.log");
+    let service = format!("stdout = \"{}\"\nattachable = true", log.display());
+    store_service_script(temp_dir.path(), script, Some(&service), Some("app"));
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/sleep 30""#,
+        Some("plain.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+
+    horustctl(&socket)
+        .args(vec!["attach", "plain"])
+        .assert()
+        .failure()
+        .stderr(contains("set `attachable = true`"));
+    let mut attach = horustctl(&socket)
+        .args(vec!["attach", "app"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(attach.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "tick\n");
+    let mut stderr = BufReader::new(attach.stderr.take().unwrap());
+    line.clear();
+    stderr.read_line(&mut line).unwrap();
+    assert_eq!(line, "tock\n");
+
+    // Ctrl-p, Ctrl-q:
+    attach
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&[0x10, 0x11])
+        .unwrap();
+    assert!(attach.wait().unwrap().success());
+    // The output is still written to the log file:
+    assert!(std::fs::read_to_string(&log).unwrap().contains("tick"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_status() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let flag = temp_dir.path().join("up");
+    let service = format!(
+        r#"command = "/bin/sleep 30"
+[healthiness]
+file-path = "{}"
+max-failed = 100
+"#,
+        flag.display()
+    );
+    store_service(temp_dir.path(), &service, Some("app.toml"));
+    let database = r#"command = "/bin/sleep 30"
+description = "The database"
+tags = ["critical", "db"]
+"#;
+    store_service(temp_dir.path(), database, Some("database.toml"));
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    // Waits for the first healthcheck.
+    thread::sleep(Duration::from_millis(1500));
+
+    let output = horustctl(&socket)
+        .args(vec!["status", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let app = &status[0];
+    assert_eq!(app["name"], "app.toml");
+    assert_eq!(app["status"], "Started");
+    assert!(app["pid"].is_number());
+    assert_eq!(app["healthcheck"]["healthy"], false);
+    assert_eq!(
+        app["healthcheck"]["output"],
+        format!("file-path: {} doesn't exist", flag.display())
+    );
+    assert!(app["healthcheck"]["latency-ms"].is_number());
+
+    std::fs::write(&flag, "").unwrap();
+    thread::sleep(Duration::from_millis(1500));
+    horustctl(&socket)
+        .args(vec!["status", "app"])
+        .assert()
+        .success()
+        .stdout(contains("app.toml: Started, pid"))
+        .stdout(contains(", healthy ("));
+    let output = horustctl(&socket)
+        .args(vec!["status", "--tag", "critical"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with("database.toml (The database) [critical, db]: Running, pid"),
+        "{}",
+        stdout
+    );
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    horustctl(&socket)
+        .args(vec!["status", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains("Service not found: missing"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_start() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let done = temp_dir.path().join("done");
+    let service = format!(
+        r#"command = "/usr/bin/touch {}"
+auto-start = false
+"#,
+        done.display()
+    );
+    store_service(temp_dir.path(), &service, Some("maintenance.toml"));
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/sleep 30""#,
+        Some("app.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+    assert!(!done.exists(), "started at boot");
+
+    horustctl(&socket)
+        .args(vec!["start", "maintenance"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(1500));
+    assert!(done.exists());
+    horustctl(&socket)
+        .args(vec!["start", "app"])
+        .assert()
+        .failure()
+        .stderr(contains("not waiting to be started"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_reload() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/sleep 30""#,
+        Some("app.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+    let status = || {
+        let output = horustctl(&socket)
+            .args(vec!["status", "app"])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let before = status();
+    assert!(before.contains("Running, pid"), "{}", before);
+
+    // Unchanged: not restarted.
+    horustctl(&socket).args(vec!["reload"]).assert().success();
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(status(), before);
+
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/sleep 31""#,
+        Some("app.toml"),
+    );
+    horustctl(&socket).args(vec!["reload"]).assert().success();
+    thread::sleep(Duration::from_millis(3000));
+    let after = status();
+    assert!(after.contains("Running, pid"), "{}", after);
+    assert_ne!(after, before);
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_switch() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"\nvariant = true",
+        Some("app@blue.toml"),
+    );
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"\nvariant = true\nauto-start = false",
+        Some("app@green.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+    let assert_status = |service: &str, expected: &str| {
+        let output = horustctl(&socket)
+            .args(vec!["status", service])
+            .output()
+            .unwrap();
+        let status = String::from_utf8_lossy(&output.stdout);
+        assert!(status.starts_with(expected), "{}", status);
+    };
+    assert_status("app@blue", "app@blue.toml: Running");
+    assert_status("app@green", "app@green.toml: Initial");
+
+    horustctl(&socket)
+        .args(vec!["switch", "app@green"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(3000));
+    assert_status("app@green", "app@green.toml: Running");
+    assert_status("app@blue", "app@blue.toml: Initial");
+
+    horustctl(&socket)
+        .args(vec!["switch", "app@purple"])
+        .assert()
+        .failure()
+        .stderr(contains("Service not found"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_restart() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    for instance in ["app@1.toml", "app@2.toml"] {
+        store_service(
+            temp_dir.path(),
+            r#"command = "/bin/sleep 30""#,
+            Some(instance),
+        );
+    }
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+    let pids = || {
+        let output = horustctl(&socket)
+            .args(vec!["status", "--json"])
+            .output()
+            .unwrap();
+        let services: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+        services
+            .iter()
+            .map(|service| service["pid"].as_i64())
+            .collect::<Vec<_>>()
+    };
+    let before = pids();
+    assert!(before.iter().all(Option::is_some), "{:?}", before);
+
+    horustctl(&socket)
+        .args(vec!["restart", "app@", "--max-unavailable", "1"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(5000));
+    let after = pids();
+    assert!(after.iter().all(Option::is_some), "{:?}", after);
+    assert!(before
+        .iter()
+        .zip(&after)
+        .all(|(before, after)| before != after));
+
+    horustctl(&socket)
+        .args(vec!["restart", "worker@"])
+        .assert()
+        .failure()
+        .stderr(contains("No instances of: worker@"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_bulk_operations() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(
+        temp_dir.path(),
+        r#"command = "/bin/sleep 30""#,
+        Some("queue.toml"),
+    );
+    let worker = r#"command = "/bin/sleep 30"
+start-after = ["queue.toml"]
+tags = ["workers"]
+"#;
+    for name in ["worker-a.toml", "worker-b.toml"] {
+        store_service(temp_dir.path(), worker, Some(name));
+    }
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    let all_running = |states: &States| states.iter().all(|(status, _)| status == "\"Running\"");
+    wait_for_states(&socket, all_running);
+
+    horustctl(&socket)
+        .args(vec!["stop", "--tag", "workers"])
+        .assert()
+        .success();
+    let stopped = wait_for_states(&socket, |states| {
+        states[1..]
+            .iter()
+            .all(|state| *state == ("\"Initial\"".to_string(), None))
+    });
+    assert_eq!(stopped[0].0, "\"Running\"", "{:?}", stopped);
+
+    horustctl(&socket)
+        .args(vec!["start", "--tag", "workers"])
+        .assert()
+        .success();
+    let started = wait_for_states(&socket, all_running);
+
+    horustctl(&socket)
+        .args(vec![
+            "restart",
+            "--tag",
+            "workers",
+            "--max-unavailable",
+            "2",
+        ])
+        .assert()
+        .success();
+    let restarted = wait_for_states(&socket, |states| {
+        all_running(states) && states[1].1 != started[1].1 && states[2].1 != started[2].1
+    });
+    assert_eq!(restarted[0], started[0]);
+
+    horustctl(&socket)
+        .args(vec!["stop", "--tag", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains("No services tagged: missing"));
+    horustctl(&socket)
+        .args(vec!["stop"])
+        .assert()
+        .failure()
+        .stderr(contains("No services selected"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_scale() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let state_dir = temp_dir.path().join("state");
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"\n[scale]\ninstances = 1\nmax = 3",
+        Some("worker@.toml"),
+    );
+    cmd.args(vec![
+        "--control-socket",
+        socket.to_str().unwrap(),
+        "--state-dir",
+        state_dir.to_str().unwrap(),
+    ]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+    let running = || {
+        let output = horustctl(&socket)
+            .args(vec!["status", "--json"])
+            .output()
+            .unwrap();
+        let services: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+        services
+            .iter()
+            .filter(|service| service["status"] == "Running")
+            .count()
+    };
+    assert_eq!(running(), 1);
+
+    horustctl(&socket)
+        .args(vec!["scale", "worker@", "3", "--persist"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(2000));
+    assert_eq!(running(), 3);
+    let persisted = std::fs::read_to_string(state_dir.join("instances.json")).unwrap();
+    assert_eq!(persisted, r#"{"worker":3}"#);
+
+    horustctl(&socket)
+        .args(vec!["scale", "worker@", "2"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(3000));
+    assert_eq!(running(), 2);
+
+    horustctl(&socket)
+        .args(vec!["scale", "worker@", "4"])
+        .assert()
+        .failure()
+        .stderr(contains("can be scaled up to 3 instances"));
+    horustctl(&socket)
+        .args(vec!["scale", "api@", "1"])
+        .assert()
+        .failure()
+        .stderr(contains("No scaled template: api@"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_clear() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let runs = temp_dir.path().join("runs");
+    store_service(
+        temp_dir.path(),
+        &format!(
+            "command = \"/bin/sh -c 'echo run >> {}; exit 1'\"\n[restart]\nstrategy = \"always\"\nattempts = 0\ncooldown = \"1h\"",
+            runs.display()
+        ),
+        Some("crashing.toml"),
+    );
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"",
+        Some("sleeper.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+    horustctl(&socket)
+        .args(vec!["status", "crashing"])
+        .assert()
+        .success()
+        .stdout(contains("crashing.toml: Broken"));
+    assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 1);
+
+    horustctl(&socket)
+        .args(vec!["clear", "crashing"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 2);
+    horustctl(&socket)
+        .args(vec!["clear", "sleeper"])
+        .assert()
+        .failure()
+        .stderr(contains("Service sleeper.toml is Running, not broken"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_why_not_running() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let ready = temp_dir.path().join("ready");
+    store_service(
+        temp_dir.path(),
+        &format!(
+            "command = \"/bin/sleep 30\"\n[conditions]\npaths = [\"{}\"]",
+            ready.display()
+        ),
+        Some("db.toml"),
+    );
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"\nstart-after = [\"db.toml\"]",
+        Some("api.toml"),
+    );
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/false\"\n[restart]\nstrategy = \"always\"\nattempts = 0\ncooldown = \"1h\"",
+        Some("crashing.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1500));
+
+    horustctl(&socket)
+        .args(vec!["why-not-running", "db"])
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "db.toml is not running (Starting):\n  - a condition isn't met: {} doesn't exist\n",
+            ready.display()
+        )));
+    horustctl(&socket)
+        .args(vec!["why-not-running", "api"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "api.toml is not running (Initial):\n  - waiting for db.toml (Starting)\n",
+        ));
+    horustctl(&socket)
+        .args(vec!["why-not-running", "crashing"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "  - it's crash-looping (failures in a row: 1): waiting for its cooldown, or to be cleared (59m",
+        ));
+
+    std::fs::write(&ready, "").unwrap();
+    thread::sleep(Duration::from_millis(2500));
+    horustctl(&socket)
+        .args(vec!["why-not-running", "api"])
+        .assert()
+        .success()
+        .stdout("api.toml is running.\n");
+    horustctl(&socket)
+        .args(vec!["why-not-running", "missing"])
+        .assert()
+        .failure();
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_pause() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"",
+        Some("noisy.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(500));
+    horustctl(&socket)
+        .args(vec!["resume", "noisy"])
+        .assert()
+        .failure()
+        .stderr(contains("Service noisy.toml is not paused"));
+    horustctl(&socket)
+        .args(vec!["pause", "noisy"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    horustctl(&socket)
+        .args(vec!["status", "noisy"])
+        .assert()
+        .success()
+        .stdout(contains("paused"));
+
+    horustctl(&socket)
+        .args(vec!["resume", "noisy"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    horustctl(&socket)
+        .args(vec!["status", "noisy"])
+        .assert()
+        .success()
+        .stdout(contains("paused").not());
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_history() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let history = config_dir.path().join("history.jsonl");
+    let config_path = config_dir.path().join("horust.toml");
+    std::fs::write(
+        &config_path,
+        format!("[history]\npath = \"{}\"", history.display()),
+    )
+    .unwrap();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nexit 3",
+        None,
+        Some("crashing"),
+    );
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"",
+        Some("sleeper.toml"),
+    );
+    cmd.args(vec![
+        "--control-socket",
+        socket.to_str().unwrap(),
+        "--config-path",
+        config_path.to_str().unwrap(),
+    ]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    thread::sleep(Duration::from_millis(1000));
+
+    horustctl(&socket)
+        .args(vec!["history", "crashing"])
+        .assert()
+        .success()
+        .stdout(contains("STARTED"))
+        .stdout(contains("crashing.toml"))
+        .stdout(contains("3 (failed)"));
+    horustctl(&socket)
+        .args(vec!["history", "--json"])
+        .assert()
+        .success()
+        .stdout(contains(r#""exit-code": 3"#))
+        .stdout(contains("sleeper.toml").not());
+    horustctl(&socket)
+        .args(vec!["history", "--until", "1h"])
+        .assert()
+        .success()
+        .stdout(contains("crashing.toml").not());
+    horustctl(&socket)
+        .args(vec!["history", "--since", "yesterday"])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid time: yesterday"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_run() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(
+        temp_dir.path(),
+        "command = \"/bin/sleep 30\"",
+        Some("sleeper.toml"),
+    );
+    cmd.args(vec!["--control-socket", socket.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    let output = temp_dir.path().join("output");
+
+    horustctl(&socket)
+        .args(vec!["run", "--name", "tmpjob", "--wall-time", "10s", "--"])
+        .args(vec!["/bin/sh", "-c", "echo $0 > \"$1\"", "hello world"])
+        .arg(&output)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello world\n");
+    horustctl(&socket)
+        .args(vec!["status", "tmpjob"])
+        .assert()
+        .success()
+        .stdout(contains("tmpjob: Finished"));
+    horustctl(&socket)
+        .args(vec!["run", "--name", "sleeper", "--", "/bin/true"])
+        .assert()
+        .failure()
+        .stderr(contains("Service sleeper already exists"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_run_not_owner() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("horust.sock");
+    store_service(temp_dir.path(), "command = \"/bin/sleep 30\"", None);
+    // Anyone can connect, and run a copy of horustctl.
+    std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let ctl = temp_dir.path().join("horustctl");
+    std::fs::copy(assert_cmd::cargo::cargo_bin("horustctl"), &ctl).unwrap();
+    cmd.args(vec![
+        "--control-socket",
+        socket.to_str().unwrap(),
+        "--control-socket-mode",
+        "666",
+    ]);
+    let recv = run_async(&mut cmd, true);
+    wait_for_socket(&socket);
+    let output = temp_dir.path().join("output");
+
+    // As nobody, it can't get a root process.
+    Command::new("setpriv")
+        .args(vec![
+            "--reuid",
+            "65534",
+            "--regid",
+            "65534",
+            "--clear-groups",
+        ])
+        .arg(&ctl)
+        .args(vec!["--socket", socket.to_str().unwrap()])
+        .args(vec!["run", "--name", "escalate", "--", "/usr/bin/touch"])
+        .arg(&output)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Permission denied: only uid 0 can send run requests",
+        ));
+    thread::sleep(Duration::from_millis(500));
+    assert!(!output.exists());
+    horustctl(&socket)
+        .args(vec!["status", "escalate"])
+        .assert()
+        .failure()
+        .stderr(contains("Service not found: escalate"));
+
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(15));
+}
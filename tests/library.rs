@@ -0,0 +1,40 @@
+use horust::horust::{ExitReason, ExitStatus, ServiceStatus};
+use horust::Horust;
+use tempdir::TempDir;
+
+#[allow(dead_code)]
+mod utils;
+use utils::*;
+
+// Test Horust used as a library
+#[test]
+fn test_run_report() {
+    let temp_dir = TempDir::new("horust").unwrap();
+    let failing_script = r#"#!/usr/bin/env bash
+echo "cannot connect"
+exit 3"#;
+    let failure = "[failure]\nstrategy = \"ignore\"\noutput-lines = 10";
+    store_service_script(temp_dir.path(), failing_script, Some(failure), Some("a"));
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nexit 0",
+        None,
+        Some("b"),
+    );
+
+    let mut horust = Horust::from_services_dirs(&[temp_dir.path().into()]).unwrap();
+    let report = horust.run();
+    assert_eq!(report.exit_status(), ExitStatus::SomeServiceFailed);
+
+    let a = report.service("a.toml").unwrap();
+    assert_eq!(a.status, ServiceStatus::FinishedFailed);
+    assert_eq!(a.last_exit, Some(ExitReason::Code(3)));
+    assert_eq!(a.spawns, 1);
+    assert_eq!(a.restarts(), 0);
+    assert_eq!(a.last_output, vec!["cannot connect".to_string()]);
+
+    let b = report.service("b.toml").unwrap();
+    assert_eq!(b.status, ServiceStatus::Finished);
+    assert_eq!(b.last_exit, Some(ExitReason::Code(0)));
+    assert!(b.last_output.is_empty());
+}
@@ -0,0 +1,82 @@
+use assert_cmd::prelude::*;
+use nix::sys::signal::{kill, Signal};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::time::Duration;
+
+#[allow(dead_code)]
+mod utils;
+use utils::*;
+
+// Test conditions section
+#[test]
+fn test_conditions_network_dns() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[conditions]
+network = { dns = "localhost" }"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(service),
+        None,
+    );
+    cmd.assert().success().stdout(contains("started"));
+}
+
+#[test]
+fn test_conditions_not_met() {
+    let (mut cmd, temp_dir) = get_cli();
+    // `.invalid` names never resolve, so the service is never spawned.
+    let service = r#"[conditions]
+network = { dns = "horust.invalid" }"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(service),
+        None,
+    );
+    let output = temp_dir.path().join("out.log");
+    cmd.stdout(std::fs::File::create(&output).unwrap());
+    let recv = run_async(&mut cmd, true);
+    std::thread::sleep(Duration::from_secs(2));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    let output = std::fs::read_to_string(output).unwrap();
+    assert!(contains("started").not().eval(&output));
+}
+
+#[test]
+fn test_conditions_paths() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("shared.sock");
+    let service = format!(
+        "[conditions]\npaths = [\"{}\"]\npaths-timeout = \"10s\"",
+        socket.display()
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(&service),
+        None,
+    );
+    let recv = run_async(&mut cmd, true);
+    std::thread::sleep(Duration::from_secs(2));
+    let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    recv.recv_or_kill(Duration::from_secs(10));
+
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[conditions]
+paths = ["/nonexistent/shared.sock"]
+paths-timeout = "1s""#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(service),
+        None,
+    );
+    cmd.assert()
+        .stdout(contains("started").not())
+        .stderr(contains(
+            "gave up waiting: /nonexistent/shared.sock doesn't exist",
+        ));
+}
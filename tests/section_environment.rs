@@ -1,6 +1,7 @@
 use assert_cmd::prelude::*;
 use predicates::prelude::*;
 use predicates::str::contains;
+use std::os::unix::fs::PermissionsExt;
 
 #[allow(dead_code)]
 mod utils;
@@ -62,6 +63,21 @@ fn test_environment_keep_env_false() {
         .stdout(contains("MyPassword").not());
 }
 
+#[test]
+fn test_environment_without_notify_socket() {
+    let (mut cmd, temp_dir) = get_cli();
+    // The socket of Horust's service manager isn't passed on, even if the environment is kept.
+    let service = r#"[environment]
+keep-env = true
+re-export = [ "NOTIFY_SOCKET" ]
+"#;
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.env("NOTIFY_SOCKET", "/nonexistent/notify")
+        .assert()
+        .success()
+        .stdout(contains("NOTIFY_SOCKET").not());
+}
+
 #[test]
 fn test_environment_re_export() {
     let (mut cmd, temp_dir) = get_cli();
@@ -76,3 +92,199 @@ re-export = [ "DB_PASS" ]
         .success()
         .stdout(contains("MyPassword"));
 }
+
+#[test]
+fn test_environment_keep() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[environment]
+keep = ["DB_*"]
+"#;
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.env("DB_PASS", "MyPassword")
+        .env("API_TOKEN", "MyToken")
+        .assert()
+        .success()
+        .stdout(contains("DB_PASS=MyPassword"))
+        .stdout(contains("MyToken").not());
+}
+
+#[test]
+fn test_environment_files() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    std::fs::write(
+        &config_path,
+        "[environment]\noverride = false\nadditional = { GLOBAL = \"global\", LEVEL = \"global\" }",
+    )
+    .unwrap();
+    let env_file = config_dir.path().join("service.env");
+    std::fs::write(
+        &env_file,
+        "# Comment\nLEVEL=file\nexport NAME=\"from file\"\n",
+    )
+    .unwrap();
+    let service = format!(
+        r#"[environment]
+files = ["{}"]
+additional = {{ NAME = "additional" }}
+"#,
+        env_file.display()
+    );
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(&service), None);
+    cmd.args(vec!["--config-path", config_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("GLOBAL=global"))
+        .stdout(contains("LEVEL=file"))
+        .stdout(contains("NAME=additional"));
+}
+
+#[test]
+fn test_environment_from_command() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[environment]
+additional = { GREETING = "overridden" }
+from-command = { GREETING = "echo hello from a command", EMPTY = "true" }
+"#;
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("GREETING=hello from a command\n"))
+        .stdout(contains("overridden").not())
+        .stdout(contains("EMPTY=\n"));
+
+    let service = r#"[environment]
+from-command = { SLOW = "sleep 10" }
+from-command-timeout = "100ms"
+"#;
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("SLOW").not())
+        .stderr(contains("Failed computing environment variable SLOW"));
+}
+
+#[test]
+fn test_environment_from_provider() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[environment]
+from-command = { DB_PASSWORD = "echo overridden" }
+from-provider = [
+    { command = "echo s3cr3t", var = "DB_PASSWORD" },
+    { command = "echo key", var = "API_KEY", timeout = "1s", cache = "0s" },
+]
+"#;
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("DB_PASSWORD=s3cr3t\n"))
+        .stdout(contains("overridden").not())
+        .stdout(contains("API_KEY=key\n"));
+
+    let service = r#"[environment]
+from-provider = { command = "sh -c 'echo token; exit 1'", var = "API_TOKEN" }
+"#;
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("API_TOKEN").not())
+        .stderr(contains("Failed fetching the secret API_TOKEN"));
+}
+
+#[test]
+fn test_environment_no_override() {
+    let (mut cmd, temp_dir) = get_cli();
+    let env_file = temp_dir.path().join("app.env");
+    std::fs::write(&env_file, "PORT=8081\n").unwrap();
+    // The secret of an already set variable isn't fetched: the failing command isn't run.
+    let service = format!(
+        r#"[environment]
+files = ["{}"]
+additional = {{ PORT = "8080", HOST = "localhost" }}
+additional-override = false
+from-provider = [
+    {{ command = "false", var = "HOST", override = false }},
+    {{ command = "echo s3cr3t", var = "TOKEN", override = false }},
+]
+"#,
+        env_file.display()
+    );
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(&service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("PORT=8081\n"))
+        .stdout(contains("HOST=localhost\n"))
+        .stdout(contains("TOKEN=s3cr3t\n"))
+        .stderr(contains("Failed fetching").not());
+}
+
+#[test]
+fn test_environment_source() {
+    let (mut cmd, temp_dir) = get_cli();
+    let bin = temp_dir.path().join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    let program = bin.join("hello-from-app");
+    std::fs::write(&program, "#!/bin/sh\necho \"hello from $APP_HOME\"\n").unwrap();
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let profile = temp_dir.path().join("app.sh");
+    let script = format!(
+        "export APP_HOME={}\nexport PATH=\"$PATH:$APP_HOME/bin\"\n[ -n \"$PS1\" ] && echo interactive\n",
+        temp_dir.path().display()
+    );
+    std::fs::write(&profile, script).unwrap();
+    // Found on the PATH set by the script, which isn't the one of Horust.
+    let service = format!(
+        r#"command = "hello-from-app"
+[environment]
+source = "{}"
+"#,
+        profile.display()
+    );
+    std::fs::write(temp_dir.path().join("app.toml"), service).unwrap();
+    cmd.assert().success().stdout(contains(format!(
+        "hello from {}",
+        temp_dir.path().display()
+    )));
+}
+
+#[test]
+fn test_environment_timezone_and_locale() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config_dir = tempdir::TempDir::new("config").unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    std::fs::write(
+        &config_path,
+        "[environment]\ntimezone = \"UTC\"\nlocale = \"C.UTF-8\"",
+    )
+    .unwrap();
+    let service = r#"[environment]
+timezone = "Europe/Rome"
+"#;
+    store_service_script(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.args(vec!["--config-path", config_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("TZ=Europe/Rome"))
+        .stdout(contains("LANG=C.UTF-8"));
+}
+
+#[test]
+fn test_environment_instances() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+echo "instance: ${HORUST_INSTANCE} port: ${PORT}""#;
+    let service = r#"[environment]
+additional = { PORT = "8080" }
+[instances.a]
+[instances.b]
+environment = { PORT = "8081" }
+"#;
+    store_service_script(temp_dir.path(), script, Some(service), Some("app@"));
+    cmd.assert()
+        .success()
+        .stdout(contains("instance: a port: 8080"))
+        .stdout(contains("instance: b port: 8081"));
+}
@@ -1,6 +1,9 @@
 mod utils;
 use utils::*;
 
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+use predicates::str::contains;
 use std::time::Duration;
 
 // Test failure strategies
@@ -46,3 +49,66 @@ fn test_failure_shutdown() {
 fn test_failure_kill_dependents() {
     test_failure_strategy("kill-dependents");
 }
+
+#[test]
+fn test_failure_output_lines() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[failure]
+strategy = "ignore"
+output-lines = 1"#;
+    let script = r#"#!/usr/bin/env bash
+echo first
+echo "cannot connect"
+exit 1"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+    cmd.assert()
+        .success()
+        .stderr(contains("  | cannot connect"))
+        .stderr(contains("  | first").not());
+}
+
+#[test]
+fn test_failure_signal() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"core-dumps = "disabled"
+[failure]
+strategy = "ignore"
+output-lines = 10"#;
+    let script = r#"#!/usr/bin/env bash
+echo "about to crash"
+kill -SEGV $$"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+    cmd.assert()
+        .success()
+        .stderr(contains("killed by SIGSEGV"))
+        .stderr(contains("(core dumped)").not());
+}
+
+#[test]
+fn test_crash_artifacts() {
+    let (mut cmd, temp_dir) = get_cli();
+    let crashes = temp_dir.path().join("crashes");
+    let service = format!(
+        "core-dumps = \"disabled\"\ncrash-artifacts = {{ dir = \"{}\" }}\n[failure]\nstrategy = \"ignore\"\noutput-lines = 10",
+        crashes.display()
+    );
+    let script = r#"#!/usr/bin/env bash
+echo "about to crash"
+kill -SEGV $$"#;
+    store_service_script(temp_dir.path(), script, Some(&service), Some("crashing"));
+    // Keeps Horust running, while the artifacts are collected.
+    store_service_script(temp_dir.path(), "#!/usr/bin/env bash\nsleep 1", None, None);
+    cmd.assert().success();
+
+    let bundles: Vec<_> = std::fs::read_dir(crashes.join("crashing.toml"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(bundles.len(), 1);
+    let crash = std::fs::read_to_string(bundles[0].join("crash.json")).unwrap();
+    assert!(crash.contains("killed by SIGSEGV"), "{}", crash);
+    let output = std::fs::read_to_string(bundles[0].join("output.log")).unwrap();
+    assert_eq!(output, "about to crash\n");
+    let proc_status = std::fs::read_to_string(bundles[0].join("proc-status")).unwrap();
+    assert!(proc_status.contains("State:"), "{}", proc_status);
+}
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use tempdir::TempDir;
 
@@ -57,6 +58,38 @@ fn test_output_redirection() {
         .for_each(|(stream, to)| test_single_output_redirection(stream, to));
 }
 
+#[test]
+fn test_output_tee() {
+    let (mut cmd, temp_dir) = get_cli();
+    let path = temp_dir.path().join("out.log");
+    let service = format!(
+        r#"stdout = {{ file = "{}", also-inherit = true }}"#,
+        path.display()
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho Hello",
+        Some(&service),
+        None,
+    );
+    cmd.assert().success().stdout(contains("Hello"));
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "Hello\n");
+}
+
+#[test]
+fn test_output_log_prefix() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho Hello",
+        Some(r#"log-prefix = "[{service}:{stream}] ""#),
+        Some("a"),
+    );
+    cmd.assert()
+        .success()
+        .stdout(contains("[a.toml:stdout] Hello\n"));
+}
+
 #[test]
 fn test_output_log_rotation() {
     let pattern = "Hello";
@@ -99,7 +132,8 @@ exit 0
 #[test]
 fn test_search_path_not_found() {
     let (mut cmd, temp_dir) = get_cli();
-    store_service(temp_dir.path(), "command = \"non-existent-command\"", None);
+    let service = "command = \"non-existent-command\"\ncommand-check = \"warn\"";
+    store_service(temp_dir.path(), service, None);
     cmd.assert().success().stderr(contains(
         "Program \"non-existent-command\" not found in any of the PATH directories",
     ));
@@ -132,7 +166,7 @@ pwd"#;
     store_service_script(temp_dir.path(), script, None, Some("a"));
     cmd.assert()
         .success()
-        .stdout(contains(&temp_dir.path().display().to_string()));
+        .stdout(contains(temp_dir.path().display().to_string()));
 }
 
 #[test]
@@ -163,6 +197,97 @@ echo "c""#;
     cmd.assert().success().stdout(contains("a\nb\nc"));
 }
 
+#[test]
+fn test_start_after_tcp() {
+    let (mut cmd, temp_dir) = get_cli();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let service = format!(
+        r#"start-after = [{{ tcp = "{}" }}]"#,
+        listener.local_addr().unwrap()
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(&service),
+        None,
+    );
+    cmd.assert().success().stdout(contains("started"));
+}
+
+#[test]
+fn test_start_after_tcp_timeout() {
+    let (mut cmd, temp_dir) = get_cli();
+    // Nothing is listening on this port anymore.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+    let service = format!(
+        r#"start-after = [{{ tcp = "{}", timeout = "1s" }}]"#,
+        address
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(&service),
+        None,
+    );
+    cmd.assert()
+        .success()
+        .stdout(contains("started").not())
+        .stderr(contains("gave up waiting"));
+}
+
+#[test]
+fn test_start_after_optional() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nexit 1",
+        None,
+        Some("a"),
+    );
+    let service = r#"start-after = [{ service = "a.toml", optional = true }]"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(service),
+        None,
+    );
+    cmd.assert().success().stdout(contains("started"));
+}
+
+#[test]
+fn test_start_after_provides() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho a",
+        Some(r#"provides = ["database"]"#),
+        Some("a"),
+    );
+    let service = r#"start-delay = "500millis"
+start-after = ["database"]"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho b",
+        Some(service),
+        None,
+    );
+    cmd.assert().success().stdout(contains("a\nb"));
+}
+
+#[test]
+fn test_core_dumps_disabled() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho \"core limit: $(ulimit -c)\"",
+        Some(r#"core-dumps = "disabled""#),
+        None,
+    );
+    cmd.assert().success().stdout(contains("core limit: 0"));
+}
+
 // Test user
 #[test]
 #[ignore]
@@ -195,3 +320,130 @@ start-delay = "10s"
     kill(recv.pid, Signal::SIGINT).expect("kill");
     recv.recv_or_kill(Duration::from_secs(5));
 }
+
+#[test]
+fn test_notify() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runtime_dir = temp_dir.path().join("run");
+    cmd.args(vec!["--runtime-dir", runtime_dir.to_str().unwrap()]);
+    let initialized = temp_dir.path().join("initialized");
+    // Ready only once it's initialized, a while after being spawned.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+sleep 1
+touch {}
+perl -MIO::Socket::UNIX -e 'IO::Socket::UNIX->new(Type => SOCK_DGRAM(), Peer => $ENV{{NOTIFY_SOCKET}})->send("READY=1")'
+sleep 1
+"#,
+        initialized.display()
+    );
+    store_service_script(
+        temp_dir.path(),
+        &script,
+        Some(r#"type = "notify""#),
+        Some("database"),
+    );
+    let script = format!(
+        r#"#!/usr/bin/env bash
+[ -f {} ] && echo "database initialized"
+"#,
+        initialized.display()
+    );
+    store_service_script(
+        temp_dir.path(),
+        &script,
+        Some(r#"start-after = ["database.toml"]"#),
+        None,
+    );
+    cmd.assert()
+        .success()
+        .stdout(contains("database initialized"));
+    assert!(!runtime_dir.join("notify/database.toml.sock").exists());
+}
+
+#[test]
+fn test_start_timeout() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runtime_dir = temp_dir.path().join("run");
+    cmd.args(vec!["--runtime-dir", runtime_dir.to_str().unwrap()]);
+    let script = r#"#!/usr/bin/env bash
+sleep 30
+"#;
+    let service = r#"type = "notify"
+start-timeout = "1s"
+"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+    cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
+    let recv = run_async(&mut cmd, false);
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_watch() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config = temp_dir.path().join("config.yaml");
+    let runs = temp_dir.path().join("runs");
+    std::fs::write(&config, "version: 1").unwrap();
+    let script = format!(
+        r#"#!/usr/bin/env bash
+cat {} >> {}
+echo >> {}
+while true ; do
+    sleep 1
+done
+"#,
+        config.display(),
+        runs.display(),
+        runs.display()
+    );
+    let service = format!(r#"watch = ["{}"]"#, config.display());
+    store_service_script(temp_dir.path(), &script, Some(&service), None);
+    let recv = run_async(&mut cmd, true);
+    sleep(Duration::from_secs(1));
+    std::fs::write(&config, "version: 2").unwrap();
+    sleep(Duration::from_secs(2));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    let runs = std::fs::read_to_string(&runs).unwrap();
+    assert_eq!(runs, "version: 1\nversion: 2\n");
+}
+
+#[test]
+fn test_restart_start_first() {
+    let (mut cmd, temp_dir) = get_cli();
+    let config = temp_dir.path().join("config.yaml");
+    let log = temp_dir.path().join("log");
+    std::fs::write(&config, "version: 1").unwrap();
+    let script = format!(
+        r#"#!/usr/bin/env bash
+version=$(cat {config})
+trap 'echo "stop $version" >> {log}; exit 0' TERM
+echo "start $version" >> {log}
+while true ; do
+    sleep 0.1
+done
+"#,
+        config = config.display(),
+        log = log.display()
+    );
+    let service = format!(
+        r#"watch = ["{}"]
+[restart]
+order = "start-first"
+"#,
+        config.display()
+    );
+    store_service_script(temp_dir.path(), &script, Some(&service), None);
+    let recv = run_async(&mut cmd, true);
+    sleep(Duration::from_secs(1));
+    std::fs::write(&config, "version: 2").unwrap();
+    sleep(Duration::from_secs(2));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    // The old process is stopped once the new one is running.
+    let log = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log,
+        "start version: 1\nstart version: 2\nstop version: 1\nstop version: 2\n"
+    );
+}
@@ -1,4 +1,6 @@
 mod utils;
+use assert_cmd::prelude::*;
+use predicates::str::contains;
 use std::io::{Read, Write};
 use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
 use std::sync::mpsc;
@@ -13,7 +15,7 @@ fn handle_requests(listener: TcpListener, stop: Receiver<()>) -> io::Result<()>
         match stream {
             Ok(mut stream) => {
                 let mut buffer = [0; 512];
-                stream.read(&mut buffer)?;
+                let _ = stream.read(&mut buffer)?;
                 let response = b"HTTP/1.1 200 OK\r\n\r\n";
                 stream.write_all(response).expect("Stream write");
             }
@@ -58,8 +60,8 @@ http-endpoint = "{}""#,
         handle_requests(listener, sl_receiver).unwrap();
         sender.send(()).expect("Chan closed");
     });
-    let mut cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
-    run_async(&mut cmd, true).recv_or_kill(Duration::from_secs(15));
+    let cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
+    run_async(cmd, true).recv_or_kill(Duration::from_secs(15));
     stop_listener.send(()).unwrap();
     receiver
         .recv_timeout(Duration::from_millis(3000))
@@ -86,7 +88,47 @@ file-path = "{}""#,
     exit 0;
     "#;
     store_service_script(tempdir.path(), script, Some(service.as_str()), None);
-    let mut cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
-    run_async(&mut cmd, true).recv_or_kill(Duration::from_secs(70));
+    let cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
+    run_async(cmd, true).recv_or_kill(Duration::from_secs(70));
+    Ok(())
+}
+
+#[test]
+fn test_healthcheck_external() -> io::Result<()> {
+    let (mut cmd, tempdir) = get_cli();
+    let check = tempdir.path().join("check.sh");
+    std::fs::write(
+        &check,
+        r#"read request
+if [ -f ready ]; then
+  echo '{"healthy": true}'
+else
+  echo '{"healthy": false, "message": "not ready yet"}'
+fi"#,
+    )?;
+    let service = format!(
+        r#"
+[restart]
+strategy = "never"
+[healthiness]
+external = "/bin/sh {}""#,
+        check.display()
+    );
+    let script = r#"#!/usr/bin/env bash
+    sleep 1
+    touch ready
+    sleep 3
+    "#;
+    store_service_script(tempdir.path(), script, Some(service.as_str()), Some("a"));
+    let script = r#"#!/usr/bin/env bash
+    echo "b started"
+    "#;
+    store_service_script(
+        tempdir.path(),
+        script,
+        Some(r#"start-after = ["a.toml"]"#),
+        Some("b"),
+    );
+    cmd.assert().success().stdout(contains("b started"));
     Ok(())
 }
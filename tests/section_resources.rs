@@ -0,0 +1,85 @@
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+use predicates::str::contains;
+
+#[allow(dead_code)]
+mod utils;
+use utils::{get_cli, store_service_script};
+
+#[test]
+fn test_resources_not_applied() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    // Either the io controller is not available, or /dev/null is not a block device: either way,
+    // the service doesn't run without its limits.
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[resources]
+io-max = [{ device = "/dev/null", rbps = "1 MB" }]
+"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho started",
+        Some(service),
+        None,
+    );
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .failure()
+        .stdout(contains("started").not());
+}
+
+#[test]
+fn test_resources_devices() {
+    if !nix::unistd::geteuid().is_root() || !std::path::Path::new("/dev/net/tun").exists() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+: < /dev/null && echo "null: allowed"
+: < /dev/net/tun && echo "tun: allowed"
+: < /dev/kmsg && echo "kmsg: allowed"
+: > /dev/net/tun && echo "tun write: allowed"
+"#;
+    let service = r#"[resources]
+devices = ["/dev/net/tun r"]
+"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("null: allowed"))
+        .stdout(contains("tun: allowed"))
+        .stdout(contains("kmsg: allowed").not())
+        .stdout(contains("tun write: allowed").not());
+}
+
+#[test]
+fn test_limits_cpu_time() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho \"cpu limit: $(ulimit -t)\"",
+        Some("[limits]\ncpu-time = \"5m\""),
+        None,
+    );
+    cmd.assert().success().stdout(contains("cpu limit: 300"));
+}
+
+#[test]
+fn test_limits_wall_time() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"[limits]
+wall-time = "1s"
+[termination]
+wait = "1s"
+"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nsleep 30",
+        Some(service),
+        None,
+    );
+    cmd.args(vec!["--unsuccessful-exit-finished-failed"])
+        .assert()
+        .failure();
+}
@@ -132,7 +132,7 @@ fn test_restart_always_killed_by_signals() -> Result<(), std::io::Error> {
         SIGSEGV, SIGSYS, SIGTERM, SIGTRAP, SIGUSR1, SIGUSR2, SIGVTALRM, SIGXCPU, SIGXFSZ,
     ];
     for sig in DEFAULT_TERMINATE {
-        test_restart_always_signal(sig as i32)?;
+        test_restart_always_signal(sig)?;
     }
     Ok(())
 }
@@ -160,3 +160,62 @@ strategy = "always"
 
     Ok(())
 }
+
+#[test]
+fn test_max_runtime() {
+    let (cmd, temp_dir) = get_cli();
+    let mut cmd = Command::from_std(cmd);
+    // Runs until it's recycled the first time, and exits right away the second time.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+if [ ! -f {0} ]; then
+    touch {0}
+    echo "first"
+    sleep 30
+fi
+echo "second"
+"#,
+        temp_dir.path().join("file.temp").display()
+    );
+    let service = r#"max-runtime = "1s"
+[failure]
+strategy = "shutdown"
+"#;
+    store_service_script(temp_dir.path(), &script, Some(service), None);
+    cmd.args(vec!["--unsuccessful-exit-finished-failed"])
+        .timeout(Duration::from_secs(15))
+        .assert()
+        .success()
+        .stdout("first\nsecond\n");
+}
+
+#[test]
+fn test_restart_window() {
+    let (cmd, temp_dir) = get_cli();
+    let mut cmd = Command::from_std(cmd);
+    // A window which is closed for the next hour.
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let hour = (now / 3600 + 2) % 24;
+    let service = format!(
+        r#"
+[restart]
+strategy = "always"
+window = "{:02}:00-{:02}:00 UTC"
+"#,
+        hour,
+        (hour + 1) % 24
+    );
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho restarting",
+        Some(&service),
+        None,
+    );
+    cmd.timeout(Duration::from_millis(2000))
+        .assert()
+        .failure()
+        .stdout("restarting\n");
+}
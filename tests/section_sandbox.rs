@@ -0,0 +1,121 @@
+use std::process::Command;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use nix::sys::signal::{kill, Signal};
+use predicates::str::contains;
+
+#[allow(dead_code)]
+mod utils;
+use utils::{get_cli, run_async, store_service_script};
+
+#[test]
+fn test_sandbox_protect_system() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    let protected = temp_dir.path().join("protected");
+    let writable = protected.join("writable");
+    std::fs::create_dir_all(&writable).unwrap();
+    let script = format!(
+        r#"#!/usr/bin/env bash
+touch {protected}/file && echo "protected: writable" || echo "protected: read-only"
+touch {writable}/file && echo "writable: writable" || echo "writable: read-only"
+"#,
+        protected = protected.display(),
+        writable = writable.display()
+    );
+    let service = format!(
+        r#"[sandbox]
+protect-system = true
+read-only-paths = ["{}"]
+read-write-paths = ["{}"]
+"#,
+        protected.display(),
+        writable.display()
+    );
+    store_service_script(temp_dir.path(), &script, Some(&service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("protected: read-only"))
+        .stdout(contains("writable: writable"));
+    // Horust's own mount namespace is not affected.
+    assert!(!protected.join("file").exists());
+    std::fs::write(protected.join("file"), "").unwrap();
+}
+
+#[test]
+fn test_sandbox_pid_namespace() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    // The background process is killed with the namespace, when the service exits.
+    let script = r#"#!/usr/bin/env bash
+sleep 987654 &
+echo "pid: $$"
+echo "processes: $(ls -d /proc/[0-9]* | wc -l)"
+"#;
+    let service = r#"[sandbox]
+pid-namespace = true
+"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("pid: 1\n"))
+        // bash, sleep, and the subshell running ls and wc.
+        .stdout(contains("processes: 5\n"));
+    let leftover = Command::new("pgrep")
+        .args(["-f", "sleep 987654"])
+        .output()
+        .unwrap();
+    assert!(!leftover.status.success());
+}
+
+#[test]
+fn test_sandbox_pid_namespace_termination() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    // As the init of its namespace, `sleep` would ignore the termination signal.
+    let service = r#"[sandbox]
+pid-namespace = true
+[termination]
+wait = "30s"
+"#;
+    store_service_script(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\nexec sleep 100",
+        Some(service),
+        None,
+    );
+    let recv = run_async(&mut cmd, true);
+    std::thread::sleep(Duration::from_secs(1));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_sandbox_hostname() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+echo "hostname: $(hostname)"
+echo "HOSTNAME: ${HOSTNAME}"
+"#;
+    let service = r#"[sandbox]
+hostname = "worker-%i"
+"#;
+    store_service_script(temp_dir.path(), script, Some(service), Some("worker@2"));
+    let own_hostname = nix::unistd::gethostname().unwrap();
+    cmd.assert()
+        .success()
+        .stdout(contains("hostname: worker-2\n"))
+        .stdout(contains("HOSTNAME: worker-2\n"));
+    // Horust's own UTS namespace is not affected.
+    assert_eq!(nix::unistd::gethostname().unwrap(), own_hostname);
+}
@@ -0,0 +1,62 @@
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+
+#[allow(dead_code)]
+mod utils;
+use utils::{get_cli, run_async, store_service_script};
+
+#[test]
+fn test_socket_listen() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("service.sock");
+    let script = r#"#!/usr/bin/env bash
+echo "fds: $LISTEN_FDS"
+[ "$LISTEN_PID" = "$$" ] && echo "pid: ok"
+[ -S /proc/self/fd/3 ] && echo "fd 3: socket"
+"#;
+    let service = format!(
+        r#"[socket]
+listen = ["{}"]
+"#,
+        socket.display()
+    );
+    store_service_script(temp_dir.path(), script, Some(&service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("fds: 1\n"))
+        .stdout(contains("pid: ok"))
+        .stdout(contains("fd 3: socket"));
+    // Removed once Horust is done.
+    assert!(!socket.exists());
+}
+
+#[test]
+fn test_socket_lazy() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket = temp_dir.path().join("admin.sock");
+    let started = temp_dir.path().join("started");
+    let script = format!(
+        r#"#!/usr/bin/env bash
+touch {}
+"#,
+        started.display()
+    );
+    let service = format!(
+        r#"[socket]
+listen = ["{}"]
+lazy = true
+"#,
+        socket.display()
+    );
+    store_service_script(temp_dir.path(), &script, Some(&service), None);
+    let recv = run_async(&mut cmd, true);
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(!started.exists(), "spawned before any connection");
+
+    let _connection = UnixStream::connect(&socket).unwrap();
+    recv.recv_or_kill(Duration::from_secs(10));
+    assert!(started.exists());
+}
@@ -119,3 +119,46 @@ exit 1
     let recv = run_async(&mut cmd, true);
     recv.recv_or_kill(Duration::from_secs(10));
 }
+
+#[test]
+fn test_termination_pre_stop() {
+    let (mut cmd, temp_dir) = get_cli();
+    // Exits successfully only if the pre-stop hook has run before the termination signal.
+    let script = r#"#!/usr/bin/env bash
+trap 'test -f drained && exit 0 || exit 1' TERM
+while true ; do
+    sleep 0.3
+done
+"#;
+    let service = r#"[termination]
+wait = "10s"
+pre-stop = { command = "/usr/bin/env touch drained" }"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+
+    let recv = run_async(&mut cmd, true);
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_termination_kill_mode() {
+    let (mut cmd, temp_dir) = get_cli();
+    // The child doesn't die with bash, unless it's signaled too.
+    let script = r#"#!/usr/bin/env bash
+sleep 987653 &
+wait
+"#;
+    let service = r#"[termination]
+kill-mode = "process-group"
+send-sigcont = true"#;
+    store_service_script(temp_dir.path(), script, Some(service), None);
+
+    let recv = run_async(&mut cmd, true);
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    let leftover = std::process::Command::new("pgrep")
+        .args(["-f", "sleep 987653"])
+        .output()
+        .unwrap();
+    assert!(!leftover.status.success());
+}
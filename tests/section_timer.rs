@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use nix::sys::signal::{kill, Signal};
+
+#[allow(dead_code)]
+mod utils;
+use utils::{get_cli, run_async, store_service_script};
+
+#[test]
+fn test_timer() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {}
+"#,
+        runs.display()
+    );
+    let service = r#"[timer]
+on-calendar = "*-*-* *:*:*"
+"#;
+    store_service_script(temp_dir.path(), &script, Some(service), None);
+    let recv = run_async(&mut cmd, true);
+    std::thread::sleep(Duration::from_secs(3));
+    // Still waiting for the next trigger, once it has finished.
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    let runs = std::fs::read_to_string(runs).unwrap();
+    assert!(runs.lines().count() >= 2, "{}", runs);
+}
+
+#[test]
+fn test_timer_persistent() {
+    let (mut cmd, temp_dir) = get_cli();
+    let state_dir = temp_dir.path().join("state");
+    std::fs::create_dir(&state_dir).unwrap();
+    let ran = temp_dir.path().join("ran");
+    let script = format!(
+        r#"#!/usr/bin/env bash
+touch {}
+"#,
+        ran.display()
+    );
+    let service = r#"[timer]
+on-calendar = "yearly"
+persistent = true
+"#;
+    let service_name = store_service_script(temp_dir.path(), &script, Some(service), None);
+    // It has last triggered at the start of 2000: the next triggers were missed.
+    std::fs::write(
+        state_dir.join("timers.json"),
+        format!(r#"{{"{}": 946684800}}"#, service_name),
+    )
+    .unwrap();
+    cmd.args(["--state-dir", state_dir.to_str().unwrap()]);
+    let recv = run_async(&mut cmd, true);
+    std::thread::sleep(Duration::from_secs(2));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+    assert!(ran.exists(), "the missed trigger didn't happen");
+    let persisted = std::fs::read_to_string(state_dir.join("timers.json")).unwrap();
+    assert!(!persisted.contains("946684800"), "{}", persisted);
+}